@@ -69,6 +69,56 @@ impl Counter {
     }
 }
 
+/// The prometheus histogram family used by [`Histogram`].
+#[cfg(feature = "metrics")]
+pub type HistogramFamily = prometheus_client::metrics::family::Family<
+    Vec<(String, String)>,
+    prometheus_client::metrics::histogram::Histogram,
+    fn() -> prometheus_client::metrics::histogram::Histogram,
+>;
+
+/// Open Metrics [`Histogram`] to measure distributions, e.g. latencies.
+///
+/// Each observation can carry labels, there is one distribution per distinct set of
+/// labels.  The buckets are suitable for durations in seconds, from 1ms to about 16s.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    /// The actual prometheus histograms, one per label set.
+    #[cfg(feature = "metrics")]
+    pub histogram: HistogramFamily,
+    /// What this histogram measures.
+    pub description: &'static str,
+}
+
+impl Histogram {
+    /// Constructs a new histogram, based on the given `description`.
+    pub fn new(description: &'static str) -> Self {
+        Histogram {
+            #[cfg(feature = "metrics")]
+            histogram: HistogramFamily::new_with_constructor(|| {
+                prometheus_client::metrics::histogram::Histogram::new(
+                    prometheus_client::metrics::histogram::exponential_buckets(0.001, 2.0, 15),
+                )
+            }),
+            description,
+        }
+    }
+
+    /// Records a value in the distribution of the given `labels`.
+    pub fn observe(&self, labels: &[(&str, &str)], value: f64) {
+        #[cfg(feature = "metrics")]
+        {
+            let labels: Vec<_> = labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            self.histogram.get_or_create(&labels).observe(value);
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = (labels, value);
+    }
+}
+
 /// Description of a group of metrics.
 pub trait Metric:
     Default + struct_iterable::Iterable + Sized + std::fmt::Debug + 'static + Send + Sync
@@ -82,6 +132,8 @@ pub trait Metric:
         for (metric, counter) in this.iter() {
             if let Some(counter) = counter.downcast_ref::<Counter>() {
                 sub_registry.register(metric, counter.description, counter.counter.clone());
+            } else if let Some(histogram) = counter.downcast_ref::<Histogram>() {
+                sub_registry.register(metric, histogram.description, histogram.histogram.clone());
             }
         }
         this
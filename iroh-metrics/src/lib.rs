@@ -28,3 +28,11 @@ macro_rules! inc_by {
         <$m as $crate::core::Metric>::with_metric(|m| m.$f.inc_by($n));
     };
 }
+
+/// Record a value with the given labels in the histogram.
+#[macro_export]
+macro_rules! observe {
+    ($m:ty, $f:ident, $labels:expr, $v:expr) => {
+        <$m as $crate::core::Metric>::with_metric(|m| m.$f.observe($labels, $v));
+    };
+}
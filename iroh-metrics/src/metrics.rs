@@ -8,6 +8,7 @@
 //!
 //! - To increment a **counter**, use the [`crate::inc`] macro with a value.
 //! - To increment a **counter** by 1, use the [`crate::inc_by`] macro.
+//! - To record a value in a **histogram**, use the [`crate::observe`] macro.
 //!
 //! To expose the metrics, start the metrics service with `start_metrics_server()`.
 //!
@@ -50,6 +50,7 @@ pub fn default_na_derp_region() -> DerpRegion {
         nodes: vec![default_n0_derp],
         avoid: false,
         region_code: "default-1".into(),
+        stun_integrity: None,
     }
 }
 
@@ -71,5 +72,6 @@ pub fn default_eu_derp_region() -> DerpRegion {
         nodes: vec![default_n0_derp],
         avoid: false,
         region_code: "default-2".into(),
+        stun_integrity: None,
     }
 }
@@ -96,6 +96,7 @@ mod tests {
                 ipv6: UseIpv6::Disabled,
             }],
             region_code: "test_region".to_string(),
+            stun_integrity: None,
         };
 
         // create clients
@@ -239,6 +240,7 @@ mod tests {
                 ipv6: UseIpv6::Disabled,
             }],
             region_code: "test_region".to_string(),
+            stun_integrity: None,
         };
 
         // create clients
@@ -1199,6 +1199,7 @@ mod tests {
                 ipv6: UseIpv6::Disabled,
             }],
             region_code: "test_region".to_string(),
+            stun_integrity: None,
         };
 
         let client = ClientBuilder::new()
@@ -10,6 +10,7 @@ use serde::{Deserialize, Serialize};
 use url::Url;
 
 use crate::defaults::DEFAULT_DERP_STUN_PORT;
+use crate::stun::IntegrityKeys;
 
 /// Configuration of all the Derp servers that can be used.
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
@@ -57,6 +58,7 @@ impl DerpMap {
                 }],
                 avoid: false,
                 region_code: "default".into(),
+                stun_integrity: None,
             },
         );
 
@@ -103,6 +105,12 @@ pub struct DerpRegion {
     pub avoid: bool,
     /// The region-specific string identifier
     pub region_code: String,
+    /// The shared secret keys for STUN message integrity with the nodes of this region.
+    ///
+    /// When set, STUN requests to this region are signed and responses are only accepted
+    /// with a valid MESSAGE-INTEGRITY.
+    #[serde(default)]
+    pub stun_integrity: Option<IntegrityKeys>,
 }
 
 impl DerpRegion {
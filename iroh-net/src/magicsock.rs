@@ -1568,6 +1568,9 @@ impl Actor {
             self.no_v4_send, !r.ipv4_can_send
         );
         self.no_v4_send = !r.ipv4_can_send;
+        if !r.udp {
+            info!(verdict = %r.udp_verdict, "UDP is not working, relying on DERP");
+        }
 
         let have_port_map = self.port_mapper.watch_external_address().borrow().is_some();
         let mut ni = config::NetInfo {
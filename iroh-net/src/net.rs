@@ -2,3 +2,57 @@
 
 pub mod interfaces;
 pub mod ip;
+
+use std::io;
+
+/// Whether an error receiving on a datagram socket only affects a single packet, the
+/// socket can still be used afterwards.
+///
+/// Includes errors of earlier packets reported on the socket, e.g. `WSAECONNRESET` on
+/// Windows, and a shortage of buffers.
+pub(crate) fn is_transient_recv_error(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    if let Some(code) = err.raw_os_error() {
+        if matches!(
+            code,
+            libc::ENOBUFS
+                | libc::ENOMEM
+                | libc::EAGAIN
+                | libc::EINTR
+                | libc::ECONNREFUSED
+                | libc::EHOSTUNREACH
+                | libc::ENETUNREACH
+                | libc::EMSGSIZE
+        ) {
+            return true;
+        }
+    }
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transient_recv_errors() {
+        let transient = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert!(is_transient_recv_error(&transient));
+        let fatal = io::Error::from(io::ErrorKind::InvalidInput);
+        assert!(!is_transient_recv_error(&fatal));
+        #[cfg(unix)]
+        {
+            let nobufs = io::Error::from_raw_os_error(libc::ENOBUFS);
+            assert!(is_transient_recv_error(&nobufs));
+            let badf = io::Error::from_raw_os_error(libc::EBADF);
+            assert!(!is_transient_recv_error(&badf));
+        }
+    }
+}
@@ -2,35 +2,50 @@
 //!
 //! Based on <https://github.com/tailscale/tailscale/blob/main/net/netcheck/netcheck.go>
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::{self, Debug};
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use anyhow::{anyhow, Context as _, Result};
 use bytes::Bytes;
 use iroh_metrics::inc;
+use serde::{Deserialize, Serialize};
 use tokio::net::UdpSocket;
-use tokio::sync::{self, mpsc, oneshot};
+use tokio::sync::{self, broadcast, mpsc, oneshot, watch};
 use tokio::time::{Duration, Instant};
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 
-use crate::net::ip::to_canonical;
+use crate::net::interfaces;
+use crate::net::ip::{is_private_v6, to_canonical};
 use crate::util::CancelOnDrop;
 
 use super::derp::DerpMap;
 use super::portmapper;
 use super::stun;
 
+mod diff;
 mod metrics;
 mod reportgen;
+mod store;
 
+pub use diff::{ReportChange, ReportChanges};
 pub use metrics::Metrics;
+pub use reportgen::{
+    AddressFamilies, CaptivePortalConfig, CaptivePortalDetails, CaptivePortalEndpoint,
+    DefaultProbePlanner, EnoughRegions, PreferredDerpMargin, Probe, ProbeBudget, ProbePlan,
+    ProbePlanner, ProbeProto, ProbeSet, ProbingStopReason, ReportEvent, ReportOptions, StunBind,
+};
+pub use store::{FileReportStore, ReportStore};
 use Metrics as NetcheckMetrics;
 
 const FULL_REPORT_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
+/// How many [`ReportEvent`]s are buffered for slow subscribers before the oldest are dropped.
+const REPORT_EVENTS_CAPACITY: usize = 64;
+
 /// The maximum latency of all regions, if none are found yet.
 ///
 /// Normally the max latency of all regions is computed, but if we don't yet know any region
@@ -43,10 +58,14 @@ const DEFAULT_MAX_LATENCY: Duration = Duration::from_millis(100);
 /// A netcheck report.
 ///
 /// Can be obtained by calling [`Client::get_report`].
-#[derive(Default, Debug, PartialEq, Eq, Clone)]
+#[derive(Default, Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct Report {
     /// A UDP STUN round trip completed.
     pub udp: bool,
+    /// Whether UDP works and if not, the most likely reason why.
+    ///
+    /// Unlike [`Report::udp`] this tells apart the reasons UDP is not working.
+    pub udp_verdict: UdpVerdict,
     /// An IPv6 STUN round trip completed.
     pub ipv6: bool,
     /// An IPv4 STUN round trip completed.
@@ -55,32 +74,147 @@ pub struct Report {
     pub ipv6_can_send: bool,
     /// an IPv4 packet was able to be sent
     pub ipv4_can_send: bool,
+    /// IPv4 was not probed, see [`ReportOptions::address_families`].
+    ///
+    /// The IPv4 results of this report, e.g. [`Report::ipv4_can_send`], are then unknown
+    /// rather than negative.
+    pub ipv4_not_probed: bool,
+    /// IPv6 was not probed, see [`ReportOptions::address_families`].
+    pub ipv6_not_probed: bool,
     /// could bind a socket to ::1
     pub os_has_ipv6: bool,
+    /// The OS has a route to the IPv6 internet, with a usable source address.
+    ///
+    /// Unlike [`Report::os_has_ipv6`], which is also `true` for hosts with only link-local
+    /// IPv6 addresses.  IPv6 probes are only sent if this is `true`.
+    pub os_has_ipv6_route: bool,
     /// an ICMPv4 round trip completed
     pub icmpv4: bool,
+    /// an ICMPv6 round trip completed
+    pub icmpv6: bool,
+    /// Our own host refused to send STUN packets, e.g. because of a local firewall.
+    ///
+    /// Unlike `!udp`, which could also mean UDP is filtered by the network.
+    pub udp_blocked_locally: bool,
     /// Whether STUN results depend which STUN server you're talking to (on IPv4).
     pub mapping_varies_by_dest_ip: Option<bool>,
+    /// The NAT's mapping and filtering behavior on IPv4, from the RFC 5780 tests.
+    ///
+    /// Only set if [`ReportOptions::nat_behavior`] is enabled and the tested DERP node's
+    /// STUN server supports RFC 5780.
+    pub nat_behavior: Option<stun::nat_behavior::NatBehavior>,
     /// Whether the router supports communicating between two local devices through the NATted
-    /// public IP address (on IPv4).
+    /// public IP address.
+    ///
+    /// This combines [`Report::hair_pinning_v4`] and [`Report::hair_pinning_v6`], it is
+    /// `true` if hairpinning works for either address family.
     pub hair_pinning: Option<bool>,
+    /// Whether hairpinning works for our public IPv4 address.
+    pub hair_pinning_v4: Option<bool>,
+    /// Whether hairpinning works for our public IPv6 address.
+    pub hair_pinning_v6: Option<bool>,
     /// Probe indicating the presence of port mapping protocols on the LAN.
     pub portmap_probe: Option<portmapper::ProbeOutput>,
     /// `0` for unknown
     pub preferred_derp: u16,
+    /// How much lower the latency of the preferred region is than that of the next fastest
+    /// region.
+    ///
+    /// `None` if there is only one region or [`Report::preferred_derp_kept`] is set.
+    pub preferred_derp_lead: Option<Duration>,
+    /// The previous preferred region was kept although another region was faster.
+    ///
+    /// The other region was not faster by [`ReportOptions::preferred_derp_margin`].
+    pub preferred_derp_kept: bool,
     /// keyed by DERP Region ID
     pub region_latency: RegionLatencies,
     /// keyed by DERP Region ID
     pub region_v4_latency: RegionLatencies,
     /// keyed by DERP Region ID
     pub region_v6_latency: RegionLatencies,
+    /// keyed by DERP node name
+    pub node_latency: NodeLatencies,
+    /// keyed by DERP node name
+    pub node_v4_latency: NodeLatencies,
+    /// keyed by DERP node name
+    pub node_v6_latency: NodeLatencies,
+    /// The DERP node addresses which answered IPv4 probes, keyed by DERP node name.
+    ///
+    /// Nodes can have several addresses, the next report tries these first.
+    pub node_v4_addrs: HashMap<String, SocketAddr>,
+    /// The DERP node addresses which answered IPv6 probes, keyed by DERP node name.
+    pub node_v6_addrs: HashMap<String, SocketAddr>,
     /// ip:port of global IPv4
     pub global_v4: Option<SocketAddr>,
     /// `[ip]:port` of global IPv6
     pub global_v6: Option<SocketAddr>,
+    /// All distinct global IPv4 endpoints observed, with the DERP node observing them.
+    ///
+    /// Unlike [`Report::global_v4`] this contains all endpoints of multi-homed hosts or
+    /// networks with multiple egress paths.
+    pub global_v4_endpoints: ObservedEndpoints,
+    /// All distinct global IPv6 endpoints observed, with the DERP node observing them.
+    pub global_v6_endpoints: ObservedEndpoints,
+    /// The local address of the IPv4 STUN socket the measurements were taken from.
+    pub stun_local_v4: Option<SocketAddr>,
+    /// The local address of the IPv6 STUN socket the measurements were taken from.
+    pub stun_local_v6: Option<SocketAddr>,
+    /// The network interface netcheck bound its STUN sockets to, see [`StunBind`].
+    ///
+    /// `None` if no STUN socket could be bound to the interface.
+    pub stun_interface: Option<String>,
+    /// Why probes failed, for each region and protocol without a successful probe.
+    ///
+    /// This helps explaining why a region has no latency or why [`Report::udp`] is
+    /// `false`.
+    pub probe_failures: ProbeFailures,
     /// CaptivePortal is set when we think there's a captive portal that is
     /// intercepting HTTP traffic.
     pub captive_portal: Option<bool>,
+    /// What was observed if a captive portal was detected.
+    ///
+    /// This can be used to point users to the captive portal's login page.
+    pub captive_portal_details: Option<CaptivePortalDetails>,
+    /// The report is incomplete.
+    ///
+    /// Report generation hit the overall timeout before all probes finished, the report
+    /// only contains what was learned until then.
+    pub partial: bool,
+    /// When the report was generated, `None` for reports not generated by netcheck.
+    pub generated_at: Option<SystemTime>,
+    /// How long generating the report took.
+    pub probe_duration: Duration,
+    /// When the latency of each region in [`Report::region_latency`] was last measured.
+    pub region_measured_at: HashMap<u16, SystemTime>,
+    /// When the report was generated, on the monotonic clock.
+    ///
+    /// Not stored, reports loaded from a [`ReportStore`] fall back to
+    /// [`Report::generated_at`].
+    #[serde(skip)]
+    generated_instant: Option<Instant>,
+}
+
+impl Report {
+    /// Returns how long ago the report was generated.
+    ///
+    /// This uses the monotonic clock unless the report was loaded from a [`ReportStore`],
+    /// in which case the wall clock is used.  Returns `None` if the report was not
+    /// generated by netcheck, or was generated in the future according to the wall clock.
+    pub fn age(&self) -> Option<Duration> {
+        match self.generated_instant {
+            Some(instant) => Some(instant.elapsed()),
+            None => self
+                .generated_at
+                .and_then(|generated_at| SystemTime::now().duration_since(generated_at).ok()),
+        }
+    }
+
+    /// Marks the report as generated now.
+    fn set_generated(&mut self, probe_duration: Duration) {
+        self.generated_at = Some(SystemTime::now());
+        self.generated_instant = Some(Instant::now());
+        self.probe_duration = probe_duration;
+    }
 }
 
 impl fmt::Display for Report {
@@ -90,7 +224,7 @@ impl fmt::Display for Report {
 }
 
 /// Latencies per DERP Region.
-#[derive(Debug, Default, PartialEq, Eq, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct RegionLatencies(HashMap<u16, Duration>);
 
 impl RegionLatencies {
@@ -144,6 +278,221 @@ impl RegionLatencies {
     }
 }
 
+/// Latencies per DERP node.
+///
+/// Unlike [`RegionLatencies`] this allows telling apart the individual nodes of a region.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct NodeLatencies(HashMap<String, Duration>);
+
+impl NodeLatencies {
+    /// Updates a node's latency, if it is faster than before.
+    fn update_node(&mut self, node_name: &str, latency: Duration) {
+        match self.0.get_mut(node_name) {
+            Some(val) => {
+                if latency < *val {
+                    *val = latency;
+                }
+            }
+            None => {
+                self.0.insert(node_name.to_string(), latency);
+            }
+        }
+    }
+
+    /// Returns an iterator over all the nodes and their latencies.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Duration)> + '_ {
+        self.0.iter().map(|(k, v)| (k.as_str(), *v))
+    }
+
+    /// Returns the latency of the node with the given name.
+    pub fn get(&self, node_name: &str) -> Option<Duration> {
+        self.0.get(node_name).copied()
+    }
+
+    /// Returns the number of nodes with a latency.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether there are no node latencies.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Public endpoints observed by DERP nodes using STUN.
+///
+/// Each distinct pair of endpoint and DERP node name is only stored once.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ObservedEndpoints(BTreeSet<(SocketAddr, String)>);
+
+impl ObservedEndpoints {
+    /// Records an endpoint observed by a DERP node.
+    fn insert(&mut self, addr: SocketAddr, node_name: &str) {
+        self.0.insert((addr, node_name.to_string()));
+    }
+
+    /// Returns an iterator over all the endpoints and the DERP nodes observing them.
+    pub fn iter(&self) -> impl Iterator<Item = (SocketAddr, &str)> + '_ {
+        self.0.iter().map(|(addr, node)| (*addr, node.as_str()))
+    }
+
+    /// Returns the distinct endpoints, regardless of which DERP nodes observed them.
+    pub fn addrs(&self) -> BTreeSet<SocketAddr> {
+        self.0.iter().map(|(addr, _)| *addr).collect()
+    }
+
+    /// Whether the observed endpoint depends on the DERP node it is observed by.
+    ///
+    /// `None` if fewer than two DERP nodes observed an endpoint.
+    fn mapping_varies(&self) -> Option<bool> {
+        if self.addrs().len() > 1 {
+            Some(true)
+        } else if self.0.len() > 1 {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of observed endpoint and DERP node pairs.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether no endpoints were observed.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Whether UDP works according to a [`Report`], see [`Report::udp_verdict`].
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, derive_more::Display,
+)]
+pub enum UdpVerdict {
+    /// A STUN round trip completed.
+    #[display("working")]
+    Working,
+    /// There were no STUN sockets to probe with.
+    #[display("no local sockets")]
+    NoLocalSockets,
+    /// Our own host refused to send the STUN packets, e.g. because of a local firewall.
+    #[display("send failed locally")]
+    SendFailedLocally,
+    /// STUN packets were sent but never answered, UDP is likely blocked by the network.
+    #[default]
+    #[display("no replies received")]
+    NoRepliesReceived,
+}
+
+/// The maximum number of entries in [`ProbeFailures`].
+const MAX_PROBE_FAILURES: usize = 64;
+
+/// Why the probes of a region and protocol did not measure a latency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, derive_more::Display)]
+pub enum ProbeFailureKind {
+    /// The probe could not be sent.
+    #[display("send failed")]
+    SendFailed,
+    /// No reply was received before probing stopped.
+    #[display("no reply")]
+    NoReply,
+    /// The address of the DERP node could not be resolved.
+    #[display("dns failure")]
+    Dns,
+    /// The STUN server answered with an error response.
+    #[display("server error")]
+    ServerError,
+    /// The probes were aborted because they would no longer improve the report.
+    #[display("aborted")]
+    Aborted,
+    /// Any other failure, e.g. no socket was available for the probe's address family.
+    #[display("other")]
+    Other,
+    /// A router on the path dropped the ICMP echo request as its TTL ran out, e.g. in a
+    /// routing loop.
+    ///
+    /// Not seen with Linux ping sockets, see [`crate::ping::PingError::TimeExceeded`].
+    #[display("time exceeded")]
+    TimeExceeded,
+}
+
+/// The failed probes of one region and protocol, see [`ProbeFailures`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProbeFailure {
+    /// The DERP region probed.
+    pub region_id: u16,
+    /// The protocol of the probes.
+    pub proto: ProbeProto,
+    /// Why the last probe failed.
+    pub kind: ProbeFailureKind,
+    /// How many probes failed.
+    pub count: u32,
+}
+
+/// The probe failures of a report, see [`Report::probe_failures`].
+///
+/// This holds at most one entry for each region and protocol, and at most
+/// `MAX_PROBE_FAILURES` entries in total.  Failures which did not fit are only counted.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ProbeFailures {
+    failures: Vec<ProbeFailure>,
+    dropped: u32,
+}
+
+impl ProbeFailures {
+    /// Records *count* failed probes of a region and protocol.
+    fn record(&mut self, region_id: u16, proto: ProbeProto, kind: ProbeFailureKind, count: u32) {
+        match self
+            .failures
+            .iter_mut()
+            .find(|f| f.region_id == region_id && f.proto == proto)
+        {
+            Some(failure) => {
+                failure.kind = kind;
+                failure.count += count;
+            }
+            None if self.failures.len() < MAX_PROBE_FAILURES => {
+                self.failures.push(ProbeFailure {
+                    region_id,
+                    proto,
+                    kind,
+                    count,
+                });
+            }
+            None => self.dropped += count,
+        }
+    }
+
+    /// Returns the failure of a region and protocol, if any was recorded.
+    pub fn get(&self, region_id: u16, proto: ProbeProto) -> Option<&ProbeFailure> {
+        self.failures
+            .iter()
+            .find(|f| f.region_id == region_id && f.proto == proto)
+    }
+
+    /// Returns an iterator over the failures, in the order they were recorded.
+    pub fn iter(&self) -> impl Iterator<Item = &ProbeFailure> + '_ {
+        self.failures.iter()
+    }
+
+    /// Returns the number of failed probes which were not recorded because of the limit.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Returns the number of regions and protocols with failures.
+    pub fn len(&self) -> usize {
+        self.failures.len()
+    }
+
+    /// Whether no probes failed.
+    pub fn is_empty(&self) -> bool {
+        self.failures.is_empty() && self.dropped == 0
+    }
+}
+
 /// Client to run netchecks.
 ///
 /// Creating this creates a netcheck actor which runs in the background.  Most of the time
@@ -162,6 +511,10 @@ pub struct Client {
     /// If all senders are dropped, in other words all clones of this struct are dropped,
     /// the actor will terminate.
     addr: Addr,
+    /// Sender of the progress events, used to create new subscriptions.
+    events: broadcast::Sender<ReportEvent>,
+    /// The most recent report of the actor.
+    last_report: watch::Receiver<Option<Arc<Report>>>,
     /// Ensures the actor is terminated when the client is dropped.
     _drop_guard: Arc<CancelOnDrop>,
 }
@@ -195,17 +548,59 @@ impl Client {
     /// This starts a connected actor in the background.  Once the client is dropped it will
     /// stop running.
     pub async fn new(port_mapper: Option<portmapper::Client>) -> Result<Self> {
-        let mut actor = Actor::new(port_mapper)?;
+        Self::with_options(port_mapper, ReportOptions::default()).await
+    }
+
+    /// Creates a new netcheck client using custom [`ReportOptions`].
+    ///
+    /// Like [`Client::new`], but the given options are used for all reports generated by
+    /// this client.  Fails if the options are not valid.
+    pub async fn with_options(
+        port_mapper: Option<portmapper::Client>,
+        options: ReportOptions,
+    ) -> Result<Self> {
+        options.validate()?;
+        let mut actor = Actor::new(port_mapper, options)?;
         let addr = actor.addr();
+        let events = actor.events.clone();
+        let last_report = actor.last_report_tx.subscribe();
         let task =
             tokio::spawn(async move { actor.run().await }.instrument(info_span!("netcheck.actor")));
         let drop_guard = CancelOnDrop::new("netcheck actor", task.abort_handle());
         Ok(Client {
             addr,
+            events,
+            last_report,
             _drop_guard: Arc::new(drop_guard),
         })
     }
 
+    /// Subscribes to the progress events of report generation.
+    ///
+    /// Events are emitted while [`Client::get_report`] is running.  A subscriber which does
+    /// not keep up loses the oldest events, it will never slow down report generation.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ReportEvent> {
+        self.events.subscribe()
+    }
+
+    /// Returns the most recent report, without running a netcheck.
+    ///
+    /// This is the report most recently returned by [`Client::get_report`] or loaded from
+    /// the [`ReportStore`] when the client was created.
+    pub fn last_report(&self) -> Option<Arc<Report>> {
+        self.last_report.borrow().clone()
+    }
+
+    /// Returns the age of the most recent report, see [`Report::age`].
+    ///
+    /// This is cheap and can be used to decide whether a new report is needed.
+    pub fn last_report_age(&self) -> Option<Duration> {
+        self.last_report
+            .borrow()
+            .as_ref()
+            .and_then(|report| report.age())
+    }
+
     /// Pass a received STUN packet to the netchecker.
     ///
     /// Normally the UDP sockets to send STUN messages from are passed in so that STUN
@@ -231,7 +626,10 @@ impl Client {
 
     /// Runs a netcheck, returning the report.
     ///
-    /// It may not be called concurrently with itself, `&mut self` takes care of that.
+    /// If a report is already being generated, e.g. by a clone of this client, no new
+    /// report is started.  Instead this waits for the running report and returns the same
+    /// report, or the same failure.  Use [`Client::get_fresh_report`] to get a report which
+    /// is started after this call.
     ///
     /// The *stun_conn4* and *stun_conn6* endpoints are bound UDP sockets to use to send out
     /// STUN packets.  This function **will not read from the sockets**, as they may be
@@ -247,6 +645,30 @@ impl Client {
         dm: DerpMap,
         stun_conn4: Option<Arc<UdpSocket>>,
         stun_conn6: Option<Arc<UdpSocket>>,
+    ) -> Result<Arc<Report>> {
+        self.run_check(dm, stun_conn4, stun_conn6, false).await
+    }
+
+    /// Runs a netcheck which starts after this call, returning the report.
+    ///
+    /// Like [`Client::get_report`], but if a report is already being generated this waits
+    /// for it to finish and then runs a new one.  Concurrent calls to this while a report
+    /// is running all share the same follow-up report.
+    pub async fn get_fresh_report(
+        &mut self,
+        dm: DerpMap,
+        stun_conn4: Option<Arc<UdpSocket>>,
+        stun_conn6: Option<Arc<UdpSocket>>,
+    ) -> Result<Arc<Report>> {
+        self.run_check(dm, stun_conn4, stun_conn6, true).await
+    }
+
+    async fn run_check(
+        &self,
+        dm: DerpMap,
+        stun_conn4: Option<Arc<UdpSocket>>,
+        stun_conn6: Option<Arc<UdpSocket>>,
+        fresh: bool,
     ) -> Result<Arc<Report>> {
         // TODO: consider if DerpMap should be made to easily clone?  It seems expensive
         // right now.
@@ -256,6 +678,7 @@ impl Client {
                 derp_map: dm,
                 stun_sock_v4: stun_conn4,
                 stun_sock_v6: stun_conn6,
+                fresh,
                 response_tx: tx,
             })
             .await?;
@@ -272,17 +695,29 @@ pub(crate) struct Inflight {
     txn: stun::TransactionId,
     /// The time the STUN probe was sent.
     start: Instant,
-    /// Response to send STUN results: latency of STUN response and the discovered address.
-    s: sync::oneshot::Sender<(Duration, SocketAddr)>,
+    /// When to give up waiting for the response.
+    ///
+    /// Expired transactions are dropped by the netcheck actor, which closes the channel.
+    deadline: Instant,
+    /// The keys to check the MESSAGE-INTEGRITY of the response with, if required.
+    ///
+    /// Responses failing the check are dropped without resolving the transaction.
+    integrity: Option<stun::IntegrityKeys>,
+    /// Response to send STUN results: latency of STUN response and the discovered address,
+    /// or the error response of the server.
+    s: sync::oneshot::Sender<StunResult>,
 }
 
+/// The result of a STUN transaction, see [`Inflight::s`].
+pub(crate) type StunResult = Result<(Duration, SocketAddr), stun::ErrorResponse>;
+
 /// Messages to send to the [`Actor`].
 #[derive(Debug)]
 pub(crate) enum Message {
     /// Run a netcheck.
     ///
-    /// Only one netcheck can be run at a time, trying to run multiple concurrently will
-    /// fail.
+    /// Only one netcheck runs at a time.  If one is already running the response is the
+    /// report of the running netcheck, unless `fresh` is set.
     RunCheck {
         /// The derp configuration.
         derp_map: DerpMap,
@@ -298,6 +733,10 @@ pub(crate) enum Message {
         ///
         /// Like `stun_sock_v4` but for IPv6.
         stun_sock_v6: Option<Arc<UdpSocket>>,
+        /// Whether the netcheck must start after this request.
+        ///
+        /// If a netcheck is already running a new one is queued to run after it.
+        fresh: bool,
         /// Channel to receive the response.
         response_tx: oneshot::Sender<Result<Arc<Report>>>,
     },
@@ -383,6 +822,12 @@ struct Actor {
     /// The port mapper is responsible for talking to routers via UPnP and the like to try
     /// and open ports.
     port_mapper: Option<portmapper::Client>,
+    /// The options used for each report generation.
+    options: ReportOptions,
+    /// Sender for the [`ReportEvent`]s of the [`reportgen`] actors.
+    events: broadcast::Sender<ReportEvent>,
+    /// Publishes the most recent report to the [`Client`]s.
+    last_report_tx: watch::Sender<Option<Arc<Report>>>,
 
     // Actor state.
     /// Information about the currently in-flight STUN requests.
@@ -391,6 +836,8 @@ struct Actor {
     in_flight_stun_requests: HashMap<stun::TransactionId, Inflight>,
     /// The [`reportgen`] actor currently generating a report.
     current_report_run: Option<ReportRun>,
+    /// The netcheck to run once the current one finishes, requested with `fresh` set.
+    queued_check: Option<QueuedCheck>,
 }
 
 impl Actor {
@@ -398,17 +845,27 @@ impl Actor {
     ///
     /// This does not start the actor, see [`Actor::run`] for this.  You should not
     /// normally create this directly but rather create a [`Client`].
-    fn new(port_mapper: Option<portmapper::Client>) -> Result<Self> {
+    fn new(port_mapper: Option<portmapper::Client>, options: ReportOptions) -> Result<Self> {
         // TODO: consider an instrumented flume channel so we have metrics.
         let (sender, receiver) = mpsc::channel(32);
+        let (events, _) = broadcast::channel(REPORT_EVENTS_CAPACITY);
+        let mut reports = Reports::default();
+        if let Some(ref store) = options.last_report_store {
+            reports.last = load_last_report(store.as_ref(), options.last_report_max_age);
+        }
+        let (last_report_tx, _) = watch::channel(reports.last.clone());
         Ok(Self {
             receiver,
             sender,
-            reports: Default::default(),
+            reports,
             skip_external_network: false,
             port_mapper,
+            options,
+            events,
+            last_report_tx,
             in_flight_stun_requests: Default::default(),
             current_report_run: None,
+            queued_check: None,
         })
     }
 
@@ -425,23 +882,39 @@ impl Actor {
     /// its clones) is dropped this will terminate.
     async fn run(&mut self) {
         debug!("netcheck actor starting");
-        while let Some(msg) = self.receiver.recv().await {
+        loop {
+            let next_deadline = self.next_in_flight_stun_deadline();
+            let msg = tokio::select! {
+                msg = self.receiver.recv() => msg,
+                _ = tokio::time::sleep_until(next_deadline.unwrap_or_else(Instant::now)),
+                    if next_deadline.is_some() =>
+                {
+                    self.expire_in_flight_stun();
+                    continue;
+                }
+            };
+            let Some(msg) = msg else {
+                break;
+            };
             trace!(?msg, "handling message");
             match msg {
                 Message::RunCheck {
                     derp_map,
                     stun_sock_v4,
                     stun_sock_v6,
+                    fresh,
                     response_tx,
                 } => {
-                    self.handle_run_check(derp_map, stun_sock_v4, stun_sock_v6, response_tx)
+                    self.handle_run_check(derp_map, stun_sock_v4, stun_sock_v6, fresh, response_tx)
                         .await;
                 }
                 Message::ReportReady { report, derp_map } => {
                     self.handle_report_ready(report, derp_map);
+                    self.start_queued_check().await;
                 }
                 Message::ReportAborted => {
                     self.handle_report_aborted();
+                    self.start_queued_check().await;
                 }
                 Message::StunPacket { payload, from_addr } => {
                     self.handle_stun_packet(&payload, from_addr);
@@ -453,51 +926,122 @@ impl Actor {
         }
     }
 
-    /// Starts a check run as requested by the [`Message::RunCheck`] message.
+    /// Handles the [`Message::RunCheck`] message.
     ///
-    /// If *stun_sock_v4* or *stun_sock_v6* are not provided this will bind the sockets
-    /// itself.  This is not ideal since really you want to send STUN probes from the
-    /// sockets you will be using.
+    /// If no check is running one is started.  Otherwise the request waits for the running
+    /// check, or if *fresh* is set, for the check queued to run after it.
     async fn handle_run_check(
         &mut self,
         derp_map: DerpMap,
         stun_sock_v4: Option<Arc<UdpSocket>>,
         stun_sock_v6: Option<Arc<UdpSocket>>,
+        fresh: bool,
         response_tx: oneshot::Sender<Result<Arc<Report>>>,
     ) {
-        if self.current_report_run.is_some() {
-            response_tx
-                .send(Err(anyhow!(
-                    "ignoring RunCheck request: reportgen actor already running"
-                )))
-                .ok();
+        let Some(ref mut run) = self.current_report_run else {
+            self.start_check(derp_map, stun_sock_v4, stun_sock_v6, vec![response_tx])
+                .await;
+            return;
+        };
+        if !fresh {
+            debug!("reportgen actor already running, waiting for its report");
+            run.report_txs.push(response_tx);
             return;
         }
+        debug!("reportgen actor already running, queueing a fresh check");
+        match self.queued_check {
+            Some(ref mut queued) => {
+                // The most recent request has the most up to date configuration.
+                queued.derp_map = derp_map;
+                queued.stun_sock_v4 = stun_sock_v4;
+                queued.stun_sock_v6 = stun_sock_v6;
+                queued.report_txs.push(response_tx);
+            }
+            None => {
+                self.queued_check = Some(QueuedCheck {
+                    derp_map,
+                    stun_sock_v4,
+                    stun_sock_v6,
+                    report_txs: vec![response_tx],
+                });
+            }
+        }
+    }
+
+    /// Starts the queued check, if any.
+    async fn start_queued_check(&mut self) {
+        if let Some(queued) = self.queued_check.take() {
+            self.start_check(
+                queued.derp_map,
+                queued.stun_sock_v4,
+                queued.stun_sock_v6,
+                queued.report_txs,
+            )
+            .await;
+        }
+    }
 
+    /// Starts a check run, the report is sent to all *report_txs*.
+    ///
+    /// If *stun_sock_v4* or *stun_sock_v6* are not provided this will bind the sockets
+    /// itself.  This is not ideal since really you want to send STUN probes from the
+    /// sockets you will be using.
+    async fn start_check(
+        &mut self,
+        derp_map: DerpMap,
+        stun_sock_v4: Option<Arc<UdpSocket>>,
+        stun_sock_v6: Option<Arc<UdpSocket>>,
+        report_txs: Vec<oneshot::Sender<Result<Arc<Report>>>>,
+    ) {
+        debug_assert!(self.current_report_run.is_none());
         let now = Instant::now();
 
         let cancel_token = CancellationToken::new();
+        let bind = if stun_sock_v4.is_none() || stun_sock_v6.is_none() {
+            StunBindAddrs::new(&self.options.stun_bind).await
+        } else {
+            StunBindAddrs::default()
+        };
+        let mut stun_interface = None;
+        // No sockets are bound for address families which are not probed.
+        let families = self.options.address_families;
         let stun_sock_v4 = match stun_sock_v4 {
             Some(sock) => Some(sock),
-            None => {
-                bind_local_stun_socket(
-                    SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
-                    self.addr(),
-                    cancel_token.clone(),
-                )
-                .await
-            }
+            None => match bind.v4.filter(|_| families.ipv4()) {
+                Some(ip) => {
+                    let sock = bind_local_stun_socket(
+                        SocketAddr::new(ip.into(), 0),
+                        bind.device.as_deref(),
+                        self.addr(),
+                        cancel_token.clone(),
+                    )
+                    .await;
+                    if sock.is_some() {
+                        stun_interface = bind.device.clone();
+                    }
+                    sock
+                }
+                None => None,
+            },
         };
         let stun_sock_v6 = match stun_sock_v6 {
             Some(sock) => Some(sock),
-            None => {
-                bind_local_stun_socket(
-                    SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
-                    self.addr(),
-                    cancel_token.clone(),
-                )
-                .await
-            }
+            None => match bind.v6.filter(|_| families.ipv6()) {
+                Some(ip) => {
+                    let sock = bind_local_stun_socket(
+                        SocketAddr::new(ip.into(), 0),
+                        bind.device.as_deref(),
+                        self.addr(),
+                        cancel_token.clone(),
+                    )
+                    .await;
+                    if sock.is_some() {
+                        stun_interface = bind.device.clone();
+                    }
+                    sock
+                }
+                None => None,
+            },
         };
         let mut do_full = self.reports.next_full
             || now.duration_since(self.reports.last_full) > FULL_REPORT_INTERVAL;
@@ -526,27 +1070,37 @@ impl Actor {
             derp_map,
             stun_sock_v4,
             stun_sock_v6,
+            self.options.clone(),
+            self.events.clone(),
         );
 
         self.current_report_run = Some(ReportRun {
             _reportgen: actor,
             _drop_guard: cancel_token.drop_guard(),
-            report_tx: response_tx,
+            report_txs,
+            stun_interface,
         });
     }
 
-    fn handle_report_ready(&mut self, report: Box<Report>, derp_map: DerpMap) {
+    fn handle_report_ready(&mut self, mut report: Box<Report>, derp_map: DerpMap) {
+        if let Some(ref run) = self.current_report_run {
+            report.stun_interface = run.stun_interface.clone();
+        }
         let report = self.finish_and_store_report(*report, &derp_map);
         self.in_flight_stun_requests.clear();
-        if let Some(ReportRun { report_tx, .. }) = self.current_report_run.take() {
-            report_tx.send(Ok(report)).ok();
+        if let Some(ReportRun { report_txs, .. }) = self.current_report_run.take() {
+            for report_tx in report_txs {
+                report_tx.send(Ok(report.clone())).ok();
+            }
         }
     }
 
     fn handle_report_aborted(&mut self) {
         self.in_flight_stun_requests.clear();
-        if let Some(ReportRun { report_tx, .. }) = self.current_report_run.take() {
-            report_tx.send(Err(anyhow!("report aborted"))).ok();
+        if let Some(ReportRun { report_txs, .. }) = self.current_report_run.take() {
+            for report_tx in report_txs {
+                report_tx.send(Err(anyhow!("report aborted"))).ok();
+            }
         }
     }
 
@@ -569,17 +1123,39 @@ impl Actor {
             }
         }
 
-        match stun::parse_response(pkt) {
-            Ok((txn, addr_port)) => match self.in_flight_stun_requests.remove(&txn) {
-                Some(inf) => {
-                    debug!(%src, %txn, "received known STUN packet");
-                    let elapsed = inf.start.elapsed();
-                    inf.s.send((elapsed, addr_port)).ok();
+        match stun::parse_binding_response(pkt) {
+            Ok(response) => {
+                let txn = response.tx();
+                if let Some(keys) = self
+                    .in_flight_stun_requests
+                    .get(&txn)
+                    .and_then(|inf| inf.integrity.as_ref())
+                {
+                    if let Err(err) = stun::check_integrity(pkt, keys) {
+                        debug!(%src, %txn, "dropping STUN response: {err}");
+                        inc!(NetcheckMetrics, stun_integrity_failures);
+                        return;
+                    }
                 }
-                None => {
-                    debug!(%src, %txn, "received unexpected STUN message response");
+                match self.in_flight_stun_requests.remove(&txn) {
+                    Some(inf) => {
+                        let elapsed = inf.start.elapsed();
+                        match response {
+                            stun::BindingResponse::Success { addr, .. } => {
+                                debug!(%src, %txn, "received known STUN packet");
+                                inf.s.send(Ok((elapsed, addr))).ok();
+                            }
+                            stun::BindingResponse::Error { error, .. } => {
+                                debug!(%src, %txn, "received STUN error response: {error}");
+                                inf.s.send(Err(error)).ok();
+                            }
+                        }
+                    }
+                    None => {
+                        debug!(%src, %txn, "received unexpected STUN message response");
+                    }
                 }
-            },
+            }
             Err(err) => {
                 match stun::parse_binding_request(pkt) {
                     Ok(txn) => {
@@ -588,7 +1164,7 @@ impl Actor {
                             Some(inf) => {
                                 debug!(%src, %txn, "received our hairpin STUN request");
                                 let elapsed = inf.start.elapsed();
-                                inf.s.send((elapsed, src)).ok();
+                                inf.s.send(Ok((elapsed, src))).ok();
                             }
                             None => {
                                 debug!(%src, %txn, "unknown STUN request");
@@ -614,9 +1190,50 @@ impl Actor {
         response_tx.send(()).ok();
     }
 
+    /// Returns the earliest deadline of the in-flight STUN requests.
+    fn next_in_flight_stun_deadline(&self) -> Option<Instant> {
+        self.in_flight_stun_requests
+            .values()
+            .map(|inflight| inflight.deadline)
+            .min()
+    }
+
+    /// Drops the in-flight STUN requests whose deadline passed.
+    ///
+    /// This closes their response channels, so the probes waiting for them give up.
+    fn expire_in_flight_stun(&mut self) {
+        let now = Instant::now();
+        self.in_flight_stun_requests.retain(|txn, inflight| {
+            if inflight.deadline > now {
+                return true;
+            }
+            trace!(%txn, "expiring in-flight STUN request");
+            inc!(NetcheckMetrics, stun_transactions_expired);
+            false
+        });
+    }
+
     fn finish_and_store_report(&mut self, report: Report, dm: &DerpMap) -> Arc<Report> {
+        // The previously published report, even if a full report discarded it as last report.
+        let prev_report = self.last_report_tx.borrow().clone();
         let report = self.add_report_history_and_set_preferred_derp(report);
         self.log_concise_report(&report, dm);
+        if let Some(prev_report) = prev_report {
+            let changes = report.diff(&prev_report);
+            if !changes.is_empty() {
+                info!(
+                    significant = changes.is_significant(),
+                    "report changed: {changes}"
+                );
+            }
+        }
+        if let Some(ref store) = self.options.last_report_store {
+            if let Err(err) =
+                store::encode(&report, SystemTime::now()).and_then(|data| store.store(&data))
+            {
+                warn!("failed to store report: {err:#}");
+            }
+        }
 
         report
     }
@@ -657,35 +1274,21 @@ impl Actor {
 
         // Then, pick which currently-alive DERP server from the
         // current report has the best latency over the past MAX_AGE.
-        let mut best_any = Duration::default();
-        let mut old_region_cur_latency = Duration::default();
-        {
-            for (region_id, d) in r.region_latency.iter() {
-                if region_id == prev_derp {
-                    old_region_cur_latency = d;
-                }
-                let best = best_recent.get(region_id).unwrap();
-                if r.preferred_derp == 0 || best < best_any {
-                    best_any = best;
-                    r.preferred_derp = region_id;
-                }
-            }
-
-            // If we're changing our preferred DERP but the old one's still
-            // accessible and the new one's not much better, just stick with
-            // where we are.
-            if prev_derp != 0
-                && r.preferred_derp != prev_derp
-                && !old_region_cur_latency.is_zero()
-                && best_any > old_region_cur_latency / 3 * 2
-            {
-                r.preferred_derp = prev_derp;
-            }
+        if let Some(selected) = select_preferred_derp(
+            &r.region_latency,
+            &best_recent,
+            prev_derp,
+            self.options.preferred_derp_margin,
+        ) {
+            r.preferred_derp = selected.region_id;
+            r.preferred_derp_lead = selected.lead;
+            r.preferred_derp_kept = selected.kept;
         }
 
         let r = Arc::new(r);
         self.reports.prev.insert(now, r.clone());
         self.reports.last = Some(r.clone());
+        self.last_report_tx.send_replace(Some(r.clone()));
 
         r
     }
@@ -693,19 +1296,31 @@ impl Actor {
     fn log_concise_report(&self, r: &Report, dm: &DerpMap) {
         let mut log = "report: ".to_string();
         log += &format!("udp={}", r.udp);
+        if r.partial {
+            log += " partial=true";
+        }
         if !r.ipv4 {
             log += &format!(" v4={}", r.ipv4)
         }
+        if r.udp_blocked_locally {
+            log += " udp_blocked_locally=true";
+        }
         if !r.udp {
-            log += &format!(" icmpv4={}", r.icmpv4)
+            log += &format!(" udp_verdict={}", r.udp_verdict);
+            log += &format!(" icmpv4={}", r.icmpv4);
+            log += &format!(" icmpv6={}", r.icmpv6);
         }
 
         log += &format!(" v6={}", r.ipv6);
         if !r.ipv6 {
             log += &format!(" v6os={}", r.os_has_ipv6);
+            log += &format!(" v6route={}", r.os_has_ipv6_route);
         }
         log += &format!(" mapvarydest={:?}", r.mapping_varies_by_dest_ip);
         log += &format!(" hair={:?}", r.hair_pinning);
+        if r.hair_pinning_v6.is_some() {
+            log += &format!(" hair6={:?}", r.hair_pinning_v6);
+        }
         if let Some(probe) = &r.portmap_probe {
             log += &format!(" {}", probe);
         } else {
@@ -753,24 +1368,89 @@ struct ReportRun {
     _reportgen: reportgen::Client,
     /// Drop guard to optionally kill workers started by netcheck to support reportgen.
     _drop_guard: tokio_util::sync::DropGuard,
-    /// Where to send the completed report.
-    report_tx: oneshot::Sender<Result<Arc<Report>>>,
+    /// Where to send the completed report, one sender for each request waiting on it.
+    report_txs: Vec<oneshot::Sender<Result<Arc<Report>>>>,
+    /// The interface the STUN sockets were bound to by netcheck, if any.
+    stun_interface: Option<String>,
+}
+
+/// A check requested while another one was running, see [`Message::RunCheck`].
+#[derive(Debug)]
+struct QueuedCheck {
+    derp_map: DerpMap,
+    stun_sock_v4: Option<Arc<UdpSocket>>,
+    stun_sock_v6: Option<Arc<UdpSocket>>,
+    /// Where to send the completed report, one sender for each request waiting on it.
+    report_txs: Vec<oneshot::Sender<Result<Arc<Report>>>>,
+}
+
+/// The local addresses to bind STUN sockets to, derived from [`StunBind`].
+#[derive(Debug, Default)]
+struct StunBindAddrs {
+    v4: Option<Ipv4Addr>,
+    v6: Option<Ipv6Addr>,
+    /// The network interface to bind to, if any.
+    device: Option<String>,
+}
+
+impl StunBindAddrs {
+    async fn new(bind: &StunBind) -> Self {
+        match bind {
+            StunBind::Any => Self {
+                v4: Some(Ipv4Addr::UNSPECIFIED),
+                v6: Some(Ipv6Addr::UNSPECIFIED),
+                device: None,
+            },
+            StunBind::Addrs { v4, v6 } => Self {
+                v4: *v4,
+                v6: *v6,
+                device: None,
+            },
+            StunBind::Interface(name) => {
+                let if_state = interfaces::State::new().await;
+                let Some(addrs) = if_state.interface_ips.get(name) else {
+                    warn!(interface = %name, "STUN bind interface not found");
+                    return Self::default();
+                };
+                let v4 = addrs.iter().find_map(|net| match net.addr() {
+                    IpAddr::V4(ip) => Some(ip),
+                    IpAddr::V6(_) => None,
+                });
+                // Link-local addresses can not reach the DERP servers.
+                let v6 = addrs.iter().find_map(|net| match net.addr() {
+                    IpAddr::V6(ip) if is_routable_v6_source(ip.into()) => Some(ip),
+                    _ => None,
+                });
+                Self {
+                    v4,
+                    v6,
+                    device: Some(name.clone()),
+                }
+            }
+        }
+    }
 }
 
 /// Attempts to bind a local socket to send STUN packets from.
 ///
 /// If successfull this returns the bound socket and will forward STUN responses to the
 /// provided *actor_addr*.  The *cancel_token* serves to stop the packet forwarding when the
-/// socket is no longer needed.
+/// socket is no longer needed.  If a *device* is given the socket is also bound to this
+/// network interface, no socket is returned if that fails.
 async fn bind_local_stun_socket(
     addr: SocketAddr,
+    device: Option<&str>,
     actor_addr: Addr,
     cancel_token: CancellationToken,
 ) -> Option<Arc<UdpSocket>> {
-    let sock = match UdpSocket::bind(addr).await {
+    let sock = match bind_stun_socket(addr, device) {
         Ok(sock) => Arc::new(sock),
+        Err(err) if device.is_some() => {
+            warn!(?device, "failed to bind STUN socket at {}: {}", addr, err);
+            return None;
+        }
         Err(err) => {
-            debug!("failed to bind STUN socket at 0.0.0.0:0: {}", err);
+            debug!("failed to bind STUN socket at {}: {}", addr, err);
             return None;
         }
     };
@@ -808,6 +1488,71 @@ async fn bind_local_stun_socket(
     Some(sock)
 }
 
+/// Binds a UDP socket for STUN to *addr*, and to the network interface *device* if given.
+///
+/// See [`bind_to_device`] for the platforms supporting binding to a device.
+fn bind_stun_socket(addr: SocketAddr, device: Option<&str>) -> std::io::Result<UdpSocket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    if let Some(device) = device {
+        bind_to_device(&socket, addr, device)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Binds *socket* to the network interface *device*.
+///
+/// On Linux and Android this uses `SO_BINDTODEVICE`, which needs privileges on older
+/// kernels.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_to_device(
+    socket: &socket2::Socket,
+    _addr: SocketAddr,
+    device: &str,
+) -> std::io::Result<()> {
+    socket.bind_device(Some(device.as_bytes()))
+}
+
+/// Binds *socket* to the network interface *device*.
+///
+/// On macOS and iOS this uses `IP_BOUND_IF` or `IPV6_BOUND_IF`, depending on the address
+/// family of *addr*.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn bind_to_device(socket: &socket2::Socket, addr: SocketAddr, device: &str) -> std::io::Result<()> {
+    let name = std::ffi::CString::new(device)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    // SAFETY: name is a valid NUL-terminated string which outlives the call.
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    let index = std::num::NonZeroU32::new(index).ok_or_else(std::io::Error::last_os_error)?;
+    match addr {
+        SocketAddr::V4(_) => socket.bind_device_by_index_v4(Some(index)),
+        SocketAddr::V6(_) => socket.bind_device_by_index_v6(Some(index)),
+    }
+}
+
+/// Binding to a network interface is not supported on this platform, fails.
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+fn bind_to_device(
+    _socket: &socket2::Socket,
+    _addr: SocketAddr,
+    device: &str,
+) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("binding to the interface {device} is not supported on this platform"),
+    ))
+}
+
 /// Receive STUN response from a UDP socket, pass it to the actor.
 async fn recv_stun_once(sock: &UdpSocket, buf: &mut [u8], actor_addr: &Addr) -> Result<()> {
     let (count, mut from_addr) = sock
@@ -823,6 +1568,77 @@ async fn recv_stun_once(sock: &UdpSocket, buf: &mut [u8], actor_addr: &Addr) ->
     actor_addr.send(msg).await.context("actor stopped")
 }
 
+/// Loads the last report from the store, if there is a usable one.
+fn load_last_report(store: &dyn ReportStore, max_age: Duration) -> Option<Arc<Report>> {
+    match store.load() {
+        Ok(Some(data)) => {
+            let report = store::decode(&data, max_age, SystemTime::now())?;
+            debug!("loaded last report from store");
+            Some(Arc::new(report))
+        }
+        Ok(None) => None,
+        Err(err) => {
+            warn!("failed to load last report: {err:#}");
+            None
+        }
+    }
+}
+
+/// A preferred DERP region chosen by [`select_preferred_derp`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PreferredDerp {
+    region_id: u16,
+    /// See [`Report::preferred_derp_lead`].
+    lead: Option<Duration>,
+    /// See [`Report::preferred_derp_kept`].
+    kept: bool,
+}
+
+/// Selects the preferred DERP region, with hysteresis.
+///
+/// The candidates are the regions in *current*, ranked by their best latency in
+/// *best_recent*.  If the previous preferred region, *prev_derp*, is still a candidate it is
+/// only replaced if the fastest candidate exceeds the *margin* compared to the current
+/// latency of *prev_derp*.
+///
+/// Returns `None` if there are no candidates.
+fn select_preferred_derp(
+    current: &RegionLatencies,
+    best_recent: &RegionLatencies,
+    prev_derp: u16,
+    margin: PreferredDerpMargin,
+) -> Option<PreferredDerp> {
+    let mut ranked: Vec<(u16, Duration)> = current
+        .iter()
+        .map(|(region_id, latency)| {
+            let best = best_recent.get(region_id).unwrap_or(latency);
+            (region_id, best)
+        })
+        .collect();
+    ranked.sort_by_key(|&(region_id, latency)| (latency, region_id));
+    let &(best_region, best_latency) = ranked.first()?;
+
+    // If we're changing our preferred DERP but the old one's still accessible and the new
+    // one's not much better, just stick with where we are.
+    if prev_derp != 0 && best_region != prev_derp {
+        if let Some(prev_latency) = current.get(prev_derp) {
+            if !prev_latency.is_zero() && !margin.is_exceeded(prev_latency, best_latency) {
+                return Some(PreferredDerp {
+                    region_id: prev_derp,
+                    lead: None,
+                    kept: true,
+                });
+            }
+        }
+    }
+
+    Some(PreferredDerp {
+        region_id: best_region,
+        lead: ranked.get(1).map(|&(_, latency)| latency - best_latency),
+        kept: false,
+    })
+}
+
 /// Test if IPv6 works at all, or if it's been hard disabled at the OS level.
 pub(crate) async fn os_has_ipv6() -> bool {
     // TODO: use socket2 to specify binding to ipv6
@@ -830,6 +1646,41 @@ pub(crate) async fn os_has_ipv6() -> bool {
     udp.is_ok()
 }
 
+/// A globally routable IPv6 address, used to look up the route to the IPv6 internet.
+///
+/// This is a public DNS server, but no packets are ever sent to it.
+const IPV6_ROUTE_CHECK_ADDR: Ipv6Addr = Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888);
+
+/// Test if the OS has a route to the IPv6 internet.
+///
+/// Connecting a UDP socket does not send any packets, but makes the OS pick a route and
+/// source address.  If there is no route or the source address can not reach the internet,
+/// e.g. because it is link-local, IPv6 probes are pointless.
+pub(crate) async fn os_has_ipv6_route() -> bool {
+    let Ok(sock) = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await else {
+        return false;
+    };
+    if let Err(err) = sock.connect((IPV6_ROUTE_CHECK_ADDR, 53)).await {
+        trace!("no IPv6 route: {err:#}");
+        return false;
+    }
+    match sock.local_addr() {
+        Ok(addr) => is_routable_v6_source(addr.ip()),
+        Err(_) => false,
+    }
+}
+
+/// Whether *ip* is an IPv6 source address which could reach the internet.
+///
+/// This is a global unicast address (2000::/3) or a unique local address (fc00::/7), the
+/// latter being used with address translation in some environments.
+fn is_routable_v6_source(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V6(ip) => ip.segments()[0] & 0xe000 == 0x2000 || is_private_v6(&ip),
+        IpAddr::V4(_) => false,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::net::Ipv4Addr;
@@ -877,6 +1728,12 @@ mod tests {
                 "preferred_derp = {}; want 1",
                 r.preferred_derp
             );
+            assert!(
+                r.node_v4_latency.get("1a").is_some(),
+                "expected node 1a in node_v4_latency; got {:?}",
+                r.node_v4_latency
+            );
+            assert_eq!(r.node_latency.len(), 1);
         }
 
         assert!(
@@ -888,6 +1745,100 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_report_age() -> Result<()> {
+        let _guard = setup_logging();
+        let (stun_addr, _stun_stats, _cleanup_guard) =
+            stun::test::serve("0.0.0.0".parse().unwrap()).await?;
+        let dm = stun::test::derp_map_of([stun_addr].into_iter());
+
+        let mut client = Client::new(None).await?;
+        assert!(client.last_report().is_none());
+        assert!(client.last_report_age().is_none());
+
+        let r = client.get_report(dm, None, None).await?;
+        assert!(r.generated_at.is_some());
+        assert!(!r.probe_duration.is_zero());
+        assert!(r.region_measured_at.contains_key(&1));
+        assert!(r.age().unwrap() < Duration::from_secs(5));
+        assert!(Arc::ptr_eq(&client.last_report().unwrap(), &r));
+        assert!(client.last_report_age().is_some());
+
+        // Reports loaded from a store only have the wall clock.
+        let loaded = Report {
+            generated_at: Some(SystemTime::now() - Duration::from_secs(60)),
+            ..Default::default()
+        };
+        assert!(loaded.age().unwrap() >= Duration::from_secs(60));
+        assert_eq!(Report::default().age(), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_reports() -> Result<()> {
+        let _guard = setup_logging();
+        let (stun_addr, _stun_stats, _cleanup_guard) =
+            stun::test::serve("0.0.0.0".parse().unwrap()).await?;
+        let dm = stun::test::derp_map_of([stun_addr].into_iter());
+
+        let mut client_a = Client::new(None).await?;
+        let mut client_b = client_a.clone();
+
+        // Concurrent requests share the running report.
+        let (a, b) = tokio::join!(
+            client_a.get_report(dm.clone(), None, None),
+            client_b.get_report(dm.clone(), None, None),
+        );
+        assert!(Arc::ptr_eq(&a?, &b?));
+
+        // Unless a fresh report is requested.
+        let (a, b) = tokio::join!(
+            client_a.get_report(dm.clone(), None, None),
+            client_b.get_fresh_report(dm.clone(), None, None),
+        );
+        assert!(!Arc::ptr_eq(&a?, &b?));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_report_events() -> Result<()> {
+        let _guard = setup_logging();
+        let (stun_addr, _stun_stats, _cleanup_guard) =
+            stun::test::serve("0.0.0.0".parse().unwrap()).await?;
+        let dm = stun::test::derp_map_of([stun_addr].into_iter());
+
+        let mut client = Client::new(None).await?;
+        let mut events = client.subscribe_events();
+        let r = client.get_report(dm, None, None).await?;
+        assert!(r.udp, "want UDP");
+
+        let mut got = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            got.push(event);
+        }
+        dbg!(&got);
+        assert!(got.contains(&ReportEvent::ProbeStarted {
+            region_id: 1,
+            proto: ProbeProto::StunIpv4,
+        }));
+        assert!(got.iter().any(|event| matches!(
+            event,
+            ReportEvent::ProbeFinished {
+                region_id: 1,
+                proto: ProbeProto::StunIpv4,
+                latency: Some(_),
+            }
+        )));
+        assert!(got.contains(&ReportEvent::GlobalAddrDiscovered(r.global_v4.unwrap())));
+        assert!(got
+            .iter()
+            .any(|event| matches!(event, ReportEvent::ProbingStopped(_))));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_iroh_computer_stun() -> Result<()> {
         let _guard = setup_logging();
@@ -920,6 +1871,7 @@ mod tests {
                     .collect(),
                 avoid: false,
                 region_code: "default".into(),
+                stun_integrity: None,
             },
         );
         dbg!(&dm);
@@ -977,8 +1929,10 @@ mod tests {
             ipv4_can_send: r.ipv4_can_send,
             // OS IPv6 test is irrelevant here, accept whatever the current machine has.
             os_has_ipv6: r.os_has_ipv6,
+            os_has_ipv6_route: r.os_has_ipv6_route,
             // Captive portal test is irrelevant; accept what the current report has.
             captive_portal: r.captive_portal,
+            captive_portal_details: r.captive_portal_details.clone(),
             // We will fall back to sending ICMP pings.  These should succeed when we have a
             // working pinger.
             icmpv4: have_pinger,
@@ -987,10 +1941,506 @@ mod tests {
                 .then(|| r.region_latency.clone())
                 .unwrap_or_default(),
             preferred_derp: have_pinger.then_some(r.preferred_derp).unwrap_or_default(),
+            region_measured_at: have_pinger
+                .then(|| r.region_measured_at.clone())
+                .unwrap_or_default(),
+            // Timing depends on the machine, it is tested separately.
+            generated_at: r.generated_at,
+            probe_duration: r.probe_duration,
+            generated_instant: r.generated_instant,
+            // Which ICMP probes failed depends on the pinger, checked below.
+            probe_failures: r.probe_failures.clone(),
             ..Default::default()
         };
 
         assert_eq!(r, want);
+        let stun_failure = r.probe_failures.get(1, ProbeProto::StunIpv4).unwrap();
+        assert_eq!(stun_failure.kind, ProbeFailureKind::NoReply);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe_failures() {
+        let mut failures = ProbeFailures::default();
+        assert!(failures.is_empty());
+
+        failures.record(1, ProbeProto::StunIpv4, ProbeFailureKind::SendFailed, 1);
+        failures.record(1, ProbeProto::StunIpv4, ProbeFailureKind::NoReply, 2);
+        failures.record(1, ProbeProto::IcmpV4, ProbeFailureKind::Aborted, 1);
+        assert_eq!(failures.len(), 2);
+        assert_eq!(
+            failures.get(1, ProbeProto::StunIpv4),
+            Some(&ProbeFailure {
+                region_id: 1,
+                proto: ProbeProto::StunIpv4,
+                kind: ProbeFailureKind::NoReply,
+                count: 3,
+            })
+        );
+
+        // The number of entries is bounded, further failures are only counted.
+        for region_id in 2..=MAX_PROBE_FAILURES as u16 + 1 {
+            failures.record(region_id, ProbeProto::StunIpv4, ProbeFailureKind::Dns, 1);
+        }
+        assert_eq!(failures.len(), MAX_PROBE_FAILURES);
+        assert_eq!(failures.dropped(), 2);
+        failures.record(1, ProbeProto::IcmpV4, ProbeFailureKind::NoReply, 1);
+        assert_eq!(failures.get(1, ProbeProto::IcmpV4).unwrap().count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_udp_blocked_short_timeouts() -> Result<()> {
+        let _guard = setup_logging();
+        let blackhole = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let stun_addr = blackhole.local_addr()?;
+        let mut dm = stun::test::derp_map_of([stun_addr].into_iter());
+        dm.regions.get_mut(&1).unwrap().nodes[0].stun_only = true;
+
+        let options = ReportOptions {
+            overall_probe_timeout: Duration::from_millis(800),
+            stun_probe_timeout: Duration::from_millis(200),
+            icmp_probe_timeout: Duration::from_millis(50),
+            captive_portal_delay: Duration::from_millis(10),
+            captive_portal_timeout: Duration::from_millis(50),
+        };
+        let mut client = Client::with_options(None, options).await?;
+
+        let start = Instant::now();
+        let r = client.get_report(dm, None, None).await?;
+        assert!(!r.udp);
+        assert!(
+            start.elapsed() < Duration::from_millis(800),
+            "report took {:?}",
+            start.elapsed()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_partial_report_on_timeout() -> Result<()> {
+        let _guard = setup_logging();
+        let (stun_addr, _stun_stats, _cleanup_guard) = stun::test::serve_v4().await?;
+        let blackhole = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let dm = stun::test::derp_map_of([stun_addr, blackhole.local_addr()?].into_iter());
+
+        // The overall timeout is shorter than the STUN probe timeout, so the unreachable
+        // region is still being probed when the report times out.
+        let options = ReportOptions {
+            overall_probe_timeout: Duration::from_millis(300),
+            captive_portal_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let mut client = Client::with_options(None, options).await?;
+
+        let r = client.get_report(dm, None, None).await?;
+        dbg!(&r);
+        assert!(r.partial, "expected partial report");
+        assert!(r.udp, "want UDP");
+        assert!(r.region_latency.get(1).is_some(), "want region 1 latency");
+        assert!(
+            r.region_latency.get(2).is_none(),
+            "want no region 2 latency"
+        );
+        assert!(r.global_v4.is_some(), "expected globalV4 set");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restricted_regions() -> Result<()> {
+        let _guard = setup_logging();
+        let (stun_addr, _stun_stats, _cleanup_guard) = stun::test::serve_v4().await?;
+        let blackhole = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let dm = stun::test::derp_map_of([stun_addr, blackhole.local_addr()?].into_iter());
+
+        // Only region 1 is probed, so the report completes without waiting for the
+        // unreachable region 2 to time out.
+        let options = ReportOptions {
+            overall_probe_timeout: Duration::from_secs(3),
+            regions: Some([1].into()),
+            ..Default::default()
+        };
+        let mut client = Client::with_options(None, options).await?;
+
+        let r = client.get_report(dm, None, None).await?;
+        dbg!(&r);
+        assert!(!r.partial, "expected a complete report");
+        assert!(r.region_latency.get(1).is_some(), "want region 1 latency");
+        assert_eq!(r.region_latency.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_custom_probe_planner() -> Result<()> {
+        /// Only sends a single STUN probe to region 1.
+        #[derive(Debug)]
+        struct SingleProbePlanner;
+
+        impl ProbePlanner for SingleProbePlanner {
+            fn plan(
+                &self,
+                derp_map: &DerpMap,
+                _if_state: &interfaces::State,
+                _last_report: Option<&Report>,
+                _options: &ReportOptions,
+            ) -> ProbePlan {
+                let node = Arc::new(derp_map.regions[&1].nodes[0].clone());
+                let mut set = ProbeSet::new(1, ProbeProto::StunIpv4);
+                set.push(Probe::StunIpv4 {
+                    delay: Duration::ZERO,
+                    node,
+                })
+                .unwrap();
+                let mut plan = ProbePlan::new();
+                plan.add(set);
+                plan
+            }
+        }
+
+        let _guard = setup_logging();
+        let (stun_addr, stun_stats, _cleanup_guard) = stun::test::serve_v4().await?;
+        let blackhole = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+        let dm = stun::test::derp_map_of([stun_addr, blackhole.local_addr()?].into_iter());
+
+        let options = ReportOptions {
+            overall_probe_timeout: Duration::from_secs(3),
+            probe_planner: Arc::new(SingleProbePlanner),
+            ..Default::default()
+        };
+        let mut client = Client::with_options(None, options).await?;
+        let r = client.get_report(dm, None, None).await?;
+        assert!(r.udp, "want UDP");
+        assert!(!r.partial, "expected a complete report");
+        assert_eq!(r.region_latency.len(), 1);
+        assert!(r.region_latency.get(1).is_some());
+        assert_eq!(stun_stats.total().await, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_address_families() -> Result<()> {
+        let _guard = setup_logging();
+        let (stun_addr, _stun_stats, _cleanup_guard) = stun::test::serve_v4().await?;
+        let dm = stun::test::derp_map_of([stun_addr].into_iter());
+
+        let options = ReportOptions {
+            overall_probe_timeout: Duration::from_secs(3),
+            address_families: AddressFamilies::Ipv4Only,
+            ..Default::default()
+        };
+        let mut client = Client::with_options(None, options).await?;
+        let r = client.get_report(dm.clone(), None, None).await?;
+        assert!(r.ipv4);
+        assert!(!r.ipv4_not_probed);
+        assert!(r.ipv6_not_probed);
+        assert!(r.stun_local_v6.is_none());
+
+        // The only STUN server is IPv4, an IPv6-only report can not measure anything.
+        let options = ReportOptions {
+            overall_probe_timeout: Duration::from_secs(1),
+            stun_probe_timeout: Duration::from_millis(500),
+            address_families: AddressFamilies::Ipv6Only,
+            ..Default::default()
+        };
+        let mut client = Client::with_options(None, options).await?;
+        let r = client.get_report(dm, None, None).await?;
+        assert!(!r.ipv4);
+        assert!(!r.ipv4_can_send);
+        assert!(r.ipv4_not_probed);
+        assert!(!r.ipv6_not_probed);
+        assert!(r.stun_local_v4.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limit_large_map() -> Result<()> {
+        let _guard = setup_logging();
+        // Many regions with latencies between 20ms and 50ms.
+        let mut servers = Vec::new();
+        for i in 0..30 {
+            let delay = Duration::from_millis(20 + i);
+            servers.push(stun::test::serve_with_delay(Ipv4Addr::UNSPECIFIED.into(), delay).await?);
+        }
+        let dm = stun::test::derp_map_of(servers.iter().map(|(addr, _, _)| *addr));
+
+        // Even one probe at a time finishes within the overall timeout.
+        for max_concurrent_probes in [1, ReportOptions::default().max_concurrent_probes] {
+            let options = ReportOptions {
+                enough_regions: EnoughRegions::All,
+                max_concurrent_probes,
+                ..Default::default()
+            };
+            let mut client = Client::with_options(None, options).await?;
+
+            let r = client.get_report(dm.clone(), None, None).await?;
+            assert!(
+                !r.partial,
+                "{max_concurrent_probes}: expected a complete report"
+            );
+            assert_eq!(r.region_latency.len(), dm.regions.len());
+            assert!(r.probe_duration < ReportOptions::default().overall_probe_timeout);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stun_bind_addrs() -> Result<()> {
+        let _guard = setup_logging();
+        let (stun_addr, _stun_stats, _cleanup_guard) = stun::test::serve_v4().await?;
+        let dm = stun::test::derp_map_of([stun_addr].into_iter());
+
+        let options = ReportOptions {
+            stun_bind: StunBind::Addrs {
+                v4: Some(Ipv4Addr::LOCALHOST),
+                v6: None,
+            },
+            ..Default::default()
+        };
+        let mut client = Client::with_options(None, options).await?;
+
+        let r = client.get_report(dm, None, None).await?;
+        assert!(r.udp, "want UDP");
+        let local_v4 = r.stun_local_v4.expect("no local IPv4 address");
+        assert_eq!(local_v4.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(r.stun_local_v6, None);
+        assert_eq!(r.stun_interface, None);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expire_in_flight_stun() -> Result<()> {
+        let mut actor = Actor::new(None, Default::default())?;
+        let now = Instant::now();
+        let (expiring_tx, mut expiring_rx) = oneshot::channel();
+        let (waiting_tx, mut waiting_rx) = oneshot::channel();
+        for (deadline, s) in [
+            (now + Duration::from_secs(1), expiring_tx),
+            (now + Duration::from_secs(5), waiting_tx),
+        ] {
+            let inflight = Inflight {
+                txn: stun::TransactionId::default(),
+                start: now,
+                deadline,
+                integrity: None,
+                s,
+            };
+            let (response_tx, _response_rx) = oneshot::channel();
+            actor.handle_in_flight_stun(inflight, response_tx);
+        }
+        assert_eq!(
+            actor.next_in_flight_stun_deadline(),
+            Some(now + Duration::from_secs(1))
+        );
+
+        time::advance(Duration::from_secs(2)).await;
+        actor.expire_in_flight_stun();
+        assert_eq!(
+            expiring_rx.try_recv(),
+            Err(oneshot::error::TryRecvError::Closed)
+        );
+        assert_eq!(
+            waiting_rx.try_recv(),
+            Err(oneshot::error::TryRecvError::Empty)
+        );
+        assert_eq!(
+            actor.next_in_flight_stun_deadline(),
+            Some(now + Duration::from_secs(5))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stun_error_response() -> Result<()> {
+        let mut actor = Actor::new(None, Default::default())?;
+        let txn = stun::TransactionId::from([7; 12]);
+        let (s, mut rx) = oneshot::channel();
+        let inflight = Inflight {
+            txn,
+            start: Instant::now(),
+            deadline: Instant::now() + Duration::from_secs(5),
+            integrity: None,
+            s,
+        };
+        let (response_tx, _response_rx) = oneshot::channel();
+        actor.handle_in_flight_stun(inflight, response_tx);
+
+        // An error response with ERROR-CODE 401 "Unauthorized".
+        let mut pkt = vec![0x01, 0x11, 0x00, 0x14, 0x21, 0x12, 0xa4, 0x42];
+        pkt.extend_from_slice(&[7; 12]);
+        pkt.extend_from_slice(&[0x00, 0x09, 0x00, 0x10, 0x00, 0x00, 0x04, 0x01]);
+        pkt.extend_from_slice(b"Unauthorized");
+        actor.handle_stun_packet(&pkt, "192.0.2.1:3478".parse().unwrap());
+
+        let err = rx.try_recv()?.unwrap_err();
+        assert_eq!(err.code, 401);
+        assert_eq!(err.reason, "Unauthorized");
+        assert!(actor.in_flight_stun_requests.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stun_integrity() -> Result<()> {
+        let mut actor = Actor::new(None, Default::default())?;
+        let txn = stun::TransactionId::default();
+        let key = stun::IntegrityKey::new("s3cret");
+        let (s, mut rx) = oneshot::channel();
+        let inflight = Inflight {
+            txn,
+            start: Instant::now(),
+            deadline: Instant::now() + Duration::from_secs(5),
+            integrity: Some(stun::IntegrityKeys::new(key.clone())),
+            s,
+        };
+        let (response_tx, _response_rx) = oneshot::channel();
+        actor.handle_in_flight_stun(inflight, response_tx);
+
+        // An unsigned response is dropped, the transaction stays in flight.
+        let src = "192.0.2.1:3478".parse().unwrap();
+        let addr = "203.0.113.1:1234".parse().unwrap();
+        actor.handle_stun_packet(&stun::response(txn, addr), src);
+        assert!(rx.try_recv().is_err());
+        assert!(actor.in_flight_stun_requests.contains_key(&txn));
+
+        let options = stun::ResponseOptions {
+            integrity: Some(key),
+            ..Default::default()
+        };
+        actor.handle_stun_packet(&stun::response_with_options(txn, addr, &options), src);
+        let (_, mapped) = rx.try_recv()?.unwrap();
+        assert_eq!(mapped, addr);
+        assert!(actor.in_flight_stun_requests.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_report_options() {
+        let options = ReportOptions {
+            overall_probe_timeout: Duration::from_secs(1),
+            captive_portal_timeout: Duration::from_secs(2),
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+        assert!(Client::with_options(None, options.clone()).await.is_err());
+        assert!(ReportOptions::default().validate().is_ok());
+
+        // Captive portal options are not used when the check is skipped.
+        let options = ReportOptions {
+            skip_captive_portal: true,
+            ..options
+        };
+        assert!(options.validate().is_ok());
+
+        let options = ReportOptions {
+            max_probe_attempts: Some(0),
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+
+        let options = ReportOptions {
+            max_concurrent_probes: 0,
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+
+        let options = ReportOptions {
+            stun_retransmit: stun::Retransmit {
+                max_requests: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+
+        let options = ReportOptions {
+            stun_bind: StunBind::Interface(String::new()),
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+
+        let options = ReportOptions {
+            probe_budget: Some(ProbeBudget {
+                max_probes: 0,
+                max_probes_per_sec: None,
+            }),
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+
+        let options = ReportOptions {
+            probe_budget: Some(ProbeBudget {
+                max_probes: 10,
+                max_probes_per_sec: Some(0),
+            }),
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+
+        let options = ReportOptions {
+            preferred_derp_margin: PreferredDerpMargin {
+                absolute: Duration::ZERO,
+                percent: 101,
+            },
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_last_report_store() -> Result<()> {
+        let _guard = setup_logging();
+
+        #[derive(Debug, Default)]
+        struct MemStore(std::sync::Mutex<Option<Vec<u8>>>);
+
+        impl ReportStore for MemStore {
+            fn load(&self) -> Result<Option<Vec<u8>>> {
+                Ok(self.0.lock().unwrap().clone())
+            }
+
+            fn store(&self, data: &[u8]) -> Result<()> {
+                *self.0.lock().unwrap() = Some(data.to_vec());
+                Ok(())
+            }
+        }
+
+        let (stun_addr, _stun_stats, _cleanup_guard) =
+            stun::test::serve("0.0.0.0".parse().unwrap()).await?;
+        let dm = stun::test::derp_map_of([stun_addr].into_iter());
+
+        let store = Arc::new(MemStore::default());
+        let options = ReportOptions {
+            last_report_store: Some(store.clone()),
+            ..Default::default()
+        };
+        let mut client = Client::with_options(None, options.clone()).await?;
+        let r = client.get_report(dm, None, None).await?;
+        assert!(store.0.lock().unwrap().is_some(), "report not stored");
+
+        // A restarted actor picks up the stored report.
+        // Only the monotonic generation time is not stored.
+        let actor = Actor::new(None, options.clone())?;
+        let want = Report {
+            generated_instant: None,
+            ..(*r).clone()
+        };
+        assert_eq!(actor.reports.last.as_deref(), Some(&want));
+        assert!(actor.reports.last.unwrap().age().is_some());
+
+        // Unless it is too old.
+        let options = ReportOptions {
+            last_report_max_age: Duration::ZERO,
+            ..options
+        };
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let actor = Actor::new(None, options)?;
+        assert!(actor.reports.last.is_none());
 
         Ok(())
     }
@@ -1152,7 +2602,7 @@ mod tests {
         ];
         for mut tt in tests {
             println!("test: {}", tt.name);
-            let mut actor = Actor::new(None).unwrap();
+            let mut actor = Actor::new(None, Default::default()).unwrap();
             for s in &mut tt.steps {
                 // trigger the timer
                 time::advance(Duration::from_secs(s.after)).await;
@@ -1171,6 +2621,80 @@ mod tests {
         Ok(())
     }
 
+    fn latencies(a: impl IntoIterator<Item = (u16, u64)>) -> RegionLatencies {
+        let mut latencies = RegionLatencies::new();
+        for (region_id, ms) in a {
+            latencies.update_region(region_id, Duration::from_millis(ms));
+        }
+        latencies
+    }
+
+    #[test]
+    fn test_select_preferred_derp_flapping() {
+        let margin = PreferredDerpMargin {
+            absolute: Duration::from_millis(5),
+            percent: 10,
+        };
+        // Two regions within jitter of each other, the faster one alternates.
+        let rounds = [
+            latencies([(1, 50), (2, 52)]),
+            latencies([(1, 53), (2, 50)]),
+            latencies([(1, 50), (2, 49)]),
+            latencies([(1, 54), (2, 51)]),
+        ];
+        let mut prev_derp = 0;
+        for (i, current) in rounds.iter().enumerate() {
+            let selected = select_preferred_derp(current, current, prev_derp, margin).unwrap();
+            assert_eq!(selected.region_id, 1, "round {i}");
+            assert_eq!(selected.kept, i != 0, "round {i}");
+            prev_derp = selected.region_id;
+        }
+        let selected = select_preferred_derp(&rounds[0], &rounds[0], 0, margin).unwrap();
+        assert_eq!(selected.lead, Some(Duration::from_millis(2)));
+    }
+
+    #[test]
+    fn test_select_preferred_derp_margin() {
+        let margin = PreferredDerpMargin {
+            absolute: Duration::from_millis(5),
+            percent: 10,
+        };
+
+        // Faster by the percentage, but not by the absolute margin.
+        let current = latencies([(1, 20), (2, 16)]);
+        let selected = select_preferred_derp(&current, &current, 1, margin).unwrap();
+        assert_eq!(selected.region_id, 1);
+        assert!(selected.kept);
+
+        // Faster by the absolute margin, but not by the percentage.
+        let current = latencies([(1, 100), (2, 94)]);
+        let selected = select_preferred_derp(&current, &current, 1, margin).unwrap();
+        assert_eq!(selected.region_id, 1);
+
+        // Faster by both.
+        let current = latencies([(1, 100), (2, 80), (3, 90)]);
+        let selected = select_preferred_derp(&current, &current, 1, margin).unwrap();
+        assert_eq!(
+            selected,
+            PreferredDerp {
+                region_id: 2,
+                lead: Some(Duration::from_millis(10)),
+                kept: false,
+            }
+        );
+
+        // The previous region is gone.
+        let current = latencies([(2, 30), (3, 29)]);
+        let selected = select_preferred_derp(&current, &current, 1, margin).unwrap();
+        assert_eq!(selected.region_id, 3);
+        assert!(!selected.kept);
+
+        assert!(
+            select_preferred_derp(&RegionLatencies::new(), &RegionLatencies::new(), 1, margin)
+                .is_none()
+        );
+    }
+
     #[tokio::test]
     async fn test_hairpin() -> Result<()> {
         // Hairpinning is initiated after we discover our own IPv4 socket address (IP +
@@ -1222,8 +2746,29 @@ mod tests {
         let r = client.get_report(dm, Some(sock), None).await?;
         dbg!(&r);
         assert_eq!(r.hair_pinning, Some(true));
+        assert_eq!(r.hair_pinning_v4, Some(true));
 
         task.abort();
         Ok(())
     }
+
+    #[test]
+    fn test_is_routable_v6_source() {
+        let routable = |ip: &str| is_routable_v6_source(ip.parse().unwrap());
+        assert!(routable("2001:db8::1"));
+        assert!(routable("3fff::1"));
+        assert!(routable("fd00::1"));
+        assert!(!routable("fe80::1"));
+        assert!(!routable("::1"));
+        assert!(!routable("::"));
+        assert!(!routable("1.2.3.4"));
+    }
+
+    #[tokio::test]
+    async fn test_os_has_ipv6_route() {
+        // Whatever the host has, a route implies a working IPv6 stack.
+        if os_has_ipv6_route().await {
+            assert!(os_has_ipv6().await);
+        }
+    }
 }
@@ -0,0 +1,268 @@
+//! Comparing netcheck [`Report`]s.
+//!
+//! Consumers of reports are usually interested in what changed since the previous report,
+//! e.g. to decide whether the local endpoints need to be advertised again.
+
+use std::fmt;
+use std::net::SocketAddr;
+
+use super::Report;
+
+/// A change of a single [`Report`] field, see [`Report::diff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReportChange {
+    /// [`Report::preferred_derp`] changed.
+    PreferredDerp {
+        /// The previous preferred region.
+        old: u16,
+        /// The new preferred region.
+        new: u16,
+    },
+    /// [`Report::udp`] changed.
+    Udp {
+        /// The previous value.
+        old: bool,
+        /// The new value.
+        new: bool,
+    },
+    /// [`Report::ipv4`] changed.
+    Ipv4 {
+        /// The previous value.
+        old: bool,
+        /// The new value.
+        new: bool,
+    },
+    /// [`Report::ipv6`] changed.
+    Ipv6 {
+        /// The previous value.
+        old: bool,
+        /// The new value.
+        new: bool,
+    },
+    /// [`Report::global_v4`] changed.
+    GlobalV4 {
+        /// The previous public IPv4 endpoint.
+        old: Option<SocketAddr>,
+        /// The new public IPv4 endpoint.
+        new: Option<SocketAddr>,
+    },
+    /// [`Report::global_v6`] changed.
+    GlobalV6 {
+        /// The previous public IPv6 endpoint.
+        old: Option<SocketAddr>,
+        /// The new public IPv6 endpoint.
+        new: Option<SocketAddr>,
+    },
+    /// [`Report::mapping_varies_by_dest_ip`] changed.
+    MappingVariesByDestIp {
+        /// The previous value.
+        old: Option<bool>,
+        /// The new value.
+        new: Option<bool>,
+    },
+    /// [`Report::hair_pinning`] changed.
+    HairPinning {
+        /// The previous value.
+        old: Option<bool>,
+        /// The new value.
+        new: Option<bool>,
+    },
+    /// [`Report::captive_portal`] changed.
+    CaptivePortal {
+        /// The previous value.
+        old: Option<bool>,
+        /// The new value.
+        new: Option<bool>,
+    },
+}
+
+impl ReportChange {
+    /// Whether this change warrants advertising our endpoints again.
+    ///
+    /// These are changes of the public endpoints, of how reachable they are and of the
+    /// preferred DERP region.
+    pub fn is_significant(&self) -> bool {
+        match self {
+            ReportChange::PreferredDerp { .. }
+            | ReportChange::Udp { .. }
+            | ReportChange::Ipv4 { .. }
+            | ReportChange::Ipv6 { .. }
+            | ReportChange::GlobalV4 { .. }
+            | ReportChange::GlobalV6 { .. }
+            | ReportChange::MappingVariesByDestIp { .. } => true,
+            ReportChange::HairPinning { .. } | ReportChange::CaptivePortal { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for ReportChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReportChange::PreferredDerp { old, new } => write!(f, "derp {old} -> {new}"),
+            ReportChange::Udp { old, new } => write!(f, "udp {old} -> {new}"),
+            ReportChange::Ipv4 { old, new } => write!(f, "v4 {old} -> {new}"),
+            ReportChange::Ipv6 { old, new } => write!(f, "v6 {old} -> {new}"),
+            ReportChange::GlobalV4 { old, new } => write!(f, "v4a {old:?} -> {new:?}"),
+            ReportChange::GlobalV6 { old, new } => write!(f, "v6a {old:?} -> {new:?}"),
+            ReportChange::MappingVariesByDestIp { old, new } => {
+                write!(f, "mapvarydest {old:?} -> {new:?}")
+            }
+            ReportChange::HairPinning { old, new } => write!(f, "hair {old:?} -> {new:?}"),
+            ReportChange::CaptivePortal { old, new } => write!(f, "captive {old:?} -> {new:?}"),
+        }
+    }
+}
+
+/// The changes between two [`Report`]s, see [`Report::diff`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReportChanges(Vec<ReportChange>);
+
+impl ReportChanges {
+    /// Returns an iterator over the changes.
+    pub fn iter(&self) -> impl Iterator<Item = &ReportChange> + '_ {
+        self.0.iter()
+    }
+
+    /// Returns the number of changes.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether nothing changed.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether any change warrants advertising our endpoints again.
+    ///
+    /// See [`ReportChange::is_significant`].
+    pub fn is_significant(&self) -> bool {
+        self.0.iter().any(ReportChange::is_significant)
+    }
+}
+
+impl fmt::Display for ReportChanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, change) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{change}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Report {
+    /// Returns what changed in this report compared to the *old* report.
+    ///
+    /// Only the fields describing the network are compared, latencies and timing
+    /// information always change and are ignored.
+    pub fn diff(&self, old: &Report) -> ReportChanges {
+        let mut changes = Vec::new();
+        macro_rules! compare {
+            ($field:ident, $change:ident) => {
+                if self.$field != old.$field {
+                    changes.push(ReportChange::$change {
+                        old: old.$field,
+                        new: self.$field,
+                    });
+                }
+            };
+        }
+        compare!(preferred_derp, PreferredDerp);
+        compare!(udp, Udp);
+        compare!(ipv4, Ipv4);
+        compare!(ipv6, Ipv6);
+        compare!(global_v4, GlobalV4);
+        compare!(global_v6, GlobalV6);
+        compare!(mapping_varies_by_dest_ip, MappingVariesByDestIp);
+        compare!(hair_pinning, HairPinning);
+        compare!(captive_portal, CaptivePortal);
+        ReportChanges(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn report() -> Report {
+        Report {
+            udp: true,
+            ipv4: true,
+            global_v4: Some("1.2.3.4:1234".parse().unwrap()),
+            mapping_varies_by_dest_ip: Some(false),
+            hair_pinning: Some(true),
+            preferred_derp: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_no_changes() {
+        let old = report();
+        let mut new = report();
+        // Latencies and timings are ignored.
+        new.region_latency
+            .update_region(1, Duration::from_millis(10));
+        new.probe_duration = Duration::from_millis(300);
+        let changes = new.diff(&old);
+        assert!(changes.is_empty());
+        assert!(!changes.is_significant());
+        assert_eq!(changes.to_string(), "");
+    }
+
+    #[test]
+    fn test_changes() {
+        let old = report();
+        let new = Report {
+            preferred_derp: 2,
+            ipv6: true,
+            global_v6: Some("[2001:db8::1]:1234".parse().unwrap()),
+            ..report()
+        };
+        let changes = new.diff(&old);
+        assert_eq!(
+            changes.iter().cloned().collect::<Vec<_>>(),
+            vec![
+                ReportChange::PreferredDerp { old: 1, new: 2 },
+                ReportChange::Ipv6 {
+                    old: false,
+                    new: true
+                },
+                ReportChange::GlobalV6 {
+                    old: None,
+                    new: new.global_v6,
+                },
+            ]
+        );
+        assert!(changes.is_significant());
+        assert_eq!(
+            changes.to_string(),
+            "derp 1 -> 2, v6 false -> true, v6a None -> Some([2001:db8::1]:1234)"
+        );
+    }
+
+    #[test]
+    fn test_insignificant_changes() {
+        let old = report();
+        let new = Report {
+            hair_pinning: Some(false),
+            captive_portal: Some(true),
+            ..report()
+        };
+        let changes = new.diff(&old);
+        assert_eq!(changes.len(), 2);
+        assert!(!changes.is_significant());
+
+        // The public endpoint moved.
+        let new = Report {
+            global_v4: Some("1.2.3.4:5678".parse().unwrap()),
+            ..report()
+        };
+        assert!(new.diff(&old).is_significant());
+    }
+}
@@ -1,5 +1,5 @@
 use iroh_metrics::{
-    core::{Counter, Metric},
+    core::{Counter, Histogram, Metric},
     struct_iterable::Iterable,
 };
 
@@ -12,9 +12,22 @@ pub struct Metrics {
     pub stun_packets_sent_ipv6: Counter,
     pub stun_packets_recv_ipv4: Counter,
     pub stun_packets_recv_ipv6: Counter,
+    pub stun_transactions_expired: Counter,
+    pub stun_error_responses: Counter,
+    pub stun_integrity_failures: Counter,
+    pub icmp_pings_sent_ipv4: Counter,
+    pub icmp_pings_sent_ipv6: Counter,
+    pub icmp_time_exceeded: Counter,
     pub reports: Counter,
     pub reports_full: Counter,
     pub reports_error: Counter,
+    pub report_duration: Histogram,
+    pub probe_latency: Histogram,
+    pub probes_succeeded: Counter,
+    pub probes_timed_out: Counter,
+    pub probes_send_failed: Counter,
+    pub probes_aborted: Counter,
+    pub hairpin_timeouts: Counter,
 }
 
 impl Default for Metrics {
@@ -27,9 +40,30 @@ impl Default for Metrics {
             stun_packets_sent_ipv6: Counter::new("Number of IPv6 STUN packets sent"),
             stun_packets_recv_ipv4: Counter::new("Number of IPv4 STUN packets received"),
             stun_packets_recv_ipv6: Counter::new("Number of IPv6 STUN packets received"),
+            stun_transactions_expired: Counter::new(
+                "Number of STUN requests which got no response before their deadline",
+            ),
+            stun_error_responses: Counter::new("Number of STUN error responses received"),
+            stun_integrity_failures: Counter::new(
+                "Number of STUN responses dropped for failing the message integrity check",
+            ),
+            icmp_pings_sent_ipv4: Counter::new("Number of ICMPv4 echo requests sent"),
+            icmp_pings_sent_ipv6: Counter::new("Number of ICMPv6 echo requests sent"),
+            icmp_time_exceeded: Counter::new(
+                "Number of ICMP echo requests dropped by a router as their TTL ran out",
+            ),
             reports: Counter::new("Number of reports executed by netcheck, including full reports"),
             reports_full: Counter::new("Number of full reports executed by netcheck"),
             reports_error: Counter::new("Number of executed reports resulting in an error"),
+            report_duration: Histogram::new("Time in seconds taken to generate a report"),
+            probe_latency: Histogram::new(
+                "Latency in seconds of successful probes, by protocol and region",
+            ),
+            probes_succeeded: Counter::new("Number of probes which measured a latency"),
+            probes_timed_out: Counter::new("Number of probes which timed out"),
+            probes_send_failed: Counter::new("Number of probes which failed to send"),
+            probes_aborted: Counter::new("Number of probes aborted before they finished"),
+            hairpin_timeouts: Counter::new("Number of hairpin checks which did not finish in time"),
         }
     }
 }
@@ -6,49 +6,59 @@
 //! messages from the client.  It follows roughly these steps:
 //!
 //! - Determines host IPv6 support.
-//! - Creates hairpin actor.
+//! - Creates hairpin actors.
 //! - Creates portmapper future.
 //! - Creates captive portal detection future.
+//! - Creates the NAT behavior tests future, if enabled.
 //! - Creates Probe Set futures.
 //!   - These send messages to the reportgen actor.
 //! - Loops driving the futures and handling actor messages:
 //!   - Disables futures as they are completed or aborted.
 //!   - Stop if there are no outstanding tasks/futures, or on timeout.
-//! - Sends the completed report to the netcheck actor.
+//! - Sends the completed, or on timeout partial, report to the netcheck actor.
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::future::Future;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
-use iroh_metrics::inc;
-use rand::seq::IteratorRandom;
-use tokio::net::UdpSocket;
-use tokio::sync::{mpsc, oneshot};
+use iroh_metrics::{inc, inc_by, observe};
+use rand::Rng;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::{broadcast, mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
 use tokio::time::{self, Instant};
-use tracing::{
-    debug, debug_span, error, info, info_span, instrument, trace, warn, Instrument, Span,
-};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, debug_span, error, info, info_span, instrument, trace, warn, Instrument};
 
 use super::NetcheckMetrics;
 use crate::defaults::DEFAULT_DERP_STUN_PORT;
-use crate::derp::{DerpMap, DerpNode, DerpRegion, UseIpv4, UseIpv6};
-use crate::dns::DNS_RESOLVER;
+use crate::derp::{DerpMap, DerpNode, UseIpv4, UseIpv6};
 use crate::net::interfaces;
-use crate::net::ip;
-use crate::netcheck::{self, Report};
-use crate::ping::Pinger;
+use crate::netcheck::{self, ProbeFailureKind, Report, UdpVerdict};
+use crate::ping::{PingError, Pinger};
+use crate::stun::nat_behavior::{self, NatBehavior};
 use crate::util::{CancelOnDrop, MaybeFuture};
 use crate::{portmapper, stun};
 
+mod captive_portal;
+mod dns_cache;
 mod hairpin;
 mod probes;
 
-use probes::{Probe, ProbePlan, ProbeProto};
+use captive_portal::check_captive_portal;
+pub use captive_portal::{CaptivePortalConfig, CaptivePortalDetails, CaptivePortalEndpoint};
+use dns_cache::DnsCache;
+pub use probes::{DefaultProbePlanner, Probe, ProbePlan, ProbePlanner, ProbeProto, ProbeSet};
+
+/// The port used for HTTPS probes if the DERP URL does not specify one.
+const DEFAULT_HTTPS_PORT: u16 = 443;
 
 /// Fake DNS TLD used in tests for an invalid hostname.
 const DOT_INVALID: &str = ".invalid";
@@ -63,14 +73,420 @@ const STUN_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
 /// The maximum amount of time netcheck will spend probing with ICMP packets.
 const ICMP_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// The maximum jitter added to delayed probes, in percent of their delay.
+const PROBE_DELAY_JITTER_PERCENT: u32 = 10;
+
 /// How long to await for a captive-portal result, chosen semi-arbitrarily.
 const CAPTIVE_PORTAL_DELAY: Duration = Duration::from_millis(200);
 
 /// Timeout for captive portal checks, must be lower than OVERALL_PROBE_TIMEOUT
 const CAPTIVE_PORTAL_TIMEOUT: Duration = Duration::from_secs(2);
 
+/// How long a hairpin check may take before it is given up.
+const HAIRPIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How old a stored report may be to still be used as last report after a restart.
+const LAST_REPORT_MAX_AGE: Duration = Duration::from_secs(30 * 60);
+
+/// The default latency improvement in percent needed to change the preferred DERP region.
+const PREFERRED_DERP_MARGIN_PERCENT: u32 = 33;
+
+/// The default number of regions after which further probes are aborted.
 const ENOUGH_REGIONS: usize = 3;
 
+/// The default number of probes which run at the same time.
+const MAX_CONCURRENT_PROBES: usize = 8;
+
+/// How long a STUN probe waits for its response before letting other probes run.
+///
+/// Lost STUN requests are never answered, without this they would hold on to their
+/// concurrency permit until the report finishes.
+const STUN_PERMIT_HOLD: Duration = Duration::from_millis(300);
+
+/// How long each request of the NAT behavior tests waits for its response.
+///
+/// The filtering tests expect some requests not to be answered, so this must be short.
+const NAT_BEHAVIOR_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The maximum number of addresses of a DERP node an ICMP probe tries.
+///
+/// Each address is given the full ICMP probe timeout.
+const MAX_ICMP_CANDIDATES: usize = 2;
+
+/// The maximum number of addresses of a DERP node an HTTPS probe tries, one after another.
+const MAX_HTTPS_CANDIDATES: usize = 2;
+
+/// How long an HTTPS probe waits for the TCP connection to each address.
+const HTTPS_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Options to tune the generation of a netcheck report.
+///
+/// The defaults are suitable for most networks.  High-latency links (satellite, cellular)
+/// may need longer timeouts while tests usually want much shorter ones.
+#[derive(Debug, Clone)]
+pub struct ReportOptions {
+    /// The maximum amount of time netcheck will spend gathering a single report.
+    pub overall_probe_timeout: Duration,
+    /// The maximum amount of time netcheck will spend probing with STUN packets without
+    /// getting a reply before switching to HTTP probing, on the assumption that outbound
+    /// UDP is blocked.
+    pub stun_probe_timeout: Duration,
+    /// How STUN probes retransmit their request while waiting for the response.
+    ///
+    /// A lost request or response is retransmitted within the same probe, the probe is
+    /// still given up after [`ReportOptions::stun_probe_timeout`].
+    pub stun_retransmit: stun::Retransmit,
+    /// The maximum amount of time netcheck will spend probing with ICMP packets.
+    pub icmp_probe_timeout: Duration,
+    /// How long to wait before starting the captive portal check.
+    pub captive_portal_delay: Duration,
+    /// Timeout for captive portal checks, must be lower than the overall probe timeout.
+    pub captive_portal_timeout: Duration,
+    /// How long a hairpin check may take after it started.
+    ///
+    /// A check which takes longer is given up, leaving its hairpinning result unknown.
+    pub hairpin_timeout: Duration,
+    /// How captive portals are detected.
+    pub captive_portal: CaptivePortalConfig,
+    /// Never check for captive portals.
+    ///
+    /// The captive portal check makes plain HTTP requests, which may be unwanted.  Reports
+    /// will always have [`Report::captive_portal`] set to `None`.
+    pub skip_captive_portal: bool,
+    /// Where to persist the last report, so it can be used again after a restart.
+    ///
+    /// Without a previous report the first report after a restart has to probe all
+    /// regions.
+    pub last_report_store: Option<Arc<dyn netcheck::ReportStore>>,
+    /// Stored reports older than this are ignored and a full report is generated instead.
+    pub last_report_max_age: Duration,
+    /// Only probe the DERP regions with these IDs.
+    ///
+    /// Regions not in the [`DerpMap`] are ignored.  `None` probes all regions.
+    pub regions: Option<BTreeSet<u16>>,
+    /// Only probe this many regions, those with the lowest latency in the last report.
+    ///
+    /// Without a last report the regions with the lowest IDs are probed.  This is applied
+    /// after [`ReportOptions::regions`].
+    pub max_regions: Option<usize>,
+    /// After how many regions reported a latency the remaining probes are aborted.
+    ///
+    /// Once enough regions have reported, the remaining probes get a little more time
+    /// before they are aborted.
+    pub enough_regions: EnoughRegions,
+    /// The maximum number of probes sent for each region and protocol, including the first.
+    ///
+    /// `None` uses as many attempts as the probe plan decides on.
+    pub max_probe_attempts: Option<usize>,
+    /// Limits the total number of probes sent for a report.
+    ///
+    /// `None` sends all the probes of the probe plan.
+    pub probe_budget: Option<ProbeBudget>,
+    /// The maximum number of probes running at the same time.
+    ///
+    /// Starting all probes at once sends a burst of packets which can exhaust the
+    /// connection tracking of small routers.  The probes of the preferred and fastest
+    /// regions start first.
+    pub max_concurrent_probes: usize,
+    /// How much faster another region must be to replace the previous preferred DERP
+    /// region.
+    pub preferred_derp_margin: PreferredDerpMargin,
+    /// Where to bind the STUN sockets, unless they are provided to
+    /// [`netcheck::Client::get_report`].
+    pub stun_bind: StunBind,
+    /// Decides which probes a report runs.
+    ///
+    /// The default is [`DefaultProbePlanner`].  Custom planners can e.g. probe fewer
+    /// regions on expensive networks.
+    pub probe_planner: Arc<dyn ProbePlanner>,
+    /// Which address families to probe.
+    ///
+    /// The other address family is not probed at all, which is marked in the report by
+    /// [`Report::ipv4_not_probed`] or [`Report::ipv6_not_probed`].
+    pub address_families: AddressFamilies,
+    /// Run the RFC 5780 NAT behavior tests during full reports.
+    ///
+    /// The tests use the IPv4 STUN server of the preferred, or else the first, DERP region
+    /// and only succeed if it supports RFC 5780.  The result is stored in
+    /// [`Report::nat_behavior`].
+    pub nat_behavior: bool,
+}
+
+/// The number of regions after which netcheck stops probing, see
+/// [`ReportOptions::enough_regions`].
+///
+/// This is never more than the number of regions being probed.  For incremental reports it
+/// is also limited to the number of regions which reported in the last report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnoughRegions {
+    /// Wait for all regions.
+    All,
+    /// Wait for this many regions.
+    Count(usize),
+}
+
+impl Default for EnoughRegions {
+    fn default() -> Self {
+        Self::Count(ENOUGH_REGIONS)
+    }
+}
+
+/// A limit on the probes sent for a single report, see [`ReportOptions::probe_budget`].
+///
+/// With many DERP regions a full report sends a lot of packets.  When the budget is
+/// exceeded the preferred region is probed first, followed by a few random regions without
+/// a known latency and then the remaining regions by their latency in the last report.
+/// Whatever does not fit in the budget is not probed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeBudget {
+    /// The maximum number of probes.
+    pub max_probes: usize,
+    /// The maximum number of probes started per second.
+    ///
+    /// Probes are delayed further as needed.  `None` does not limit the rate.
+    pub max_probes_per_sec: Option<u32>,
+}
+
+/// How much faster a region must be to replace the previous preferred DERP region, see
+/// [`ReportOptions::preferred_derp_margin`].
+///
+/// Changing the preferred DERP region moves the DERP connection, which is expensive.  To
+/// avoid flapping between regions with similar latencies another region only becomes the
+/// preferred region if its latency is lower than the current latency of the previous
+/// preferred region by both margins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreferredDerpMargin {
+    /// The minimum latency improvement.
+    pub absolute: Duration,
+    /// The minimum latency improvement, in percent of the previous region's latency.
+    pub percent: u32,
+}
+
+impl Default for PreferredDerpMargin {
+    fn default() -> Self {
+        Self {
+            absolute: Duration::ZERO,
+            percent: PREFERRED_DERP_MARGIN_PERCENT,
+        }
+    }
+}
+
+impl PreferredDerpMargin {
+    /// Whether *challenger* is fast enough to replace a region with latency *previous*.
+    pub(super) fn is_exceeded(&self, previous: Duration, challenger: Duration) -> bool {
+        let improvement = previous.saturating_sub(challenger);
+        !improvement.is_zero()
+            && improvement >= self.absolute
+            && improvement >= previous * self.percent / 100
+    }
+}
+
+/// Where netcheck binds its own STUN sockets, see [`ReportOptions::stun_bind`].
+///
+/// On machines with several network interfaces, e.g. with a VPN, the OS picks the
+/// interface of the default route for sockets bound to the unspecified address.  Binding to
+/// an interface or one of its addresses measures that interface instead.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum StunBind {
+    /// Bind to the unspecified addresses, the OS picks the interface.
+    #[default]
+    Any,
+    /// Bind to these local addresses.
+    ///
+    /// No socket is bound for an address family without an address, which disables its
+    /// STUN probes.
+    Addrs {
+        /// The local IPv4 address.
+        v4: Option<Ipv4Addr>,
+        /// The local IPv6 address.
+        v6: Option<Ipv6Addr>,
+    },
+    /// Bind to this network interface, e.g. `eth0`.
+    ///
+    /// The sockets are bound to the addresses of the interface, and to the interface itself
+    /// using `SO_BINDTODEVICE` on Linux and Android or `IP_BOUND_IF` on macOS and iOS.  If
+    /// that fails, e.g. without the privileges older Linux kernels require, no socket is
+    /// bound and the STUN probes of the address family are disabled.  Not supported on
+    /// other platforms, where [`ReportOptions::validate`] fails.
+    Interface(String),
+}
+
+/// The address families netcheck probes, see [`ReportOptions::address_families`].
+///
+/// Hosts which are known to only have IPv4 or IPv6 connectivity, e.g. in an IPv6-only
+/// container, can skip probing the other address family instead of waiting for its probes
+/// to fail.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamilies {
+    /// Probe both IPv4 and IPv6.
+    #[default]
+    Both,
+    /// Only probe IPv4.
+    Ipv4Only,
+    /// Only probe IPv6.
+    Ipv6Only,
+}
+
+impl AddressFamilies {
+    /// Whether IPv4 is probed.
+    pub fn ipv4(&self) -> bool {
+        matches!(self, Self::Both | Self::Ipv4Only)
+    }
+
+    /// Whether IPv6 is probed.
+    pub fn ipv6(&self) -> bool {
+        matches!(self, Self::Both | Self::Ipv6Only)
+    }
+}
+
+impl Default for ReportOptions {
+    fn default() -> Self {
+        Self {
+            overall_probe_timeout: OVERALL_PROBE_TIMEOUT,
+            stun_probe_timeout: STUN_PROBE_TIMEOUT,
+            stun_retransmit: stun::Retransmit::default(),
+            icmp_probe_timeout: ICMP_PROBE_TIMEOUT,
+            captive_portal_delay: CAPTIVE_PORTAL_DELAY,
+            captive_portal_timeout: CAPTIVE_PORTAL_TIMEOUT,
+            hairpin_timeout: HAIRPIN_TIMEOUT,
+            captive_portal: CaptivePortalConfig::default(),
+            skip_captive_portal: false,
+            last_report_store: None,
+            last_report_max_age: LAST_REPORT_MAX_AGE,
+            regions: None,
+            max_regions: None,
+            enough_regions: EnoughRegions::default(),
+            max_probe_attempts: None,
+            probe_budget: None,
+            max_concurrent_probes: MAX_CONCURRENT_PROBES,
+            preferred_derp_margin: PreferredDerpMargin::default(),
+            stun_bind: StunBind::default(),
+            probe_planner: Arc::new(DefaultProbePlanner),
+            address_families: AddressFamilies::default(),
+            nat_behavior: false,
+        }
+    }
+}
+
+impl ReportOptions {
+    /// Checks the options are consistent with each other.
+    ///
+    /// The captive portal options are only checked if the captive portal check is not
+    /// skipped.
+    pub fn validate(&self) -> Result<()> {
+        if !self.skip_captive_portal {
+            ensure!(
+                self.captive_portal_timeout < self.overall_probe_timeout,
+                "captive portal timeout ({:?}) must be lower than the overall probe timeout ({:?})",
+                self.captive_portal_timeout,
+                self.overall_probe_timeout,
+            );
+            self.captive_portal.validate()?;
+        }
+        if let Some(ref regions) = self.regions {
+            ensure!(!regions.is_empty(), "regions must not be empty");
+        }
+        ensure!(self.max_regions != Some(0), "max_regions must not be zero");
+        ensure!(
+            self.enough_regions != EnoughRegions::Count(0),
+            "enough_regions must not be zero"
+        );
+        ensure!(
+            self.stun_retransmit.max_requests > 0 && !self.stun_retransmit.rto.is_zero(),
+            "stun_retransmit must send a request and have a non-zero RTO"
+        );
+        ensure!(
+            self.max_probe_attempts != Some(0),
+            "max_probe_attempts must not be zero"
+        );
+        ensure!(
+            self.max_concurrent_probes > 0,
+            "max_concurrent_probes must not be zero"
+        );
+        if let Some(budget) = self.probe_budget {
+            ensure!(budget.max_probes > 0, "probe budget must not be zero");
+            ensure!(
+                budget.max_probes_per_sec != Some(0),
+                "probe budget rate must not be zero"
+            );
+        }
+        if let StunBind::Interface(ref name) = self.stun_bind {
+            ensure!(
+                !name.is_empty(),
+                "stun_bind interface name must not be empty"
+            );
+            #[cfg(not(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios"
+            )))]
+            bail!("stun_bind to an interface is not supported on this platform");
+        }
+        ensure!(
+            self.preferred_derp_margin.percent <= 100,
+            "preferred_derp_margin percent must not be more than 100"
+        );
+        Ok(())
+    }
+}
+
+/// Progress events emitted while a report is being generated.
+///
+/// Subscribe to these using [`netcheck::Client::subscribe_events`].  They are purely
+/// informational, e.g. to show progress in a UI, the final result is still the [`Report`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReportEvent {
+    /// A probe to a DERP region was started.
+    ProbeStarted {
+        /// The region of the probed DERP node.
+        region_id: u16,
+        /// The protocol of the probe.
+        proto: ProbeProto,
+    },
+    /// A probe to a DERP region finished.
+    ProbeFinished {
+        /// The region of the probed DERP node.
+        region_id: u16,
+        /// The protocol of the probe.
+        proto: ProbeProto,
+        /// The measured latency, if the probe got any.
+        latency: Option<Duration>,
+    },
+    /// The first public address of an address family was discovered using STUN.
+    GlobalAddrDiscovered(SocketAddr),
+    /// The hairpinning check of an address family finished.
+    HairpinDone {
+        /// Whether this was the IPv6 check, rather than the IPv4 one.
+        ipv6: bool,
+        /// Whether hairpinning works.
+        works: bool,
+    },
+    /// The portmapper probe finished.
+    PortmapperDone(Option<portmapper::ProbeOutput>),
+    /// The captive portal check finished.
+    CaptivePortalDone(Option<bool>),
+    /// No further probes will be run.
+    ProbingStopped(ProbingStopReason),
+}
+
+/// Why [`ReportEvent::ProbingStopped`] was emitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, derive_more::Display)]
+pub enum ProbingStopReason {
+    /// Enough regions reported latencies, the remaining probes were not needed.
+    #[display("enough regions")]
+    EnoughRegions,
+    /// All probes in the probe plan have finished.
+    #[display("all probes finished")]
+    AllProbesFinished,
+    /// The STUN probes did not get a reply in time.
+    #[display("stun probe timeout")]
+    StunTimeout,
+    /// The overall report timeout was reached.
+    #[display("overall timeout")]
+    OverallTimeout,
+}
+
 /// Holds the state for a single invocation of [`netcheck::Client::get_report`].
 ///
 /// Dropping this will cancel the actor and stop the report generation.
@@ -85,6 +501,7 @@ impl Client {
     ///
     /// The actor starts running immediately and only generates a single report, after which
     /// it shuts down.  Dropping this handle will abort the actor.
+    #[allow(clippy::too_many_arguments)]
     pub(super) fn new(
         netcheck: netcheck::Addr,
         last_report: Option<Arc<Report>>,
@@ -93,12 +510,31 @@ impl Client {
         derp_map: DerpMap,
         stun_sock4: Option<Arc<UdpSocket>>,
         stun_sock6: Option<Arc<UdpSocket>>,
+        options: ReportOptions,
+        events: broadcast::Sender<ReportEvent>,
     ) -> Self {
         let (msg_tx, msg_rx) = mpsc::channel(32);
         let addr = Addr {
             sender: msg_tx.clone(),
         };
         let incremental = last_report.is_some();
+        // The STUN sockets of an address family which is not probed are not needed.
+        let ipv4_not_probed = !options.address_families.ipv4();
+        let ipv6_not_probed = !options.address_families.ipv6();
+        let stun_sock4 = stun_sock4.filter(|_| !ipv4_not_probed);
+        let stun_sock6 = stun_sock6.filter(|_| !ipv6_not_probed);
+        let stun_local_v4 = stun_local_addr(stun_sock4.as_deref());
+        let stun_local_v6 = stun_local_addr(stun_sock6.as_deref());
+        // Without an IPv6 STUN socket we will never discover a global IPv6 address.
+        let hairpin_v6_actor = stun_sock6
+            .is_some()
+            .then(|| hairpin::Client::new(netcheck.clone(), addr.clone(), true));
+        let derp_map = probes::restrict_regions(
+            &derp_map,
+            options.regions.as_ref(),
+            options.max_regions,
+            last_report.as_deref(),
+        );
         let mut actor = Actor {
             msg_tx,
             msg_rx,
@@ -110,9 +546,26 @@ impl Client {
             derp_map,
             stun_sock4,
             stun_sock6,
-            report: Report::default(),
-            hairpin_actor: hairpin::Client::new(netcheck, addr),
+            options,
+            events,
+            report: Report {
+                stun_local_v4,
+                stun_local_v6,
+                ipv4_not_probed,
+                ipv6_not_probed,
+                ..Default::default()
+            },
+            hairpin_v4_actor: hairpin::Client::new(netcheck, addr, false),
+            hairpin_v6_actor,
             outstanding_tasks: OutstandingTasks::default(),
+            enough_regions_timer: MaybeFuture::default(),
+            hairpin_v4_timer: MaybeFuture::default(),
+            hairpin_v6_timer: MaybeFuture::default(),
+            pending_probes: Vec::new(),
+            unfinished_sets: BTreeMap::new(),
+            dns_cache: Default::default(),
+            dns_prewarm: JoinSet::new(),
+            stun_sent: Default::default(),
         };
         let task = tokio::spawn(
             async move { actor.run().await }.instrument(info_span!("reportgen.actor")),
@@ -143,15 +596,13 @@ impl Addr {
 /// Messages to send to the reportstate [`Actor`].
 #[derive(Debug)]
 enum Message {
-    /// Set the hairpinning availability in the report.
-    HairpinResult(bool),
-    /// Check whether executing a probe would still help.
-    // TODO: Ideally we remove the need for this message and the logic is inverted: once we
-    // get a probe result we cancel all probes that are no longer needed.  But for now it's
-    // this way around to ease conversion.
-    ProbeWouldHelp(Probe, Arc<DerpNode>, oneshot::Sender<bool>),
-    /// Abort all remaining probes.
-    AbortProbes,
+    /// Set the hairpinning availability of an address family in the report.
+    HairpinResult {
+        /// Whether this is the result for IPv6, rather than IPv4.
+        ipv6: bool,
+        /// Whether hairpinning works.
+        works: bool,
+    },
 }
 
 /// The reportstate actor.
@@ -173,30 +624,66 @@ struct Actor {
     port_mapper: Option<portmapper::Client>,
     skip_external_network: bool,
     /// The DERP configuration.
+    ///
+    /// Only contains the regions to probe, see [`ReportOptions::regions`].
     derp_map: DerpMap,
     /// Socket to send IPv4 STUN requests from.
     stun_sock4: Option<Arc<UdpSocket>>,
     /// Socket so send IPv6 STUN requests from.
     stun_sock6: Option<Arc<UdpSocket>>,
+    /// The timeouts and other options for this report.
+    options: ReportOptions,
+    /// Where to emit [`ReportEvent`]s.
+    ///
+    /// Sending never blocks, if subscribers lag behind they lose the oldest events.
+    events: broadcast::Sender<ReportEvent>,
 
     // Internal state.
     /// Whether we're doing an incremental report.
     incremental: bool,
     /// The report being built.
     report: Report,
-    /// The hairping actor.
-    hairpin_actor: hairpin::Client,
+    /// The hairpin actor checking IPv4.
+    hairpin_v4_actor: hairpin::Client,
+    /// The hairpin actor checking IPv6, if there is an IPv6 STUN socket.
+    hairpin_v6_actor: Option<hairpin::Client>,
     /// Which tasks the [`Actor`] is still waiting on.
     ///
     /// This is essentially the summary of all the work the [`Actor`] is doing.
     outstanding_tasks: OutstandingTasks,
+    /// Timer to stop probing once enough regions have reported.
+    ///
+    /// Armed at most once, by [`Actor::add_stun_addr_latency`], and disarmed when probing
+    /// stops.
+    enough_regions_timer: MaybeFuture<Pin<Box<time::Sleep>>>,
+    /// Timer to give up the IPv4 hairpin check, armed when the check starts.
+    hairpin_v4_timer: MaybeFuture<Pin<Box<time::Sleep>>>,
+    /// Timer to give up the IPv6 hairpin check, armed when the check starts.
+    hairpin_v6_timer: MaybeFuture<Pin<Box<time::Sleep>>>,
+    /// The probes which may not have started yet, with the tokens to cancel them.
+    ///
+    /// See [`Actor::cancel_useless_probes`].
+    pending_probes: Vec<(Probe, CancellationToken)>,
+    /// The probe sets which did not finish yet, keyed by region and protocol.
+    ///
+    /// Sets still unfinished when probing stops are recorded in [`Report::probe_failures`].
+    unfinished_sets: BTreeMap<(u16, ProbeProto), UnfinishedSet>,
+    /// The DNS resolutions of the DERP nodes for this report.
+    dns_cache: Arc<DnsCache>,
+    /// Resolves the DERP nodes of the probe plan ahead of the probes.
+    ///
+    /// Aborted when the actor is dropped.
+    dns_prewarm: JoinSet<()>,
+    /// Whether any STUN request left the host, shared with the probes.
+    ///
+    /// See [`Actor::udp_verdict`].
+    stun_sent: Arc<AtomicBool>,
 }
 
 impl Actor {
-    fn addr(&self) -> Addr {
-        Addr {
-            sender: self.msg_tx.clone(),
-        }
+    /// Emits a progress event, it is fine if nobody is listening.
+    fn emit(&self, event: ReportEvent) {
+        self.events.send(event).ok();
     }
 
     async fn run(&mut self) {
@@ -224,6 +711,9 @@ impl Actor {
     ///   - Receives actor messages (sent by those futures).
     ///   - Updates the report, cancels unneeded futures.
     /// - Sends the report to the netcheck actor.
+    ///
+    /// If the overall timeout is reached the report is sent anyway, marked as partial,
+    /// unless nothing at all was learned in which case the report is aborted.
     async fn run_inner(&mut self) -> Result<()> {
         debug!(
             port_mapper = %self.port_mapper.is_some(),
@@ -232,14 +722,17 @@ impl Actor {
         );
 
         self.report.os_has_ipv6 = super::os_has_ipv6().await;
+        self.report.os_has_ipv6_route = super::os_has_ipv6_route().await;
 
         let mut port_mapping = self.prepare_portmapper_task();
         let mut captive_task = self.prepare_captive_portal_task();
+        let mut nat_behavior_task = self.prepare_nat_behavior_task();
         let mut probes = self.prepare_probes_task().await?;
+        let start = Instant::now();
 
-        let total_timer = tokio::time::sleep(OVERALL_PROBE_TIMEOUT);
+        let total_timer = tokio::time::sleep(self.options.overall_probe_timeout);
         tokio::pin!(total_timer);
-        let probe_timer = tokio::time::sleep(STUN_PROBE_TIMEOUT);
+        let probe_timer = tokio::time::sleep(self.options.stun_probe_timeout);
         tokio::pin!(probe_timer);
 
         loop {
@@ -250,17 +743,34 @@ impl Actor {
             }
             tokio::select! {
                 _ = &mut total_timer => {
-                    bail!("report timed out");
+                    warn!("report timed out, finishing with partial results");
+                    self.handle_abort_probes(ProbingStopReason::OverallTimeout);
+                    self.report.partial = true;
+                    break;
                 }
 
-                _ = &mut probe_timer => {
+                _ = &mut probe_timer, if self.outstanding_tasks.probes => {
                     warn!("probes timed out");
-                    self.handle_abort_probes();
+                    self.handle_abort_probes(ProbingStopReason::StunTimeout);
+                }
+
+                _ = &mut self.enough_regions_timer, if self.outstanding_tasks.probes => {
+                    debug!("enough regions reported, aborting remaining probes");
+                    self.handle_abort_probes(ProbingStopReason::EnoughRegions);
+                }
+
+                _ = &mut self.hairpin_v4_timer, if self.outstanding_tasks.hairpin_v4 => {
+                    self.handle_hairpin_timeout(false);
+                }
+
+                _ = &mut self.hairpin_v6_timer, if self.outstanding_tasks.hairpin_v6 => {
+                    self.handle_hairpin_timeout(true);
                 }
 
                 // Drive the portmapper.
                 pm = &mut port_mapping, if self.outstanding_tasks.port_mapper => {
                     info!(report=?pm, "Portmapper probe report");
+                    self.emit(ReportEvent::PortmapperDone(pm.clone()));
                     self.report.portmap_probe = pm;
                     port_mapping.inner = None;
                     self.outstanding_tasks.port_mapper = false;
@@ -271,19 +781,30 @@ impl Actor {
                 set_result = probes.next(), if self.outstanding_tasks.probes => {
                     match set_result {
                         Some(Ok(report)) => self.handle_probe_report(report),
-                        Some(Err(_)) => (),
-                        None => self.handle_abort_probes(),
+                        Some(Err(err)) => self.handle_probe_set_error(err),
+                        None => self.handle_abort_probes(ProbingStopReason::AllProbesFinished),
                     }
                 }
 
                 // Drive the captive task.
                 found = &mut captive_task, if self.outstanding_tasks.captive_task => {
-                    self.report.captive_portal = found;
+                    let captive_portal = found.as_ref().map(Option::is_some);
+                    self.emit(ReportEvent::CaptivePortalDone(captive_portal));
+                    self.report.captive_portal = captive_portal;
+                    self.report.captive_portal_details = found.flatten();
                     captive_task.inner = None;
                     self.outstanding_tasks.captive_task = false;
                     trace!("captive portal task future done");
                 }
 
+                // Drive the NAT behavior tests.
+                behavior = &mut nat_behavior_task, if self.outstanding_tasks.nat_behavior => {
+                    self.report.nat_behavior = behavior;
+                    nat_behavior_task.inner = None;
+                    self.outstanding_tasks.nat_behavior = false;
+                    trace!("NAT behavior task future done");
+                }
+
                 // Handle actor messages.
                 msg = self.msg_rx.recv() => {
                     match msg {
@@ -302,6 +823,18 @@ impl Actor {
             drop(probes);
         }
 
+        if self.report.partial && !self.has_results() {
+            bail!("report timed out without any results");
+        }
+
+        self.report.udp_verdict = self.udp_verdict();
+        self.report.set_generated(start.elapsed());
+        observe!(
+            NetcheckMetrics,
+            report_duration,
+            &[],
+            self.report.probe_duration.as_secs_f64()
+        );
         debug!("Sending report to netcheck actor");
         self.netcheck
             .send(netcheck::Message::ReportReady {
@@ -313,24 +846,61 @@ impl Actor {
         Ok(())
     }
 
+    /// Determines the [`UdpVerdict`] from what the STUN probes observed.
+    fn udp_verdict(&self) -> UdpVerdict {
+        if self.report.udp {
+            return UdpVerdict::Working;
+        }
+        if self.stun_sock4.is_none() && self.stun_sock6.is_none() {
+            return UdpVerdict::NoLocalSockets;
+        }
+        // A STUN request which was sent shows that at least some packets did leave the
+        // host.  Probes which never started say nothing about this.
+        let sent = self.stun_sent.load(Ordering::Relaxed);
+        if self.report.udp_blocked_locally && !sent {
+            UdpVerdict::SendFailedLocally
+        } else {
+            UdpVerdict::NoRepliesReceived
+        }
+    }
+
+    /// Whether the report has learned anything at all about the network.
+    fn has_results(&self) -> bool {
+        !self.report.region_latency.is_empty()
+            || self.report.global_v4.is_some()
+            || self.report.global_v6.is_some()
+            || self.report.hair_pinning.is_some()
+            || self.report.portmap_probe.is_some()
+            || self.report.captive_portal.is_some()
+    }
+
     /// Handles an actor message.
     ///
     /// Returns `true` if all the probes need to be aborted.
     fn handle_message(&mut self, msg: Message) {
         trace!(?msg, "handling message");
         match msg {
-            Message::HairpinResult(works) => {
-                self.report.hair_pinning = Some(works);
-                self.outstanding_tasks.hairpin = false;
-            }
-            Message::ProbeWouldHelp(probe, derp_node, response_tx) => {
-                let res = self.probe_would_help(probe, derp_node);
-                if response_tx.send(res).is_err() {
-                    debug!("probe dropped before ProbeWouldHelp response sent");
+            Message::HairpinResult { ipv6, works } => {
+                let outstanding = match ipv6 {
+                    true => self.outstanding_tasks.hairpin_v6,
+                    false => self.outstanding_tasks.hairpin_v4,
+                };
+                if !outstanding {
+                    debug!(ipv6, "ignoring hairpin result after timeout");
+                    return;
                 }
-            }
-            Message::AbortProbes => {
-                self.handle_abort_probes();
+                self.emit(ReportEvent::HairpinDone { ipv6, works });
+                if ipv6 {
+                    self.report.hair_pinning_v6 = Some(works);
+                    self.outstanding_tasks.hairpin_v6 = false;
+                    self.hairpin_v6_timer.inner = None;
+                } else {
+                    self.report.hair_pinning_v4 = Some(works);
+                    self.outstanding_tasks.hairpin_v4 = false;
+                    self.hairpin_v4_timer.inner = None;
+                }
+                self.report.hair_pinning =
+                    combined_hair_pinning(self.report.hair_pinning_v4, self.report.hair_pinning_v6);
             }
         }
     }
@@ -338,37 +908,219 @@ impl Actor {
     fn handle_probe_report(&mut self, probe_report: ProbeReport) {
         info!("finished probe: {:?}", probe_report);
         let derp_node = probe_report.probe.node();
+        self.unfinished_sets
+            .remove(&(derp_node.region_id, probe_report.probe.proto()));
+        if let Some(kind) = probe_report.failure {
+            self.report.probe_failures.record(
+                derp_node.region_id,
+                probe_report.probe.proto(),
+                kind,
+                1,
+            );
+        }
+        self.emit(ReportEvent::ProbeFinished {
+            region_id: derp_node.region_id,
+            proto: probe_report.probe.proto(),
+            latency: probe_report.delay,
+        });
         if let Some(latency) = probe_report.delay {
+            inc!(NetcheckMetrics, probes_succeeded);
+            observe!(
+                NetcheckMetrics,
+                probe_latency,
+                &[
+                    ("proto", &probe_report.probe.proto().to_string()),
+                    ("region", &derp_node.region_id.to_string()),
+                ],
+                latency.as_secs_f64()
+            );
             self.report
                 .region_latency
                 .update_region(derp_node.region_id, latency);
+            self.report
+                .region_measured_at
+                .insert(derp_node.region_id, SystemTime::now());
+            self.report
+                .node_latency
+                .update_node(&derp_node.name, latency);
             match probe_report.probe {
                 Probe::StunIpv4 { .. } | Probe::StunIpv6 { .. } => {
                     self.add_stun_addr_latency(derp_node, probe_report.addr, latency);
-                    if let Some(ref addr) = self.report.global_v4 {
-                        // Only needed for the first IPv4 address discovered, but hairpin
-                        // actor ignores subsequent messages.
-                        if !self.hairpin_actor.has_started() {
-                            self.hairpin_actor.start_check(*addr);
-                            self.outstanding_tasks.hairpin = true;
-                        }
+                    self.start_hairpin_checks();
+                }
+                Probe::HttpsIpv4 { .. } => {
+                    self.report
+                        .region_v4_latency
+                        .update_region(derp_node.region_id, latency);
+                    self.report
+                        .node_v4_latency
+                        .update_node(&derp_node.name, latency);
+                }
+                Probe::HttpsIpv6 { .. } => {
+                    self.report
+                        .region_v6_latency
+                        .update_region(derp_node.region_id, latency);
+                    self.report
+                        .node_v6_latency
+                        .update_node(&derp_node.name, latency);
+                }
+                Probe::IcmpV4 { .. } => {
+                    // When STUN is blocked the ping is the only latency we get over IPv4.
+                    if self
+                        .report
+                        .region_v4_latency
+                        .get(derp_node.region_id)
+                        .is_none()
+                    {
+                        self.report
+                            .region_v4_latency
+                            .update_region(derp_node.region_id, latency);
+                        self.report
+                            .node_v4_latency
+                            .update_node(&derp_node.name, latency);
+                    }
+                }
+                Probe::IcmpV6 { .. } => {
+                    if self
+                        .report
+                        .region_v6_latency
+                        .get(derp_node.region_id)
+                        .is_none()
+                    {
+                        self.report
+                            .region_v6_latency
+                            .update_region(derp_node.region_id, latency);
+                        self.report
+                            .node_v6_latency
+                            .update_node(&derp_node.name, latency);
                     }
                 }
-                Probe::Https { .. } | Probe::Icmp { .. } => (),
             }
         }
-        self.report.ipv4_can_send = probe_report.ipv4_can_send;
-        self.report.ipv6_can_send = probe_report.ipv6_can_send;
-        self.report.icmpv4 = probe_report.icmpv4;
+        if let (Some(_), Some(derp_addr)) = (probe_report.delay, probe_report.derp_addr) {
+            let addrs = match derp_addr {
+                SocketAddr::V4(_) => &mut self.report.node_v4_addrs,
+                SocketAddr::V6(_) => &mut self.report.node_v6_addrs,
+            };
+            addrs.insert(derp_node.name.clone(), derp_addr);
+        }
+        if probe_report.send_error == Some(SendErrorKind::BlockedLocally) {
+            self.report.udp_blocked_locally = true;
+        }
+        // A single probe which could send is enough, do not let failing probes reset it.
+        self.report.ipv4_can_send |= probe_report.ipv4_can_send;
+        self.report.ipv6_can_send |= probe_report.ipv6_can_send;
+        // Only ICMP probes set these, do not let other probe reports reset them.
+        self.report.icmpv4 |= probe_report.icmpv4;
+        self.report.icmpv6 |= probe_report.icmpv6;
+        self.cancel_useless_probes();
+    }
+
+    /// Records a probe set which failed in the report's diagnostics.
+    fn handle_probe_set_error(&mut self, err: ProbeSetError) {
+        let region_id = err.probe.node().region_id;
+        let proto = err.probe.proto();
+        self.unfinished_sets.remove(&(region_id, proto));
+        self.report
+            .probe_failures
+            .record(region_id, proto, err.kind, err.failed);
+    }
+
+    /// Starts the hairpin checks for the global addresses discovered so far.
+    ///
+    /// Each address family is only checked for the first global address discovered, the
+    /// hairpin actors ignore subsequent requests.
+    fn start_hairpin_checks(&mut self) {
+        let timeout = self.options.hairpin_timeout;
+        if let Some(addr) = self.report.global_v4 {
+            if !self.hairpin_v4_actor.has_started() {
+                self.hairpin_v4_actor.start_check(addr);
+                self.outstanding_tasks.hairpin_v4 = true;
+                self.hairpin_v4_timer.inner = Some(Box::pin(time::sleep(timeout)));
+            }
+        }
+        if let (Some(addr), Some(actor)) = (self.report.global_v6, &mut self.hairpin_v6_actor) {
+            if !actor.has_started() {
+                actor.start_check(addr);
+                self.outstanding_tasks.hairpin_v6 = true;
+                self.hairpin_v6_timer.inner = Some(Box::pin(time::sleep(timeout)));
+            }
+        }
+    }
+
+    /// Gives up on a hairpin check which did not finish in time.
+    ///
+    /// The hairpinning result of the address family stays unknown, a result arriving later
+    /// is ignored.
+    fn handle_hairpin_timeout(&mut self, ipv6: bool) {
+        debug!(ipv6, "hairpin check timed out");
+        inc!(NetcheckMetrics, hairpin_timeouts);
+        if ipv6 {
+            self.outstanding_tasks.hairpin_v6 = false;
+            self.hairpin_v6_timer.inner = None;
+        } else {
+            self.outstanding_tasks.hairpin_v4 = false;
+            self.hairpin_v4_timer.inner = None;
+        }
+    }
+
+    /// Returns the address of the probe's DERP node which answered in the last report.
+    fn preferred_derp_addr(&self, probe: &Probe) -> Option<SocketAddr> {
+        let last_report = self.last_report.as_ref()?;
+        let addrs = match probe.proto().is_ipv6() {
+            false => &last_report.node_v4_addrs,
+            true => &last_report.node_v6_addrs,
+        };
+        addrs.get(&probe.node().name).copied()
+    }
+
+    /// Starts resolving the DERP nodes of all probes in the plan.
+    ///
+    /// The probes find the results in the [`DnsCache`], so they do not have to wait for
+    /// the DNS lookups once they start.
+    fn prewarm_dns_cache(&mut self, plan: &ProbePlan) {
+        let mut seen = BTreeSet::new();
+        for probe in plan.iter().flat_map(|set| set.into_iter()) {
+            let proto = probe.proto();
+            if !seen.insert((probe.node().name.clone(), proto)) {
+                continue;
+            }
+            let node = probe.node().clone();
+            let dns_cache = self.dns_cache.clone();
+            self.dns_prewarm.spawn(async move {
+                if let Err(err) = get_derp_addrs(&node, proto, &dns_cache).await {
+                    debug!(node = %node.name, ?proto, "resolving derp addr failed: {err:#}");
+                }
+            });
+        }
+    }
+
+    /// Cancels the pending probes which would no longer improve our report.
+    ///
+    /// Called after each probe report, as only those change the outcome of
+    /// [`Actor::probe_would_help`].  Cancelled probes abort their entire probe set.
+    fn cancel_useless_probes(&mut self) {
+        let pending = std::mem::take(&mut self.pending_probes);
+        for (probe, token) in pending {
+            if token.is_cancelled() {
+                continue;
+            }
+            if self.probe_would_help(&probe) {
+                self.pending_probes.push((probe, token));
+            } else {
+                trace!(%probe, "cancelling probe");
+                token.cancel();
+            }
+        }
     }
 
     /// Whether running this probe would still improve our report.
-    fn probe_would_help(&mut self, probe: Probe, derp_node: Arc<DerpNode>) -> bool {
+    fn probe_would_help(&self, probe: &Probe) -> bool {
         // If the probe is for a region we don't yet know about, that would help.
         if self
             .report
             .region_latency
-            .get(derp_node.region_id)
+            .get(probe.node().region_id)
             .is_none()
         {
             return true;
@@ -390,10 +1142,44 @@ impl Actor {
             return true;
         }
 
+        // An ICMP probe helps as long as nothing else measured the region over its address
+        // family, e.g. because STUN is blocked.
+        let region_id = probe.node().region_id;
+        match probe.proto() {
+            ProbeProto::IcmpV4 if self.report.region_v4_latency.get(region_id).is_none() => {
+                return true;
+            }
+            ProbeProto::IcmpV6 if self.report.region_v6_latency.get(region_id).is_none() => {
+                return true;
+            }
+            _ => (),
+        }
+
         // Otherwise not interesting.
         false
     }
 
+    /// Returns the number of regions after which probing should stop.
+    fn enough_regions(&self) -> usize {
+        let mut enough = match self.options.enough_regions {
+            EnoughRegions::All => self.derp_map.regions.len(),
+            EnoughRegions::Count(n) => n.min(self.derp_map.regions.len()),
+        };
+        if self.incremental {
+            if let Some(ref last) = self.last_report {
+                let last_regions = last
+                    .region_latency
+                    .iter()
+                    .filter(|(region_id, _)| self.derp_map.regions.contains_key(region_id))
+                    .count();
+                if last_regions > 0 {
+                    enough = enough.min(last_regions);
+                }
+            }
+        }
+        enough
+    }
+
     /// Updates the report to note that node's latency and discovered address from STUN.
     ///
     /// Since this is only called for STUN probes, in other words [`Probe::StunIpv4`] and
@@ -407,36 +1193,25 @@ impl Actor {
         debug!(derp_node = %derp_node.name, ?latency, "add udp node latency");
         self.report.udp = true;
 
-        // Once we've heard from enough regions (3), start a timer to
+        // Once we've heard from enough regions, start a timer to
         // give up on the other ones. The timer's duration is a
         // function of whether this is our initial full probe or an
         // incremental one. For incremental ones, wait for the
         // duration of the slowest region. For initial ones, double that.
-        let enough_regions = std::cmp::min(self.derp_map.regions.len(), ENOUGH_REGIONS);
-        if self.report.region_latency.len() == enough_regions {
+        if self.report.region_latency.len() >= self.enough_regions()
+            && self.outstanding_tasks.probes
+            && self.enough_regions_timer.inner.is_none()
+        {
             let mut timeout = self.report.region_latency.max_latency();
             if !self.incremental {
                 timeout *= 2;
             }
-            let reportcheck = self.addr();
             info!(
                 reports=self.report.region_latency.len(),
                 delay=?timeout,
                 "Have enough probe reports, aborting further probes soon",
             );
-            tokio::spawn(
-                async move {
-                    time::sleep(timeout).await;
-                    // Because we do this after a timeout it is entirely normal that the actor
-                    // is no longer there by the time we send this message.
-                    reportcheck
-                        .send(Message::AbortProbes)
-                        .await
-                        .map_err(|err| trace!("Failed to abort all probes: {err:#}"))
-                        .ok();
-                }
-                .instrument(Span::current()),
-            );
+            self.enough_regions_timer.inner = Some(Box::pin(time::sleep(timeout)));
         }
 
         if let Some(ipp) = ipp {
@@ -445,21 +1220,31 @@ impl Actor {
                     self.report
                         .region_v4_latency
                         .update_region(derp_node.region_id, latency);
+                    self.report
+                        .node_v4_latency
+                        .update_node(&derp_node.name, latency);
                     self.report.ipv4 = true;
                     if self.report.global_v4.is_none() {
                         self.report.global_v4 = Some(ipp);
-                    } else if self.report.global_v4 != Some(ipp) {
-                        self.report.mapping_varies_by_dest_ip = Some(true);
-                    } else if self.report.mapping_varies_by_dest_ip.is_none() {
-                        self.report.mapping_varies_by_dest_ip = Some(false);
+                        self.emit(ReportEvent::GlobalAddrDiscovered(ipp));
                     }
+                    self.report.global_v4_endpoints.insert(ipp, &derp_node.name);
+                    self.report.mapping_varies_by_dest_ip =
+                        self.report.global_v4_endpoints.mapping_varies();
                 }
                 SocketAddr::V6(_) => {
                     self.report
                         .region_v6_latency
                         .update_region(derp_node.region_id, latency);
+                    self.report
+                        .node_v6_latency
+                        .update_node(&derp_node.name, latency);
                     self.report.ipv6 = true;
+                    if self.report.global_v6.is_none() {
+                        self.emit(ReportEvent::GlobalAddrDiscovered(ipp));
+                    }
                     self.report.global_v6 = Some(ipp);
+                    self.report.global_v6_endpoints.insert(ipp, &derp_node.name);
                     // TODO: track MappingVariesByDestIP for IPv6 too? Would be sad if so, but
                     // who knows.
                 }
@@ -472,8 +1257,36 @@ impl Actor {
     /// This makes sure that no further probes are run and also cancels the captive portal
     /// task if there were successful probes.  Be sure to only handle this after all the
     /// required [`ProbeReport`]s have been processed.
-    fn handle_abort_probes(&mut self) {
+    fn handle_abort_probes(&mut self, reason: ProbingStopReason) {
+        if self.outstanding_tasks.probes {
+            debug!(%reason, "stopping probes");
+            self.emit(ReportEvent::ProbingStopped(reason));
+            let kind = match reason {
+                ProbingStopReason::EnoughRegions => ProbeFailureKind::Aborted,
+                ProbingStopReason::AllProbesFinished
+                | ProbingStopReason::StunTimeout
+                | ProbingStopReason::OverallTimeout => ProbeFailureKind::NoReply,
+            };
+            for ((region_id, proto), set) in std::mem::take(&mut self.unfinished_sets) {
+                // Only the probes which are still running are stopped here, the others
+                // were already counted when they finished.
+                let stopped = u64::from(set.running.load(Ordering::Relaxed));
+                match reason {
+                    ProbingStopReason::EnoughRegions => {
+                        inc_by!(NetcheckMetrics, probes_aborted, stopped);
+                    }
+                    ProbingStopReason::StunTimeout | ProbingStopReason::OverallTimeout => {
+                        inc_by!(NetcheckMetrics, probes_timed_out, stopped);
+                    }
+                    ProbingStopReason::AllProbesFinished => (),
+                }
+                self.report
+                    .probe_failures
+                    .record(region_id, proto, kind, set.probes);
+            }
+        }
         self.outstanding_tasks.probes = false;
+        self.enough_regions_timer.inner = None;
         if self.report.udp {
             self.outstanding_tasks.captive_task = false;
         }
@@ -510,23 +1323,26 @@ impl Actor {
     /// Creates the future which will perform the captive portal check.
     fn prepare_captive_portal_task(
         &mut self,
-    ) -> MaybeFuture<Pin<Box<impl Future<Output = Option<bool>>>>> {
+    ) -> MaybeFuture<Pin<Box<impl Future<Output = Option<Option<CaptivePortalDetails>>>>>> {
         // If we're doing a full probe, also check for a captive portal. We
         // delay by a bit to wait for UDP STUN to finish, to avoid the probe if
         // it's unnecessary.
-        if !self.incremental {
+        if !self.incremental && !self.options.skip_captive_portal {
             // Even if we're doing a non-incremental update, we may want to try our
             // preferred DERP region for captive portal detection.
             let preferred_derp = self.last_report.as_ref().map(|l| l.preferred_derp);
 
             let dm = self.derp_map.clone();
+            let delay = self.options.captive_portal_delay;
+            let timeout = self.options.captive_portal_timeout;
+            let config = self.options.captive_portal.clone();
             self.outstanding_tasks.captive_task = true;
             MaybeFuture {
                 inner: Some(Box::pin(async move {
-                    tokio::time::sleep(CAPTIVE_PORTAL_DELAY).await;
+                    tokio::time::sleep(delay).await;
                     let captive_portal_check = tokio::time::timeout(
-                        CAPTIVE_PORTAL_TIMEOUT,
-                        check_captive_portal(&dm, preferred_derp)
+                        timeout,
+                        check_captive_portal(&dm, preferred_derp, &config)
                             .instrument(debug_span!("captive-portal")),
                     );
                     match captive_portal_check.await {
@@ -548,6 +1364,70 @@ impl Actor {
         }
     }
 
+    /// Creates the future which will run the RFC 5780 NAT behavior tests.
+    ///
+    /// The tests only run for full reports if [`ReportOptions::nat_behavior`] is enabled,
+    /// incremental reports keep the result of the last report.
+    fn prepare_nat_behavior_task(
+        &mut self,
+    ) -> MaybeFuture<Pin<Box<impl Future<Output = Option<NatBehavior>>>>> {
+        let mut task = MaybeFuture::default();
+        if !self.options.nat_behavior || !self.options.address_families.ipv4() {
+            return task;
+        }
+        if self.incremental {
+            self.report.nat_behavior = self.last_report.as_ref().and_then(|r| r.nat_behavior);
+            return task;
+        }
+
+        let preferred_derp = self.last_report.as_ref().map(|r| r.preferred_derp);
+        let region = preferred_derp
+            .and_then(|region_id| self.derp_map.regions.get(&region_id))
+            .or_else(|| {
+                self.derp_map
+                    .regions
+                    .iter()
+                    .min_by_key(|(region_id, _)| **region_id)
+                    .map(|(_, region)| region)
+            });
+        let Some(node) = region.and_then(|region| region.nodes.first()).cloned() else {
+            return task;
+        };
+        let dns_cache = self.dns_cache.clone();
+        self.outstanding_tasks.nat_behavior = true;
+        task.inner = Some(Box::pin(
+            async move {
+                let server = match get_derp_addrs(&node, ProbeProto::StunIpv4, &dns_cache).await {
+                    Ok(addrs) => addrs[0],
+                    Err(err) => {
+                        debug!("skipping NAT behavior tests: {err:#}");
+                        return None;
+                    }
+                };
+                // A fresh socket, the STUN sockets are read by the netcheck actor.
+                let sock = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await {
+                    Ok(sock) => sock,
+                    Err(err) => {
+                        debug!("skipping NAT behavior tests: {err:#}");
+                        return None;
+                    }
+                };
+                match nat_behavior::discover(&sock, server, NAT_BEHAVIOR_TIMEOUT).await {
+                    Ok(behavior) => {
+                        debug!(%server, ?behavior, "NAT behavior tests done");
+                        Some(behavior)
+                    }
+                    Err(err) => {
+                        debug!(%server, "skipping NAT behavior tests: {err}");
+                        None
+                    }
+                }
+            }
+            .instrument(debug_span!("nat-behavior")),
+        ));
+        task
+    }
+
     /// Prepares the future which will run all the probes as per generated ProbePlan.
     ///
     /// Probes operate like the following:
@@ -568,48 +1448,116 @@ impl Actor {
     ///     aborted.  That is, the main actor loop stops polling them.
     async fn prepare_probes_task(
         &mut self,
-    ) -> Result<FuturesUnordered<Pin<Box<impl Future<Output = Result<ProbeReport>>>>>> {
-        let if_state = interfaces::State::new().await;
-        let plan = match self.last_report {
-            Some(ref report) => ProbePlan::with_last_report(&self.derp_map, &if_state, report),
-            None => ProbePlan::initial(&self.derp_map, &if_state),
+    ) -> Result<FuturesUnordered<Pin<Box<impl Future<Output = Result<ProbeReport, ProbeSetError>>>>>>
+    {
+        let mut if_state = interfaces::State::new().await;
+        // An IPv6 address is not enough, without a route IPv6 probes can not succeed.
+        if_state.have_v6 &= self.report.os_has_ipv6_route;
+        if_state.have_v4 &= self.options.address_families.ipv4();
+        if_state.have_v6 &= self.options.address_families.ipv6();
+        let plan = self.options.probe_planner.plan(
+            &self.derp_map,
+            &if_state,
+            self.last_report.as_deref(),
+            &self.options,
+        );
+        let plan = match self.options.max_probe_attempts {
+            Some(max_attempts) => plan.limit_attempts(max_attempts),
+            None => plan,
+        };
+        let mut plan = match self.options.probe_budget {
+            Some(ref budget) => {
+                let plan =
+                    plan.apply_budget(budget, self.last_report.as_deref(), &mut rand::thread_rng());
+                if !plan.dropped().is_empty() {
+                    debug!(dropped = ?plan.dropped(), "probe budget exceeded");
+                }
+                plan
+            }
+            None => plan,
         };
-        trace!(%plan, "probe plan");
 
         let pinger = if plan.has_icmp_probes() {
             match Pinger::new().await {
                 Ok(pinger) => Some(pinger),
                 Err(err) => {
-                    debug!("failed to create pinger: {err:#}");
+                    debug!("skipping ICMP probes: {err}");
                     None
                 }
             }
         } else {
             None
         };
+        // Without a socket for its address family an ICMP probe can not be sent at all.
+        let can_ping = |addr: IpAddr| matches!(pinger, Some(ref pinger) if pinger.supports(addr));
+        let (can_ping_v4, can_ping_v6) = (
+            can_ping(Ipv4Addr::UNSPECIFIED.into()),
+            can_ping(Ipv6Addr::UNSPECIFIED.into()),
+        );
+        plan.retain(|set| match set.proto() {
+            ProbeProto::IcmpV4 => can_ping_v4,
+            ProbeProto::IcmpV6 => can_ping_v6,
+            _ => true,
+        });
+        trace!(%plan, "probe plan");
+        self.prewarm_dns_cache(&plan);
+
+        // Limits the number of probes running at once.  The probes which start right away
+        // take their permits here, in order of priority, so that the preferred and fastest
+        // regions are not held up by the others.
+        let limiter = Arc::new(Semaphore::new(self.options.max_concurrent_probes));
+        let ctx = Arc::new(ProbeContext {
+            limiter: limiter.clone(),
+            stun_sock4: self.stun_sock4.clone(),
+            stun_sock6: self.stun_sock6.clone(),
+            netcheck: self.netcheck.clone(),
+            pinger,
+            dns_cache: self.dns_cache.clone(),
+            stun_timeout: self.options.stun_probe_timeout,
+            stun_retransmit: self.options.stun_retransmit,
+            stun_sent: self.stun_sent.clone(),
+            icmp_timeout: self.options.icmp_probe_timeout,
+            events: self.events.clone(),
+        });
 
         // A collection of futures running probe sets.
         let probes = FuturesUnordered::default();
-        for probe_set in plan.iter() {
+        for probe_set in plan.by_priority(self.last_report.as_deref()) {
+            let Some(region_id) = probe_set.region_id() else {
+                continue;
+            };
+            let unfinished = UnfinishedSet::new(probe_set.len() as u32);
+            let running = unfinished.running.clone();
+            self.unfinished_sets
+                .insert((region_id, probe_set.proto()), unfinished);
             let mut set = FuturesUnordered::default();
-            for probe in probe_set {
-                let reportstate = self.addr();
-                let stun_sock4 = self.stun_sock4.clone();
-                let stun_sock6 = self.stun_sock6.clone();
-                let derp_node = probe.node().clone();
+            for (attempt, probe) in probe_set.into_iter().enumerate() {
+                let preferred_addr = self.preferred_derp_addr(probe);
+                let permit = if probe.delay().is_zero() {
+                    limiter.clone().try_acquire_owned().ok()
+                } else {
+                    None
+                };
+                let ctx = ctx.clone();
+                let cancel_token = CancellationToken::new();
+                self.pending_probes
+                    .push((probe.clone(), cancel_token.clone()));
+                let integrity = self
+                    .derp_map
+                    .regions
+                    .get(&probe.node().region_id)
+                    .and_then(|region| region.stun_integrity.clone());
                 let probe = probe.clone();
-                let netcheck = self.netcheck.clone();
-                let pinger = pinger.clone();
 
                 set.push(Box::pin(async move {
                     run_probe(
-                        reportstate,
-                        stun_sock4,
-                        stun_sock6,
-                        derp_node,
+                        ctx,
+                        permit,
+                        cancel_token,
+                        attempt,
+                        preferred_addr,
+                        integrity,
                         probe,
-                        netcheck,
-                        pinger,
                     )
                     .await
                 }));
@@ -618,24 +1566,37 @@ impl Actor {
             // Add the probe set to all futures of probe sets.  Handle aborting a probe set
             // if needed, only normal errors means the set continues.
             probes.push(Box::pin(async move {
-                // Hack because ProbeSet is not it's own type yet.
-                let mut probe_proto = None;
+                let mut last_failure = None;
+                let mut failed = 0;
                 while let Some(res) = set.next().await {
+                    running.fetch_sub(1, Ordering::Relaxed);
                     match res {
                         Ok(report) => return Ok(report),
-                        Err(ProbeError::Error(err, probe)) => {
-                            probe_proto = Some(probe.proto());
+                        Err(ProbeError::Error(err, probe, kind)) => {
                             warn!(?probe, "probe failed: {:#}", err);
+                            failed += 1;
+                            last_failure = Some((probe, kind));
                             continue;
                         }
-                        Err(ProbeError::AbortSet(err, probe)) => {
+                        Err(ProbeError::AbortSet(err, probe, kind)) => {
+                            // The probes left in the set are dropped with it.
+                            inc_by!(NetcheckMetrics, probes_aborted, set.len() as u64);
                             debug!(?probe, "probe set aborted: {:#}", err);
-                            return Err(err);
+                            return Err(ProbeSetError {
+                                probe,
+                                kind,
+                                failed: failed + 1,
+                            });
                         }
                     }
                 }
-                warn!(?probe_proto, "no successfull probes in ProbeSet");
-                Err(anyhow!("All probes in ProbeSet failed"))
+                let (probe, kind) = last_failure.expect("probe sets are never empty");
+                warn!(proto = ?probe.proto(), "no successfull probes in ProbeSet");
+                Err(ProbeSetError {
+                    probe,
+                    kind,
+                    failed,
+                })
             }));
         }
         self.outstanding_tasks.probes = true;
@@ -653,15 +1614,77 @@ struct OutstandingTasks {
     probes: bool,
     port_mapper: bool,
     captive_task: bool,
-    hairpin: bool,
+    nat_behavior: bool,
+    hairpin_v4: bool,
+    hairpin_v6: bool,
 }
 
 impl OutstandingTasks {
     fn all_done(&self) -> bool {
-        !(self.probes || self.port_mapper || self.captive_task || self.hairpin)
+        !(self.probes
+            || self.port_mapper
+            || self.captive_task
+            || self.nat_behavior
+            || self.hairpin_v4
+            || self.hairpin_v6)
+    }
+}
+
+/// A probe set which did not finish yet, see [`Actor::unfinished_sets`].
+#[derive(Debug)]
+struct UnfinishedSet {
+    /// The number of probes in the set.
+    probes: u32,
+    /// The number of probes of the set which did not finish yet, shared with the future
+    /// running the set.
+    running: Arc<AtomicU32>,
+}
+
+impl UnfinishedSet {
+    fn new(probes: u32) -> Self {
+        Self {
+            probes,
+            running: Arc::new(AtomicU32::new(probes)),
+        }
+    }
+}
+
+/// Combines the per address family hairpinning results into [`Report::hair_pinning`].
+///
+/// Hairpinning works if it works for either address family.
+fn combined_hair_pinning(v4: Option<bool>, v6: Option<bool>) -> Option<bool> {
+    match (v4, v6) {
+        (Some(v4), Some(v6)) => Some(v4 || v6),
+        (v4, v6) => v4.or(v6),
     }
 }
 
+/// The state shared by all probes of a report, see [`run_probe`].
+struct ProbeContext {
+    /// Limits the number of probes running at once.
+    limiter: Arc<Semaphore>,
+    /// The socket for IPv4 STUN probes, they are disabled if `None`.
+    stun_sock4: Option<Arc<UdpSocket>>,
+    /// The socket for IPv6 STUN probes, they are disabled if `None`.
+    stun_sock6: Option<Arc<UdpSocket>>,
+    /// The netcheck actor, which receives the STUN responses.
+    netcheck: netcheck::Addr,
+    /// The pinger for ICMP probes, they are disabled if `None`.
+    pinger: Option<Pinger>,
+    /// Resolves the hostnames of DERP nodes.
+    dns_cache: Arc<DnsCache>,
+    /// How long a STUN probe waits for its response.
+    stun_timeout: Duration,
+    /// How STUN probes retransmit their request.
+    stun_retransmit: stun::Retransmit,
+    /// Set once any STUN request was sent.
+    stun_sent: Arc<AtomicBool>,
+    /// How long an ICMP probe waits for its response.
+    icmp_timeout: Duration,
+    /// Receives a [`ReportEvent::ProbeStarted`] for each probe.
+    events: broadcast::Sender<ReportEvent>,
+}
+
 /// The success result of [`run_probe`].
 #[derive(Debug)]
 struct ProbeReport {
@@ -671,12 +1694,20 @@ struct ProbeReport {
     ipv6_can_send: bool,
     /// Whether we can send ICMP packets.
     icmpv4: bool,
+    /// Whether we can send ICMPv6 packets.
+    icmpv6: bool,
     /// The latency to the derp node.
     delay: Option<Duration>,
     /// The probe that generated this report.
     probe: Probe,
     /// The discovered public address.
     addr: Option<SocketAddr>,
+    /// Why sending the STUN packet failed, if it did.
+    send_error: Option<SendErrorKind>,
+    /// The address of the derp node which answered.
+    derp_addr: Option<SocketAddr>,
+    /// Why the probe failed, if it did not measure a latency.
+    failure: Option<ProbeFailureKind>,
 }
 
 impl ProbeReport {
@@ -686,167 +1717,317 @@ impl ProbeReport {
             ipv4_can_send: false,
             ipv6_can_send: false,
             icmpv4: false,
+            icmpv6: false,
             delay: None,
             addr: None,
+            send_error: None,
+            derp_addr: None,
+            failure: None,
         }
     }
 }
 
-/// Errors for [`run_probe`].
+/// Categories of errors when sending a UDP packet.
 ///
-/// The main purpose is to signal whether other probes in this probe set should still be
-/// run.  Recall that a probe set is normally a set of identical probes with delays,
-/// effectively creating retries, and the first successful probe of a probe set will cancel
-/// the others in the set.  So this allows an unsuccessful probe to cancel the remainder of
-/// the set or not.
-#[derive(Debug)]
-enum ProbeError {
-    /// Abort the current set.
-    AbortSet(anyhow::Error, Probe),
-    /// Continue the other probes in the set.
-    Error(anyhow::Error, Probe),
+/// This allows telling apart our own host refusing to send from the network dropping
+/// packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendErrorKind {
+    /// The local host refused to send, usually a firewall rule.
+    BlockedLocally,
+    /// There is no route to the destination, e.g. no IPv6 connectivity.
+    NoRoute,
+    /// The packet was dropped locally, e.g. because buffers were full.
+    ///
+    /// This is treated as if the packet was sent and lost on the network.
+    Lost,
+    /// Any other error, possibly transient.
+    Other,
+}
+
+impl SendErrorKind {
+    /// Classifies an error returned from sending a UDP packet.
+    fn classify(err: &std::io::Error) -> Self {
+        #[cfg(unix)]
+        if let Some(code) = err.raw_os_error() {
+            match code {
+                libc::EPERM | libc::EACCES => return Self::BlockedLocally,
+                libc::ENETUNREACH | libc::EHOSTUNREACH | libc::EADDRNOTAVAIL => {
+                    return Self::NoRoute
+                }
+                libc::ENOBUFS | libc::EAGAIN => return Self::Lost,
+                _ => (),
+            }
+        }
+        match err.kind() {
+            std::io::ErrorKind::PermissionDenied => Self::BlockedLocally,
+            std::io::ErrorKind::AddrNotAvailable => Self::NoRoute,
+            std::io::ErrorKind::WouldBlock => Self::Lost,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Whether a UDP packet was sent, or the send error should be treated as a lost packet.
+fn udp_packet_sent(res: &std::io::Result<usize>, len: usize) -> bool {
+    match res {
+        Ok(n) => *n == len,
+        Err(err) => SendErrorKind::classify(err) == SendErrorKind::Lost,
+    }
+}
+
+/// Errors for [`run_probe`].
+///
+/// The main purpose is to signal whether other probes in this probe set should still be
+/// run.  Recall that a probe set is normally a set of identical probes with delays,
+/// effectively creating retries, and the first successful probe of a probe set will cancel
+/// the others in the set.  So this allows an unsuccessful probe to cancel the remainder of
+/// the set or not.
+#[derive(Debug)]
+enum ProbeError {
+    /// Abort the current set.
+    AbortSet(anyhow::Error, Probe, ProbeFailureKind),
+    /// Continue the other probes in the set.
+    Error(anyhow::Error, Probe, ProbeFailureKind),
+}
+
+/// A probe set which did not produce a [`ProbeReport`].
+#[derive(Debug)]
+struct ProbeSetError {
+    /// The last probe which failed.
+    probe: Probe,
+    /// Why the last probe failed.
+    kind: ProbeFailureKind,
+    /// The number of probes of the set which failed.
+    failed: u32,
+}
+
+/// Adds a random jitter of up to [`PROBE_DELAY_JITTER_PERCENT`] to a probe delay.
+///
+/// Without this clients which start at the same time, e.g. after a network change, would
+/// keep sending their retries in lockstep.
+fn jittered(delay: Duration) -> Duration {
+    let percent = rand::thread_rng().gen_range(0..=PROBE_DELAY_JITTER_PERCENT);
+    delay + delay * percent / 100
 }
 
 /// Executes a particular [`Probe`], including using a delayed start if needed.
 ///
-/// If *stun_sock4* and *stun_sock6* are `None` the STUN probes are disabled.
-#[allow(clippy::too_many_arguments)]
+/// The probe runs with the sockets and timeouts of the report in *ctx*.  It waits for a
+/// permit of the report's limiter unless it was given a *permit* already, and is aborted
+/// without being sent if *cancel_token* is cancelled before it starts.
+///
+/// A DERP node can have several addresses, the *preferred_addr* is tried first.  STUN
+/// probes use a different address for each *attempt*, the index of the probe in its probe
+/// set.  If the region has STUN *integrity* keys the request is signed and the response
+/// must pass the integrity check.
 #[instrument(level = "debug", skip_all, fields(probe = %probe))]
 async fn run_probe(
-    reportstate: Addr,
-    stun_sock4: Option<Arc<UdpSocket>>,
-    stun_sock6: Option<Arc<UdpSocket>>,
-    derp_node: Arc<DerpNode>,
+    ctx: Arc<ProbeContext>,
+    permit: Option<OwnedSemaphorePermit>,
+    cancel_token: CancellationToken,
+    attempt: usize,
+    preferred_addr: Option<SocketAddr>,
+    integrity: Option<stun::IntegrityKeys>,
     probe: Probe,
-    netcheck: netcheck::Addr,
-    pinger: Option<Pinger>,
 ) -> Result<ProbeReport, ProbeError> {
+    let cancelled = || {
+        ProbeError::AbortSet(
+            anyhow!("probe set no longer useful"),
+            probe.clone(),
+            ProbeFailureKind::Aborted,
+        )
+    };
     if !probe.delay().is_zero() {
-        trace!("delaying probe");
-        tokio::time::sleep(probe.delay()).await;
+        let delay = jittered(probe.delay());
+        trace!(?delay, "delaying probe");
+        tokio::select! {
+            biased;
+            _ = cancel_token.cancelled() => return Err(cancelled()),
+            _ = time::sleep(delay) => (),
+        }
+    }
+    let permit = match permit {
+        Some(permit) => permit,
+        None => tokio::select! {
+            biased;
+            _ = cancel_token.cancelled() => return Err(cancelled()),
+            permit = ctx.limiter.clone().acquire_owned() => {
+                permit.map_err(|err| {
+                    ProbeError::AbortSet(err.into(), probe.clone(), ProbeFailureKind::Other)
+                })?
+            }
+        },
+    };
+    if cancel_token.is_cancelled() {
+        return Err(cancelled());
     }
     debug!("starting probe");
+    let derp_node = probe.node();
 
-    let (would_help_tx, would_help_rx) = oneshot::channel();
-    reportstate
-        .send(Message::ProbeWouldHelp(
-            probe.clone(),
-            derp_node.clone(),
-            would_help_tx,
-        ))
-        .await
-        .map_err(|err| {
-            error!("Failed to check if probe would help: {err:#}");
-            err
+    ctx.events
+        .send(ReportEvent::ProbeStarted {
+            region_id: derp_node.region_id,
+            proto: probe.proto(),
         })
-        .map_err(|err| ProbeError::AbortSet(err.into(), probe.clone()))?;
-    if !would_help_rx.await.map_err(|_| {
-        ProbeError::AbortSet(
-            anyhow!("ReportCheck actor dropped sender while waiting for ProbeWouldHelp response"),
-            probe.clone(),
-        )
-    })? {
-        return Err(ProbeError::AbortSet(
-            anyhow!("ReportCheck says probe set no longer useful"),
-            probe,
-        ));
-    }
+        .ok();
 
-    let derp_addr = get_derp_addr(&derp_node, probe.proto())
+    let candidates = get_derp_addrs(derp_node, probe.proto(), &ctx.dns_cache)
         .await
         .context("no derp node addr")
-        .map_err(|e| ProbeError::AbortSet(e, probe.clone()))?;
+        .map_err(|e| ProbeError::AbortSet(e, probe.clone(), ProbeFailureKind::Dns))?;
+    let candidates = order_candidates(candidates, preferred_addr);
+    // Each retry in a probe set tries the next address, in case one is unreachable.
+    let derp_addr = candidates[attempt % candidates.len()];
     let txid = stun::TransactionId::default();
-    let req = stun::request(txid);
-
-    let (stun_tx, stun_rx) = oneshot::channel();
-    let (stun_ready_tx, stun_ready_rx) = oneshot::channel();
-    netcheck
-        .send(netcheck::Message::InFlightStun(
-            netcheck::Inflight {
-                txn: txid,
-                start: Instant::now(),
-                s: stun_tx,
-            },
-            stun_ready_tx,
-        ))
-        .await
-        .map_err(|e| ProbeError::Error(e.into(), probe.clone()))?;
-    stun_ready_rx
-        .await
-        .map_err(|e| ProbeError::Error(e.into(), probe.clone()))?;
+    let req = stun_request(txid, integrity.as_ref());
     let mut result = ProbeReport::new(probe.clone());
 
     match probe {
         Probe::StunIpv4 { .. } => {
-            if let Some(ref sock) = stun_sock4 {
-                let n = sock.send_to(&req, derp_addr).await;
+            if let Some(ref sock) = ctx.stun_sock4 {
+                // Only STUN probes register an inflight transaction with the netcheck actor.
+                let stun_rx = start_stun_transaction(
+                    &ctx.netcheck,
+                    txid,
+                    ctx.stun_timeout,
+                    integrity.clone(),
+                )
+                .await
+                .map_err(|e| ProbeError::Error(e, probe.clone(), ProbeFailureKind::Other))?;
+                let client = stun::Client::new(sock, ctx.stun_retransmit).on_retransmit(|| {
+                    inc!(NetcheckMetrics, stun_packets_sent_ipv4);
+                });
+                let n = client.send(derp_addr, &req).await;
                 inc!(NetcheckMetrics, stun_packets_sent_ipv4);
                 debug!(%derp_addr, send_res=?n, %txid, "sending probe StunIpv4");
-                // TODO:  || neterror.TreatAsLostUDP(err)
-                if n.is_ok() && n.unwrap() == req.len() {
+                result.send_error = n.as_ref().err().map(SendErrorKind::classify);
+                if udp_packet_sent(&n, req.len()) {
+                    ctx.stun_sent.store(true, Ordering::Relaxed);
                     result.ipv4_can_send = true;
 
-                    let (delay, addr) = stun_rx
-                        .await
-                        .map_err(|e| ProbeError::Error(e.into(), probe.clone()))?;
+                    let (delay, addr, derp_addr) = stun_probe_response(
+                        stun_rx,
+                        permit,
+                        &client,
+                        derp_addr,
+                        &req,
+                        &ctx.netcheck,
+                        ctx.stun_timeout,
+                        integrity.as_ref(),
+                        &probe,
+                    )
+                    .await?;
                     result.delay = Some(delay);
                     result.addr = Some(addr);
+                    result.derp_addr = Some(derp_addr);
+                } else {
+                    inc!(NetcheckMetrics, probes_send_failed);
+                    result.failure = Some(ProbeFailureKind::SendFailed);
                 }
+            } else {
+                result.failure = Some(ProbeFailureKind::Other);
             }
         }
         Probe::StunIpv6 { .. } => {
-            if let Some(ref pc6) = stun_sock6 {
-                let n = pc6.send_to(&req, derp_addr).await;
+            if let Some(ref pc6) = ctx.stun_sock6 {
+                let stun_rx = start_stun_transaction(
+                    &ctx.netcheck,
+                    txid,
+                    ctx.stun_timeout,
+                    integrity.clone(),
+                )
+                .await
+                .map_err(|e| ProbeError::Error(e, probe.clone(), ProbeFailureKind::Other))?;
+                let client = stun::Client::new(pc6, ctx.stun_retransmit).on_retransmit(|| {
+                    inc!(NetcheckMetrics, stun_packets_sent_ipv6);
+                });
+                let n = client.send(derp_addr, &req).await;
                 inc!(NetcheckMetrics, stun_packets_sent_ipv6);
                 debug!(%derp_addr, snd_res=?n, %txid, "sending probe StunIpv6");
-                // TODO:  || neterror.TreatAsLostUDP(err)
-                if n.is_ok() && n.unwrap() == req.len() {
+                result.send_error = n.as_ref().err().map(SendErrorKind::classify);
+                if udp_packet_sent(&n, req.len()) {
+                    ctx.stun_sent.store(true, Ordering::Relaxed);
                     result.ipv6_can_send = true;
 
-                    let (delay, addr) = stun_rx
-                        .await
-                        .map_err(|e| ProbeError::Error(e.into(), probe.clone()))?;
+                    let (delay, addr, derp_addr) = stun_probe_response(
+                        stun_rx,
+                        permit,
+                        &client,
+                        derp_addr,
+                        &req,
+                        &ctx.netcheck,
+                        ctx.stun_timeout,
+                        integrity.as_ref(),
+                        &probe,
+                    )
+                    .await?;
                     result.delay = Some(delay);
                     result.addr = Some(addr);
+                    result.derp_addr = Some(derp_addr);
+                } else {
+                    inc!(NetcheckMetrics, probes_send_failed);
+                    result.failure = Some(ProbeFailureKind::SendFailed);
                 }
+            } else {
+                result.failure = Some(ProbeFailureKind::Other);
             }
         }
-        Probe::Icmp { .. } => {
-            if let Some(ref pinger) = pinger {
-                match time::timeout(
-                    ICMP_PROBE_TIMEOUT,
-                    measure_icmp_latency(pinger, derp_node, derp_addr),
-                )
-                .await
-                {
-                    Ok(Ok(latency)) => {
+        Probe::IcmpV4 { .. } => {
+            // The pinger may lack a socket for the address family of the probe.
+            let pinger = ctx
+                .pinger
+                .as_ref()
+                .filter(|pinger| pinger.supports(derp_addr.ip()));
+            if let Some(pinger) = pinger {
+                inc!(NetcheckMetrics, icmp_pings_sent_ipv4);
+                match ping_candidates(pinger, derp_node, &candidates, ctx.icmp_timeout).await {
+                    Ok((latency, addr)) => {
                         result.delay = Some(latency);
+                        result.derp_addr = Some(addr);
                         result.ipv4_can_send = true;
                         result.icmpv4 = true;
                     }
-                    Ok(Err(err)) => warn!("icmp latency measurement failed: {:#}", err),
-                    Err(_) => warn!("icmp latency measurement timed out"),
+                    Err(kind) => result.failure = Some(kind),
+                }
+            } else {
+                result.failure = Some(ProbeFailureKind::Other);
+            }
+        }
+        Probe::IcmpV6 { .. } => {
+            let pinger = ctx
+                .pinger
+                .as_ref()
+                .filter(|pinger| pinger.supports(derp_addr.ip()));
+            if let Some(pinger) = pinger {
+                inc!(NetcheckMetrics, icmp_pings_sent_ipv6);
+                match ping_candidates(pinger, derp_node, &candidates, ctx.icmp_timeout).await {
+                    Ok((latency, addr)) => {
+                        result.delay = Some(latency);
+                        result.derp_addr = Some(addr);
+                        result.ipv6_can_send = true;
+                        result.icmpv6 = true;
+                    }
+                    Err(kind) => result.failure = Some(kind),
                 }
+            } else {
+                result.failure = Some(ProbeFailureKind::Other);
             }
         }
-        Probe::Https { ref region, .. } => {
-            debug!("sending probe HTTPS");
-            match measure_https_latency(region).await {
-                Ok((latency, ip)) => {
+        Probe::HttpsIpv4 { .. } | Probe::HttpsIpv6 { .. } => {
+            match https_candidates(&candidates).await {
+                Ok((latency, derp_addr)) => {
                     result.delay = Some(latency);
-                    // We set these IPv4 and IPv6 but they're not really used
-                    // and we don't necessarily set them both. If UDP is blocked
-                    // and both IPv4 and IPv6 are available over TCP, it's basically
-                    // random which fields end up getting set here.
-                    // Since they're not needed, that's fine for now.
-                    match ip {
-                        IpAddr::V4(_) => result.ipv4_can_send = true,
-                        IpAddr::V6(_) => result.ipv6_can_send = true,
+                    result.derp_addr = Some(derp_addr);
+                    // The connection is pinned to the address family of the probe.
+                    match derp_addr {
+                        SocketAddr::V4(_) => result.ipv4_can_send = true,
+                        SocketAddr::V6(_) => result.ipv6_can_send = true,
                     }
                 }
                 Err(err) => {
                     warn!("https latency measurement failed: {:?}", err);
+                    result.failure = Some(ProbeFailureKind::Other);
                 }
             }
         }
@@ -856,213 +2037,951 @@ async fn run_probe(
     Ok(result)
 }
 
-/// Reports whether or not we think the system is behind a
-/// captive portal, detected by making a request to a URL that we know should
-/// return a "204 No Content" response and checking if that's what we get.
-///
-/// The boolean return is whether we think we have a captive portal.
-async fn check_captive_portal(dm: &DerpMap, preferred_derp: Option<u16>) -> Result<bool> {
-    // If we have a preferred DERP region with more than one node, try
-    // that; otherwise, pick a random one not marked as "Avoid".
-    let preferred_derp = if preferred_derp.is_none()
-        || dm.regions.get(&preferred_derp.unwrap()).is_none()
-        || (preferred_derp.is_some()
-            && dm
-                .regions
-                .get(&preferred_derp.unwrap())
-                .unwrap()
-                .nodes
-                .is_empty())
-    {
-        let mut rids = Vec::with_capacity(dm.regions.len());
-        for (id, reg) in dm.regions.iter() {
-            if reg.avoid || reg.nodes.is_empty() {
-                continue;
-            }
-            rids.push(id);
-        }
+/// Returns the local address of a STUN socket, for [`Report::stun_local_v4`] and
+/// [`Report::stun_local_v6`].
+fn stun_local_addr(sock: Option<&UdpSocket>) -> Option<SocketAddr> {
+    sock.and_then(|sock| sock.local_addr().ok())
+}
 
-        if rids.is_empty() {
-            return Ok(false);
-        }
+/// Generates a STUN binding request, signed if the region has *integrity* keys.
+fn stun_request(txid: stun::TransactionId, integrity: Option<&stun::IntegrityKeys>) -> Vec<u8> {
+    match integrity {
+        Some(keys) => stun::request_with_integrity(txid, Default::default(), &keys.current),
+        None => stun::request(txid),
+    }
+}
 
-        let i = (0..rids.len())
-            .choose(&mut rand::thread_rng())
-            .unwrap_or_default();
-        *rids[i]
-    } else {
-        preferred_derp.unwrap()
+/// Registers a STUN transaction with the netcheck actor.
+///
+/// Returns the channel on which the response to the request with *txid* will arrive, the
+/// transaction expires after *stun_timeout*.  With *integrity* keys only responses passing
+/// the integrity check are accepted.
+async fn start_stun_transaction(
+    netcheck: &netcheck::Addr,
+    txid: stun::TransactionId,
+    stun_timeout: Duration,
+    integrity: Option<stun::IntegrityKeys>,
+) -> Result<oneshot::Receiver<netcheck::StunResult>> {
+    let (stun_tx, stun_rx) = oneshot::channel();
+    let (stun_ready_tx, stun_ready_rx) = oneshot::channel();
+    netcheck
+        .send(netcheck::Message::InFlightStun(
+            netcheck::Inflight {
+                txn: txid,
+                start: Instant::now(),
+                deadline: Instant::now() + stun_timeout,
+                integrity,
+                s: stun_tx,
+            },
+            stun_ready_tx,
+        ))
+        .await?;
+    stun_ready_rx.await?;
+    Ok(stun_rx)
+}
+
+/// Waits for the response of the STUN probe *req* sent to *derp_addr* using *client*.
+///
+/// The request is retransmitted until the response arrives.  A `300 Try Alternate` error
+/// response is a hint to retarget the probe: the request is sent once more to the
+/// ALTERNATE-SERVER, if it has the same address family.  Any other error response fails the
+/// probe set right away rather than waiting for a timeout.
+///
+/// Returns the latency, our discovered address and the address of the server which
+/// answered.
+async fn stun_probe_response(
+    stun_rx: oneshot::Receiver<netcheck::StunResult>,
+    permit: OwnedSemaphorePermit,
+    client: &stun::Client<'_>,
+    derp_addr: SocketAddr,
+    req: &[u8],
+    netcheck: &netcheck::Addr,
+    stun_timeout: Duration,
+    integrity: Option<&stun::IntegrityKeys>,
+    probe: &Probe,
+) -> Result<(Duration, SocketAddr, SocketAddr), ProbeError> {
+    let no_reply = |err| ProbeError::Error(err, probe.clone(), ProbeFailureKind::NoReply);
+    let server_error = |err: stun::ErrorResponse| {
+        inc!(NetcheckMetrics, stun_error_responses);
+        ProbeError::AbortSet(err.into(), probe.clone(), ProbeFailureKind::ServerError)
     };
 
-    // Has a node, as we filtered out regions without nodes above.
-    let node = &dm.regions.get(&preferred_derp).unwrap().nodes[0];
+    let response = recv_stun_response(stun_rx, Some(permit));
+    let err = match client
+        .retransmit(derp_addr, req, response)
+        .await
+        .map_err(|err| no_reply(anyhow::Error::from(err)))?
+        .map_err(no_reply)?
+    {
+        Ok((delay, addr)) => return Ok((delay, addr, derp_addr)),
+        Err(err) => err,
+    };
+    let alternate = match err.alternate_server {
+        Some(alternate) if err.is_try_alternate() && alternate.is_ipv4() == derp_addr.is_ipv4() => {
+            alternate
+        }
+        _ => return Err(server_error(err)),
+    };
+    inc!(NetcheckMetrics, stun_error_responses);
+    debug!(%derp_addr, %alternate, "STUN server asked to try an alternate server");
 
-    if node
-        .url
-        .host_str()
-        .map(|s| s.ends_with(&DOT_INVALID))
-        .unwrap_or_default()
+    let txid = stun::TransactionId::default();
+    let req = stun_request(txid, integrity);
+    let stun_rx = start_stun_transaction(netcheck, txid, stun_timeout, integrity.cloned())
+        .await
+        .map_err(|e| ProbeError::Error(e, probe.clone(), ProbeFailureKind::Other))?;
+    let n = client.send(alternate, &req).await;
+    if alternate.is_ipv4() {
+        inc!(NetcheckMetrics, stun_packets_sent_ipv4);
+    } else {
+        inc!(NetcheckMetrics, stun_packets_sent_ipv6);
+    }
+    if !udp_packet_sent(&n, req.len()) {
+        inc!(NetcheckMetrics, probes_send_failed);
+        return Err(ProbeError::Error(
+            anyhow!("sending to alternate STUN server {alternate} failed"),
+            probe.clone(),
+            ProbeFailureKind::SendFailed,
+        ));
+    }
+    match client
+        .retransmit(alternate, &req, recv_stun_response(stun_rx, None))
+        .await
+        .map_err(|err| no_reply(anyhow::Error::from(err)))?
+        .map_err(no_reply)?
     {
-        // Don't try to connect to invalid hostnames. This occurred in tests:
-        // https://github.com/tailscale/tailscale/issues/6207
-        // TODO(bradfitz,andrew-d): how to actually handle this nicely?
-        return Ok(false);
-    }
-
-    let client = reqwest::ClientBuilder::new()
-        .redirect(reqwest::redirect::Policy::none())
-        .build()?;
-
-    // Note: the set of valid characters in a challenge and the total
-    // length is limited; see is_challenge_char in bin/derper for more
-    // details.
-
-    let host_name = node.url.host_str().unwrap_or_default();
-    let challenge = format!("ts_{}", host_name);
-    let portal_url = format!("http://{}/generate_204", host_name);
-    let res = client
-        .request(reqwest::Method::GET, portal_url)
-        .header("X-Tailscale-Challenge", &challenge)
-        .send()
-        .await?;
+        Ok((delay, addr)) => Ok((delay, addr, alternate)),
+        // Only one redirect is followed.
+        Err(err) => Err(server_error(err)),
+    }
+}
 
-    let expected_response = format!("response {challenge}");
-    let is_valid_response = res
-        .headers()
-        .get("X-Tailscale-Response")
-        .map(|s| s.to_str().unwrap_or_default())
-        == Some(&expected_response);
-
-    info!(
-        "check_captive_portal url={} status_code={} valid_response={}",
-        res.url(),
-        res.status(),
-        is_valid_response,
-    );
-    let has_captive = res.status() != 204 || !is_valid_response;
-
-    Ok(has_captive)
+/// Waits for the response to a STUN probe.
+///
+/// The concurrency *permit*, if any, is released after [`STUN_PERMIT_HOLD`] even if the
+/// response did not arrive yet.  Fails once the netcheck actor expires the request, see
+/// [`netcheck::Inflight::deadline`].
+async fn recv_stun_response(
+    mut stun_rx: oneshot::Receiver<netcheck::StunResult>,
+    permit: Option<OwnedSemaphorePermit>,
+) -> Result<netcheck::StunResult> {
+    let res = match time::timeout(STUN_PERMIT_HOLD, &mut stun_rx).await {
+        Ok(res) => res,
+        Err(_) => {
+            drop(permit);
+            stun_rx.await
+        }
+    };
+    res.map_err(|_| {
+        inc!(NetcheckMetrics, probes_timed_out);
+        anyhow!("no STUN response before the deadline")
+    })
 }
 
-/// Returns the IP address to use to communicate to this derp node.
+/// Returns the candidate addresses to use to communicate to this derp node.
 ///
-/// *proto* specifies the protocol we want to use to talk to the node.
-async fn get_derp_addr(n: &DerpNode, proto: ProbeProto) -> Result<SocketAddr> {
-    let mut port = n.stun_port;
-    if port == 0 {
-        port = DEFAULT_DERP_STUN_PORT;
-    }
-    if let Some(ip) = n.stun_test_ip {
+/// *proto* specifies the protocol we want to use to talk to the node, including its
+/// address family.  The probe plan only has probes for the address families the interface
+/// state says work, so all candidates are of a usable family.  Hostnames are resolved using
+/// the report's *dns_cache* and may have several addresses, a configured IP address always
+/// is the only candidate.  The candidates are never empty.
+async fn get_derp_addrs(
+    n: &DerpNode,
+    proto: ProbeProto,
+    dns_cache: &DnsCache,
+) -> Result<Vec<SocketAddr>> {
+    let https = matches!(proto, ProbeProto::HttpsIpv4 | ProbeProto::HttpsIpv6);
+    let port = if https {
+        n.url.port_or_known_default().unwrap_or(DEFAULT_HTTPS_PORT)
+    } else if n.stun_port == 0 {
+        DEFAULT_DERP_STUN_PORT
+    } else {
+        n.stun_port
+    };
+    let ipv6 = proto.is_ipv6();
+    if let Some(ip) = n.stun_test_ip.filter(|_| !https) {
         if proto == ProbeProto::StunIpv4 && ip.is_ipv6() {
             bail!("STUN test IP set has mismatching protocol");
         }
         if proto == ProbeProto::StunIpv6 && ip.is_ipv4() {
             bail!("STUN test IP set has mismatching protocol");
         }
-        return Ok(SocketAddr::new(ip, port));
-    }
-
-    match proto {
-        ProbeProto::StunIpv4 => {
-            if let UseIpv4::Some(ip) = n.ipv4 {
-                return Ok(SocketAddr::new(IpAddr::V4(ip), port));
-            }
-        }
-        ProbeProto::StunIpv6 => {
-            if let UseIpv6::Some(ip) = n.ipv6 {
-                return Ok(SocketAddr::new(IpAddr::V6(ip), port));
-            }
-        }
-        ProbeProto::Icmp => {
-            if let UseIpv4::Some(ip) = n.ipv4 {
-                return Ok(SocketAddr::new(IpAddr::V4(ip), port));
-            }
+        if proto == ProbeProto::IcmpV4 && ip.is_ipv6() {
+            bail!("STUN test IP set is IPv6, can not send an ICMPv4 probe to it");
         }
-        ProbeProto::Https => {
-            anyhow::bail!("not implemented");
+        if proto == ProbeProto::IcmpV6 && ip.is_ipv4() {
+            bail!("STUN test IP set is IPv4, can not send an ICMPv6 probe to it");
         }
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    match (ipv6, n.ipv4, n.ipv6) {
+        (false, UseIpv4::Some(ip), _) => return Ok(vec![SocketAddr::new(IpAddr::V4(ip), port)]),
+        (true, _, UseIpv6::Some(ip)) => return Ok(vec![SocketAddr::new(IpAddr::V6(ip), port)]),
+        _ => (),
     }
 
     match n.url.host() {
         Some(url::Host::Domain(hostname)) => {
-            async move {
-                debug!(?proto, %hostname, "Performing DNS lookup for derp addr");
-
-                if let Ok(addrs) = DNS_RESOLVER.lookup_ip(hostname).await {
-                    for addr in addrs {
-                        let addr = ip::to_canonical(addr);
-                        if addr.is_ipv4() && proto == ProbeProto::StunIpv4 {
-                            return Ok(SocketAddr::new(addr, port));
-                        }
-                        if addr.is_ipv6() && proto == ProbeProto::StunIpv6 {
-                            return Ok(SocketAddr::new(addr, port));
-                        }
-                        if proto == ProbeProto::Https {
-                            // For now just return the first one
-                            return Ok(SocketAddr::new(addr, port));
-                        }
-                    }
-                }
-                Err(anyhow!("no suitable addr found for derp config"))
-            }
-            .instrument(debug_span!("dns"))
-            .await
+            let ips = dns_cache.resolve(hostname, ipv6).await?;
+            Ok(ips
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect())
         }
-        Some(url::Host::Ipv4(ip)) => Ok(SocketAddr::new(IpAddr::V4(ip), port)),
-        Some(url::Host::Ipv6(ip)) => Ok(SocketAddr::new(IpAddr::V6(ip), port)),
+        Some(url::Host::Ipv4(ip)) if !ipv6 => Ok(vec![SocketAddr::new(IpAddr::V4(ip), port)]),
+        Some(url::Host::Ipv6(ip)) if ipv6 => Ok(vec![SocketAddr::new(IpAddr::V6(ip), port)]),
+        Some(_) => Err(anyhow!("derp url has a mismatching address family")),
         None => Err(anyhow!("no valid hostname available")),
     }
 }
 
-async fn measure_icmp_latency(
+/// Moves the candidate with the IP address of *preferred*, if any, to the front of the
+/// candidates.
+///
+/// The preferred address is the one which worked for the node in the last report.  Only
+/// its IP address is compared, it may have been recorded by a probe of another protocol
+/// which uses another port.
+fn order_candidates(
+    mut candidates: Vec<SocketAddr>,
+    preferred: Option<SocketAddr>,
+) -> Vec<SocketAddr> {
+    if let Some(pos) = preferred.and_then(|preferred| {
+        candidates
+            .iter()
+            .position(|addr| addr.ip() == preferred.ip())
+    }) {
+        let addr = candidates.remove(pos);
+        candidates.insert(0, addr);
+    }
+    candidates
+}
+
+/// Pings the *candidates* at once, each is given the full *timeout*.
+///
+/// At most [`MAX_ICMP_CANDIDATES`] addresses are pinged.  Returns the latency and the
+/// address of the first candidate which answered, the pings to the other candidates are
+/// cancelled.  If none answered fails with [`ProbeFailureKind::TimeExceeded`] if a router
+/// dropped a ping, otherwise with [`ProbeFailureKind::NoReply`].
+async fn ping_candidates(
     pinger: &Pinger,
-    derp_node: Arc<DerpNode>,
-    derp_addr: SocketAddr,
-) -> Result<Duration> {
-    debug!(
-        "ICMP ping start to {} with payload len {} - derp {}",
-        derp_addr,
-        derp_node.name.as_bytes().len(),
-        derp_node.name,
-    );
+    derp_node: &Arc<DerpNode>,
+    candidates: &[SocketAddr],
+    timeout: Duration,
+) -> Result<(Duration, SocketAddr), ProbeFailureKind> {
+    let candidates = &candidates[..candidates.len().min(MAX_ICMP_CANDIDATES)];
+    debug!(?candidates, derp = %derp_node.name, "ICMP ping start");
     // Use the unique node.name field as the packet data to reduce the
     // likelihood that we get a mismatched echo response.
-    let latency = pinger
-        .send(derp_addr.ip(), derp_node.name.as_bytes())
-        .await?;
-    debug!(
-        "ICMP ping done {} with latency {}ms - derp {}",
-        derp_addr,
-        latency.as_millis(),
-        derp_node.name,
-    );
+    let payload = derp_node.name.as_bytes();
+    let mut seen = BTreeSet::new();
+    let mut pings: FuturesUnordered<_> = candidates
+        .iter()
+        // The same IP address with another port, pinging it once is enough.
+        .filter(|derp_addr| seen.insert(derp_addr.ip()))
+        .map(|derp_addr| async move {
+            let res = pinger.send(derp_addr.ip(), payload, timeout).await;
+            (*derp_addr, res)
+        })
+        .collect();
+
+    let mut answer = None;
+    let mut timed_out = false;
+    let mut time_exceeded = false;
+    while let Some((derp_addr, res)) = pings.next().await {
+        match res {
+            Ok(latency) => {
+                answer = Some((latency, derp_addr));
+                break;
+            }
+            Err(PingError::Timeout) => {
+                timed_out = true;
+                debug!(%derp_addr, "icmp latency measurement timed out");
+            }
+            // Not filtered at the edge, but lost on the path e.g. in a routing loop.
+            Err(PingError::TimeExceeded { router }) => {
+                time_exceeded = true;
+                inc!(NetcheckMetrics, icmp_time_exceeded);
+                debug!(%derp_addr, %router, "icmp echo request exceeded its TTL");
+            }
+            Err(err) => {
+                inc!(NetcheckMetrics, probes_send_failed);
+                debug!(%derp_addr, "icmp latency measurement failed: {:#}", err);
+            }
+        }
+    }
+    // Dropping the remaining pings cancels them.
+    drop(pings);
+    match answer {
+        Some((latency, derp_addr)) => {
+            debug!(%derp_addr, ?latency, derp = %derp_node.name, "ICMP ping done")
+        }
+        None => {
+            // The probe timed out, even if it pinged several addresses.
+            if timed_out {
+                inc!(NetcheckMetrics, probes_timed_out);
+            }
+            warn!(derp = %derp_node.name, "no ICMP reply from any address");
+        }
+    }
+    answer.ok_or(if time_exceeded {
+        ProbeFailureKind::TimeExceeded
+    } else {
+        ProbeFailureKind::NoReply
+    })
+}
+
+/// Measures the HTTPS latency to the *candidates* in order, until one succeeds.
+///
+/// At most [`MAX_HTTPS_CANDIDATES`] addresses are tried.  Returns the latency and the
+/// address which succeeded, or the error of the last candidate.
+async fn https_candidates(candidates: &[SocketAddr]) -> Result<(Duration, SocketAddr)> {
+    let mut last_err = None;
+    for &derp_addr in candidates.iter().take(MAX_HTTPS_CANDIDATES) {
+        debug!(%derp_addr, "sending probe HTTPS");
+        match measure_https_latency(derp_addr).await {
+            Ok(latency) => return Ok((latency, derp_addr)),
+            Err(err) => {
+                debug!(%derp_addr, "https latency measurement failed: {:#}", err);
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| anyhow!("no derp node addr")))
+}
+
+/// Measures the latency to a DERP node by connecting to its HTTPS port at *derp_addr*.
+///
+/// Connecting to the given address rather than the DERP hostname pins the measurement to an
+/// address family.  The latency is the time the TCP handshake takes, no TLS handshake or
+/// HTTP request is made.
+async fn measure_https_latency(derp_addr: SocketAddr) -> Result<Duration> {
+    let start = Instant::now();
+    let conn = time::timeout(HTTPS_CONNECT_TIMEOUT, TcpStream::connect(derp_addr))
+        .await
+        .context("timeout connecting to derp node")?
+        .context("failed to connect to derp node")?;
+    let latency = start.elapsed();
+    drop(conn);
     Ok(latency)
 }
 
-async fn measure_https_latency(_reg: &DerpRegion) -> Result<(Duration, IpAddr)> {
-    anyhow::bail!("not implemented");
-    // TODO:
-    // - needs derphttp::Client
-    // - measurement hooks to measure server processing time
-
-    // metricHTTPSend.Add(1)
-    // let ctx, cancel := context.WithTimeout(httpstat.WithHTTPStat(ctx, &result), overallProbeTimeout);
-    // let dc := derphttp.NewNetcheckClient(c.logf);
-    // let tlsConn, tcpConn, node := dc.DialRegionTLS(ctx, reg)?;
-    // if ta, ok := tlsConn.RemoteAddr().(*net.TCPAddr);
-    // req, err := http.NewRequestWithContext(ctx, "GET", "https://"+node.HostName+"/derp/latency-check", nil);
-    // resp, err := hc.Do(req);
-
-    // // DERPs should give us a nominal status code, so anything else is probably
-    // // an access denied by a MITM proxy (or at the very least a signal not to
-    // // trust this latency check).
-    // if resp.StatusCode > 299 {
-    //     return 0, ip, fmt.Errorf("unexpected status code: %d (%s)", resp.StatusCode, resp.Status)
-    // }
-    // _, err = io.Copy(io.Discard, io.LimitReader(resp.Body, 8<<10));
-    // result.End(c.timeNow())
-
-    // // TODO: decide best timing heuristic here.
-    // // Maybe the server should return the tcpinfo_rtt?
-    // return result.ServerProcessing, ip, nil
+#[cfg(test)]
+mod tests {
+    use crate::defaults::default_derp_map;
+
+    use super::*;
+
+    /// Creates an [`Actor`] without running it.
+    fn test_actor(derp_map: DerpMap) -> Actor {
+        let (netcheck_tx, _netcheck_rx) = mpsc::channel(8);
+        let netcheck = netcheck::Addr {
+            sender: netcheck_tx,
+        };
+        let (msg_tx, msg_rx) = mpsc::channel(32);
+        let addr = Addr {
+            sender: msg_tx.clone(),
+        };
+        let (events, _) = broadcast::channel(8);
+        Actor {
+            msg_tx,
+            msg_rx,
+            netcheck: netcheck.clone(),
+            last_report: None,
+            port_mapper: None,
+            skip_external_network: true,
+            incremental: false,
+            derp_map,
+            stun_sock4: None,
+            stun_sock6: None,
+            options: Default::default(),
+            events,
+            report: Report::default(),
+            hairpin_v4_actor: hairpin::Client::new(netcheck, addr, false),
+            hairpin_v6_actor: None,
+            outstanding_tasks: OutstandingTasks::default(),
+            enough_regions_timer: MaybeFuture::default(),
+            hairpin_v4_timer: MaybeFuture::default(),
+            hairpin_v6_timer: MaybeFuture::default(),
+            pending_probes: Vec::new(),
+            unfinished_sets: BTreeMap::new(),
+            dns_cache: Default::default(),
+            dns_prewarm: JoinSet::new(),
+            stun_sent: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_skip_captive_portal() {
+        let mut actor = test_actor(default_derp_map());
+        let captive_task = actor.prepare_captive_portal_task();
+        assert!(captive_task.inner.is_some());
+        assert!(actor.outstanding_tasks.captive_task);
+
+        actor.options.skip_captive_portal = true;
+        let captive_task = actor.prepare_captive_portal_task();
+        assert!(captive_task.inner.is_none());
+        assert!(!actor.outstanding_tasks.captive_task);
+    }
+
+    #[tokio::test]
+    async fn test_nat_behavior_task() {
+        let (stun_addr, _stun_stats, _cleanup_guard) = stun::test::serve_v4().await.unwrap();
+        let mut actor = test_actor(stun::test::derp_map_of([stun_addr].into_iter()));
+        assert!(actor.prepare_nat_behavior_task().inner.is_none());
+        assert!(!actor.outstanding_tasks.nat_behavior);
+
+        // The test server does not support RFC 5780, the tests are skipped.
+        actor.options.nat_behavior = true;
+        let task = actor.prepare_nat_behavior_task();
+        assert!(actor.outstanding_tasks.nat_behavior);
+        assert_eq!(task.inner.unwrap().await, None);
+
+        // Incremental reports keep the previous result.
+        let behavior = NatBehavior {
+            mapping: Some(nat_behavior::MappingBehavior::EndpointIndependent),
+            filtering: None,
+        };
+        actor.outstanding_tasks.nat_behavior = false;
+        actor.incremental = true;
+        actor.last_report = Some(Arc::new(Report {
+            nat_behavior: Some(behavior),
+            ..Default::default()
+        }));
+        assert!(actor.prepare_nat_behavior_task().inner.is_none());
+        assert!(!actor.outstanding_tasks.nat_behavior);
+        assert_eq!(actor.report.nat_behavior, Some(behavior));
+    }
+
+    #[tokio::test]
+    async fn test_probe_failures() {
+        let mut actor = test_actor(default_derp_map());
+        let node = Arc::new(actor.derp_map.regions[&1].nodes[0].clone());
+        let stun = Probe::StunIpv4 {
+            delay: Duration::ZERO,
+            node: node.clone(),
+        };
+        let icmp = Probe::IcmpV4 {
+            delay: Duration::ZERO,
+            node: node.clone(),
+        };
+        actor.outstanding_tasks.probes = true;
+        for proto in [
+            ProbeProto::StunIpv4,
+            ProbeProto::IcmpV4,
+            ProbeProto::HttpsIpv4,
+        ] {
+            actor
+                .unfinished_sets
+                .insert((1, proto), UnfinishedSet::new(3));
+        }
+
+        let mut report = ProbeReport::new(icmp);
+        report.failure = Some(ProbeFailureKind::NoReply);
+        actor.handle_probe_report(report);
+        actor.handle_probe_set_error(ProbeSetError {
+            probe: stun,
+            kind: ProbeFailureKind::Dns,
+            failed: 1,
+        });
+        actor.handle_abort_probes(ProbingStopReason::OverallTimeout);
+
+        let failures = &actor.report.probe_failures;
+        assert_eq!(failures.len(), 3);
+        let failure = |proto| failures.get(1, proto).unwrap();
+        assert_eq!(failure(ProbeProto::IcmpV4).kind, ProbeFailureKind::NoReply);
+        assert_eq!(failure(ProbeProto::StunIpv4).kind, ProbeFailureKind::Dns);
+        // The HTTPS probes never finished.
+        assert_eq!(
+            failure(ProbeProto::HttpsIpv4).kind,
+            ProbeFailureKind::NoReply
+        );
+        assert_eq!(failure(ProbeProto::HttpsIpv4).count, 3);
+        assert!(actor.unfinished_sets.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_udp_verdict() {
+        let mut actor = test_actor(default_derp_map());
+        assert_eq!(actor.udp_verdict(), UdpVerdict::NoLocalSockets);
+
+        actor.stun_sock4 = Some(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()));
+        assert_eq!(actor.udp_verdict(), UdpVerdict::NoRepliesReceived);
+
+        actor.report.udp_blocked_locally = true;
+        assert_eq!(actor.udp_verdict(), UdpVerdict::SendFailedLocally);
+
+        // Probes stopped before they were sent do not count as sent.
+        actor
+            .report
+            .probe_failures
+            .record(1, ProbeProto::StunIpv4, ProbeFailureKind::NoReply, 1);
+        assert_eq!(actor.udp_verdict(), UdpVerdict::SendFailedLocally);
+
+        // Some packets got out but were not answered.
+        actor.stun_sent.store(true, Ordering::Relaxed);
+        assert_eq!(actor.udp_verdict(), UdpVerdict::NoRepliesReceived);
+
+        actor.report.udp = true;
+        assert_eq!(actor.udp_verdict(), UdpVerdict::Working);
+    }
+
+    #[tokio::test]
+    async fn test_enough_regions() {
+        // The default derp map has two regions.
+        let mut actor = test_actor(default_derp_map());
+        assert_eq!(actor.enough_regions(), 2);
+
+        actor.options.enough_regions = EnoughRegions::Count(1);
+        assert_eq!(actor.enough_regions(), 1);
+
+        actor.options.enough_regions = EnoughRegions::All;
+        assert_eq!(actor.enough_regions(), 2);
+
+        // Incremental reports wait for the regions which reported last time.
+        let mut last_report = Report::default();
+        last_report
+            .region_latency
+            .update_region(2, Duration::from_millis(10));
+        last_report
+            .region_latency
+            .update_region(42, Duration::from_millis(10));
+        actor.last_report = Some(Arc::new(last_report));
+        actor.incremental = true;
+        assert_eq!(actor.enough_regions(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_observed_endpoints() {
+        let derp_map = default_derp_map();
+        let node_1 = derp_map.regions[&1].nodes[0].clone();
+        let node_2 = derp_map.regions[&2].nodes[0].clone();
+        let ipp_1: SocketAddr = "1.2.3.4:1234".parse().unwrap();
+        let ipp_2: SocketAddr = "1.2.3.4:5678".parse().unwrap();
+        let ipp_v6: SocketAddr = "[2001:db8::1]:1234".parse().unwrap();
+        let latency = Duration::from_millis(10);
+        let mut actor = test_actor(derp_map);
+
+        actor.add_stun_addr_latency(&node_1, Some(ipp_1), latency);
+        actor.add_stun_addr_latency(&node_1, Some(ipp_1), latency);
+        assert_eq!(actor.report.global_v4_endpoints.len(), 1);
+        assert_eq!(actor.report.mapping_varies_by_dest_ip, None);
+
+        actor.add_stun_addr_latency(&node_2, Some(ipp_1), latency);
+        assert_eq!(actor.report.global_v4_endpoints.len(), 2);
+        assert_eq!(actor.report.mapping_varies_by_dest_ip, Some(false));
+
+        actor.add_stun_addr_latency(&node_2, Some(ipp_2), latency);
+        assert_eq!(actor.report.global_v4, Some(ipp_1));
+        assert_eq!(
+            actor.report.global_v4_endpoints.addrs(),
+            [ipp_1, ipp_2].into_iter().collect()
+        );
+        assert_eq!(actor.report.mapping_varies_by_dest_ip, Some(true));
+
+        actor.add_stun_addr_latency(&node_1, Some(ipp_v6), latency);
+        let v6: Vec<_> = actor.report.global_v6_endpoints.iter().collect();
+        assert_eq!(v6, vec![(ipp_v6, node_1.name.as_str())]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_enough_regions_timer() {
+        let derp_map = default_derp_map();
+        let node_1 = derp_map.regions[&1].nodes[0].clone();
+        let node_2 = derp_map.regions[&2].nodes[0].clone();
+        let ipp: SocketAddr = "1.2.3.4:1234".parse().unwrap();
+        let mut actor = test_actor(derp_map);
+        actor.outstanding_tasks.probes = true;
+
+        let latency = Duration::from_millis(10);
+        actor.report.region_latency.update_region(1, latency);
+        actor.add_stun_addr_latency(&node_1, Some(ipp), latency);
+        assert!(actor.enough_regions_timer.inner.is_none());
+
+        // All regions reported, the timer is armed.
+        actor.report.region_latency.update_region(2, latency);
+        actor.add_stun_addr_latency(&node_2, Some(ipp), latency);
+        let deadline = actor
+            .enough_regions_timer
+            .inner
+            .as_ref()
+            .unwrap()
+            .deadline();
+
+        // Further latencies do not re-arm it.
+        time::advance(Duration::from_millis(5)).await;
+        actor.add_stun_addr_latency(&node_2, Some(ipp), latency);
+        assert_eq!(
+            actor
+                .enough_regions_timer
+                .inner
+                .as_ref()
+                .unwrap()
+                .deadline(),
+            deadline
+        );
+
+        // The timer fires after twice the max latency, this is a full report.
+        (&mut actor.enough_regions_timer).await;
+        assert_eq!(Instant::now(), deadline);
+
+        // Once probing stops the timer is disarmed and can not be armed again.
+        actor.handle_abort_probes(ProbingStopReason::EnoughRegions);
+        assert!(actor.enough_regions_timer.inner.is_none());
+        actor.add_stun_addr_latency(&node_1, Some(ipp), latency);
+        assert!(actor.enough_regions_timer.inner.is_none());
+    }
+
+    #[test]
+    fn test_classify_send_error() {
+        use std::io::{Error, ErrorKind};
+
+        let err = Error::from(ErrorKind::PermissionDenied);
+        assert_eq!(SendErrorKind::classify(&err), SendErrorKind::BlockedLocally);
+        let err = Error::from(ErrorKind::AddrNotAvailable);
+        assert_eq!(SendErrorKind::classify(&err), SendErrorKind::NoRoute);
+        let err = Error::from(ErrorKind::ConnectionRefused);
+        assert_eq!(SendErrorKind::classify(&err), SendErrorKind::Other);
+
+        #[cfg(unix)]
+        {
+            let err = Error::from_raw_os_error(libc::EPERM);
+            assert_eq!(SendErrorKind::classify(&err), SendErrorKind::BlockedLocally);
+            let err = Error::from_raw_os_error(libc::ENETUNREACH);
+            assert_eq!(SendErrorKind::classify(&err), SendErrorKind::NoRoute);
+            let err = Error::from_raw_os_error(libc::ENOBUFS);
+            assert_eq!(SendErrorKind::classify(&err), SendErrorKind::Lost);
+        }
+    }
+
+    #[test]
+    fn test_udp_packet_sent() {
+        use std::io::{Error, ErrorKind};
+
+        assert!(udp_packet_sent(&Ok(20), 20));
+        assert!(!udp_packet_sent(&Ok(10), 20));
+        assert!(!udp_packet_sent(
+            &Err(Error::from(ErrorKind::PermissionDenied)),
+            20
+        ));
+        #[cfg(unix)]
+        assert!(udp_packet_sent(
+            &Err(Error::from_raw_os_error(libc::ENOBUFS)),
+            20
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hairpin_timeout() {
+        let mut actor = test_actor(DerpMap::default());
+        // A local socket which never answers the hairpin check.
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        actor.report.global_v4 = Some(silent.local_addr().unwrap());
+        actor.start_hairpin_checks();
+        assert!(actor.outstanding_tasks.hairpin_v4);
+        let start = Instant::now();
+
+        (&mut actor.hairpin_v4_timer).await;
+        assert_eq!(start.elapsed(), HAIRPIN_TIMEOUT);
+        actor.handle_hairpin_timeout(false);
+        assert!(actor.outstanding_tasks.all_done());
+        assert!(actor.hairpin_v4_timer.inner.is_none());
+
+        // A late result is ignored.
+        actor.handle_message(Message::HairpinResult {
+            ipv6: false,
+            works: true,
+        });
+        assert_eq!(actor.report.hair_pinning_v4, None);
+        assert_eq!(actor.report.hair_pinning, None);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_useless_probes() {
+        let derp_map = stun::test::derp_map_of(
+            [
+                "127.0.0.1:1".parse().unwrap(),
+                "127.0.0.1:2".parse().unwrap(),
+                "127.0.0.1:3".parse().unwrap(),
+            ]
+            .into_iter(),
+        );
+        let nodes: Vec<Arc<DerpNode>> = (1..=3)
+            .map(|region_id| Arc::new(derp_map.regions[&region_id].nodes[0].clone()))
+            .collect();
+        let mut actor = test_actor(derp_map);
+        let mut tokens = Vec::new();
+        for node in nodes.iter() {
+            let token = CancellationToken::new();
+            let retry = Probe::StunIpv4 {
+                delay: Duration::from_millis(100),
+                node: node.clone(),
+            };
+            actor.pending_probes.push((retry, token.clone()));
+            tokens.push(token);
+        }
+        let report = |node: &Arc<DerpNode>| {
+            let mut report = ProbeReport::new(Probe::StunIpv4 {
+                delay: Duration::ZERO,
+                node: node.clone(),
+            });
+            report.ipv4_can_send = true;
+            report.delay = Some(Duration::from_millis(10));
+            report.addr = Some("1.2.3.4:1234".parse().unwrap());
+            report
+        };
+
+        // A single IPv4 result can not tell whether the mapping varies, retries still help.
+        actor.handle_probe_report(report(&nodes[0]));
+        assert!(tokens.iter().all(|token| !token.is_cancelled()));
+
+        // After two identical mappings the retries for the measured regions are useless.
+        actor.handle_probe_report(report(&nodes[1]));
+        assert_eq!(actor.report.mapping_varies_by_dest_ip, Some(false));
+        assert!(tokens[0].is_cancelled());
+        assert!(tokens[1].is_cancelled());
+        assert!(!tokens[2].is_cancelled());
+        assert_eq!(actor.pending_probes.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_icmp_probe_report() {
+        let derp_map = stun::test::derp_map_of(
+            [
+                "127.0.0.1:1".parse().unwrap(),
+                "127.0.0.1:2".parse().unwrap(),
+            ]
+            .into_iter(),
+        );
+        let nodes: Vec<Arc<DerpNode>> = (1..=2)
+            .map(|region_id| Arc::new(derp_map.regions[&region_id].nodes[0].clone()))
+            .collect();
+        let mut actor = test_actor(derp_map);
+        let icmp = |node: &Arc<DerpNode>| Probe::IcmpV4 {
+            delay: Duration::ZERO,
+            node: node.clone(),
+        };
+
+        // Region 1 is reachable over STUN, region 2 only answers pings.
+        let mut stun_report = ProbeReport::new(Probe::StunIpv4 {
+            delay: Duration::ZERO,
+            node: nodes[0].clone(),
+        });
+        stun_report.ipv4_can_send = true;
+        stun_report.delay = Some(Duration::from_millis(20));
+        stun_report.addr = Some("1.2.3.4:1234".parse().unwrap());
+        actor.handle_probe_report(stun_report);
+        assert!(!actor.probe_would_help(&icmp(&nodes[0])));
+        assert!(actor.probe_would_help(&icmp(&nodes[1])));
+
+        for node in nodes.iter() {
+            let mut report = ProbeReport::new(icmp(node));
+            report.ipv4_can_send = true;
+            report.icmpv4 = true;
+            report.delay = Some(Duration::from_millis(10));
+            actor.handle_probe_report(report);
+        }
+        assert!(actor.report.icmpv4);
+        assert_eq!(
+            actor.report.region_v4_latency.get(1),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(
+            actor.report.region_v4_latency.get(2),
+            Some(Duration::from_millis(10))
+        );
+
+        // A later probe failing does not reset the ICMP result.
+        actor.handle_probe_report(ProbeReport::new(icmp(&nodes[0])));
+        assert!(actor.report.icmpv4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancelled_probe_aborts_promptly() {
+        let derp_map = default_derp_map();
+        let node = Arc::new(derp_map.regions[&1].nodes[0].clone());
+        let probe = Probe::StunIpv4 {
+            delay: Duration::from_secs(10),
+            node,
+        };
+        let (netcheck_tx, _netcheck_rx) = mpsc::channel(8);
+        let netcheck = netcheck::Addr {
+            sender: netcheck_tx,
+        };
+        let (events, _) = broadcast::channel(8);
+        let cancel_token = CancellationToken::new();
+        let ctx = Arc::new(ProbeContext {
+            limiter: Arc::new(Semaphore::new(1)),
+            stun_sock4: None,
+            stun_sock6: None,
+            netcheck,
+            pinger: None,
+            dns_cache: Default::default(),
+            stun_timeout: STUN_PROBE_TIMEOUT,
+            stun_retransmit: Default::default(),
+            stun_sent: Default::default(),
+            icmp_timeout: ICMP_PROBE_TIMEOUT,
+            events,
+        });
+        let task = tokio::spawn(run_probe(
+            ctx,
+            None,
+            cancel_token.clone(),
+            0,
+            None,
+            None,
+            probe,
+        ));
+
+        let start = Instant::now();
+        time::sleep(Duration::from_millis(50)).await;
+        cancel_token.cancel();
+        let res = task.await.unwrap();
+        assert!(matches!(res, Err(ProbeError::AbortSet(..))));
+        assert_eq!(start.elapsed(), Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_get_derp_addrs_ports() {
+        let stun_addr: SocketAddr = "127.0.0.1:3479".parse().unwrap();
+        let derp_map = stun::test::derp_map_of([stun_addr].into_iter());
+        let node = &derp_map.regions[&1].nodes[0];
+        let dns_cache = DnsCache::default();
+
+        let addrs = get_derp_addrs(node, ProbeProto::StunIpv4, &dns_cache)
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec![stun_addr]);
+
+        // HTTPS probes connect to the port of the DERP URL instead.
+        let addrs = get_derp_addrs(node, ProbeProto::HttpsIpv4, &dns_cache)
+            .await
+            .unwrap();
+        let http_port = node.url.port_or_known_default().unwrap();
+        assert_eq!(addrs, vec![SocketAddr::new(stun_addr.ip(), http_port)]);
+
+        // A test IP of the other address family is rejected for ICMP probes as well.
+        let node = DerpNode {
+            stun_test_ip: Some(stun_addr.ip()),
+            ..node.clone()
+        };
+        let err = get_derp_addrs(&node, ProbeProto::IcmpV6, &dns_cache)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("ICMPv6"));
+    }
+
+    #[tokio::test]
+    async fn test_https_candidates() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        // A port which was just released, connections to it are refused.
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let (latency, addr) = https_candidates(&[closed, open]).await.unwrap();
+        assert_eq!(addr, open);
+        assert!(latency < HTTPS_CONNECT_TIMEOUT);
+        assert!(https_candidates(&[closed]).await.is_err());
+        // Only the first addresses are tried.
+        assert!(https_candidates(&[closed, closed, open]).await.is_err());
+    }
+
+    #[test]
+    fn test_order_candidates() {
+        let a: SocketAddr = "1.1.1.1:3478".parse().unwrap();
+        let b: SocketAddr = "2.2.2.2:3478".parse().unwrap();
+        let c: SocketAddr = "3.3.3.3:3478".parse().unwrap();
+        assert_eq!(order_candidates(vec![a, b, c], None), vec![a, b, c]);
+        assert_eq!(order_candidates(vec![a, b, c], Some(c)), vec![c, a, b]);
+        // A previous address which no longer resolves is ignored.
+        let d: SocketAddr = "4.4.4.4:3478".parse().unwrap();
+        assert_eq!(order_candidates(vec![a, b, c], Some(d)), vec![a, b, c]);
+        // The address may have been recorded by a probe using another port.
+        let https_c: SocketAddr = "3.3.3.3:443".parse().unwrap();
+        assert_eq!(
+            order_candidates(vec![a, b, c], Some(https_c)),
+            vec![c, a, b]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_preferred_derp_addr() {
+        let derp_map = default_derp_map();
+        let node = Arc::new(derp_map.regions[&1].nodes[0].clone());
+        let derp_addr: SocketAddr = "1.1.1.1:3478".parse().unwrap();
+        let mut actor = test_actor(derp_map);
+
+        let mut report = ProbeReport::new(Probe::StunIpv4 {
+            delay: Duration::ZERO,
+            node: node.clone(),
+        });
+        report.delay = Some(Duration::from_millis(10));
+        report.addr = Some("1.2.3.4:1234".parse().unwrap());
+        report.derp_addr = Some(derp_addr);
+        actor.handle_probe_report(report);
+        assert_eq!(actor.report.node_v4_addrs.get(&node.name), Some(&derp_addr));
+        assert!(actor.report.node_v6_addrs.is_empty());
+
+        // The next report tries this address first.
+        actor.last_report = Some(Arc::new(actor.report.clone()));
+        let retry = Probe::StunIpv4 {
+            delay: Duration::from_millis(100),
+            node: node.clone(),
+        };
+        assert_eq!(actor.preferred_derp_addr(&retry), Some(derp_addr));
+        let v6_probe = Probe::StunIpv6 {
+            delay: Duration::ZERO,
+            node,
+        };
+        assert_eq!(actor.preferred_derp_addr(&v6_probe), None);
+    }
+
+    #[test]
+    fn test_combined_hair_pinning() {
+        assert_eq!(combined_hair_pinning(None, None), None);
+        assert_eq!(combined_hair_pinning(Some(true), None), Some(true));
+        assert_eq!(combined_hair_pinning(None, Some(false)), Some(false));
+        assert_eq!(combined_hair_pinning(Some(false), Some(true)), Some(true));
+        assert_eq!(combined_hair_pinning(Some(false), Some(false)), Some(false));
+    }
+
+    #[tokio::test]
+    async fn test_hairpin_results() {
+        let mut actor = test_actor(DerpMap::default());
+        actor.outstanding_tasks.hairpin_v4 = true;
+        actor.outstanding_tasks.hairpin_v6 = true;
+
+        actor.handle_message(Message::HairpinResult {
+            ipv6: true,
+            works: true,
+        });
+        // The IPv4 check is still running.
+        assert!(actor.outstanding_tasks.hairpin_v4);
+        assert!(!actor.outstanding_tasks.all_done());
+        assert_eq!(actor.report.hair_pinning_v4, None);
+        assert_eq!(actor.report.hair_pinning_v6, Some(true));
+        assert_eq!(actor.report.hair_pinning, Some(true));
+
+        actor.handle_message(Message::HairpinResult {
+            ipv6: false,
+            works: false,
+        });
+        assert!(actor.outstanding_tasks.all_done());
+        assert_eq!(actor.report.hair_pinning_v4, Some(false));
+        assert_eq!(actor.report.hair_pinning, Some(true));
+    }
+
+    #[test]
+    fn test_jittered() {
+        let delay = Duration::from_millis(100);
+        for _ in 0..100 {
+            let jittered = jittered(delay);
+            assert!(jittered >= delay);
+            assert!(jittered <= Duration::from_millis(110));
+        }
+        assert_eq!(jittered(Duration::ZERO), Duration::ZERO);
+    }
 }
@@ -0,0 +1,365 @@
+//! Captive portal detection.
+//!
+//! A captive portal intercepts HTTP traffic until the user logged in, usually on a
+//! redirected login page.  We detect them by making plain HTTP requests to URLs for which we
+//! know the response, if the response is different we're likely behind a captive portal.
+//!
+//! Two kinds of checks exist:
+//!
+//! - The DERP challenge: a request to `/generate_204` on a DERP server with a challenge
+//!   header which the DERP server answers.
+//! - Generic endpoints: any URL with a known status code and optionally body, e.g. the
+//!   `generate_204` URLs of large providers.
+
+use anyhow::{bail, ensure, Result};
+use futures::future;
+use rand::seq::IteratorRandom;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+use url::Url;
+
+use crate::derp::DerpMap;
+
+use super::DOT_INVALID;
+
+/// Configures how captive portals are detected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptivePortalConfig {
+    /// Whether to use the challenge mechanism of the DERP servers.
+    ///
+    /// This needs DERP servers which answer the challenge, like iroh's derper does.
+    pub derp_challenge: bool,
+    /// Generic endpoints to check.
+    pub endpoints: Vec<CaptivePortalEndpoint>,
+    /// How many checks need to detect a captive portal before we believe there is one.
+    pub min_agreement: usize,
+}
+
+impl Default for CaptivePortalConfig {
+    fn default() -> Self {
+        Self {
+            derp_challenge: true,
+            endpoints: Vec::new(),
+            min_agreement: 1,
+        }
+    }
+}
+
+impl CaptivePortalConfig {
+    /// Checks the configuration can ever detect a captive portal.
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.min_agreement > 0, "min_agreement must not be zero");
+        ensure!(
+            self.min_agreement <= self.num_checks(),
+            "min_agreement ({}) is larger than the number of captive portal checks ({})",
+            self.min_agreement,
+            self.num_checks(),
+        );
+        Ok(())
+    }
+
+    fn num_checks(&self) -> usize {
+        usize::from(self.derp_challenge) + self.endpoints.len()
+    }
+}
+
+/// A URL with a known response, used to detect captive portals.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptivePortalEndpoint {
+    /// The URL to request, should be plain HTTP.
+    pub url: Url,
+    /// The status code the URL responds with when there is no captive portal.
+    pub expected_status: u16,
+    /// The body the URL responds with when there is no captive portal, if it should be
+    /// checked.
+    pub expected_body: Option<String>,
+}
+
+impl CaptivePortalEndpoint {
+    /// Creates an endpoint which responds with `204 No Content`.
+    pub fn no_content(url: Url) -> Self {
+        Self {
+            url,
+            expected_status: 204,
+            expected_body: None,
+        }
+    }
+}
+
+/// What was observed when a captive portal was detected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptivePortalDetails {
+    /// The URL of the check which detected the captive portal.
+    pub probe_url: Url,
+    /// The HTTP status code the captive portal responded with.
+    pub status: u16,
+    /// Where the captive portal redirected to, usually its login page.
+    pub redirect_url: Option<Url>,
+}
+
+impl CaptivePortalDetails {
+    fn new(probe_url: Url, res: &reqwest::Response) -> Self {
+        // Redirects are not followed, so this is the portal's own redirect.
+        let redirect_url = res
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| probe_url.join(location).ok());
+        Self {
+            probe_url,
+            status: res.status().as_u16(),
+            redirect_url,
+        }
+    }
+}
+
+/// Reports whether or not we think the system is behind a captive portal.
+///
+/// All checks configured in `config` are run concurrently, if at least
+/// [`CaptivePortalConfig::min_agreement`] of them detect a captive portal we think there is
+/// one.  Checks which fail are ignored, unless they all fail.
+///
+/// Returns the details of the first check which detected the captive portal, or `None` if
+/// we think there is no captive portal.
+pub(super) async fn check_captive_portal(
+    dm: &DerpMap,
+    preferred_derp: Option<u16>,
+    config: &CaptivePortalConfig,
+) -> Result<Option<CaptivePortalDetails>> {
+    let client = reqwest::ClientBuilder::new()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+
+    let mut checks = Vec::new();
+    if config.derp_challenge {
+        checks.push(future::Either::Left(check_derp_challenge(
+            &client,
+            dm,
+            preferred_derp,
+        )));
+    }
+    for endpoint in &config.endpoints {
+        checks.push(future::Either::Right(check_endpoint(&client, endpoint)));
+    }
+
+    let mut detected = Vec::new();
+    let mut succeeded = 0;
+    for res in future::join_all(checks).await {
+        match res {
+            Ok(Some(details)) => {
+                detected.push(details);
+                succeeded += 1;
+            }
+            Ok(None) => succeeded += 1,
+            Err(err) => debug!("captive portal check failed: {err:#}"),
+        }
+    }
+    if succeeded == 0 {
+        bail!("all captive portal checks failed");
+    }
+    if detected.len() >= config.min_agreement {
+        Ok(detected.into_iter().next())
+    } else {
+        Ok(None)
+    }
+}
+
+/// Checks for a captive portal using the challenge of a DERP server.
+///
+/// A request is made to the `generate_204` URL of the DERP server, which should respond
+/// with "204 No Content" and the answer to our challenge.
+async fn check_derp_challenge(
+    client: &reqwest::Client,
+    dm: &DerpMap,
+    preferred_derp: Option<u16>,
+) -> Result<Option<CaptivePortalDetails>> {
+    // If we have a preferred DERP region with more than one node, try
+    // that; otherwise, pick a random one not marked as "Avoid".
+    let preferred_derp = if preferred_derp.is_none()
+        || dm.regions.get(&preferred_derp.unwrap()).is_none()
+        || (preferred_derp.is_some()
+            && dm
+                .regions
+                .get(&preferred_derp.unwrap())
+                .unwrap()
+                .nodes
+                .is_empty())
+    {
+        let mut rids = Vec::with_capacity(dm.regions.len());
+        for (id, reg) in dm.regions.iter() {
+            if reg.avoid || reg.nodes.is_empty() {
+                continue;
+            }
+            rids.push(id);
+        }
+
+        if rids.is_empty() {
+            return Ok(None);
+        }
+
+        let i = (0..rids.len())
+            .choose(&mut rand::thread_rng())
+            .unwrap_or_default();
+        *rids[i]
+    } else {
+        preferred_derp.unwrap()
+    };
+
+    // Has a node, as we filtered out regions without nodes above.
+    let node = &dm.regions.get(&preferred_derp).unwrap().nodes[0];
+
+    if node
+        .url
+        .host_str()
+        .map(|s| s.ends_with(&DOT_INVALID))
+        .unwrap_or_default()
+    {
+        // Don't try to connect to invalid hostnames. This occurred in tests:
+        // https://github.com/tailscale/tailscale/issues/6207
+        // TODO(bradfitz,andrew-d): how to actually handle this nicely?
+        return Ok(None);
+    }
+
+    // Note: the set of valid characters in a challenge and the total
+    // length is limited; see is_challenge_char in bin/derper for more
+    // details.
+
+    let host_name = node.url.host_str().unwrap_or_default();
+    let challenge = format!("ts_{}", host_name);
+    let portal_url: Url = format!("http://{}/generate_204", host_name).parse()?;
+    let res = client
+        .request(reqwest::Method::GET, portal_url.clone())
+        .header("X-Tailscale-Challenge", &challenge)
+        .send()
+        .await?;
+
+    let expected_response = format!("response {challenge}");
+    let is_valid_response = res
+        .headers()
+        .get("X-Tailscale-Response")
+        .map(|s| s.to_str().unwrap_or_default())
+        == Some(&expected_response);
+
+    info!(
+        "check_captive_portal url={} status_code={} valid_response={}",
+        res.url(),
+        res.status(),
+        is_valid_response,
+    );
+    let has_captive = res.status() != 204 || !is_valid_response;
+
+    Ok(has_captive.then(|| CaptivePortalDetails::new(portal_url, &res)))
+}
+
+/// Checks for a captive portal using a generic endpoint.
+async fn check_endpoint(
+    client: &reqwest::Client,
+    endpoint: &CaptivePortalEndpoint,
+) -> Result<Option<CaptivePortalDetails>> {
+    let res = client
+        .request(reqwest::Method::GET, endpoint.url.clone())
+        .send()
+        .await?;
+    let details = CaptivePortalDetails::new(endpoint.url.clone(), &res);
+    let has_captive = if res.status() != endpoint.expected_status {
+        true
+    } else {
+        match endpoint.expected_body {
+            Some(ref expected_body) => res.text().await? != *expected_body,
+            None => false,
+        }
+    };
+    info!(
+        "check_captive_portal url={} status_code={} has_captive={}",
+        endpoint.url, details.status, has_captive,
+    );
+    Ok(has_captive.then_some(details))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Serves every HTTP request with `response`.
+    async fn serve_http(response: &'static str) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                // Read the request headers until the empty line, there is no body.
+                let mut reader = BufReader::new(&mut stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).await.unwrap_or_default() > 2 {
+                    line.clear();
+                }
+                stream.write_all(response.as_bytes()).await.ok();
+                stream.shutdown().await.ok();
+            }
+        });
+        format!("http://{addr}/generate_204").parse().unwrap()
+    }
+
+    const NO_CONTENT: &str = "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n";
+    const REDIRECT: &str =
+        "HTTP/1.1 302 Found\r\nLocation: http://portal.example/login\r\nContent-Length: 0\r\n\r\n";
+
+    fn config(endpoints: Vec<CaptivePortalEndpoint>, min_agreement: usize) -> CaptivePortalConfig {
+        CaptivePortalConfig {
+            derp_challenge: false,
+            endpoints,
+            min_agreement,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_endpoints() {
+        let ok = CaptivePortalEndpoint::no_content(serve_http(NO_CONTENT).await);
+        let portal = CaptivePortalEndpoint::no_content(serve_http(REDIRECT).await);
+        let dm = DerpMap::default();
+
+        let res = check_captive_portal(&dm, None, &config(vec![ok.clone()], 1)).await;
+        assert_eq!(res.unwrap(), None);
+
+        let res = check_captive_portal(&dm, None, &config(vec![portal.clone()], 1)).await;
+        assert_eq!(
+            res.unwrap(),
+            Some(CaptivePortalDetails {
+                probe_url: portal.url.clone(),
+                status: 302,
+                redirect_url: Some("http://portal.example/login".parse().unwrap()),
+            })
+        );
+
+        // Not enough endpoints agree.
+        let cfg = config(vec![ok, portal.clone()], 2);
+        let res = check_captive_portal(&dm, None, &cfg).await;
+        assert_eq!(res.unwrap(), None);
+
+        // Failing checks are ignored.
+        let unreachable = CaptivePortalEndpoint::no_content("http://127.0.0.1:1/".parse().unwrap());
+        let cfg = config(vec![unreachable.clone(), portal], 1);
+        let res = check_captive_portal(&dm, None, &cfg).await;
+        assert!(res.unwrap().is_some());
+
+        // Unless all of them fail.
+        let res = check_captive_portal(&dm, None, &config(vec![unreachable], 1)).await;
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(CaptivePortalConfig::default().validate().is_ok());
+        assert!(config(vec![], 1).validate().is_err());
+        let endpoint = CaptivePortalEndpoint::no_content("http://example.com/".parse().unwrap());
+        assert!(config(vec![endpoint.clone()], 0).validate().is_err());
+        assert!(config(vec![endpoint.clone()], 2).validate().is_err());
+        assert!(config(vec![endpoint.clone(), endpoint], 2)
+            .validate()
+            .is_ok());
+    }
+}
@@ -0,0 +1,155 @@
+//! Caching DNS resolutions of DERP nodes for a single report.
+//!
+//! Every probe needs the address of its DERP node, a probe set with retries would resolve
+//! the same hostname several times during a report and a slow resolver eats into the probe
+//! timeouts.  The cache resolves each hostname and address family once per report, the
+//! resolutions are started while the probe plan is prepared.
+//!
+//! Failed resolutions are cached as well, so the retries of a probe set fail fast instead
+//! of querying a dead resolver again.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, ensure, Result};
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+use tracing::{debug, debug_span, Instrument};
+
+use crate::dns::DNS_RESOLVER;
+use crate::net::ip;
+
+/// A cache of DNS resolutions, see the [module docs](self).
+#[derive(Debug, Default)]
+pub(super) struct DnsCache {
+    entries: Mutex<HashMap<(String, bool), Arc<OnceCell<Resolution>>>>,
+}
+
+/// The outcome of resolving a hostname for one address family.
+#[derive(Debug)]
+struct Resolution {
+    /// The resolved addresses in the resolver's order, or why none could be resolved.
+    addrs: Result<Vec<IpAddr>, String>,
+    /// When the resolution finished.
+    resolved_at: Instant,
+}
+
+impl DnsCache {
+    /// Resolves *hostname* to IPv4 or IPv6 addresses, using the cached result if any.
+    ///
+    /// Concurrent calls for the same hostname and address family share a single lookup.
+    /// The returned addresses are never empty.
+    pub(super) async fn resolve(&self, hostname: &str, ipv6: bool) -> Result<Vec<IpAddr>> {
+        self.resolve_with(hostname, ipv6, lookup(hostname.to_string(), ipv6))
+            .await
+    }
+
+    /// Like [`DnsCache::resolve`] but with a custom *lookup* future.
+    async fn resolve_with(
+        &self,
+        hostname: &str,
+        ipv6: bool,
+        lookup: impl Future<Output = Result<Vec<IpAddr>>>,
+    ) -> Result<Vec<IpAddr>> {
+        let cell = self
+            .entries
+            .lock()
+            .unwrap()
+            .entry((hostname.to_string(), ipv6))
+            .or_default()
+            .clone();
+        let resolution = cell
+            .get_or_init(|| async {
+                let addrs = lookup.await.map_err(|err| format!("{err:#}"));
+                Resolution {
+                    addrs,
+                    resolved_at: Instant::now(),
+                }
+            })
+            .await;
+        match &resolution.addrs {
+            Ok(addrs) => Ok(addrs.clone()),
+            Err(err) => Err(anyhow!(
+                "resolving {hostname} failed {:?} ago: {err}",
+                resolution.resolved_at.elapsed()
+            )),
+        }
+    }
+}
+
+/// Looks up the addresses of the requested family for *hostname*.
+async fn lookup(hostname: String, ipv6: bool) -> Result<Vec<IpAddr>> {
+    async move {
+        debug!(%hostname, ipv6, "performing DNS lookup for derp addr");
+        let addrs: Vec<IpAddr> = DNS_RESOLVER
+            .lookup_ip(hostname.as_str())
+            .await?
+            .into_iter()
+            .map(ip::to_canonical)
+            .filter(|addr| addr.is_ipv6() == ipv6)
+            .collect();
+        ensure!(!addrs.is_empty(), "no suitable addr found for derp config");
+        Ok(addrs)
+    }
+    .instrument(debug_span!("dns"))
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolve_once() {
+        let cache = DnsCache::default();
+        let lookups = AtomicUsize::new(0);
+        let lookup = |addr: &'static str| {
+            let lookups = &lookups;
+            async move {
+                lookups.fetch_add(1, Ordering::Relaxed);
+                Ok(vec![addr.parse().unwrap()])
+            }
+        };
+
+        let addr = cache
+            .resolve_with("derp.example", false, lookup("1.2.3.4"))
+            .await
+            .unwrap();
+        assert_eq!(addr, vec!["1.2.3.4".parse::<IpAddr>().unwrap()]);
+        let addr = cache
+            .resolve_with("derp.example", false, lookup("5.6.7.8"))
+            .await
+            .unwrap();
+        assert_eq!(addr, vec!["1.2.3.4".parse::<IpAddr>().unwrap()]);
+        assert_eq!(lookups.load(Ordering::Relaxed), 1);
+
+        // The address families are cached separately.
+        let addr = cache
+            .resolve_with("derp.example", true, lookup("2001:db8::1"))
+            .await
+            .unwrap();
+        assert_eq!(addr, vec!["2001:db8::1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(lookups.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_negative_cache() {
+        let cache = DnsCache::default();
+        let res = cache
+            .resolve_with("derp.example", false, async { Err(anyhow!("timed out")) })
+            .await;
+        assert!(res.is_err());
+
+        // The failure is remembered, the lookup is not tried again.
+        let res = cache
+            .resolve_with("derp.example", false, async {
+                Ok(vec!["1.2.3.4".parse().unwrap()])
+            })
+            .await;
+        assert!(res.is_err());
+    }
+}
@@ -11,12 +11,15 @@
 //!
 //! Note it will only perform a single hairpin check before shutting down.  Any further
 //! requests to it will fail which is intentional.
+//!
+//! Each actor checks a single address family, IPv4 and IPv6 hairpinning are checked by
+//! separate actors.
 
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::ops::Deref;
 use std::time::Duration;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{Context, Result};
 use tokio::net::UdpSocket;
 use tokio::sync::{mpsc, oneshot};
 use tokio::time::Instant;
@@ -40,17 +43,20 @@ pub(super) struct Client {
 }
 
 impl Client {
-    pub(super) fn new(netcheck: netcheck::Addr, reportgen: reportgen::Addr) -> Self {
+    /// Creates a new hairpin actor, checking IPv6 if *ipv6* is set and IPv4 otherwise.
+    pub(super) fn new(netcheck: netcheck::Addr, reportgen: reportgen::Addr, ipv6: bool) -> Self {
         let (msg_tx, msg_rx) = mpsc::channel(32);
         let mut actor = Actor {
             msg_tx,
             msg_rx,
             netcheck,
             reportgen,
+            ipv6,
         };
         let addr = actor.addr();
-        let task =
-            tokio::spawn(async move { actor.run().await }.instrument(info_span!("hairpin.actor")));
+        let task = tokio::spawn(
+            async move { actor.run().await }.instrument(info_span!("hairpin.actor", ipv6)),
+        );
         Self {
             addr,
             has_started: false,
@@ -104,6 +110,8 @@ struct Actor {
     msg_rx: mpsc::Receiver<Message>,
     netcheck: netcheck::Addr,
     reportgen: reportgen::Addr,
+    /// Whether this actor checks IPv6 hairpinning, rather than IPv4.
+    ipv6: bool,
 }
 
 impl Actor {
@@ -121,10 +129,14 @@ impl Actor {
     }
 
     async fn run_inner(&mut self) -> Result<()> {
-        let sock = UdpSocket::bind("0.0.0.0:0")
+        let bind_addr = match self.ipv6 {
+            true => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            false => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        };
+        let sock = UdpSocket::bind(bind_addr)
             .await
-            .context("Failed to bind hairpin socket on 0.0.0.0:0")?;
-        if let Err(err) = Self::prepare_hairpin(&sock).await {
+            .with_context(|| format!("Failed to bind hairpin socket on {bind_addr}"))?;
+        if let Err(err) = Self::prepare_hairpin(&sock, self.ipv6).await {
             warn!("unable to send hairpin prep: {err:#}");
             // Continue anyway, most routers are fine.
         }
@@ -140,6 +152,8 @@ impl Actor {
         let inflight = Inflight {
             txn,
             start: Instant::now(), // ignored by hairping probe
+            deadline: Instant::now() + HAIRPIN_CHECK_TIMEOUT,
+            integrity: None,
             s: stun_tx,
         };
         let (msg_response_tx, msg_response_rx) = oneshot::channel();
@@ -155,20 +169,26 @@ impl Actor {
         }
 
         let hairpinning_works = match tokio::time::timeout(HAIRPIN_CHECK_TIMEOUT, stun_rx).await {
-            Ok(Ok(_)) => true,
-            Ok(Err(_)) => bail!("netcheck actor dropped stun response channel"),
+            Ok(Ok(Ok(_))) => true,
+            // Our own request never comes back as an error response.
+            Ok(Ok(Err(_))) => false,
+            // The netcheck actor expired the transaction.
+            Ok(Err(_)) => false,
             Err(_) => false, // Elapsed
         };
 
         self.reportgen
-            .send(super::Message::HairpinResult(hairpinning_works))
+            .send(super::Message::HairpinResult {
+                ipv6: self.ipv6,
+                works: hairpinning_works,
+            })
             .await
             .context("Failed to send hairpin result to reportgen actor")?;
 
         Ok(())
     }
 
-    async fn prepare_hairpin(sock: &UdpSocket) -> Result<()> {
+    async fn prepare_hairpin(sock: &UdpSocket, ipv6: bool) -> Result<()> {
         // At least the Apple Airport Extreme doesn't allow hairpin
         // sends from a private socket until it's seen traffic from
         // that src IP:port to something else out on the internet.
@@ -179,7 +199,12 @@ impl Actor {
         // documentation-only IPv4 range is enough to set up the mapping.
         // So do that for now. In the future we might want to classify networks
         // that do and don't require this separately. But for now help it.
-        let documentation_ip: SocketAddr = "203.0.113.1:12345".parse().unwrap();
+        //
+        // For IPv6 the RFC 3849 documentation range is used likewise.
+        let documentation_ip: SocketAddr = match ipv6 {
+            true => "[2001:db8::1]:12345".parse().unwrap(),
+            false => "203.0.113.1:12345".parse().unwrap(),
+        };
 
         sock.send_to(
             b"tailscale netcheck; see https://github.com/tailscale/tailscale/issues/188",
@@ -192,8 +217,6 @@ impl Actor {
 
 #[cfg(test)]
 mod tests {
-    use std::net::Ipv4Addr;
-
     use bytes::BytesMut;
     use tokio::sync::mpsc::error::TrySendError;
     use tracing::info;
@@ -226,7 +249,7 @@ mod tests {
         };
 
         // Create hairpin actor
-        let mut actor = Client::new(netcheck_addr, reportstate_addr);
+        let mut actor = Client::new(netcheck_addr, reportstate_addr, false);
 
         // Hairpinning works by asking the hairpin actor to send a STUN request to our
         // discovered public address.  If the router returns it hairpinning works.  We
@@ -241,9 +264,10 @@ mod tests {
         let dummy_netcheck = tokio::spawn(
             async move {
                 let netcheck::Message::InFlightStun(inflight, resp_tx) =
-            netcheck_rx.recv().await.unwrap() else {
-                panic!("Wrong message received");
-            };
+                    netcheck_rx.recv().await.unwrap()
+                else {
+                    panic!("Wrong message received");
+                };
                 resp_tx.send(()).unwrap();
 
                 let mut buf = BytesMut::zeroed(64 << 10);
@@ -259,7 +283,7 @@ mod tests {
 
                 if hairpinning_works {
                     // We want hairpinning to work, send back the STUN request.
-                    inflight.s.send((Duration::new(0, 1), addr)).unwrap();
+                    inflight.s.send(Ok((Duration::new(0, 1), addr))).unwrap();
                 } else {
                     // We want hairpinning to fail, just wait but do not drop the STUN response
                     // channel because that would make the hairpin actor detect an error.
@@ -272,7 +296,10 @@ mod tests {
 
         // Next we expect our dummy reportstate to receive the result.
         match reportstate_rx.recv().await {
-            Some(reportgen::Message::HairpinResult(val)) => assert_eq!(val, hairpinning_works),
+            Some(reportgen::Message::HairpinResult { ipv6, works }) => {
+                assert!(!ipv6);
+                assert_eq!(works, hairpinning_works);
+            }
             Some(msg) => panic!("Unexpected reportstate message: {msg:?}"),
             None => panic!("reportstate mpsc has no senders"),
         }
@@ -312,7 +339,7 @@ mod tests {
         };
 
         // Create hairpin actor
-        let client = Client::new(netcheck_addr, reportstate_addr);
+        let client = Client::new(netcheck_addr, reportstate_addr, false);
 
         // Save the addr, drop the client
         let addr = client.addr.clone();
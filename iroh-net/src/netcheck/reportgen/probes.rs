@@ -4,17 +4,22 @@
 //! probes work and we also learn about our public IP addresses and ports.  But fallback
 //! probes for HTTPS and ICMP exist as well.
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 use std::sync::Arc;
 
 use anyhow::{ensure, Result};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 use tokio::time::Duration;
 
 use crate::derp::{DerpMap, DerpNode, DerpRegion};
 use crate::net::interfaces;
 use crate::netcheck::Report;
 
+use super::{ProbeBudget, ReportOptions};
+
 /// The retransmit interval used when netcheck first runs.
 ///
 /// We have no past context to work with, and we want answers relatively quickly, so it's
@@ -32,33 +37,62 @@ const DEFAULT_INITIAL_RETRANSMIT: Duration = Duration::from_millis(100);
 /// is very far away and we have no data because we timed out the last time we probed it.
 const DEFAULT_ACTIVE_RETRANSMIT_DELAY: Duration = Duration::from_millis(200);
 
-/// The extra time to add to retransmits if a previous report exists.
+/// The minimum retransmit interval used when a previous report exists.
+///
+/// The retransmit interval is derived from the latency of the previous report, but for
+/// very close DERP nodes retrying after a few milliseconds would only add load without
+/// giving the first packet a fair chance.
+const MIN_ACTIVE_RETRANSMIT_DELAY: Duration = Duration::from_millis(20);
+
+/// The extra time to add to fallback probes if a previous report exists.
 ///
 /// When in an active steady-state, i.e. a previous report exists, we add this delay
-/// multiplied with the attempt to probe retries to give later attempts increasingly more
-/// time.
+/// multiplied with the attempt to the HTTPS and ICMP probes to give the STUN probes
+/// increasingly more time.
 const ACTIVE_RETRANSMIT_EXTRA_DELAY: Duration = Duration::from_millis(50);
 
+/// The number of random regions without a known latency which are kept when applying a
+/// [`ProbeBudget`].
+const EXPLORATORY_REGIONS: usize = 2;
+
 /// The number of fastest regions to periodically re-query during incremental netcheck
 /// reports. (During a full report, all regions are scanned.)
 const NUM_INCREMENTAL_REGIONS: usize = 3;
 
 /// The protocol used to time a node's latency.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, derive_more::Display)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, derive_more::Display,
+)]
 #[repr(u8)]
-pub(super) enum ProbeProto {
+pub enum ProbeProto {
     /// STUN IPv4
     StunIpv4,
     /// STUN IPv6
     StunIpv6,
-    /// HTTPS
-    Https,
-    /// ICMP
-    Icmp,
+    /// HTTPS over IPv4
+    HttpsIpv4,
+    /// HTTPS over IPv6
+    HttpsIpv6,
+    /// ICMPv4
+    IcmpV4,
+    /// ICMPv6
+    IcmpV6,
+}
+
+impl ProbeProto {
+    /// Whether this protocol probes over IPv6.
+    pub(super) fn is_ipv6(&self) -> bool {
+        match self {
+            ProbeProto::StunIpv4 | ProbeProto::HttpsIpv4 | ProbeProto::IcmpV4 => false,
+            ProbeProto::StunIpv6 | ProbeProto::HttpsIpv6 | ProbeProto::IcmpV6 => true,
+        }
+    }
 }
 
+/// A single probe of a DERP node, part of a [`ProbeSet`].
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, derive_more::Display)]
-pub(super) enum Probe {
+pub enum Probe {
+    /// A STUN request over IPv4.
     #[display("Ipv4 after {delay:?} to {node}")]
     StunIpv4 {
         /// When the probe is started, relative to the time that `get_report` is called.
@@ -70,52 +104,100 @@ pub(super) enum Probe {
         /// unique so there's no region ID.
         node: Arc<DerpNode>,
     },
+    /// A STUN request over IPv6.
     #[display("Ipv6 after {delay:?} to {node}")]
     StunIpv6 {
+        /// When the probe is started, see [`Probe::StunIpv4`].
         delay: Duration,
+        /// The DERP node to probe.
         node: Arc<DerpNode>,
     },
     // TODO: Probably can remove DerpRegion since the DerpNode already contains the region
     // ID which can then be looked up in the DerpMap.  But Https isn't even implemented
     // right now so leave it.
-    #[display("Https after {delay:?} to {node}")]
-    Https {
+    /// An HTTPS request over IPv4.
+    #[display("HttpsIpv4 after {delay:?} to {node}")]
+    HttpsIpv4 {
+        /// When the probe is started, see [`Probe::StunIpv4`].
         delay: Duration,
+        /// The DERP node to probe.
         node: Arc<DerpNode>,
+        /// The region of the DERP node.
         region: DerpRegion,
     },
-    #[display("Icmp after {delay:?} to {node}")]
-    Icmp {
+    /// An HTTPS request over IPv6.
+    #[display("HttpsIpv6 after {delay:?} to {node}")]
+    HttpsIpv6 {
+        /// When the probe is started, see [`Probe::StunIpv4`].
         delay: Duration,
+        /// The DERP node to probe.
+        node: Arc<DerpNode>,
+        /// The region of the DERP node.
+        region: DerpRegion,
+    },
+    /// An ICMPv4 echo request.
+    #[display("IcmpV4 after {delay:?} to {node}")]
+    IcmpV4 {
+        /// When the probe is started, see [`Probe::StunIpv4`].
+        delay: Duration,
+        /// The DERP node to probe.
+        node: Arc<DerpNode>,
+    },
+    /// An ICMPv6 echo request.
+    #[display("IcmpV6 after {delay:?} to {node}")]
+    IcmpV6 {
+        /// When the probe is started, see [`Probe::StunIpv4`].
+        delay: Duration,
+        /// The DERP node to probe.
         node: Arc<DerpNode>,
     },
 }
 
 impl Probe {
-    pub(super) fn delay(&self) -> Duration {
+    /// Returns when the probe is started, relative to the start of the report.
+    pub fn delay(&self) -> Duration {
         match self {
             Probe::StunIpv4 { delay, .. }
             | Probe::StunIpv6 { delay, .. }
-            | Probe::Https { delay, .. }
-            | Probe::Icmp { delay, .. } => *delay,
+            | Probe::HttpsIpv4 { delay, .. }
+            | Probe::HttpsIpv6 { delay, .. }
+            | Probe::IcmpV4 { delay, .. }
+            | Probe::IcmpV6 { delay, .. } => *delay,
         }
     }
 
-    pub(super) fn proto(&self) -> ProbeProto {
+    /// Returns the protocol of the probe.
+    pub fn proto(&self) -> ProbeProto {
         match self {
             Probe::StunIpv4 { .. } => ProbeProto::StunIpv4,
             Probe::StunIpv6 { .. } => ProbeProto::StunIpv6,
-            Probe::Https { .. } => ProbeProto::Https,
-            Probe::Icmp { .. } => ProbeProto::Icmp,
+            Probe::HttpsIpv4 { .. } => ProbeProto::HttpsIpv4,
+            Probe::HttpsIpv6 { .. } => ProbeProto::HttpsIpv6,
+            Probe::IcmpV4 { .. } => ProbeProto::IcmpV4,
+            Probe::IcmpV6 { .. } => ProbeProto::IcmpV6,
+        }
+    }
+
+    fn delay_mut(&mut self) -> &mut Duration {
+        match self {
+            Probe::StunIpv4 { delay, .. }
+            | Probe::StunIpv6 { delay, .. }
+            | Probe::HttpsIpv4 { delay, .. }
+            | Probe::HttpsIpv6 { delay, .. }
+            | Probe::IcmpV4 { delay, .. }
+            | Probe::IcmpV6 { delay, .. } => delay,
         }
     }
 
-    pub(super) fn node(&self) -> &Arc<DerpNode> {
+    /// Returns the DERP node the probe is sent to.
+    pub fn node(&self) -> &Arc<DerpNode> {
         match self {
             Probe::StunIpv4 { node, .. }
             | Probe::StunIpv6 { node, .. }
-            | Probe::Https { node, .. }
-            | Probe::Icmp { node, .. } => node,
+            | Probe::HttpsIpv4 { node, .. }
+            | Probe::HttpsIpv6 { node, .. }
+            | Probe::IcmpV4 { node, .. }
+            | Probe::IcmpV6 { node, .. } => node,
         }
     }
 }
@@ -132,8 +214,8 @@ impl Probe {
 /// associated exploding types.
 ///
 /// A [`ProbeSet`] implements [`IntoIterator`] similar to how [`Vec`] does.
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub(super) struct ProbeSet {
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProbeSet {
     /// Name of this probe set, informational without uniqueness guarantee.
     name: String,
     /// The [`ProbeProto`] all the probes in this set have.
@@ -143,7 +225,8 @@ pub(super) struct ProbeSet {
 }
 
 impl ProbeSet {
-    fn new(region_id: u16, proto: ProbeProto) -> Self {
+    /// Creates an empty probe set for the probes of *proto* to a region.
+    pub fn new(region_id: u16, proto: ProbeProto) -> Self {
         let name = format!("region-{}-{}", region_id, proto.to_string().to_lowercase());
         Self {
             probes: Vec::new(),
@@ -152,15 +235,37 @@ impl ProbeSet {
         }
     }
 
-    fn push(&mut self, probe: Probe) -> Result<()> {
+    /// Adds a probe, which must have the protocol of the set.
+    pub fn push(&mut self, probe: Probe) -> Result<()> {
         ensure!(probe.proto() == self.proto, "mismatching probe proto");
         self.probes.push(probe);
         Ok(())
     }
 
-    fn is_empty(&self) -> bool {
+    /// Returns the name of the set, informational only.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the protocol of the probes in the set.
+    pub fn proto(&self) -> ProbeProto {
+        self.proto
+    }
+
+    /// Returns the number of probes in the set.
+    pub fn len(&self) -> usize {
+        self.probes.len()
+    }
+
+    /// Whether the set has no probes.
+    pub fn is_empty(&self) -> bool {
         self.probes.is_empty()
     }
+
+    /// The region probed by this set, `None` if the set is empty.
+    pub fn region_id(&self) -> Option<u16> {
+        self.probes.first().map(|probe| probe.node().region_id)
+    }
 }
 
 impl<'a> IntoIterator for &'a ProbeSet {
@@ -183,23 +288,76 @@ impl fmt::Display for ProbeSet {
     }
 }
 
+/// Decides which probes a netcheck report runs, see [`ReportOptions::probe_planner`].
+///
+/// The plan is further limited by [`ReportOptions::max_probe_attempts`] and
+/// [`ReportOptions::probe_budget`], and probing still stops once enough regions reported
+/// their latency.
+pub trait ProbePlanner: fmt::Debug + Send + Sync + 'static {
+    /// Creates the probe plan for a report.
+    ///
+    /// The *derp_map* is already restricted to the regions selected by the
+    /// [`ReportOptions`].  The *last_report* is `None` for full reports.
+    fn plan(
+        &self,
+        derp_map: &DerpMap,
+        if_state: &interfaces::State,
+        last_report: Option<&Report>,
+        options: &ReportOptions,
+    ) -> ProbePlan;
+}
+
+/// The [`ProbePlanner`] netcheck uses by default.
+///
+/// Full reports probe all regions using [`ProbePlan::initial`], incremental reports the
+/// preferred and fastest regions using [`ProbePlan::with_last_report`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultProbePlanner;
+
+impl ProbePlanner for DefaultProbePlanner {
+    fn plan(
+        &self,
+        derp_map: &DerpMap,
+        if_state: &interfaces::State,
+        last_report: Option<&Report>,
+        _options: &ReportOptions,
+    ) -> ProbePlan {
+        match last_report {
+            Some(last_report) => ProbePlan::with_last_report(derp_map, if_state, last_report),
+            None => ProbePlan::initial(derp_map, if_state),
+        }
+    }
+}
+
 /// A probe plan.
 ///
 /// A probe plan contains a number of [`ProbeSet`]s containing probes to be executed.
 /// Generally the first probe of of a set which completes aborts the remaining probes of a
 /// set.  Sometimes a failing probe can also abort the remaining probes of a set.
 ///
-/// The [`reportgen`] actor will also abort all the remaining [`ProbeSet`]s once it has
+/// The reportgen actor will also abort all the remaining [`ProbeSet`]s once it has
 /// sufficient information for a report.
 ///
-/// [`reportgen`]: crate::netcheck::reportgen
-#[derive(Debug, PartialEq, Eq)]
-pub(super) struct ProbePlan(BTreeSet<ProbeSet>);
+/// Plans are created by a [`ProbePlanner`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ProbePlan {
+    /// The probe sets to run.
+    sets: BTreeSet<ProbeSet>,
+    /// Descriptions of the probes dropped to stay within the [`ProbeBudget`].
+    ///
+    /// Only kept to log them, so it is possible to tell why a region was not measured.
+    dropped: Vec<String>,
+}
 
 impl ProbePlan {
+    /// Creates an empty probe plan.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
     /// Creates an initial probe plan.
-    pub(super) fn initial(derp_map: &DerpMap, if_state: &interfaces::State) -> Self {
-        let mut plan = Self(BTreeSet::new());
+    pub fn initial(derp_map: &DerpMap, if_state: &interfaces::State) -> Self {
+        let mut plan = Self::default();
         let mut derp_nodes_cache = DerpNodeCache::new();
 
         let mut sorted_regions: Vec<_> = derp_map.regions.iter().collect();
@@ -209,7 +367,7 @@ impl ProbePlan {
             let mut stun_ipv4_probes = ProbeSet::new(region.region_id, ProbeProto::StunIpv4);
             let mut stun_ipv6_probes = ProbeSet::new(region.region_id, ProbeProto::StunIpv6);
 
-            for attempt in 0..3 {
+            for attempt in 0..stun_attempts(region, 3) {
                 let derp_node = &region.nodes[attempt % region.nodes.len()];
                 let derp_node = derp_nodes_cache.get(derp_node);
                 let delay = DEFAULT_INITIAL_RETRANSMIT * attempt as u32;
@@ -234,39 +392,64 @@ impl ProbePlan {
             plan.add(stun_ipv4_probes);
             plan.add(stun_ipv6_probes);
 
-            // The HTTP and ICMP probes only start after the STUN probes have had a chance.
-            let mut https_probes = ProbeSet::new(region.region_id, ProbeProto::Https);
-            let mut icmp_probes = ProbeSet::new(region.region_id, ProbeProto::Icmp);
+            // The HTTP and ICMP probes only start after the STUN probes have had a chance,
+            // as long as three staggered attempts would take even if fewer were planned.
+            let mut https_ipv4_probes = ProbeSet::new(region.region_id, ProbeProto::HttpsIpv4);
+            let mut https_ipv6_probes = ProbeSet::new(region.region_id, ProbeProto::HttpsIpv6);
+            let mut icmp_probes = ProbeSet::new(region.region_id, ProbeProto::IcmpV4);
+            let mut icmpv6_probes = ProbeSet::new(region.region_id, ProbeProto::IcmpV6);
+            let start =
+                plan.max_delay().max(DEFAULT_INITIAL_RETRANSMIT * 2) + DEFAULT_INITIAL_RETRANSMIT;
             for attempt in 0..3 {
                 let derp_node = &region.nodes[attempt % region.nodes.len()];
                 let derp_node = derp_nodes_cache.get(derp_node);
-                let start = plan.max_delay() + DEFAULT_INITIAL_RETRANSMIT;
                 let delay = start + DEFAULT_INITIAL_RETRANSMIT * attempt as u32;
 
-                if region.has_derp_node() {
-                    https_probes
-                        .push(Probe::Https {
+                if region.has_derp_node() && if_state.have_v4 && derp_node.ipv4.is_enabled() {
+                    https_ipv4_probes
+                        .push(Probe::HttpsIpv4 {
                             delay,
                             node: derp_node.clone(),
                             region: region.clone(),
                         })
-                        .expect("adding Https probe to a Https probe set");
+                        .expect("adding HttpsIpv4 probe to a HttpsIpv4 probe set");
+                }
+                if region.has_derp_node() && if_state.have_v6 && derp_node.ipv6.is_enabled() {
+                    https_ipv6_probes
+                        .push(Probe::HttpsIpv6 {
+                            delay,
+                            node: derp_node.clone(),
+                            region: region.clone(),
+                        })
+                        .expect("adding HttpsIpv6 probe to a HttpsIpv6 probe set");
+                }
+                if if_state.have_v4 && derp_node.ipv4.is_enabled() {
+                    icmp_probes
+                        .push(Probe::IcmpV4 {
+                            delay,
+                            node: derp_node.clone(),
+                        })
+                        .expect("adding IcmpV4 probe to an IcmpV4 probe set");
+                }
+                if if_state.have_v6 && derp_node.ipv6.is_enabled() {
+                    icmpv6_probes
+                        .push(Probe::IcmpV6 {
+                            delay,
+                            node: derp_node.clone(),
+                        })
+                        .expect("adding IcmpV6 probe to an IcmpV6 probe set");
                 }
-                icmp_probes
-                    .push(Probe::Icmp {
-                        delay,
-                        node: derp_node.clone(),
-                    })
-                    .expect("adding Icmp probe to an Icmp probe set");
             }
-            plan.add(https_probes);
+            plan.add(https_ipv4_probes);
+            plan.add(https_ipv6_probes);
             plan.add(icmp_probes);
+            plan.add(icmpv6_probes);
         }
         plan
     }
 
     /// Creates a follow up probe plan using a previous netcheck report.
-    pub(super) fn with_last_report(
+    pub fn with_last_report(
         derp_map: &DerpMap,
         if_state: &interfaces::State,
         last_report: &Report,
@@ -274,34 +457,48 @@ impl ProbePlan {
         if last_report.region_latency.is_empty() {
             return Self::initial(derp_map, if_state);
         }
-        let mut plan = Self(Default::default());
+        let mut plan = Self::default();
         let mut derp_nodes_cache = DerpNodeCache::new();
 
         let had_stun_ipv4 = !last_report.region_v4_latency.is_empty();
         let had_stun_ipv6 = !last_report.region_v6_latency.is_empty();
-        let had_both = if_state.have_v6 && had_stun_ipv4 && had_stun_ipv6;
-        let sorted_regions = sort_regions(derp_map, last_report);
-        for (ri, reg) in sorted_regions.into_iter().enumerate() {
+        let had_both = if_state.have_v4 && if_state.have_v6 && had_stun_ipv4 && had_stun_ipv6;
+        // The preferred region goes first, whatever its latency.  Whether our DERP home is
+        // still good is the most important result of an incremental report.  The other
+        // regions are ranked by latency among themselves, so the preferred region does not
+        // take the place of a fast one.
+        let (preferred, others): (Vec<_>, Vec<_>) = sort_regions(derp_map, last_report)
+            .into_iter()
+            .partition(|reg| reg.region_id == last_report.preferred_derp);
+        let ranked_regions = preferred.into_iter().map(|reg| (None, reg)).chain(
+            others
+                .into_iter()
+                .filter(|reg| !reg.nodes.is_empty()) // Shouldn't be possible.
+                .take(NUM_INCREMENTAL_REGIONS)
+                .enumerate()
+                .map(|(rank, reg)| (Some(rank), reg)),
+        );
+        for (rank, reg) in ranked_regions {
             if reg.nodes.is_empty() {
                 continue; // Shouldn't be possible.
             }
-            if ri == NUM_INCREMENTAL_REGIONS {
-                break;
-            }
             let mut do4 = if_state.have_v4;
             let mut do6 = if_state.have_v6;
 
             // By default, each node only gets one STUN packet sent,
-            // except the fastest two from the previous round.
+            // except the preferred and fastest regions from the previous round.
             let mut attempts = 1;
-            let is_fastest_two = ri < 2;
+            let is_fastest_two = match rank {
+                Some(rank) => rank < 2,
+                None => true,
+            };
 
             if is_fastest_two {
                 attempts = 2;
             } else if had_both {
                 // For dual stack machines, make the 3rd & slower nodes alternate between
                 // IPv4 and IPv6 STUN probes.
-                if ri % 2 == 0 {
+                if rank.unwrap_or_default() % 2 == 0 {
                     (do4, do6) = (true, false);
                 } else {
                     (do4, do6) = (false, true);
@@ -315,20 +512,15 @@ impl ProbePlan {
                 // make sure it's there so we don't flip flop around.
                 attempts = 4;
             }
-            let retransmit_delay = last_report
-                .region_latency
-                .get(reg.region_id)
-                .map(|l| l * 120 / 100) // increases latency by 20%, why?
-                .unwrap_or(DEFAULT_ACTIVE_RETRANSMIT_DELAY);
+            let retransmit_delay = retransmit_delay(last_report, reg.region_id);
 
             let mut stun_ipv4_probes = ProbeSet::new(reg.region_id, ProbeProto::StunIpv4);
             let mut stun_ipv6_probes = ProbeSet::new(reg.region_id, ProbeProto::StunIpv6);
 
-            for attempt in 0..attempts {
+            for attempt in 0..stun_attempts(reg, attempts) {
                 let derp_node = &reg.nodes[attempt % reg.nodes.len()];
                 let derp_node = derp_nodes_cache.get(derp_node);
-                let delay = (retransmit_delay * attempt as u32)
-                    + (ACTIVE_RETRANSMIT_EXTRA_DELAY * attempt as u32);
+                let delay = retransmit_delay * attempt as u32;
                 if do4 {
                     stun_ipv4_probes
                         .push(Probe::StunIpv4 {
@@ -349,62 +541,194 @@ impl ProbePlan {
             plan.add(stun_ipv4_probes);
             plan.add(stun_ipv6_probes);
 
-            // The HTTP and ICMP probes only start after the STUN probes have had a chance.
-            let mut https_probes = ProbeSet::new(reg.region_id, ProbeProto::Https);
-            let mut icmp_probes = ProbeSet::new(reg.region_id, ProbeProto::Icmp);
-            let start = plan.max_delay();
+            // The HTTP and ICMP probes only start after the STUN probes have had a chance,
+            // as long as all staggered attempts would take even if fewer were planned.
+            let mut https_ipv4_probes = ProbeSet::new(reg.region_id, ProbeProto::HttpsIpv4);
+            let mut https_ipv6_probes = ProbeSet::new(reg.region_id, ProbeProto::HttpsIpv6);
+            let mut icmp_probes = ProbeSet::new(reg.region_id, ProbeProto::IcmpV4);
+            let mut icmpv6_probes = ProbeSet::new(reg.region_id, ProbeProto::IcmpV6);
+            let start = plan
+                .max_delay()
+                .max(retransmit_delay * (attempts as u32 - 1));
             for attempt in 0..attempts {
                 let derp_node = &reg.nodes[attempt % reg.nodes.len()];
                 let derp_node = derp_nodes_cache.get(derp_node);
                 let delay = start
                     + (retransmit_delay * attempt as u32)
                     + (ACTIVE_RETRANSMIT_EXTRA_DELAY * (attempt as u32 + 1));
-                if reg.has_derp_node() {
-                    https_probes
-                        .push(Probe::Https {
+                if reg.has_derp_node() && do4 {
+                    https_ipv4_probes
+                        .push(Probe::HttpsIpv4 {
                             delay,
                             node: derp_node.clone(),
                             region: reg.clone(),
                         })
-                        .expect("Pushing Https Probe to an Https ProbeSet");
+                        .expect("Pushing HttpsIpv4 Probe to an HttpsIpv4 ProbeSet");
+                }
+                if reg.has_derp_node() && do6 {
+                    https_ipv6_probes
+                        .push(Probe::HttpsIpv6 {
+                            delay,
+                            node: derp_node.clone(),
+                            region: reg.clone(),
+                        })
+                        .expect("Pushing HttpsIpv6 Probe to an HttpsIpv6 ProbeSet");
+                }
+                if do4 {
+                    icmp_probes
+                        .push(Probe::IcmpV4 {
+                            delay,
+                            node: derp_node.clone(),
+                        })
+                        .expect("Pushing IcmpV4 Probe to an IcmpV4 ProbeSet");
+                }
+                if do6 {
+                    icmpv6_probes
+                        .push(Probe::IcmpV6 {
+                            delay,
+                            node: derp_node.clone(),
+                        })
+                        .expect("Pushing IcmpV6 Probe to an IcmpV6 ProbeSet");
                 }
-                icmp_probes
-                    .push(Probe::Icmp {
-                        delay,
-                        node: derp_node.clone(),
-                    })
-                    .expect("Pushing Icmp Probe to an Icmp ProbeSet");
             }
-            plan.add(https_probes);
+            plan.add(https_ipv4_probes);
+            plan.add(https_ipv6_probes);
             plan.add(icmp_probes);
+            plan.add(icmpv6_probes);
         }
         plan
     }
 
     /// Returns an iterator over the [`ProbeSet`]s in this plan.
-    pub(super) fn iter(&self) -> impl Iterator<Item = &ProbeSet> {
-        self.0.iter()
+    pub fn iter(&self) -> impl Iterator<Item = &ProbeSet> {
+        self.sets.iter()
     }
 
     pub(super) fn has_icmp_probes(&self) -> bool {
         for probe_set in self.iter() {
-            if probe_set.proto == ProbeProto::Icmp {
+            if matches!(probe_set.proto, ProbeProto::IcmpV4 | ProbeProto::IcmpV6) {
                 return true;
             }
         }
         false
     }
 
+    /// Limits the number of probes, i.e. attempts, in each [`ProbeSet`] to `max_attempts`.
+    ///
+    /// The probes with the shortest delay are kept.
+    pub(super) fn limit_attempts(self, max_attempts: usize) -> Self {
+        let sets = self
+            .sets
+            .into_iter()
+            .map(|mut set| {
+                set.probes.sort_by_key(|probe| probe.delay());
+                set.probes.truncate(max_attempts);
+                set
+            })
+            .collect();
+        Self {
+            sets,
+            dropped: self.dropped,
+        }
+    }
+
+    /// Limits the plan to the [`ProbeBudget`].
+    ///
+    /// Regions are kept in order of priority, each with all its probe sets until the budget
+    /// runs out.  The priority is:
+    ///
+    /// - The preferred region of the last report.
+    /// - A few random regions which have no latency in the last report, so that regions
+    ///   can not be starved forever.
+    /// - All other regions, fastest first.
+    ///
+    /// If the budget limits the rate of probes, the delays of the remaining probes are
+    /// increased as needed.
+    pub(super) fn apply_budget<R: Rng>(
+        self,
+        budget: &ProbeBudget,
+        last_report: Option<&Report>,
+        rng: &mut R,
+    ) -> Self {
+        let mut dropped = self.dropped;
+        let mut by_region: BTreeMap<u16, Vec<ProbeSet>> = BTreeMap::new();
+        for set in self.sets {
+            if let Some(region_id) = set.region_id() {
+                by_region.entry(region_id).or_default().push(set);
+            }
+        }
+        let priority = region_priority(by_region.keys().copied(), last_report, rng);
+
+        let mut sets = Vec::new();
+        let mut remaining = budget.max_probes;
+        for region_id in priority {
+            let mut region_sets = by_region.remove(&region_id).unwrap_or_default();
+            // The STUN probes are the most useful, they come first.
+            region_sets.sort_by_key(|set| set.proto);
+            for mut set in region_sets {
+                if remaining == 0 {
+                    dropped.push(format!("{}: {} probes", set.name, set.probes.len()));
+                    continue;
+                }
+                if set.probes.len() > remaining {
+                    set.probes.sort_by_key(|probe| probe.delay());
+                    let excess = set.probes.len() - remaining;
+                    set.probes.truncate(remaining);
+                    dropped.push(format!("{}: {} probes", set.name, excess));
+                }
+                remaining -= set.probes.len();
+                sets.push(set);
+            }
+        }
+
+        if let Some(per_sec) = budget.max_probes_per_sec {
+            pace_probes(&mut sets, per_sec);
+        }
+        Self {
+            sets: sets.into_iter().collect(),
+            dropped,
+        }
+    }
+
+    /// Returns the probe sets ordered by how useful they are.
+    ///
+    /// The preferred region of the last report comes first, followed by the regions
+    /// ordered by their latency in the last report.  Within a region STUN comes first.
+    pub(super) fn by_priority(&self, last_report: Option<&Report>) -> Vec<&ProbeSet> {
+        let default_report = Report::default();
+        let last_report = last_report.unwrap_or(&default_report);
+        let mut sets: Vec<&ProbeSet> = self.sets.iter().collect();
+        sets.sort_by_key(|set| {
+            let region_id = set.region_id().unwrap_or(u16::MAX);
+            (
+                region_id != last_report.preferred_derp,
+                latency_rank(last_report, region_id),
+                set.proto,
+            )
+        });
+        sets
+    }
+
+    /// Returns the descriptions of the probes dropped by [`ProbePlan::apply_budget`].
+    pub(super) fn dropped(&self) -> &[String] {
+        &self.dropped
+    }
+
     /// Adds a [`ProbeSet`] if it contains probes.
-    fn add(&mut self, set: ProbeSet) {
+    pub fn add(&mut self, set: ProbeSet) {
         if !set.is_empty() {
-            self.0.insert(set);
+            self.sets.insert(set);
         }
     }
 
+    /// Only keeps the [`ProbeSet`]s for which *f* returns `true`.
+    pub fn retain(&mut self, f: impl FnMut(&ProbeSet) -> bool) {
+        self.sets.retain(f);
+    }
+
     /// Returns the delay of the last probe in the probe plan.
     fn max_delay(&self) -> Duration {
-        self.0
+        self.sets
             .iter()
             .flatten()
             .map(|probe| probe.delay())
@@ -416,20 +740,26 @@ impl ProbePlan {
 impl fmt::Display for ProbePlan {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "ProbePlan {{")?;
-        for probe_set in self.0.iter() {
+        for probe_set in self.sets.iter() {
             writeln!(f, r#"    ProbeSet("{}") {{"#, probe_set.name)?;
             for probe in probe_set.probes.iter() {
                 writeln!(f, "        {probe},")?;
             }
             writeln!(f, "    }}")?;
         }
+        for dropped in self.dropped.iter() {
+            writeln!(f, "    Dropped({dropped}),")?;
+        }
         writeln!(f, "}}")
     }
 }
 
 impl FromIterator<ProbeSet> for ProbePlan {
     fn from_iter<T: IntoIterator<Item = ProbeSet>>(iter: T) -> Self {
-        Self(iter.into_iter().collect())
+        Self {
+            sets: iter.into_iter().collect(),
+            dropped: Vec::new(),
+        }
     }
 }
 
@@ -462,6 +792,117 @@ impl DerpNodeCache {
     }
 }
 
+/// Returns a [`DerpMap`] with only the regions which should be probed.
+///
+/// If `regions` is given only those regions are kept.  If `max_regions` is given only that
+/// many regions are kept, preferring the fastest regions of the last report.
+pub(super) fn restrict_regions(
+    derp_map: &DerpMap,
+    regions: Option<&BTreeSet<u16>>,
+    max_regions: Option<usize>,
+    last_report: Option<&Report>,
+) -> DerpMap {
+    let mut derp_map = derp_map.clone();
+    if let Some(regions) = regions {
+        derp_map
+            .regions
+            .retain(|region_id, _| regions.contains(region_id));
+    }
+    if let Some(max_regions) = max_regions {
+        if derp_map.regions.len() > max_regions {
+            let default_report = Report::default();
+            let last_report = last_report.unwrap_or(&default_report);
+            let keep: BTreeSet<u16> = sort_regions(&derp_map, last_report)
+                .into_iter()
+                .take(max_regions)
+                .map(|region| region.region_id)
+                .collect();
+            derp_map
+                .regions
+                .retain(|region_id, _| keep.contains(region_id));
+        }
+    }
+    derp_map
+}
+
+/// Orders the regions for [`ProbePlan::apply_budget`], highest priority first.
+fn region_priority<R: Rng>(
+    regions: impl Iterator<Item = u16>,
+    last_report: Option<&Report>,
+    rng: &mut R,
+) -> Vec<u16> {
+    let default_report = Report::default();
+    let last_report = last_report.unwrap_or(&default_report);
+    let mut regions: Vec<u16> = regions.collect();
+    let mut priority = Vec::with_capacity(regions.len());
+
+    if let Some(pos) = regions
+        .iter()
+        .position(|region_id| *region_id == last_report.preferred_derp)
+    {
+        priority.push(regions.remove(pos));
+    }
+
+    let unmeasured: Vec<u16> = regions
+        .iter()
+        .copied()
+        .filter(|region_id| last_report.region_latency.get(*region_id).is_none())
+        .collect();
+    for region_id in unmeasured.choose_multiple(rng, EXPLORATORY_REGIONS) {
+        priority.push(*region_id);
+        regions.retain(|id| id != region_id);
+    }
+
+    regions.sort_by_key(|region_id| latency_rank(last_report, *region_id));
+    priority.extend(regions);
+    priority
+}
+
+/// Sort key for regions by their latency in the last report.
+///
+/// Regions with a latency first, fastest first, then by region ID.
+fn latency_rank(last_report: &Report, region_id: u16) -> (bool, Option<Duration>, u16) {
+    let latency = last_report.region_latency.get(region_id);
+    (latency.is_none(), latency, region_id)
+}
+
+/// Increases probe delays so that no more than *per_sec* probes start each second.
+///
+/// Probes keep their order, the n-th probe does not start before n / *per_sec* seconds.
+fn pace_probes(sets: &mut [ProbeSet], per_sec: u32) {
+    let mut probes: Vec<&mut Probe> = sets
+        .iter_mut()
+        .flat_map(|set| set.probes.iter_mut())
+        .collect();
+    probes.sort_by_key(|probe| probe.delay());
+    for (i, probe) in probes.into_iter().enumerate() {
+        let earliest = Duration::from_secs(1) * i as u32 / per_sec;
+        let delay = probe.delay_mut();
+        *delay = (*delay).max(earliest);
+    }
+}
+
+/// Returns the number of staggered STUN attempts in a probe set, at most one per DERP node.
+///
+/// Each STUN probe already retransmits its request, see [`crate::stun::Retransmit`], so
+/// another attempt to the same node would only multiply the packets sent.
+fn stun_attempts(region: &DerpRegion, attempts: usize) -> usize {
+    attempts.min(region.nodes.len())
+}
+
+/// Returns the delay between STUN retries for a region, based on a previous report.
+///
+/// This is 1.5 times the latency of the region in the previous report, but at least
+/// [`MIN_ACTIVE_RETRANSMIT_DELAY`].  If the region had no latency
+/// [`DEFAULT_ACTIVE_RETRANSMIT_DELAY`] is used.
+fn retransmit_delay(last_report: &Report, region_id: u16) -> Duration {
+    last_report
+        .region_latency
+        .get(region_id)
+        .map(|latency| (latency * 3 / 2).max(MIN_ACTIVE_RETRANSMIT_DELAY))
+        .unwrap_or(DEFAULT_ACTIVE_RETRANSMIT_DELAY)
+}
+
 /// Sorts the regions in the [`DerpMap`] from fastest to slowest.
 ///
 /// This uses the latencies from the last report to determine the order.  Regions with no
@@ -496,6 +937,8 @@ fn sort_regions<'a>(derp_map: &'a DerpMap, last_report: &Report) -> Vec<&'a Derp
 
 #[cfg(test)]
 mod tests {
+    use std::net::SocketAddr;
+
     use pretty_assertions::assert_eq;
 
     use crate::defaults::default_derp_map;
@@ -516,36 +959,26 @@ mod tests {
             ProbeSet {
                 name: "region-1-stunipv4".into(),
                 proto: ProbeProto::StunIpv4,
-                probes: vec![
-                    Probe::StunIpv4 {
-                        delay: Duration::ZERO,
-                        node: derp_node_1.clone(),
-                    },
-                    Probe::StunIpv4 {
-                        delay: Duration::from_millis(100),
-                        node: derp_node_1.clone(),
-                    },
-                    Probe::StunIpv4 {
-                        delay: Duration::from_millis(200),
-                        node: derp_node_1.clone(),
-                    },
-                ],
+                probes: vec![Probe::StunIpv4 {
+                    delay: Duration::ZERO,
+                    node: derp_node_1.clone(),
+                }],
             },
             ProbeSet {
-                name: "region-1-https".into(),
-                proto: ProbeProto::Https,
+                name: "region-1-httpsipv4".into(),
+                proto: ProbeProto::HttpsIpv4,
                 probes: vec![
-                    Probe::Https {
+                    Probe::HttpsIpv4 {
                         delay: Duration::from_millis(300),
                         node: derp_node_1.clone(),
                         region: derp_map.regions[&1].clone(),
                     },
-                    Probe::Https {
+                    Probe::HttpsIpv4 {
                         delay: Duration::from_millis(400),
                         node: derp_node_1.clone(),
                         region: derp_map.regions[&1].clone(),
                     },
-                    Probe::Https {
+                    Probe::HttpsIpv4 {
                         delay: Duration::from_millis(500),
                         node: derp_node_1.clone(),
                         region: derp_map.regions[&1].clone(),
@@ -553,18 +986,18 @@ mod tests {
                 ],
             },
             ProbeSet {
-                name: "region-1-icmp".into(),
-                proto: ProbeProto::Icmp,
+                name: "region-1-icmpv4".into(),
+                proto: ProbeProto::IcmpV4,
                 probes: vec![
-                    Probe::Icmp {
+                    Probe::IcmpV4 {
                         delay: Duration::from_millis(300),
                         node: derp_node_1.clone(),
                     },
-                    Probe::Icmp {
+                    Probe::IcmpV4 {
                         delay: Duration::from_millis(400),
                         node: derp_node_1.clone(),
                     },
-                    Probe::Icmp {
+                    Probe::IcmpV4 {
                         delay: Duration::from_millis(500),
                         node: derp_node_1,
                     },
@@ -573,36 +1006,26 @@ mod tests {
             ProbeSet {
                 name: "region-2-stunipv4".into(),
                 proto: ProbeProto::StunIpv4,
-                probes: vec![
-                    Probe::StunIpv4 {
-                        delay: Duration::ZERO,
-                        node: derp_node_2.clone(),
-                    },
-                    Probe::StunIpv4 {
-                        delay: Duration::from_millis(100),
-                        node: derp_node_2.clone(),
-                    },
-                    Probe::StunIpv4 {
-                        delay: Duration::from_millis(200),
-                        node: derp_node_2.clone(),
-                    },
-                ],
+                probes: vec![Probe::StunIpv4 {
+                    delay: Duration::ZERO,
+                    node: derp_node_2.clone(),
+                }],
             },
             ProbeSet {
-                name: "region-2-https".into(),
-                proto: ProbeProto::Https,
+                name: "region-2-httpsipv4".into(),
+                proto: ProbeProto::HttpsIpv4,
                 probes: vec![
-                    Probe::Https {
+                    Probe::HttpsIpv4 {
                         delay: Duration::from_millis(600),
                         node: derp_node_2.clone(),
                         region: derp_map.regions[&2].clone(),
                     },
-                    Probe::Https {
+                    Probe::HttpsIpv4 {
                         delay: Duration::from_millis(700),
                         node: derp_node_2.clone(),
                         region: derp_map.regions[&2].clone(),
                     },
-                    Probe::Https {
+                    Probe::HttpsIpv4 {
                         delay: Duration::from_millis(800),
                         node: derp_node_2.clone(),
                         region: derp_map.regions[&2].clone(),
@@ -610,18 +1033,18 @@ mod tests {
                 ],
             },
             ProbeSet {
-                name: "region-2-icmp".into(),
-                proto: ProbeProto::Icmp,
+                name: "region-2-icmpv4".into(),
+                proto: ProbeProto::IcmpV4,
                 probes: vec![
-                    Probe::Icmp {
+                    Probe::IcmpV4 {
                         delay: Duration::from_millis(600),
                         node: derp_node_2.clone(),
                     },
-                    Probe::Icmp {
+                    Probe::IcmpV4 {
                         delay: Duration::from_millis(700),
                         node: derp_node_2.clone(),
                     },
-                    Probe::Icmp {
+                    Probe::IcmpV4 {
                         delay: Duration::from_millis(800),
                         node: derp_node_2,
                     },
@@ -654,91 +1077,105 @@ mod tests {
             latencies.update_region(2, Duration::from_millis(2));
             let last_report = Report {
                 udp: true,
+                udp_verdict: Default::default(),
                 ipv6: true,
                 ipv4: true,
                 ipv6_can_send: true,
                 ipv4_can_send: true,
+                ipv4_not_probed: false,
+                ipv6_not_probed: false,
                 os_has_ipv6: true,
+                os_has_ipv6_route: true,
                 icmpv4: true,
+                icmpv6: false,
+                udp_blocked_locally: false,
                 mapping_varies_by_dest_ip: Some(false),
+                nat_behavior: None,
                 hair_pinning: Some(true),
+                hair_pinning_v4: Some(true),
+                hair_pinning_v6: None,
                 portmap_probe: None,
                 preferred_derp: 1,
+                preferred_derp_lead: None,
+                preferred_derp_kept: false,
                 region_latency: latencies.clone(),
                 region_v4_latency: latencies.clone(),
                 region_v6_latency: latencies.clone(),
+                node_latency: Default::default(),
+                node_v4_latency: Default::default(),
+                node_v6_latency: Default::default(),
+                node_v4_addrs: Default::default(),
+                node_v6_addrs: Default::default(),
                 global_v4: None,
                 global_v6: None,
+                global_v4_endpoints: Default::default(),
+                global_v6_endpoints: Default::default(),
+                probe_failures: Default::default(),
+                stun_local_v4: None,
+                stun_local_v6: None,
+                stun_interface: None,
                 captive_portal: None,
+                captive_portal_details: None,
+                partial: false,
+                generated_at: None,
+                probe_duration: Duration::ZERO,
+                region_measured_at: Default::default(),
+                generated_instant: None,
             };
             let plan = ProbePlan::with_last_report(&derp_map, &if_state, &last_report);
             let expected_plan: ProbePlan = [
                 ProbeSet {
                     name: "region-1-stunipv4".into(),
                     proto: ProbeProto::StunIpv4,
-                    probes: vec![
-                        Probe::StunIpv4 {
-                            delay: Duration::ZERO,
-                            node: derp_node_1.clone(),
-                        },
-                        Probe::StunIpv4 {
-                            delay: Duration::from_micros(52_400),
-                            node: derp_node_1.clone(),
-                        },
-                        Probe::StunIpv4 {
-                            delay: Duration::from_micros(104_800),
-                            node: derp_node_1.clone(),
-                        },
-                        Probe::StunIpv4 {
-                            delay: Duration::from_micros(157_200),
-                            node: derp_node_1.clone(),
-                        },
-                    ],
+                    probes: vec![Probe::StunIpv4 {
+                        delay: Duration::ZERO,
+                        node: derp_node_1.clone(),
+                    }],
                 },
                 ProbeSet {
-                    name: "region-1-https".into(),
-                    proto: ProbeProto::Https,
+                    name: "region-1-httpsipv4".into(),
+                    proto: ProbeProto::HttpsIpv4,
                     probes: vec![
-                        Probe::Https {
-                            delay: Duration::from_micros(207_200),
+                        Probe::HttpsIpv4 {
+                            delay: Duration::from_micros(110_000),
                             node: derp_node_1.clone(),
                             region: derp_map.regions[&1].clone(),
                         },
-                        Probe::Https {
-                            delay: Duration::from_micros(259_600),
+                        Probe::HttpsIpv4 {
+                            delay: Duration::from_micros(180_000),
                             node: derp_node_1.clone(),
                             region: derp_map.regions[&1].clone(),
                         },
-                        Probe::Https {
-                            delay: Duration::from_micros(312_000),
+                        Probe::HttpsIpv4 {
+                            delay: Duration::from_micros(250_000),
                             node: derp_node_1.clone(),
                             region: derp_map.regions[&1].clone(),
                         },
-                        Probe::Https {
-                            delay: Duration::from_micros(364_400),
+                        Probe::HttpsIpv4 {
+                            delay: Duration::from_micros(320_000),
                             node: derp_node_1.clone(),
                             region: derp_map.regions[&1].clone(),
                         },
                     ],
                 },
                 ProbeSet {
-                    name: "region-1-icmp".into(),
-                    proto: ProbeProto::Icmp,
+                    name: "region-1-icmpv4".into(),
+                    proto: ProbeProto::IcmpV4,
                     probes: vec![
-                        Probe::Icmp {
-                            delay: Duration::from_micros(207_200),
+                        Probe::IcmpV4 {
+                            delay: Duration::from_micros(110_000),
                             node: derp_node_1.clone(),
                         },
-                        Probe::Icmp {
-                            delay: Duration::from_micros(259_600),
+                        Probe::IcmpV4 {
+                            delay: Duration::from_micros(180_000),
                             node: derp_node_1.clone(),
                         },
-                        Probe::Icmp {
-                            delay: Duration::from_micros(312_000),
+                        Probe::IcmpV4 {
+                            delay: Duration::from_micros(250_000),
                             node: derp_node_1.clone(),
                         },
-                        Probe::Icmp {
-                            delay: Duration::from_micros(364_400),
+                        Probe::IcmpV4 {
+                            delay: Duration::from_micros(320_000),
                             node: derp_node_1.clone(),
                         },
                     ],
@@ -746,43 +1183,37 @@ mod tests {
                 ProbeSet {
                     name: "region-2-stunipv4".into(),
                     proto: ProbeProto::StunIpv4,
-                    probes: vec![
-                        Probe::StunIpv4 {
-                            delay: Duration::ZERO,
-                            node: derp_node_2.clone(),
-                        },
-                        Probe::StunIpv4 {
-                            delay: Duration::from_micros(52_400),
-                            node: derp_node_2.clone(),
-                        },
-                    ],
+                    probes: vec![Probe::StunIpv4 {
+                        delay: Duration::ZERO,
+                        node: derp_node_2.clone(),
+                    }],
                 },
                 ProbeSet {
-                    name: "region-2-https".into(),
-                    proto: ProbeProto::Https,
+                    name: "region-2-httpsipv4".into(),
+                    proto: ProbeProto::HttpsIpv4,
                     probes: vec![
-                        Probe::Https {
-                            delay: Duration::from_micros(414_400),
+                        Probe::HttpsIpv4 {
+                            delay: Duration::from_micros(370_000),
                             node: derp_node_2.clone(),
                             region: derp_map.regions[&2].clone(),
                         },
-                        Probe::Https {
-                            delay: Duration::from_micros(466_800),
+                        Probe::HttpsIpv4 {
+                            delay: Duration::from_micros(440_000),
                             node: derp_node_2.clone(),
                             region: derp_map.regions[&2].clone(),
                         },
                     ],
                 },
                 ProbeSet {
-                    name: "region-2-icmp".into(),
-                    proto: ProbeProto::Icmp,
+                    name: "region-2-icmpv4".into(),
+                    proto: ProbeProto::IcmpV4,
                     probes: vec![
-                        Probe::Icmp {
-                            delay: Duration::from_micros(414_400),
+                        Probe::IcmpV4 {
+                            delay: Duration::from_micros(370_000),
                             node: derp_node_2.clone(),
                         },
-                        Probe::Icmp {
-                            delay: Duration::from_micros(466_800),
+                        Probe::IcmpV4 {
+                            delay: Duration::from_micros(440_000),
                             node: derp_node_2.clone(),
                         },
                     ],
@@ -804,6 +1235,329 @@ mod tests {
         }
     }
 
+    fn stun_delays(plan: &ProbePlan, name: &str) -> Vec<Duration> {
+        plan.iter()
+            .find(|set| set.name == name)
+            .expect("missing probe set")
+            .into_iter()
+            .map(|probe| probe.delay())
+            .collect()
+    }
+
+    /// Gives every region *count* nodes, so its probe sets can have as many STUN attempts.
+    fn with_nodes(mut derp_map: DerpMap, count: usize) -> DerpMap {
+        for region in derp_map.regions.values_mut() {
+            let node = region.nodes[0].clone();
+            region.nodes = (0..count)
+                .map(|i| DerpNode {
+                    name: format!("{}-{i}", node.name),
+                    ..node.clone()
+                })
+                .collect();
+        }
+        derp_map
+    }
+
+    #[test]
+    fn test_plan_single_node_stun_attempts() {
+        let derp_map = default_derp_map();
+        let if_state = interfaces::State::fake();
+        let last_report = create_last_report(
+            Some(Duration::from_millis(100)),
+            Some(Duration::from_millis(10)),
+        );
+        let plan = ProbePlan::with_last_report(&derp_map, &if_state, &last_report);
+
+        // The STUN probes retransmit, a single node is only probed once per probe set.
+        assert_eq!(
+            stun_delays(&plan, "region-1-stunipv4"),
+            vec![Duration::ZERO]
+        );
+        assert_eq!(
+            stun_delays(&plan, "region-2-stunipv4"),
+            vec![Duration::ZERO]
+        );
+        // The fallback probes still give the STUN probes their time.
+        assert_eq!(
+            stun_delays(&plan, "region-1-httpsipv4")[0],
+            Duration::from_millis(450) + ACTIVE_RETRANSMIT_EXTRA_DELAY
+        );
+    }
+
+    #[test]
+    fn test_plan_retransmit_delays() {
+        let derp_map = with_nodes(default_derp_map(), 4);
+        let if_state = interfaces::State::fake();
+        let last_report = create_last_report(
+            Some(Duration::from_millis(100)),
+            Some(Duration::from_millis(10)),
+        );
+        let plan = ProbePlan::with_last_report(&derp_map, &if_state, &last_report);
+
+        // The preferred region retries after 1.5 times its latency.
+        assert_eq!(
+            stun_delays(&plan, "region-1-stunipv4"),
+            vec![
+                Duration::ZERO,
+                Duration::from_millis(150),
+                Duration::from_millis(300),
+                Duration::from_millis(450),
+            ]
+        );
+        // Fast regions use the minimum retransmit delay.
+        assert_eq!(
+            stun_delays(&plan, "region-2-stunipv4"),
+            vec![Duration::ZERO, MIN_ACTIVE_RETRANSMIT_DELAY]
+        );
+
+        // Regions without a latency use the default.
+        let last_report = create_last_report(None, Some(Duration::from_millis(10)));
+        let plan = ProbePlan::with_last_report(&derp_map, &if_state, &last_report);
+        assert_eq!(
+            stun_delays(&plan, "region-1-stunipv4"),
+            vec![
+                Duration::ZERO,
+                DEFAULT_ACTIVE_RETRANSMIT_DELAY,
+                DEFAULT_ACTIVE_RETRANSMIT_DELAY * 2,
+                DEFAULT_ACTIVE_RETRANSMIT_DELAY * 3,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_preferred_region_first() {
+        let derp_map = with_nodes(
+            crate::stun::test::derp_map_of(
+                (1..=5).map(|port| SocketAddr::from(([127, 0, 0, 1], port))),
+            ),
+            4,
+        );
+        let if_state = interfaces::State::fake();
+        // The preferred region is the slowest, it was kept because of the margin.
+        let mut last_report = Report {
+            preferred_derp: 5,
+            ..Default::default()
+        };
+        for region_id in 1..=5 {
+            let latency = Duration::from_millis(10 * region_id as u64);
+            last_report.region_latency.update_region(region_id, latency);
+            last_report
+                .region_v4_latency
+                .update_region(region_id, latency);
+        }
+        let plan = ProbePlan::with_last_report(&derp_map, &if_state, &last_report);
+        println!("{plan}");
+
+        // It is probed right away and with the most retries.
+        assert_eq!(
+            stun_delays(&plan, "region-5-stunipv4"),
+            vec![
+                Duration::ZERO,
+                Duration::from_millis(75),
+                Duration::from_millis(150),
+                Duration::from_millis(225),
+            ]
+        );
+        assert_eq!(
+            plan.by_priority(Some(&last_report))[0].name,
+            "region-5-stunipv4"
+        );
+
+        // The other incremental regions are the fastest ones, with their usual retries.
+        let regions: BTreeSet<u16> = plan.iter().filter_map(|set| set.region_id()).collect();
+        assert_eq!(regions, [1, 2, 3, 5].into());
+        assert_eq!(
+            stun_delays(&plan, "region-1-stunipv4"),
+            vec![Duration::ZERO, MIN_ACTIVE_RETRANSMIT_DELAY]
+        );
+        assert_eq!(
+            stun_delays(&plan, "region-2-stunipv4"),
+            vec![Duration::ZERO, Duration::from_millis(30)]
+        );
+        assert_eq!(
+            stun_delays(&plan, "region-3-stunipv4"),
+            vec![Duration::ZERO]
+        );
+    }
+
+    #[test]
+    fn test_plan_ipv6_only_with_dual_stack_report() {
+        let derp_map = crate::stun::test::derp_map_of(
+            (1..=5).map(|port| SocketAddr::from(([127, 0, 0, 1], port))),
+        );
+        let mut if_state = interfaces::State::fake();
+        if_state.have_v4 = false;
+        if_state.have_v6 = true;
+        // The last report, e.g. loaded from the store, saw both address families.
+        let mut last_report = Report {
+            preferred_derp: 1,
+            ..Default::default()
+        };
+        for region_id in 1..=5 {
+            let latency = Duration::from_millis(10 * region_id as u64);
+            last_report.region_latency.update_region(region_id, latency);
+            last_report
+                .region_v4_latency
+                .update_region(region_id, latency);
+            last_report
+                .region_v6_latency
+                .update_region(region_id, latency);
+        }
+        let plan = ProbePlan::with_last_report(&derp_map, &if_state, &last_report);
+        println!("{plan}");
+
+        assert!(plan.iter().next().is_some());
+        assert!(plan.iter().all(|set| set.proto.is_ipv6()));
+    }
+
+    #[test]
+    fn test_plan_limit_attempts() {
+        let derp_map = with_nodes(default_derp_map(), 4);
+        let if_state = interfaces::State::fake();
+        let last_report = create_last_report(
+            Some(Duration::from_millis(100)),
+            Some(Duration::from_millis(10)),
+        );
+        let plan = ProbePlan::with_last_report(&derp_map, &if_state, &last_report);
+        let plan = plan.limit_attempts(2);
+        assert!(plan.iter().all(|set| set.probes.len() <= 2));
+        assert_eq!(
+            stun_delays(&plan, "region-1-stunipv4"),
+            vec![Duration::ZERO, Duration::from_millis(150)]
+        );
+
+        let plan = ProbePlan::initial(&derp_map, &if_state).limit_attempts(1);
+        assert!(plan.iter().all(|set| set.probes.len() == 1));
+    }
+
+    #[test]
+    fn test_plan_budget() {
+        let derp_map = with_nodes(default_derp_map(), 4);
+        let if_state = interfaces::State::fake();
+        // Region 2 is faster, but region 1 is the preferred region.
+        let last_report = create_last_report(
+            Some(Duration::from_millis(100)),
+            Some(Duration::from_millis(10)),
+        );
+        let plan = ProbePlan::with_last_report(&derp_map, &if_state, &last_report);
+        let total: usize = plan.iter().map(|set| set.probes.len()).sum();
+
+        let budget = ProbeBudget {
+            max_probes: 2,
+            max_probes_per_sec: None,
+        };
+        let plan = plan.apply_budget(&budget, Some(&last_report), &mut rand::thread_rng());
+        println!("{plan}");
+        let names: Vec<_> = plan.iter().map(|set| set.name.as_str()).collect();
+        assert_eq!(names, vec!["region-1-stunipv4"]);
+        assert_eq!(plan.iter().map(|set| set.probes.len()).sum::<usize>(), 2);
+        assert!(!plan.dropped().is_empty());
+        assert!(plan.to_string().contains("Dropped("));
+
+        // A budget which fits everything changes nothing.
+        let plan = ProbePlan::with_last_report(&derp_map, &if_state, &last_report);
+        let budget = ProbeBudget {
+            max_probes: total,
+            max_probes_per_sec: None,
+        };
+        let budgeted = ProbePlan::with_last_report(&derp_map, &if_state, &last_report)
+            .apply_budget(&budget, Some(&last_report), &mut rand::thread_rng());
+        assert_eq!(budgeted, plan);
+    }
+
+    #[test]
+    fn test_plan_by_priority() {
+        let derp_map = default_derp_map();
+        let if_state = interfaces::State::fake();
+        let plan = ProbePlan::initial(&derp_map, &if_state);
+
+        // Without a last report the regions are ordered by ID.
+        let sets = plan.by_priority(None);
+        assert_eq!(sets[0].name, "region-1-stunipv4");
+        assert_eq!(sets[sets.len() - 1].region_id(), Some(2));
+
+        // The preferred region goes first, even if it is slower.
+        let mut last_report = create_last_report(
+            Some(Duration::from_millis(100)),
+            Some(Duration::from_millis(10)),
+        );
+        let sets = plan.by_priority(Some(&last_report));
+        assert_eq!(sets[0].name, "region-1-stunipv4");
+        last_report.preferred_derp = 0;
+        let sets = plan.by_priority(Some(&last_report));
+        assert_eq!(sets[0].name, "region-2-stunipv4");
+    }
+
+    #[test]
+    fn test_plan_budget_pacing() {
+        let derp_map = default_derp_map();
+        let if_state = interfaces::State::fake();
+        let budget = ProbeBudget {
+            max_probes: usize::MAX,
+            max_probes_per_sec: Some(10),
+        };
+        let plan = ProbePlan::initial(&derp_map, &if_state).apply_budget(
+            &budget,
+            None,
+            &mut rand::thread_rng(),
+        );
+        let mut delays: Vec<_> = plan
+            .iter()
+            .flat_map(|set| set.probes.iter().map(|probe| probe.delay()))
+            .collect();
+        delays.sort();
+        for (i, delay) in delays.into_iter().enumerate() {
+            assert!(
+                delay >= Duration::from_millis(100) * i as u32,
+                "{i}: {delay:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_initial_probeplan_icmpv6() {
+        let derp_map = default_derp_map();
+        let mut if_state = interfaces::State::fake();
+        if_state.have_v6 = true;
+        let plan = ProbePlan::initial(&derp_map, &if_state);
+
+        for region_id in derp_map.region_ids() {
+            let icmp_set = plan
+                .iter()
+                .find(|set| set.name == format!("region-{region_id}-icmpv4"))
+                .expect("missing icmp probe set");
+            let icmpv6_set = plan
+                .iter()
+                .find(|set| set.name == format!("region-{region_id}-icmpv6"))
+                .expect("missing icmpv6 probe set");
+            assert_eq!(icmpv6_set.proto, ProbeProto::IcmpV6);
+            let icmp_delays: Vec<_> = icmp_set.into_iter().map(|p| p.delay()).collect();
+            let icmpv6_delays: Vec<_> = icmpv6_set.into_iter().map(|p| p.delay()).collect();
+            assert_eq!(icmp_delays, icmpv6_delays);
+        }
+    }
+
+    #[test]
+    fn test_initial_probeplan_https_families() {
+        let derp_map = default_derp_map();
+        let mut if_state = interfaces::State::fake();
+        if_state.have_v6 = true;
+        let plan = ProbePlan::initial(&derp_map, &if_state);
+        for region_id in derp_map.region_ids() {
+            for proto in [ProbeProto::HttpsIpv4, ProbeProto::HttpsIpv6] {
+                let set = plan
+                    .iter()
+                    .find(|set| set.region_id() == Some(region_id) && set.proto == proto)
+                    .expect("missing https probe set");
+                assert!(set.into_iter().all(|probe| probe.proto() == proto));
+            }
+        }
+
+        if_state.have_v6 = false;
+        let plan = ProbePlan::initial(&derp_map, &if_state);
+        assert!(plan.iter().all(|set| set.proto != ProbeProto::HttpsIpv6));
+    }
+
     fn create_last_report(latency_1: Option<Duration>, latency_2: Option<Duration>) -> Report {
         let mut latencies = RegionLatencies::new();
         if let Some(latency_1) = latency_1 {
@@ -814,22 +1568,50 @@ mod tests {
         }
         Report {
             udp: true,
+            udp_verdict: Default::default(),
             ipv6: true,
             ipv4: true,
             ipv6_can_send: true,
             ipv4_can_send: true,
+            ipv4_not_probed: false,
+            ipv6_not_probed: false,
             os_has_ipv6: true,
+            os_has_ipv6_route: true,
             icmpv4: true,
+            icmpv6: false,
+            udp_blocked_locally: false,
             mapping_varies_by_dest_ip: Some(false),
+            nat_behavior: None,
             hair_pinning: Some(true),
+            hair_pinning_v4: Some(true),
+            hair_pinning_v6: None,
             portmap_probe: None,
             preferred_derp: 1,
+            preferred_derp_lead: None,
+            preferred_derp_kept: false,
             region_latency: latencies.clone(),
             region_v4_latency: latencies.clone(),
             region_v6_latency: latencies.clone(),
+            node_latency: Default::default(),
+            node_v4_latency: Default::default(),
+            node_v6_latency: Default::default(),
+            node_v4_addrs: Default::default(),
+            node_v6_addrs: Default::default(),
             global_v4: None,
             global_v6: None,
+            global_v4_endpoints: Default::default(),
+            global_v6_endpoints: Default::default(),
+            probe_failures: Default::default(),
+            stun_local_v4: None,
+            stun_local_v6: None,
+            stun_interface: None,
             captive_portal: None,
+            captive_portal_details: None,
+            partial: false,
+            generated_at: None,
+            probe_duration: Duration::ZERO,
+            region_measured_at: Default::default(),
+            generated_instant: None,
         }
     }
 
@@ -890,4 +1672,27 @@ mod tests {
         // sorted by region id only
         assert_eq!(sorted, vec![1, 2]);
     }
+
+    #[test]
+    fn test_restrict_regions() {
+        let derp_map = default_derp_map();
+        let all = restrict_regions(&derp_map, None, None, None);
+        assert_eq!(all, derp_map);
+
+        let only_2 = BTreeSet::from([2, 42]);
+        let restricted = restrict_regions(&derp_map, Some(&only_2), None, None);
+        assert_eq!(restricted.region_ids(), vec![2]);
+
+        // Without a last report the lowest region IDs are kept.
+        let restricted = restrict_regions(&derp_map, None, Some(1), None);
+        assert_eq!(restricted.region_ids(), vec![1]);
+
+        // Otherwise the fastest regions.
+        let last_report = create_last_report(
+            Some(Duration::from_millis(20)),
+            Some(Duration::from_millis(10)),
+        );
+        let restricted = restrict_regions(&derp_map, None, Some(1), Some(&last_report));
+        assert_eq!(restricted.region_ids(), vec![2]);
+    }
 }
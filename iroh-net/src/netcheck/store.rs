@@ -0,0 +1,181 @@
+//! Persisting the last netcheck [`Report`] across restarts.
+//!
+//! Reports are generated incrementally based on the previous report, which is a lot faster
+//! and lighter than probing all regions.  After a restart there is no previous report
+//! however, unless it was stored using a [`ReportStore`].
+//!
+//! The stored format is a version byte followed by the postcard encoded report and the time
+//! it was saved.  Data with a different version, which can not be decoded or which is too
+//! old is discarded, in which case the next report will simply be a full report.
+
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::Report;
+
+/// The version of the stored report format.
+///
+/// This must be bumped whenever the [`Report`] struct changes in any way.
+const STORE_VERSION: u8 = 15;
+
+/// Storage for the last netcheck [`Report`].
+///
+/// The netcheck actor calls [`ReportStore::store`] after each report and
+/// [`ReportStore::load`] once when it starts.  Both are called from the actor directly, so
+/// they should be quick.
+pub trait ReportStore: Debug + Send + Sync + 'static {
+    /// Loads the previously stored data, `None` if nothing was stored yet.
+    fn load(&self) -> Result<Option<Vec<u8>>>;
+
+    /// Stores the data, replacing any previously stored data.
+    fn store(&self, data: &[u8]) -> Result<()>;
+}
+
+/// A [`ReportStore`] which keeps the report in a file.
+#[derive(Debug, Clone)]
+pub struct FileReportStore {
+    path: PathBuf,
+}
+
+impl FileReportStore {
+    /// Creates a new store using the file at `path`.
+    ///
+    /// The file does not need to exist yet, but its directory does.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl ReportStore for FileReportStore {
+    fn load(&self) -> Result<Option<Vec<u8>>> {
+        match std::fs::read(&self.path) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("reading {}", self.path.display())),
+        }
+    }
+
+    fn store(&self, data: &[u8]) -> Result<()> {
+        // Write to a temporary file first so a crash never leaves a truncated report.
+        let tmp_path = self.path.with_extension("tmp");
+        std::fs::write(&tmp_path, data)
+            .with_context(|| format!("writing {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("renaming to {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredReport {
+    /// Seconds since the UNIX epoch when the report was stored.
+    saved_at: u64,
+    report: Report,
+}
+
+/// Encodes the report for a [`ReportStore`].
+pub(super) fn encode(report: &Report, now: SystemTime) -> Result<Vec<u8>> {
+    let saved_at = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let stored = StoredReport {
+        saved_at,
+        report: report.clone(),
+    };
+    let mut data = vec![STORE_VERSION];
+    data.extend(postcard::to_stdvec(&stored).context("encoding report")?);
+    Ok(data)
+}
+
+/// Decodes a report loaded from a [`ReportStore`].
+///
+/// Returns `None` if the data is from a different version, invalid or older than `max_age`.
+pub(super) fn decode(data: &[u8], max_age: Duration, now: SystemTime) -> Option<Report> {
+    let Some((&version, data)) = data.split_first() else {
+        debug!("ignoring stored report: empty");
+        return None;
+    };
+    if version != STORE_VERSION {
+        debug!(version, "ignoring stored report: unknown version");
+        return None;
+    }
+    let stored: StoredReport = match postcard::from_bytes(data) {
+        Ok(stored) => stored,
+        Err(err) => {
+            debug!("ignoring stored report: {err:#}");
+            return None;
+        }
+    };
+    let saved_at = UNIX_EPOCH + Duration::from_secs(stored.saved_at);
+    match now.duration_since(saved_at) {
+        Ok(age) if age <= max_age => Some(stored.report),
+        Ok(age) => {
+            debug!(?age, "ignoring stored report: too old");
+            None
+        }
+        Err(_) => {
+            debug!("ignoring stored report: saved in the future");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_AGE: Duration = Duration::from_secs(60);
+
+    fn report() -> Report {
+        let mut report = Report {
+            udp: true,
+            ipv4: true,
+            global_v4: Some("1.2.3.4:1234".parse().unwrap()),
+            preferred_derp: 1,
+            captive_portal: Some(false),
+            generated_at: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            probe_duration: Duration::from_millis(120),
+            ..Default::default()
+        };
+        report
+            .region_latency
+            .update_region(1, Duration::from_millis(10));
+        report
+            .node_v4_latency
+            .update_node("1a", Duration::from_millis(10));
+        report
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let now = SystemTime::now();
+        let data = encode(&report(), now).unwrap();
+        let decoded = decode(&data, MAX_AGE, now + Duration::from_secs(10));
+        assert_eq!(decoded, Some(report()));
+    }
+
+    #[test]
+    fn test_too_old() {
+        let now = SystemTime::now();
+        let data = encode(&report(), now).unwrap();
+        assert!(decode(&data, MAX_AGE, now + MAX_AGE * 2).is_none());
+    }
+
+    #[test]
+    fn test_other_version() {
+        let now = SystemTime::now();
+        let mut data = encode(&report(), now).unwrap();
+        data[0] = STORE_VERSION + 1;
+        assert!(decode(&data, MAX_AGE, now).is_none());
+    }
+
+    #[test]
+    fn test_garbage() {
+        let now = SystemTime::now();
+        assert!(decode(&[], MAX_AGE, now).is_none());
+        assert!(decode(&[STORE_VERSION, 0xff, 0xff], MAX_AGE, now).is_none());
+    }
+}
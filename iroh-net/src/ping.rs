@@ -1,85 +1,618 @@
 //! Allows sending ICMP echo requests to a host in order to determine network latency.
 
-use std::{fmt::Debug, net::IpAddr, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use anyhow::{Context, Result};
-use surge_ping::{Client, Config, IcmpPacket, PingIdentifier, PingSequence, ICMP};
-use tracing::debug;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use tokio::{net::UdpSocket, sync::oneshot, task::JoinHandle, time::Instant};
+use tracing::{debug, trace, warn};
+
+use crate::net::is_transient_recv_error;
+
+/// The ICMPv4 echo request type.
+const ICMPV4_ECHO_REQUEST: u8 = 8;
+
+/// The ICMPv4 echo reply type.
+const ICMPV4_ECHO_REPLY: u8 = 0;
+
+/// The ICMPv6 echo request type.
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+
+/// The ICMPv6 echo reply type.
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// The ICMPv4 Time Exceeded type.
+const ICMPV4_TIME_EXCEEDED: u8 = 11;
+
+/// The ICMPv6 Time Exceeded type.
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+
+/// The size of the IPv6 header.
+const IPV6_HEADER_SIZE: usize = 40;
+
+/// The size of the ICMP echo header: type, code, checksum, identifier and sequence number.
+const ECHO_HEADER_SIZE: usize = 8;
+
+/// The interval between the echo requests of [`Pinger::send_batch`].
+///
+/// Avoids sending a burst of packets, which may be dropped or rate limited.
+const BATCH_SEND_INTERVAL: Duration = Duration::from_millis(2);
 
 /// Allows sending ICMP echo requests to a host in order to determine network latency.
 /// Will gracefully handle both IPv4 and IPv6.
+///
+/// ICMPv6 echo requests are sent from their own socket, the kernel computes their checksum
+/// including the IPv6 pseudo-header.  A host which can only open an ICMP socket for one
+/// address family can still ping addresses of that family.
+///
+/// Concurrent pings share the socket of their address family.  A task per socket receives
+/// the echo replies and hands each to the ping waiting for it, matched by identifier,
+/// sequence number, source address and payload.
 #[derive(Debug, Clone)]
 pub struct Pinger(Arc<Inner>);
 
-impl Debug for Inner {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Inner").finish()
-    }
+/// The kind of socket a [`Pinger`] sends ICMP echo requests from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcmpMode {
+    /// An unprivileged datagram ICMP socket.
+    ///
+    /// On Linux these are ping sockets, allowed for the groups in
+    /// `net.ipv4.ping_group_range`.  macOS allows them for all users.  Linux ping sockets
+    /// do not receive Time Exceeded errors, see [`PingError::TimeExceeded`].
+    Dgram,
+    /// A raw socket, which usually needs root or `CAP_NET_RAW`.
+    Raw,
+}
+
+/// Errors creating a [`Pinger`].
+#[derive(Debug, thiserror::Error)]
+pub enum PingerError {
+    /// Neither datagram nor raw ICMP sockets can be opened, for either address family.
+    ///
+    /// ICMP probes can not be sent on this host.
+    #[error("ICMP is not supported, IPv4: {v4}, IPv6: {v6}")]
+    Unsupported {
+        /// Why no ICMPv4 socket can be opened.
+        v4: io::Error,
+        /// Why no ICMPv6 socket can be opened.
+        v6: io::Error,
+    },
+}
+
+/// Errors sending a ping, see [`Pinger::send`].
+#[derive(Debug, thiserror::Error)]
+pub enum PingError {
+    /// There is no ICMP socket for the address family of the destination.
+    ///
+    /// See [`Pinger::supports`].
+    #[error("no ICMP socket to ping {0}")]
+    Unsupported(IpAddr),
+    /// The echo request could not be sent.
+    #[error("sending ICMP echo request failed: {0}")]
+    Send(#[from] io::Error),
+    /// No echo reply arrived before the timeout.
+    #[error("no ICMP echo reply before the timeout")]
+    Timeout,
+    /// A router dropped the echo request as its TTL, or IPv6 hop limit, ran out.
+    ///
+    /// Linux ping sockets do not receive these errors, their pings time out instead.  The
+    /// kernel queues them on the error queue of the socket, which is only read with
+    /// `IP_RECVERR` and `MSG_ERRQUEUE`, and the [`Pinger`] does not read it.
+    #[error("ICMP echo request exceeded its TTL at {router}")]
+    TimeExceeded {
+        /// The router which reported the error.
+        router: IpAddr,
+    },
 }
 
+#[derive(Debug)]
 struct Inner {
-    client_v6: Client,
-    client_v4: Client,
+    v4: Option<Family>,
+    v6: Option<Family>,
+}
+
+/// Pings waiting for their echo reply, by identifier and sequence number.
+type Waiters = Arc<Mutex<HashMap<(u16, u16), Waiter>>>;
+
+/// A ping waiting for its echo reply.
+#[derive(Debug)]
+struct Waiter {
+    dst: IpAddr,
+    payload: Vec<u8>,
+    tx: oneshot::Sender<Response>,
+}
+
+/// What answered an echo request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Response {
+    /// The echo reply, arrived at this time.
+    Reply(Instant),
+    /// A Time Exceeded error from this router.
+    TimeExceeded(IpAddr),
+}
+
+/// The ICMP socket of one address family.
+#[derive(Debug)]
+struct Family {
+    mode: IcmpMode,
+    socket: Arc<UdpSocket>,
+    /// The identifier of our echo requests.
+    ///
+    /// Linux ping sockets replace it with their local port, which is used as identifier.
+    ident: u16,
+    /// The next sequence number, making concurrent echo requests distinct.
+    seq: AtomicU16,
+    /// The TTL, or IPv6 hop limit, of pings without their own.
+    default_ttl: u32,
+    /// The TTL the socket currently sends with.
+    ///
+    /// Held while sending, concurrent pings must not change the TTL before a packet is
+    /// sent.
+    ttl: tokio::sync::Mutex<u32>,
+    waiters: Waiters,
+    /// Cleared when receiving on the socket failed fatally, no replies arrive anymore.
+    usable: Arc<AtomicBool>,
+    recv_task: JoinHandle<()>,
+}
+
+impl Drop for Family {
+    fn drop(&mut self) {
+        self.recv_task.abort();
+    }
+}
+
+/// Removes the [`Waiter`] of a ping when dropped, including when the ping is cancelled.
+struct WaiterGuard<'a> {
+    waiters: &'a Waiters,
+    key: (u16, u16),
 }
 
-const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        self.waiters.lock().unwrap().remove(&self.key);
+    }
+}
 
 impl Pinger {
     /// Create a new [Pinger].
-    pub async fn new() -> Result<Self> {
-        let client_v4 = Client::new(&Config::builder().kind(ICMP::V4).build())
-            .context("failed creating IPv4 pinger")?;
-        let client_v6 = Client::new(&Config::builder().kind(ICMP::V6).build())
-            .context("failed creating IPv6 pinger")?;
+    ///
+    /// For each address family an unprivileged datagram ICMP socket is preferred, raw
+    /// sockets are the fallback.  Fails with [`PingerError::Unsupported`] only if neither
+    /// can be opened for both address families.
+    pub async fn new() -> Result<Self, PingerError> {
+        let (v4, v6) = match (Family::open(false), Family::open(true)) {
+            (Err(v4), Err(v6)) => return Err(PingerError::Unsupported { v4, v6 }),
+            (v4, v6) => (
+                v4.map_err(|err| debug!("ICMPv4 unavailable: {}", err)).ok(),
+                v6.map_err(|err| debug!("ICMPv6 unavailable: {}", err)).ok(),
+            ),
+        };
+        Ok(Self(Arc::new(Inner { v4, v6 })))
+    }
 
-        Ok(Self(Arc::new(Inner {
-            client_v4,
-            client_v6,
-        })))
+    /// Returns the kind of socket used to ping addresses of the family of *addr*.
+    ///
+    /// `None` if addresses of this family can not be pinged.
+    pub fn mode(&self, addr: IpAddr) -> Option<IcmpMode> {
+        self.family(addr).map(|family| family.mode)
+    }
+
+    /// Whether ICMP echo requests can be sent to addresses of the family of *addr*.
+    ///
+    /// Becomes `false` if receiving on the ICMP socket of this family failed fatally.
+    pub fn supports(&self, addr: IpAddr) -> bool {
+        self.family(addr).is_some()
+    }
+
+    fn family(&self, addr: IpAddr) -> Option<&Family> {
+        let family = match addr {
+            IpAddr::V4(_) => self.0.v4.as_ref(),
+            IpAddr::V6(_) => self.0.v6.as_ref(),
+        };
+        family.filter(|family| family.usable.load(Ordering::Relaxed))
     }
 
     /// Send a ping request with asociated data, returning the perceived latency.
-    pub async fn send(&self, addr: IpAddr, data: &[u8]) -> Result<Duration> {
-        let client = match addr {
-            IpAddr::V4(_) => &self.0.client_v4,
-            IpAddr::V6(_) => &self.0.client_v6,
-        };
-        let mut pinger = client.pinger(addr, PingIdentifier(rand::random())).await;
-        pinger.timeout(DEFAULT_TIMEOUT);
-        match pinger.ping(PingSequence(0), data).await? {
-            (IcmpPacket::V4(packet), dur) => {
+    ///
+    /// Fails with [`PingError::Timeout`] if the echo reply does not arrive within
+    /// *timeout*.  Only a reply with the same *data* is accepted.
+    pub async fn send(
+        &self,
+        addr: IpAddr,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<Duration, PingError> {
+        self.ping(addr, data, None, timeout).await
+    }
+
+    /// Send a ping request with the IP *ttl*, or the hop limit for IPv6.
+    ///
+    /// Like [`Pinger::send`], but a router where the TTL runs out fails the ping with
+    /// [`PingError::TimeExceeded`].  Increasing the TTL one by one traces the route to
+    /// *addr*.
+    pub async fn send_with_ttl(
+        &self,
+        addr: IpAddr,
+        data: &[u8],
+        ttl: u32,
+        timeout: Duration,
+    ) -> Result<Duration, PingError> {
+        self.ping(addr, data, Some(ttl), timeout).await
+    }
+
+    async fn ping(
+        &self,
+        addr: IpAddr,
+        data: &[u8],
+        ttl: Option<u32>,
+        timeout: Duration,
+    ) -> Result<Duration, PingError> {
+        let family = self.family(addr).ok_or(PingError::Unsupported(addr))?;
+        let seq = family.seq.fetch_add(1, Ordering::Relaxed);
+        let key = (family.ident, seq);
+        let (tx, rx) = oneshot::channel();
+        family.waiters.lock().unwrap().insert(
+            key,
+            Waiter {
+                dst: addr,
+                payload: data.to_vec(),
+                tx,
+            },
+        );
+        let _guard = WaiterGuard {
+            waiters: &family.waiters,
+            key,
+        };
+
+        let pkt = echo_request(addr.is_ipv6(), family.ident, seq, data);
+        let start = Instant::now();
+        family
+            .send(addr, &pkt, ttl.unwrap_or(family.default_ttl))
+            .await?;
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(Response::TimeExceeded(router))) => {
+                debug!(%addr, %router, icmp_seq = seq, "TTL exceeded");
+                Err(PingError::TimeExceeded { router })
+            }
+            Ok(Ok(Response::Reply(received))) => {
+                let latency = received.duration_since(start);
                 debug!(
-                    "{} bytes from {}: icmp_seq={} ttl={:?} time={:0.2?}",
-                    packet.get_size(),
-                    packet.get_source(),
-                    packet.get_sequence(),
-                    packet.get_ttl(),
-                    dur
+                    "{} bytes from {}: icmp_seq={} time={:0.2?}",
+                    data.len(),
+                    addr,
+                    seq,
+                    latency
                 );
-                Ok(dur)
+                Ok(latency)
             }
+            Ok(Err(_)) | Err(_) => Err(PingError::Timeout),
+        }
+    }
 
-            (IcmpPacket::V6(packet), dur) => {
-                debug!(
-                    "{} bytes from {}: icmp_seq={} hlim={} time={:0.2?}",
-                    packet.get_size(),
-                    packet.get_source(),
-                    packet.get_sequence(),
-                    packet.get_max_hop_limit(),
-                    dur
-                );
-                Ok(dur)
+    /// Pings several targets at once, each with its own payload.
+    ///
+    /// The echo requests are sent [`BATCH_SEND_INTERVAL`] apart and each is given the full
+    /// *timeout* after it was sent.  Resolves once all targets answered or timed out, with
+    /// the result of each target.  Of duplicate targets only the last result is kept.
+    ///
+    /// This is meant to survey many targets, to wait only for the first reply use
+    /// [`Pinger::send`] for each target instead.
+    pub async fn send_batch(
+        &self,
+        targets: Vec<(IpAddr, Vec<u8>)>,
+        timeout: Duration,
+    ) -> BTreeMap<IpAddr, Result<Duration, PingError>> {
+        let pings = targets
+            .into_iter()
+            .enumerate()
+            .map(|(i, (addr, payload))| async move {
+                tokio::time::sleep(BATCH_SEND_INTERVAL * i as u32).await;
+                (addr, self.send(addr, &payload, timeout).await)
+            });
+        futures::future::join_all(pings).await.into_iter().collect()
+    }
+}
+
+impl Family {
+    /// Opens the ICMP socket of an address family, preferring datagram over raw sockets.
+    fn open(v6: bool) -> io::Result<Self> {
+        let (domain, protocol, unspecified) = if v6 {
+            (
+                Domain::IPV6,
+                Protocol::ICMPV6,
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            )
+        } else {
+            (
+                Domain::IPV4,
+                Protocol::ICMPV4,
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            )
+        };
+        let (mode, socket) = match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+            Ok(socket) => (IcmpMode::Dgram, socket),
+            Err(dgram_err) => match Socket::new(domain, Type::RAW, Some(protocol)) {
+                Ok(socket) => (IcmpMode::Raw, socket),
+                Err(raw_err) => {
+                    return Err(io::Error::new(
+                        raw_err.kind(),
+                        format!("datagram socket: {dgram_err}, raw socket: {raw_err}"),
+                    ))
+                }
+            },
+        };
+        let mut ident = rand::random();
+        if mode == IcmpMode::Dgram {
+            socket.bind(&SocketAddr::new(unspecified, 0).into())?;
+            if let Some(port) = socket.local_addr()?.as_socket().map(|addr| addr.port()) {
+                if port != 0 {
+                    ident = port;
+                }
+            }
+        }
+        let default_ttl = if v6 {
+            socket.unicast_hops_v6()?
+        } else {
+            socket.ttl()?
+        };
+        socket.set_nonblocking(true)?;
+        let socket = Arc::new(UdpSocket::from_std(socket.into())?);
+        let waiters = Waiters::default();
+        let usable = Arc::new(AtomicBool::new(true));
+        let recv_task = tokio::spawn(recv_replies(
+            socket.clone(),
+            v6,
+            waiters.clone(),
+            usable.clone(),
+        ));
+        debug!(v6, ?mode, "opened ICMP socket");
+        Ok(Self {
+            mode,
+            socket,
+            ident,
+            seq: AtomicU16::new(0),
+            default_ttl,
+            ttl: tokio::sync::Mutex::new(default_ttl),
+            waiters,
+            usable,
+            recv_task,
+        })
+    }
+
+    /// Sends *pkt* to *dst* with the IP *ttl*, or the hop limit for IPv6.
+    async fn send(&self, dst: IpAddr, pkt: &[u8], ttl: u32) -> io::Result<()> {
+        let mut current = self.ttl.lock().await;
+        if *current != ttl {
+            let sock = SockRef::from(&*self.socket);
+            match dst {
+                IpAddr::V4(_) => sock.set_ttl(ttl)?,
+                IpAddr::V6(_) => sock.set_unicast_hops_v6(ttl)?,
             }
+            *current = ttl;
+        }
+        self.socket.send_to(pkt, SocketAddr::new(dst, 0)).await?;
+        Ok(())
+    }
+}
+
+/// Receives echo replies and Time Exceeded errors on *socket* and hands them to their
+/// [`Waiter`].
+///
+/// Transient receive errors are skipped.  On any other error *usable* is cleared and the
+/// waiting pings fail, as no more replies can be received.
+async fn recv_replies(socket: Arc<UdpSocket>, v6: bool, waiters: Waiters, usable: Arc<AtomicBool>) {
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let (n, src) = match socket.recv_from(&mut buf).await {
+            Ok(res) => res,
+            Err(err) if is_transient_recv_error(&err) => {
+                debug!(v6, "receiving ICMP packet failed, ignoring: {:?}", err);
+                continue;
+            }
+            Err(err) => {
+                warn!(v6, "receiving ICMP packets failed: {:?}", err);
+                usable.store(false, Ordering::Relaxed);
+                waiters.lock().unwrap().clear();
+                return;
+            }
+        };
+        let received = Instant::now();
+        if let Some(reply) = parse_echo_reply(v6, &buf[..n]) {
+            dispatch(&waiters, src.ip(), reply, received);
+        } else if let Some(exceeded) = parse_time_exceeded(v6, &buf[..n]) {
+            dispatch_time_exceeded(&waiters, src.ip(), exceeded);
         }
     }
 }
 
+/// Hands an echo *reply* from *src* to the ping waiting for it, if any.
+fn dispatch(waiters: &Waiters, src: IpAddr, reply: EchoReply<'_>, received: Instant) {
+    let key = (reply.ident, reply.seq);
+    complete(
+        waiters,
+        key,
+        |waiter| waiter.dst == src && waiter.payload == reply.payload,
+        Response::Reply(received),
+    );
+}
+
+/// Hands a Time Exceeded error from *router* to the ping which caused it, if any.
+///
+/// The error only quotes the start of the echo request, its payload can not be checked.
+fn dispatch_time_exceeded(waiters: &Waiters, router: IpAddr, exceeded: TimeExceeded) {
+    let key = (exceeded.ident, exceeded.seq);
+    complete(
+        waiters,
+        key,
+        |waiter| waiter.dst == exceeded.dst,
+        Response::TimeExceeded(router),
+    );
+}
+
+/// Sends the *response* to the waiter of *key*, if it *matches*.
+fn complete(
+    waiters: &Waiters,
+    key: (u16, u16),
+    matches: impl FnOnce(&Waiter) -> bool,
+    response: Response,
+) {
+    let mut waiters = waiters.lock().unwrap();
+    match waiters.get(&key) {
+        Some(waiter) if matches(waiter) => {
+            if let Some(waiter) = waiters.remove(&key) {
+                waiter.tx.send(response).ok();
+            }
+        }
+        _ => trace!(?key, ?response, "ignoring unexpected ICMP packet"),
+    }
+}
+
+/// Builds an ICMP echo request.
+///
+/// The ICMPv6 checksum covers the IPv6 pseudo-header and is filled in by the kernel.
+fn echo_request(v6: bool, ident: u16, seq: u16, payload: &[u8]) -> Vec<u8> {
+    let mut pkt = Vec::with_capacity(ECHO_HEADER_SIZE + payload.len());
+    pkt.push(if v6 {
+        ICMPV6_ECHO_REQUEST
+    } else {
+        ICMPV4_ECHO_REQUEST
+    });
+    pkt.extend_from_slice(&[0, 0, 0]); // code and checksum
+    pkt.extend_from_slice(&ident.to_be_bytes());
+    pkt.extend_from_slice(&seq.to_be_bytes());
+    pkt.extend_from_slice(payload);
+    if !v6 {
+        let checksum = checksum(&pkt);
+        pkt[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    pkt
+}
+
+/// An ICMP echo reply, see [`parse_echo_reply`].
+#[derive(Debug, PartialEq, Eq)]
+struct EchoReply<'a> {
+    ident: u16,
+    seq: u16,
+    payload: &'a [u8],
+}
+
+/// Parses an ICMP echo reply, returns `None` for any other packet.
+fn parse_echo_reply(v6: bool, b: &[u8]) -> Option<EchoReply<'_>> {
+    let b = icmp_message(v6, b)?;
+    let reply_type = if v6 {
+        ICMPV6_ECHO_REPLY
+    } else {
+        ICMPV4_ECHO_REPLY
+    };
+    if b.len() < ECHO_HEADER_SIZE || b[0] != reply_type || b[1] != 0 {
+        return None;
+    }
+    Some(EchoReply {
+        ident: u16::from_be_bytes([b[4], b[5]]),
+        seq: u16::from_be_bytes([b[6], b[7]]),
+        payload: &b[ECHO_HEADER_SIZE..],
+    })
+}
+
+/// A Time Exceeded error caused by one of our echo requests, see [`parse_time_exceeded`].
+#[derive(Debug, PartialEq, Eq)]
+struct TimeExceeded {
+    /// The destination of the echo request.
+    dst: IpAddr,
+    ident: u16,
+    seq: u16,
+}
+
+/// Parses an ICMP Time Exceeded error quoting an echo request.
+///
+/// Returns `None` for any other packet, including errors caused by other packets.
+fn parse_time_exceeded(v6: bool, b: &[u8]) -> Option<TimeExceeded> {
+    let b = icmp_message(v6, b)?;
+    let exceeded_type = if v6 {
+        ICMPV6_TIME_EXCEEDED
+    } else {
+        ICMPV4_TIME_EXCEEDED
+    };
+    if b.len() < ECHO_HEADER_SIZE || b[0] != exceeded_type || b[1] != 0 {
+        return None;
+    }
+    // The ICMP header is followed by the start of the packet which exceeded its TTL.
+    let quoted = &b[ECHO_HEADER_SIZE..];
+    let (dst, request) = if v6 {
+        // The next header must be ICMPv6, echo requests have no extension headers.
+        if quoted.len() < IPV6_HEADER_SIZE || quoted[6] != 58 {
+            return None;
+        }
+        let dst: [u8; 16] = quoted[24..40].try_into().ok()?;
+        (IpAddr::from(dst), &quoted[IPV6_HEADER_SIZE..])
+    } else {
+        let header_len = usize::from(*quoted.first()? & 0x0f) * 4;
+        if header_len < 20 || quoted.len() < header_len || quoted[9] != 1 {
+            return None;
+        }
+        let dst: [u8; 4] = quoted[16..20].try_into().ok()?;
+        (IpAddr::from(dst), &quoted[header_len..])
+    };
+    let request_type = if v6 {
+        ICMPV6_ECHO_REQUEST
+    } else {
+        ICMPV4_ECHO_REQUEST
+    };
+    if request.len() < ECHO_HEADER_SIZE || request[0] != request_type {
+        return None;
+    }
+    Some(TimeExceeded {
+        dst,
+        ident: u16::from_be_bytes([request[4], request[5]]),
+        seq: u16::from_be_bytes([request[6], request[7]]),
+    })
+}
+
+/// Returns the ICMP message of a received packet.
+///
+/// Raw IPv4 sockets, and datagram sockets on macOS, include the IPv4 header.
+fn icmp_message(v6: bool, b: &[u8]) -> Option<&[u8]> {
+    if !v6 && b.first()? >> 4 == 4 {
+        b.get(usize::from(b[0] & 0x0f) * 4..)
+    } else {
+        Some(b)
+    }
+}
+
+/// Computes the internet checksum of RFC 1071.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|chunk| match *chunk {
+            [hi, lo] => u32::from(u16::from_be_bytes([hi, lo])),
+            [hi] => u32::from(hi) << 8,
+            _ => unreachable!("chunks of two"),
+        })
+        .sum::<u32>();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use anyhow::Result;
     use tracing_subscriber::{prelude::*, EnvFilter};
 
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
     #[tokio::test]
     #[ignore] // Doesn't work in CI
     async fn test_ping_google() -> Result<()> {
@@ -95,12 +628,18 @@ mod tests {
         let pinger = Pinger::new().await?;
 
         // IPv4
-        let dur = pinger.send("8.8.8.8".parse()?, &[1u8; 8]).await?;
+        let dur = pinger
+            .send("8.8.8.8".parse()?, &[1u8; 8], DEFAULT_TIMEOUT)
+            .await?;
         assert!(!dur.is_zero());
 
         // IPv6
         match pinger
-            .send("2001:4860:4860:0:0:0:0:8888".parse()?, &[1u8; 8])
+            .send(
+                "2001:4860:4860:0:0:0:0:8888".parse()?,
+                &[1u8; 8],
+                DEFAULT_TIMEOUT,
+            )
             .await
         {
             Ok(dur) => {
@@ -113,4 +652,246 @@ mod tests {
 
         Ok(())
     }
+
+    /// Creates a pinger, `None` if this environment does not permit ICMP sockets.
+    async fn local_pinger() -> Option<Pinger> {
+        match Pinger::new().await {
+            Ok(pinger) => Some(pinger),
+            Err(err) => {
+                tracing::warn!("skipping, no ICMP sockets: {:#}", err);
+                None
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping_localhost() -> Result<()> {
+        let _guard = crate::test_utils::setup_logging();
+        let Some(pinger) = local_pinger().await else {
+            return Ok(());
+        };
+        for addr in ["127.0.0.1", "::1"] {
+            let addr: IpAddr = addr.parse()?;
+            let Some(mode) = pinger.mode(addr) else {
+                tracing::warn!(%addr, "skipping, no ICMP socket");
+                continue;
+            };
+            tracing::info!(%addr, ?mode, "pinging");
+            match pinger.send(addr, b"iroh ping", DEFAULT_TIMEOUT).await {
+                Ok(dur) => assert!(dur < DEFAULT_TIMEOUT),
+                // E.g. a container without a loopback IPv6 address.
+                Err(err) => tracing::warn!(%addr, "skipping, ping failed: {:#}", err),
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_pings() -> Result<()> {
+        let _guard = crate::test_utils::setup_logging();
+        let Some(pinger) = local_pinger().await else {
+            return Ok(());
+        };
+        let addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        if !pinger.supports(addr) {
+            return Ok(());
+        }
+        // Each ping only accepts the reply carrying its own payload.
+        let payloads: Vec<_> = (0u8..8).map(|i| vec![i; 16]).collect();
+        let pings = payloads
+            .iter()
+            .map(|payload| pinger.send(addr, payload, DEFAULT_TIMEOUT));
+        for res in futures::future::join_all(pings).await {
+            assert!(res.is_ok(), "{res:?}");
+        }
+        assert!(pinger
+            .0
+            .v4
+            .as_ref()
+            .unwrap()
+            .waiters
+            .lock()
+            .unwrap()
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_batch() -> Result<()> {
+        let _guard = crate::test_utils::setup_logging();
+        let Some(pinger) = local_pinger().await else {
+            return Ok(());
+        };
+        let v4: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let v6: IpAddr = Ipv6Addr::LOCALHOST.into();
+        let targets = vec![(v4, b"batch v4".to_vec()), (v6, b"batch v6".to_vec())];
+        let results = pinger.send_batch(targets, DEFAULT_TIMEOUT).await;
+        assert_eq!(results.len(), 2);
+        if pinger.supports(v4) {
+            assert!(results[&v4].is_ok(), "{:?}", results[&v4]);
+        }
+        if !pinger.supports(v6) {
+            assert!(matches!(results[&v6], Err(PingError::Unsupported(_))));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_ping_cleans_up() -> Result<()> {
+        let Some(pinger) = local_pinger().await else {
+            return Ok(());
+        };
+        let addr: IpAddr = Ipv4Addr::LOCALHOST.into();
+        if !pinger.supports(addr) {
+            return Ok(());
+        }
+        let ping = pinger.send(addr, b"cancelled", DEFAULT_TIMEOUT);
+        tokio::time::timeout(Duration::ZERO, ping).await.ok();
+        assert!(pinger
+            .0
+            .v4
+            .as_ref()
+            .unwrap()
+            .waiters
+            .lock()
+            .unwrap()
+            .is_empty());
+
+        let res = pinger.send(addr, b"timeout", Duration::ZERO).await;
+        assert!(matches!(res, Err(PingError::Timeout)));
+        assert!(pinger
+            .0
+            .v4
+            .as_ref()
+            .unwrap()
+            .waiters
+            .lock()
+            .unwrap()
+            .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let waiters = Waiters::default();
+        let dst: IpAddr = "192.0.2.1".parse().unwrap();
+        let register = |seq, payload: &[u8]| {
+            let (tx, rx) = oneshot::channel();
+            let waiter = Waiter {
+                dst,
+                payload: payload.to_vec(),
+                tx,
+            };
+            waiters.lock().unwrap().insert((7, seq), waiter);
+            rx
+        };
+        let mut first = register(1, b"first");
+        let mut second = register(2, b"second");
+        let now = Instant::now();
+        let reply = |seq, payload: &'static [u8]| EchoReply {
+            ident: 7,
+            seq,
+            payload,
+        };
+
+        // Replies with the wrong payload, source or sequence number are ignored.
+        dispatch(&waiters, dst, reply(1, b"second"), now);
+        dispatch(
+            &waiters,
+            "192.0.2.2".parse().unwrap(),
+            reply(1, b"first"),
+            now,
+        );
+        dispatch(&waiters, dst, reply(3, b"first"), now);
+        assert!(first.try_recv().is_err());
+        assert!(second.try_recv().is_err());
+
+        dispatch(&waiters, dst, reply(2, b"second"), now);
+        assert!(first.try_recv().is_err());
+        assert_eq!(second.try_recv().unwrap(), Response::Reply(now));
+        assert_eq!(waiters.lock().unwrap().len(), 1);
+
+        // Time Exceeded errors are matched by the destination of the request.
+        let router: IpAddr = "198.51.100.1".parse().unwrap();
+        let exceeded = |dst| TimeExceeded {
+            dst,
+            ident: 7,
+            seq: 1,
+        };
+        dispatch_time_exceeded(&waiters, router, exceeded(router));
+        assert!(first.try_recv().is_err());
+        dispatch_time_exceeded(&waiters, router, exceeded(dst));
+        assert_eq!(first.try_recv().unwrap(), Response::TimeExceeded(router));
+        assert!(waiters.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_time_exceeded() {
+        let request = echo_request(false, 0x1234, 5, b"payload");
+        let mut pkt = vec![ICMPV4_TIME_EXCEEDED, 0, 0, 0, 0, 0, 0, 0];
+        // The quoted IPv4 header of the echo request to 192.0.2.1.
+        pkt.extend_from_slice(&[0x45, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0]);
+        pkt.extend_from_slice(&[198, 51, 100, 7, 192, 0, 2, 1]);
+        pkt.extend_from_slice(&request[..ECHO_HEADER_SIZE]);
+        let want = TimeExceeded {
+            dst: "192.0.2.1".parse().unwrap(),
+            ident: 0x1234,
+            seq: 5,
+        };
+        assert_eq!(parse_time_exceeded(false, &pkt), Some(want));
+        // An error caused by another protocol, here UDP.
+        pkt[8 + 9] = 17;
+        assert_eq!(parse_time_exceeded(false, &pkt), None);
+
+        let request = echo_request(true, 0x1234, 5, b"payload");
+        let mut pkt = vec![ICMPV6_TIME_EXCEEDED, 0, 0, 0, 0, 0, 0, 0];
+        let dst: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        pkt.extend_from_slice(&[0x60, 0, 0, 0, 0, 0, 58, 1]);
+        pkt.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        pkt.extend_from_slice(&dst.octets());
+        pkt.extend_from_slice(&request);
+        let want = TimeExceeded {
+            dst: dst.into(),
+            ident: 0x1234,
+            seq: 5,
+        };
+        assert_eq!(parse_time_exceeded(true, &pkt), Some(want));
+        assert_eq!(parse_echo_reply(true, &pkt), None);
+    }
+
+    #[test]
+    fn test_echo_packets() {
+        let req = echo_request(false, 0x1234, 5, b"payload");
+        assert_eq!(req[0], ICMPV4_ECHO_REQUEST);
+        // A packet including its checksum sums up to zero.
+        assert_eq!(checksum(&req), 0);
+        // Requests are not replies.
+        assert_eq!(parse_echo_reply(false, &req), None);
+
+        let mut reply = req.clone();
+        reply[0] = ICMPV4_ECHO_REPLY;
+        let want = EchoReply {
+            ident: 0x1234,
+            seq: 5,
+            payload: b"payload",
+        };
+        assert_eq!(parse_echo_reply(false, &reply), Some(want));
+
+        // With the IPv4 header of a raw socket.
+        let mut with_header = vec![0x45];
+        with_header.extend_from_slice(&[0; 19]);
+        with_header.extend_from_slice(&reply);
+        let want = EchoReply {
+            ident: 0x1234,
+            seq: 5,
+            payload: b"payload",
+        };
+        assert_eq!(parse_echo_reply(false, &with_header), Some(want));
+
+        let req = echo_request(true, 0x1234, 5, b"payload");
+        assert_eq!(req[0], ICMPV6_ECHO_REQUEST);
+        let mut reply = req;
+        reply[0] = ICMPV6_ECHO_REPLY;
+        assert!(parse_echo_reply(true, &reply).is_some());
+    }
 }
@@ -38,7 +38,9 @@ const SERVICE_CHANNEL_CAPACITY: usize = 32; // should be plenty
 const UNAVAILABILITY_TRUST_DURATION: Duration = Duration::from_secs(5);
 
 /// Output of a port mapping probe.
-#[derive(Debug, Clone, PartialEq, Eq, derive_more::Display)]
+#[derive(
+    Debug, Clone, PartialEq, Eq, derive_more::Display, serde::Serialize, serde::Deserialize,
+)]
 #[display("portmap={{ UPnP: {upnp}, PMP: {nat_pmp}, PCP: {pcp} }}")]
 pub struct ProbeOutput {
     /// If UPnP can be considered available.
@@ -213,8 +215,8 @@ struct Probe {
     last_probe: Instant,
     /// The last [`igd::aio::Gateway`] and when was it last seen.
     last_upnp_gateway_addr: Option<(upnp::Gateway, Instant)>,
-    /// Last time PCP was seen.
-    last_pcp: Option<Instant>,
+    /// Last epoch announced by a PCP server, which includes when it was seen.
+    last_pcp: Option<pcp::Epoch>,
     /// Last time NAT-PMP was seen.
     last_nat_pmp: Option<Instant>,
 }
@@ -258,9 +260,7 @@ impl Probe {
             inner: (enable_pcp && !pcp).then(|| {
                 Box::pin(async {
                     inc!(Metrics, pcp_probes);
-                    pcp::probe_available(local_ip, gateway)
-                        .await
-                        .then(Instant::now)
+                    pcp::probe_available(local_ip, gateway).await
                 })
             }),
         };
@@ -322,7 +322,7 @@ impl Probe {
         let pcp = self
             .last_pcp
             .as_ref()
-            .map(|last_probed| *last_probed + AVAILABILITY_TRUST_DURATION > now)
+            .map(|epoch| epoch.received_at() + AVAILABILITY_TRUST_DURATION > now)
             .unwrap_or_default();
 
         let nat_pmp = self
@@ -494,7 +494,11 @@ impl Service {
         let result = match result {
             Err(e) => Err(e.to_string()),
             Ok(probe) => {
+                let pcp_epoch = probe.last_pcp;
                 self.full_probe.update(probe);
+                if let Some(epoch) = pcp_epoch {
+                    self.on_pcp_epoch(epoch);
+                }
                 // TODO(@divma): the gateway of the current mapping could have changed. Tailscale
                 // still assumes the current mapping is valid/active and will return it even after
                 // this
@@ -512,13 +516,69 @@ impl Service {
     async fn on_mapping_result(&mut self, result: Result<mapping::Mapping>) {
         match result {
             Ok(mapping) => {
+                if let mapping::Mapping::Pcp(ref mapping) = mapping {
+                    self.on_pcp_map_epoch(*mapping.epoch());
+                }
                 self.current_mapping.update(Some(mapping));
             }
             Err(e) => {
                 debug!("failed to get a port mapping {e}");
                 inc!(Metrics, mapping_failures);
+                match e.downcast_ref::<pcp::Error>() {
+                    Some(pcp::Error::NoResources) => {
+                        inc!(Metrics, pcp_no_resources);
+                    }
+                    Some(pcp::Error::NotAuthorized) => {
+                        inc!(Metrics, pcp_not_authorized);
+                        // the server will keep refusing, allow falling back to other protocols
+                        // until PCP is probed again
+                        self.full_probe.last_pcp = None;
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+
+    /// Checks a newly observed PCP server epoch against the current mapping.
+    ///
+    /// If the server lost its state, for example because the NAT rebooted, the mapping is gone
+    /// and a new one is requested.
+    fn on_pcp_epoch(&mut self, epoch: pcp::Epoch) {
+        let Some(mapping::Mapping::Pcp(mapping)) = self.current_mapping.mapping() else {
+            return;
+        };
+        if epoch.is_continuation_of(mapping.epoch()) {
+            return;
+        }
+        debug!("PCP server lost its state, renewing the mapping");
+        inc!(Metrics, pcp_epoch_resets);
+        let external_addr = self.current_mapping.external();
+        // the server no longer knows the mapping, there is nothing to release
+        self.current_mapping.update(None);
+        // a mapping in progress is created in the new epoch, its result is checked on arrival
+        if self.mapping_task.is_none() {
+            self.get_mapping(external_addr);
+        }
+    }
+
+    /// Checks the epoch of a PCP MAP response, including renewals, against the last one seen.
+    ///
+    /// The mapping of the response was created in the server's current epoch and replaces the
+    /// current one, a reset only needs to be noted.  The epoch becomes the one later responses
+    /// and probes are compared against.
+    fn on_pcp_map_epoch(&mut self, epoch: pcp::Epoch) {
+        let previous = match self.current_mapping.mapping() {
+            Some(mapping::Mapping::Pcp(mapping)) => Some(*mapping.epoch()),
+            _ => self.full_probe.last_pcp,
+        };
+        if let Some(previous) = previous {
+            if !epoch.is_continuation_of(&previous) {
+                debug!("PCP server lost its state since the last MAP response");
+                inc!(Metrics, pcp_epoch_resets);
             }
         }
+        self.full_probe.last_pcp = Some(epoch);
     }
 
     async fn handle_msg(&mut self, msg: Message) {
@@ -690,3 +750,87 @@ fn ip_and_gateway() -> Result<(Ipv4Addr, Ipv4Addr)> {
 
     Ok((local_ip, gateway))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_service() -> Service {
+        let (_tx, rx) = mpsc::channel(1);
+        Service::new(Config::default(), rx).0
+    }
+
+    fn pcp_mapping(epoch: pcp::Epoch) -> mapping::Mapping {
+        mapping::Mapping::Pcp(pcp::Mapping::with_epoch(epoch))
+    }
+
+    #[tokio::test]
+    async fn test_pcp_epoch_reset() {
+        let start = Instant::now();
+        let mut service = test_service();
+        service
+            .current_mapping
+            .update(Some(pcp_mapping(pcp::Epoch::at(1000, start))));
+
+        // the server's epoch advanced with ours, the mapping is kept
+        service.on_pcp_epoch(pcp::Epoch::at(1600, start + Duration::from_secs(600)));
+        assert!(service.current_mapping.mapping().is_some());
+
+        // the server rebooted, the mapping is gone
+        service.on_pcp_epoch(pcp::Epoch::at(30, start + Duration::from_secs(600)));
+        assert!(service.current_mapping.mapping().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_pcp_epoch_reset_keeps_mapping_task() {
+        let start = Instant::now();
+        let mut service = test_service();
+        service.local_port = NonZeroU16::new(9586);
+        service
+            .current_mapping
+            .update(Some(pcp_mapping(pcp::Epoch::at(1000, start))));
+        let task = tokio::spawn(async { Err(anyhow!("in flight")) });
+        service.mapping_task = Some(task.into());
+
+        service.on_pcp_epoch(pcp::Epoch::at(30, start + Duration::from_secs(600)));
+        assert!(service.current_mapping.mapping().is_none());
+        let res = service.mapping_task.take().unwrap().await.unwrap();
+        assert_eq!(res.unwrap_err().to_string(), "in flight");
+    }
+
+    #[tokio::test]
+    async fn test_pcp_map_response_epoch() {
+        let start = Instant::now();
+        let mut service = test_service();
+        service
+            .on_mapping_result(Ok(pcp_mapping(pcp::Epoch::at(1000, start))))
+            .await;
+        assert_eq!(
+            service.full_probe.last_pcp,
+            Some(pcp::Epoch::at(1000, start))
+        );
+
+        // a renewal after a reboot replaces the mapping and the epoch to compare against
+        let rebooted = pcp::Epoch::at(30, start + Duration::from_secs(600));
+        service.on_mapping_result(Ok(pcp_mapping(rebooted))).await;
+        assert_eq!(service.full_probe.last_pcp, Some(rebooted));
+        match service.current_mapping.mapping() {
+            Some(mapping::Mapping::Pcp(mapping)) => assert_eq!(*mapping.epoch(), rebooted),
+            other => panic!("unexpected mapping {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pcp_not_authorized() {
+        let mut service = test_service();
+        service.full_probe.last_pcp = Some(pcp::Epoch::at(1000, Instant::now()));
+        assert!(service.full_probe.output().pcp);
+
+        service
+            .on_mapping_result(Err(pcp::Error::NotAuthorized.into()))
+            .await;
+        assert_eq!(service.full_probe.last_pcp, None);
+        assert!(!service.full_probe.output().pcp);
+        assert!(service.current_mapping.mapping().is_none());
+    }
+}
@@ -157,6 +157,11 @@ impl<M: Mapping> CurrentMapping<M> {
         Poll::Pending
     }
 
+    /// Returns the active mapping, if any.
+    pub(super) fn mapping(&self) -> Option<&M> {
+        self.mapping.as_ref().map(|mapping| &mapping.mapping)
+    }
+
     pub(crate) fn external(&self) -> Option<(Ipv4Addr, NonZeroU16)> {
         self.mapping
             .as_ref()
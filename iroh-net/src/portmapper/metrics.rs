@@ -29,6 +29,9 @@ pub struct Metrics {
      */
     pub pcp_probes: Counter,
     pub pcp_available: Counter,
+    pub pcp_no_resources: Counter,
+    pub pcp_not_authorized: Counter,
+    pub pcp_epoch_resets: Counter,
 }
 
 impl Default for Metrics {
@@ -57,6 +60,15 @@ impl Default for Metrics {
              */
             pcp_probes: Counter::new("Number of PCP probes executed."),
             pcp_available: Counter::new("Number of PCP probes that found it available."),
+            pcp_no_resources: Counter::new(
+                "Number of PCP mappings refused because the server had no resources.",
+            ),
+            pcp_not_authorized: Counter::new(
+                "Number of PCP mappings refused because they were not authorized.",
+            ),
+            pcp_epoch_resets: Counter::new(
+                "Number of times a PCP server was detected to have lost its state.",
+            ),
         }
     }
 }
@@ -1,6 +1,10 @@
 //! Definitions and utilities to interact with a PCP server.
 
-use std::{net::Ipv4Addr, num::NonZeroU16, time::Duration};
+use std::{
+    net::Ipv4Addr,
+    num::NonZeroU16,
+    time::{Duration, Instant},
+};
 
 use rand::RngCore;
 use tracing::{debug, trace};
@@ -14,6 +18,65 @@ const RECV_TIMEOUT: Duration = Duration::from_millis(500);
 /// <https://datatracker.ietf.org/doc/html/rfc6886#section-3.3>
 const MAPPING_REQUESTED_LIFETIME_SECONDS: u32 = 60 * 60;
 
+/// Errors reported by a PCP server that are handled distinctly by the port mapping service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum Error {
+    /// The server does not have the resources to create the mapping at this time.
+    ///
+    /// This is a short lifetime error, the request can be retried later.
+    #[error("PCP server has no resources for the mapping")]
+    NoResources,
+    /// The server's policy does not allow this client to create mappings.
+    ///
+    /// This is a long lifetime error, retrying will not help.
+    #[error("PCP server did not authorize the mapping")]
+    NotAuthorized,
+}
+
+/// The epoch of a PCP server, as observed by this client.
+///
+/// A server that loses its state, for example after a reboot, resets its epoch. Comparing two
+/// observations allows to detect this and recreate any mapping. See
+/// [RFC 6887 Epoch](https://datatracker.ietf.org/doc/html/rfc6887#section-8.5)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Epoch {
+    /// Epoch time reported by the server, in seconds.
+    server_time: u32,
+    /// When the server's epoch was received.
+    received_at: Instant,
+}
+
+impl Epoch {
+    fn new(server_time: u32) -> Self {
+        Epoch {
+            server_time,
+            received_at: Instant::now(),
+        }
+    }
+
+    /// When the server's epoch was received.
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+
+    /// Checks whether the server kept its state between a `previous` observation and this one.
+    pub fn is_continuation_of(&self, previous: &Epoch) -> bool {
+        // the server's clock going back more than a rounding error is a reset
+        if self.server_time.saturating_add(1) < previous.server_time {
+            return false;
+        }
+        // both clocks must advance at roughly the same rate, with a 1/16 tolerance plus 2
+        // seconds for rounding and transmission
+        let server_delta = u64::from(self.server_time.saturating_sub(previous.server_time));
+        let client_delta = self
+            .received_at
+            .saturating_duration_since(previous.received_at)
+            .as_secs();
+        client_delta + 2 >= server_delta - server_delta / 16
+            && server_delta + 2 >= client_delta - client_delta / 16
+    }
+}
+
 /// A mapping sucessfully registered with a PCP server.
 #[derive(Debug)]
 pub struct Mapping {
@@ -32,6 +95,8 @@ pub struct Mapping {
     /// The nonce of the mapping, used for modifications with the PCP server, for example releasing
     /// the mapping.
     nonce: [u8; 12],
+    /// Epoch of the server when the mapping was created.
+    epoch: Epoch,
 }
 
 impl super::mapping::PortMapped for Mapping {
@@ -78,12 +143,21 @@ impl Mapping {
         // wait for the response and decode it
         let mut buffer = vec![0; protocol::Response::MAX_SIZE];
         let read = tokio::time::timeout(RECV_TIMEOUT, socket.recv(&mut buffer)).await??;
-        let response = protocol::Response::decode(&buffer[..read])?;
+        let response = match protocol::Response::decode(&buffer[..read]) {
+            Ok(response) => response,
+            Err(protocol::Error::ErrorCode(protocol::ErrorCode::NoResources)) => {
+                return Err(Error::NoResources.into())
+            }
+            Err(protocol::Error::ErrorCode(protocol::ErrorCode::NotAuthorized)) => {
+                return Err(Error::NotAuthorized.into())
+            }
+            Err(e) => return Err(e.into()),
+        };
 
         // verify that the response is correct and matches the request
         let protocol::Response {
             lifetime_seconds,
-            epoch_time: _,
+            epoch_time,
             data,
         } = response;
 
@@ -125,6 +199,7 @@ impl Mapping {
                     local_ip,
                     local_port,
                     gateway,
+                    epoch: Epoch::new(epoch_time),
                 })
             }
             protocol::OpcodeData::Announce => {
@@ -133,6 +208,11 @@ impl Mapping {
         }
     }
 
+    /// Epoch of the server when the mapping was created.
+    pub fn epoch(&self) -> &Epoch {
+        &self.epoch
+    }
+
     pub async fn release(self) -> anyhow::Result<()> {
         let Mapping {
             nonce,
@@ -157,27 +237,29 @@ impl Mapping {
 }
 
 /// Probes the local gateway for PCP support.
-pub async fn probe_available(local_ip: Ipv4Addr, gateway: Ipv4Addr) -> bool {
+///
+/// If PCP is available, returns the epoch announced by the server.
+pub async fn probe_available(local_ip: Ipv4Addr, gateway: Ipv4Addr) -> Option<Epoch> {
     match probe_available_fallible(local_ip, gateway).await {
         Ok(response) => {
             trace!("probe response: {response:?}");
             let protocol::Response {
                 lifetime_seconds: _,
-                epoch_time: _,
+                epoch_time,
                 data,
             } = response;
             match data {
-                protocol::OpcodeData::Announce => true,
+                protocol::OpcodeData::Announce => Some(Epoch::new(epoch_time)),
                 _ => {
                     debug!("server returned an unexpected response type for probe");
                     // missbehaving server is not useful
-                    false
+                    None
                 }
             }
         }
         Err(e) => {
             debug!("probe failed: {e}");
-            false
+            None
         }
     }
 }
@@ -199,3 +281,70 @@ async fn probe_available_fallible(
 
     Ok(response)
 }
+
+#[cfg(test)]
+impl Epoch {
+    /// Creates an epoch with a given server time, received at *received_at*.
+    pub(crate) fn at(server_time: u32, received_at: Instant) -> Self {
+        Epoch {
+            server_time,
+            received_at,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Mapping {
+    /// Creates a mapping as if it was registered in the server's *epoch*.
+    pub(crate) fn with_epoch(epoch: Epoch) -> Self {
+        Mapping {
+            local_ip: Ipv4Addr::new(192, 168, 1, 2),
+            local_port: NonZeroU16::new(9586).expect("non zero"),
+            gateway: Ipv4Addr::new(192, 168, 1, 1),
+            external_port: NonZeroU16::new(9586).expect("non zero"),
+            external_address: Ipv4Addr::new(203, 0, 113, 1),
+            lifetime_seconds: 7200,
+            nonce: [0; 12],
+            epoch,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn epoch_at(server_time: u32, received_at: Instant) -> Epoch {
+        Epoch::at(server_time, received_at)
+    }
+
+    #[test]
+    fn test_epoch_continuation() {
+        let start = Instant::now();
+        let previous = epoch_at(1000, start);
+
+        // both clocks advanced at the same rate
+        let later = epoch_at(1600, start + Duration::from_secs(600));
+        assert!(later.is_continuation_of(&previous));
+
+        // small drift and rounding are tolerated
+        let drifted = epoch_at(1620, start + Duration::from_secs(600));
+        assert!(drifted.is_continuation_of(&previous));
+        let rounded = epoch_at(999, start);
+        assert!(rounded.is_continuation_of(&previous));
+    }
+
+    #[test]
+    fn test_epoch_reset() {
+        let start = Instant::now();
+        let previous = epoch_at(1000, start);
+
+        // the server restarted its epoch
+        let rebooted = epoch_at(30, start + Duration::from_secs(600));
+        assert!(!rebooted.is_continuation_of(&previous));
+
+        // the server's clock did not advance with ours, it must have lost state in between
+        let stalled = epoch_at(1100, start + Duration::from_secs(600));
+        assert!(!stalled.is_continuation_of(&previous));
+    }
+}
@@ -1,17 +1,29 @@
 //! STUN packets sending and receiving.
 
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use stun_rs::{
-    attributes::stun::{Fingerprint, XorMappedAddress},
-    DecoderContextBuilder, MessageDecoderBuilder, MessageEncoderBuilder, StunMessageBuilder,
+    attributes::stun::{Fingerprint, Software, XorMappedAddress},
+    DecoderContextBuilder, MessageDecoderBuilder, MessageEncoderBuilder, StunMessage,
+    StunMessageBuilder,
 };
 pub use stun_rs::{
     attributes::StunAttribute, error::StunDecodeError, methods, MessageClass, MessageDecoder,
     TransactionId,
 };
 
-use crate::net::ip::to_canonical;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tracing::{debug, trace, warn};
+
+use crate::net::{ip::to_canonical, is_transient_recv_error};
+
+mod client;
+mod integrity;
+pub mod nat_behavior;
+
+pub use client::{Client, Retransmit, TransactError};
+pub use integrity::{check_integrity, IntegrityKey, IntegrityKeys};
 
 /// Errors that can occurr when handling a STUN packet.
 #[derive(Debug, thiserror::Error)]
@@ -34,53 +46,315 @@ pub enum Error {
     /// STUN request had bogus fingerprint.
     #[error("invalid fingerprint")]
     InvalidFingerprint,
+    /// STUN message had no MESSAGE-INTEGRITY when it should have.
+    #[error("no message integrity")]
+    NoIntegrity,
+    /// STUN message failed the MESSAGE-INTEGRITY check.
+    #[error("invalid message integrity")]
+    InvalidIntegrity,
+}
+
+/// A STUN error response, see [`BindingResponse::Error`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("STUN error {code} {reason}")]
+pub struct ErrorResponse {
+    /// The numeric error code, e.g. `300` for [`TRY_ALTERNATE`].
+    pub code: u16,
+    /// The reason phrase of the ERROR-CODE attribute.
+    pub reason: String,
+    /// The server to use instead, from the ALTERNATE-SERVER attribute.
+    pub alternate_server: Option<SocketAddr>,
+    /// The SOFTWARE attribute of the server, if any.
+    pub software: Option<String>,
+}
+
+impl ErrorResponse {
+    /// Whether the server asked to retry with the [`ErrorResponse::alternate_server`].
+    pub fn is_try_alternate(&self) -> bool {
+        self.code == TRY_ALTERNATE && self.alternate_server.is_some()
+    }
+}
+
+/// A parsed binding response, see [`parse_binding_response`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindingResponse {
+    /// A success response.
+    Success {
+        /// The STUN transaction ID.
+        tx: TransactionId,
+        /// The address of the client as seen by the server.
+        addr: SocketAddr,
+        /// The SOFTWARE attribute of the server, if any.
+        software: Option<String>,
+        /// The server's alternate address from the OTHER-ADDRESS attribute, RFC 5780.
+        ///
+        /// Only servers supporting NAT behavior discovery include it.
+        other_address: Option<SocketAddr>,
+        /// The address the response was sent from, from the RESPONSE-ORIGIN attribute.
+        response_origin: Option<SocketAddr>,
+    },
+    /// An error response.
+    Error {
+        /// The STUN transaction ID.
+        tx: TransactionId,
+        /// The error.
+        error: ErrorResponse,
+    },
+}
+
+impl BindingResponse {
+    /// Returns the STUN transaction ID of the response.
+    pub fn tx(&self) -> TransactionId {
+        match self {
+            BindingResponse::Success { tx, .. } | BindingResponse::Error { tx, .. } => *tx,
+        }
+    }
+}
+
+/// The STUN attribute type of ERROR-CODE.
+const ERROR_CODE_TYPE: u16 = 0x0009;
+
+/// The STUN attribute type of SOFTWARE.
+const SOFTWARE_TYPE: u16 = 0x8022;
+
+/// The STUN attribute type of ALTERNATE-SERVER.
+const ALTERNATE_SERVER_TYPE: u16 = 0x8023;
+
+/// The STUN attribute type of CHANGE-REQUEST, RFC 5780 §7.2.
+const CHANGE_REQUEST_TYPE: u16 = 0x0003;
+
+/// The STUN attribute type of RESPONSE-ORIGIN, RFC 5780 §7.3.
+const RESPONSE_ORIGIN_TYPE: u16 = 0x802b;
+
+/// The STUN attribute type of OTHER-ADDRESS, RFC 5780 §7.4.
+const OTHER_ADDRESS_TYPE: u16 = 0x802c;
+
+/// The CHANGE-REQUEST flag asking the server to respond from its other IP address.
+const CHANGE_IP: u32 = 0x04;
+
+/// The CHANGE-REQUEST flag asking the server to respond from its other port.
+const CHANGE_PORT: u32 = 0x02;
+
+/// The STUN message type bits of the binding method.
+const BINDING_METHOD: u16 = 0x0001;
+
+/// The bits of the STUN message type holding the message class.
+const CLASS_MASK: u16 = 0x0110;
+
+/// The message class bits of a success response.
+const CLASS_SUCCESS_RESPONSE: u16 = 0x0100;
+
+/// The message class bits of an error response.
+const CLASS_ERROR_RESPONSE: u16 = 0x0110;
+
+/// The STUN error code asking the client to use the ALTERNATE-SERVER.
+pub const TRY_ALTERNATE: u16 = 300;
+
+/// Options for generating binding requests, see [`request_with_options`].
+#[derive(Debug, Clone, Copy)]
+pub struct RequestOptions {
+    /// Whether to add a FINGERPRINT attribute to the request.
+    ///
+    /// Servers using [`parse_binding_request`] reject unsigned requests without one.
+    /// Defaults to `true`.  The FINGERPRINT is computed by stun-rs, which can not encode
+    /// CHANGE-REQUEST or MESSAGE-INTEGRITY, so requests carrying these have none.
+    pub fingerprint: bool,
+    /// Ask the server to respond from its other IP address, using CHANGE-REQUEST.
+    ///
+    /// Only servers supporting RFC 5780 honour this, see [`nat_behavior`].
+    pub change_ip: bool,
+    /// Ask the server to respond from its other port, using CHANGE-REQUEST.
+    pub change_port: bool,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self {
+            fingerprint: true,
+            change_ip: false,
+            change_port: false,
+        }
+    }
 }
 
 /// Generates a binding request STUN packet.
+///
+/// The request includes a FINGERPRINT attribute, see [`request_with_options`].
 pub fn request(tx: TransactionId) -> Vec<u8> {
-    let fp = Fingerprint::default();
-    let msg = StunMessageBuilder::new(methods::BINDING, MessageClass::Request)
-        .with_transaction_id(tx)
-        .with_attribute(fp)
-        .build();
+    request_with_options(tx, RequestOptions::default())
+}
 
-    let encoder = MessageEncoderBuilder::default().build();
-    let mut buffer = vec![0u8; 150];
-    let size = encoder.encode(&mut buffer, &msg).expect("invalid encoding");
-    buffer.truncate(size);
+/// Generates a binding request STUN packet using custom *options*.
+pub fn request_with_options(tx: TransactionId, options: RequestOptions) -> Vec<u8> {
+    build_request(tx, options, None)
+}
+
+/// Generates a binding request STUN packet with MESSAGE-INTEGRITY computed using *key*.
+pub fn request_with_integrity(
+    tx: TransactionId,
+    options: RequestOptions,
+    key: &IntegrityKey,
+) -> Vec<u8> {
+    build_request(tx, options, Some(key))
+}
+
+fn build_request(
+    tx: TransactionId,
+    options: RequestOptions,
+    integrity: Option<&IntegrityKey>,
+) -> Vec<u8> {
+    let change_request = options.change_ip || options.change_port;
+    let mut msg =
+        StunMessageBuilder::new(methods::BINDING, MessageClass::Request).with_transaction_id(tx);
+    if options.fingerprint && !change_request && integrity.is_none() {
+        msg = msg.with_attribute(Fingerprint::default());
+    }
+    let mut buffer = encode(&msg.build());
+
+    if change_request {
+        let mut flags = 0;
+        if options.change_ip {
+            flags |= CHANGE_IP;
+        }
+        if options.change_port {
+            flags |= CHANGE_PORT;
+        }
+        append_attribute(&mut buffer, CHANGE_REQUEST_TYPE, &flags.to_be_bytes());
+    }
+    if let Some(key) = integrity {
+        integrity::append_message_integrity(&mut buffer, key);
+    }
     buffer
 }
 
+/// Options for generating binding responses, see [`response_with_options`].
+#[derive(Debug, Clone, Default)]
+pub struct ResponseOptions {
+    /// The SOFTWARE attribute to add to responses, if any.
+    pub software: Option<String>,
+    /// Whether to add a FINGERPRINT attribute to responses.
+    ///
+    /// Responses with MESSAGE-INTEGRITY have none, see [`RequestOptions::fingerprint`].
+    pub fingerprint: bool,
+    /// The key to compute the MESSAGE-INTEGRITY of responses with, if any.
+    pub integrity: Option<IntegrityKey>,
+}
+
 /// Generates a binding response.
 pub fn response(tx: TransactionId, addr: SocketAddr) -> Vec<u8> {
-    let msg = StunMessageBuilder::new(methods::BINDING, MessageClass::SuccessResponse)
+    response_with_options(tx, addr, &ResponseOptions::default())
+}
+
+/// Generates a binding response using custom *options*.
+pub fn response_with_options(
+    tx: TransactionId,
+    addr: SocketAddr,
+    options: &ResponseOptions,
+) -> Vec<u8> {
+    let mut msg = StunMessageBuilder::new(methods::BINDING, MessageClass::SuccessResponse)
         .with_transaction_id(tx)
-        .with_attribute(XorMappedAddress::from(addr))
-        .build();
+        .with_attribute(XorMappedAddress::from(addr));
+    if let Some(ref software) = options.software {
+        match Software::new(software.as_str()) {
+            Ok(software) => msg = msg.with_attribute(software),
+            Err(err) => warn!("STUN: invalid SOFTWARE attribute {software:?}: {err:?}"),
+        }
+    }
+    if options.fingerprint && options.integrity.is_none() {
+        msg = msg.with_attribute(Fingerprint::default());
+    }
+    let mut buffer = encode(&msg.build());
 
+    if let Some(ref key) = options.integrity {
+        integrity::append_message_integrity(&mut buffer, key);
+    }
+    buffer
+}
+
+/// Encodes a STUN message, stun-rs computes its FINGERPRINT attribute if it has one.
+fn encode(msg: &StunMessage) -> Vec<u8> {
     let encoder = MessageEncoderBuilder::default().build();
-    let mut buffer = vec![0u8; 150];
-    let size = encoder.encode(&mut buffer, &msg).expect("invalid encoding");
+    // Large enough for the longest SOFTWARE attribute stun-rs accepts.
+    let mut buffer = vec![0u8; 1024];
+    let size = encoder.encode(&mut buffer, msg).expect("invalid encoding");
     buffer.truncate(size);
     buffer
 }
 
+/// Appends an attribute to an encoded STUN message, updating the message length.
+fn append_attribute(msg: &mut Vec<u8>, attr_type: u16, value: &[u8]) {
+    msg.extend_from_slice(&attr_type.to_be_bytes());
+    msg.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    msg.extend_from_slice(value);
+    // Attribute values are padded to a multiple of 4 bytes.
+    msg.resize((msg.len() + 3) / 4 * 4, 0);
+    let len = (msg.len() - stun_rs::MESSAGE_HEADER_SIZE) as u16;
+    msg[2..4].copy_from_slice(&len.to_be_bytes());
+}
+
 /// Reports whether b is a STUN message.
 pub fn is(b: &[u8]) -> bool {
+    if b.len() < stun_rs::MESSAGE_HEADER_SIZE {
+        return false;
+    }
     let cookie: [u8; 4] = b[4..8].try_into().unwrap();
 
-    b.len() >= stun_rs::MESSAGE_HEADER_SIZE &&
-	b[0]&0b11000000 == 0 && // top two bits must be zero
+    b[0]&0b11000000 == 0 && // top two bits must be zero
 	cookie == stun_rs::MAGIC_COOKIE
 }
 
+/// Runs a STUN server on *socket*, answering binding requests.
+///
+/// See [`serve_with_options`].
+pub fn serve(socket: UdpSocket) -> JoinHandle<()> {
+    serve_with_options(socket, ResponseOptions::default())
+}
+
+/// Runs a STUN server on *socket*, answering binding requests using custom *options*.
+///
+/// Each binding request is answered with the sender's address in the XOR-MAPPED-ADDRESS
+/// attribute.  Anything else, including malformed packets, is ignored, so this is safe to
+/// run on a public port.  The server runs until the returned task is aborted, or until
+/// receiving fails with an error other than a transient one.
+pub fn serve_with_options(socket: UdpSocket, options: ResponseOptions) -> JoinHandle<()> {
+    tokio::task::spawn(async move {
+        let mut buf = vec![0u8; 64 << 10];
+        loop {
+            let (n, src) = match socket.recv_from(&mut buf).await {
+                Ok(res) => res,
+                Err(err) if is_transient_recv_error(&err) => {
+                    // E.g. ICMP errors of previous responses, the socket is still usable.
+                    debug!("STUN: failed to receive: {err:#}");
+                    continue;
+                }
+                Err(err) => {
+                    warn!("STUN: failed to receive, stopping the server: {err:#}");
+                    return;
+                }
+            };
+            let pkt = &buf[..n];
+            if !is(pkt) {
+                trace!(%src, "STUN: ignoring non STUN packet");
+                continue;
+            }
+            let txid = match parse_binding_request(pkt) {
+                Ok(txid) => txid,
+                Err(err) => {
+                    debug!(%src, "STUN: invalid binding request: {err}");
+                    continue;
+                }
+            };
+            let res = response_with_options(txid, src, &options);
+            if let Err(err) = socket.send_to(&res, src).await {
+                warn!(%src, %txid, "STUN: failed to send response: {err:#}");
+            }
+        }
+    })
+}
+
 /// Parses a STUN binding request.
 pub fn parse_binding_request(b: &[u8]) -> Result<TransactionId, Error> {
-    let ctx = DecoderContextBuilder::default()
-        .with_validation() // ensure fingerprint is validated
-        .build();
-    let decoder = MessageDecoderBuilder::default().with_context(ctx).build();
-    let (msg, _) = decoder.decode(b).map_err(|_| Error::InvalidMessage)?;
+    let msg = decode(b)?;
 
     let tx = *msg.transaction_id();
     if msg.method() != methods::BINDING {
@@ -89,19 +363,85 @@ pub fn parse_binding_request(b: &[u8]) -> Result<TransactionId, Error> {
 
     // TODO: Tailscale sets the software to tailscale, we should check if we want to do this too.
 
-    let attrs = msg.attributes();
-    if attrs.is_empty() || !attrs.last().unwrap().is_fingerprint() {
+    // Signed requests end in their MESSAGE-INTEGRITY instead, see `RequestOptions`.
+    let ends_checked = matches!(
+        msg.attributes().last(),
+        Some(
+            StunAttribute::Fingerprint(_)
+                | StunAttribute::MessageIntegrity(_)
+                | StunAttribute::MessageIntegritySha256(_)
+        )
+    );
+    if !ends_checked {
         return Err(Error::NoFingerprint);
     }
 
     Ok(tx)
 }
 
+/// An attribute of a STUN message, as returned by [`raw_attributes`].
+#[derive(Debug, Clone, Copy)]
+struct RawAttribute<'a> {
+    /// The offset of the attribute in the message.
+    offset: usize,
+    /// The attribute type.
+    attr_type: u16,
+    /// The attribute value, without padding.
+    value: &'a [u8],
+}
+
+/// Splits a STUN message into its attributes without interpreting them.
+fn raw_attributes(b: &[u8]) -> Result<Vec<RawAttribute<'_>>, Error> {
+    let header_size = stun_rs::MESSAGE_HEADER_SIZE;
+    if b.len() < header_size {
+        return Err(Error::InvalidMessage);
+    }
+    let msg_len = u16::from_be_bytes([b[2], b[3]]) as usize;
+    let end = header_size + msg_len;
+    if b.len() < end {
+        return Err(Error::InvalidMessage);
+    }
+
+    let mut attrs = Vec::new();
+    let mut offset = header_size;
+    while offset + 4 <= end {
+        let attr_type = u16::from_be_bytes([b[offset], b[offset + 1]]);
+        let attr_len = u16::from_be_bytes([b[offset + 2], b[offset + 3]]) as usize;
+        let value_start = offset + 4;
+        let value_end = value_start + attr_len;
+        if value_end > end {
+            return Err(Error::MalformedAttrs);
+        }
+        attrs.push(RawAttribute {
+            offset,
+            attr_type,
+            value: &b[value_start..value_end],
+        });
+        // Attribute values are padded to a multiple of 4 bytes.
+        offset = value_start + (attr_len + 3) / 4 * 4;
+    }
+    Ok(attrs)
+}
+
+/// Decodes a STUN message, verifying its FINGERPRINT attribute if it has one.
+///
+/// Messages without a FINGERPRINT are accepted, not all servers add one.
+fn decode(b: &[u8]) -> Result<StunMessage, Error> {
+    let ctx = DecoderContextBuilder::default()
+        .with_validation() // ensure fingerprint is validated
+        .build();
+    let decoder = MessageDecoderBuilder::default().with_context(ctx).build();
+    let (msg, _) = decoder.decode(b).map_err(|_| Error::InvalidMessage)?;
+    Ok(msg)
+}
+
 /// Parses a successful binding response STUN packet.
 /// The IP address is extracted from the XOR-MAPPED-ADDRESS attribute.
+///
+/// If the response has a FINGERPRINT attribute it is verified, responses with a bad
+/// fingerprint are rejected with [`Error::InvalidMessage`].
 pub fn parse_response(b: &[u8]) -> Result<(TransactionId, SocketAddr), Error> {
-    let decoder = MessageDecoder::default();
-    let (msg, _) = decoder.decode(b).map_err(|_| Error::InvalidMessage)?;
+    let msg = decode(b)?;
 
     let tx = *msg.transaction_id();
     if msg.class() != MessageClass::SuccessResponse {
@@ -143,11 +483,102 @@ pub fn parse_response(b: &[u8]) -> Result<(TransactionId, SocketAddr), Error> {
     Err(Error::MalformedAttrs)
 }
 
+/// Parses a binding response STUN packet, either a success or an error response.
+///
+/// Unlike [`parse_response`] this surfaces the ERROR-CODE, ALTERNATE-SERVER and SOFTWARE
+/// attributes, so an error response can be told apart from no response at all.
+pub fn parse_binding_response(b: &[u8]) -> Result<BindingResponse, Error> {
+    if !is(b) {
+        return Err(Error::InvalidMessage);
+    }
+    decode(b)?;
+    let msg_type = u16::from_be_bytes([b[0], b[1]]);
+    if msg_type & !CLASS_MASK != BINDING_METHOD {
+        return Err(Error::NotBinding);
+    }
+
+    let attrs = raw_attributes(b)?;
+    let software = attrs
+        .iter()
+        .find(|attr| attr.attr_type == SOFTWARE_TYPE)
+        .map(|attr| String::from_utf8_lossy(attr.value).into_owned());
+
+    match msg_type & CLASS_MASK {
+        CLASS_SUCCESS_RESPONSE => {
+            let (tx, addr) = parse_response(b)?;
+            let find_address = |attr_type| {
+                attrs
+                    .iter()
+                    .find(|attr| attr.attr_type == attr_type)
+                    .and_then(|attr| parse_address(attr.value))
+            };
+            Ok(BindingResponse::Success {
+                tx,
+                addr,
+                software,
+                other_address: find_address(OTHER_ADDRESS_TYPE),
+                response_origin: find_address(RESPONSE_ORIGIN_TYPE),
+            })
+        }
+        CLASS_ERROR_RESPONSE => {
+            let tx: [u8; 12] = b[8..20].try_into().unwrap();
+            let (code, reason) = attrs
+                .iter()
+                .find(|attr| attr.attr_type == ERROR_CODE_TYPE)
+                .and_then(|attr| parse_error_code(attr.value))
+                .ok_or(Error::MalformedAttrs)?;
+            let alternate_server = attrs
+                .iter()
+                .find(|attr| attr.attr_type == ALTERNATE_SERVER_TYPE)
+                .and_then(|attr| parse_address(attr.value));
+            Ok(BindingResponse::Error {
+                tx: TransactionId::from(tx),
+                error: ErrorResponse {
+                    code,
+                    reason,
+                    alternate_server,
+                    software,
+                },
+            })
+        }
+        _ => Err(Error::NotSuccessResponse),
+    }
+}
+
+/// Parses the value of an ERROR-CODE attribute into the code and reason phrase.
+fn parse_error_code(value: &[u8]) -> Option<(u16, String)> {
+    if value.len() < 4 {
+        return None;
+    }
+    let class = u16::from(value[2] & 0x07);
+    let number = u16::from(value[3]);
+    if !(3..=6).contains(&class) || number >= 100 {
+        return None;
+    }
+    let reason = String::from_utf8_lossy(&value[4..]).into_owned();
+    Some((class * 100 + number, reason))
+}
+
+/// Parses an address attribute in the MAPPED-ADDRESS format, e.g. ALTERNATE-SERVER.
+fn parse_address(value: &[u8]) -> Option<SocketAddr> {
+    if value.len() < 4 {
+        return None;
+    }
+    let port = u16::from_be_bytes([value[2], value[3]]);
+    let ip = match (value[1], &value[4..]) {
+        (0x01, ip) => Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?).into(),
+        (0x02, ip) => Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?).into(),
+        _ => return None,
+    };
+    Some(SocketAddr::new(to_canonical(ip), port))
+}
+
 #[cfg(test)]
 pub mod test {
     use std::{
         net::{IpAddr, Ipv4Addr, SocketAddr},
         sync::Arc,
+        time::Duration,
     };
 
     use crate::{
@@ -161,7 +592,6 @@ pub mod test {
         net,
         sync::{oneshot, Mutex},
     };
-    use tracing::{debug, trace};
 
     // (read_ipv4, read_ipv5)
     #[derive(Debug, Default, Clone)]
@@ -204,6 +634,7 @@ pub mod test {
                     region_code: "".to_string(),
                     avoid: false,
                     nodes: vec![node],
+                    stun_integrity: None,
                 },
             );
         }
@@ -220,6 +651,16 @@ pub mod test {
 
     /// Sets up a simple STUN server.
     pub(crate) async fn serve(ip: IpAddr) -> Result<(SocketAddr, StunStats, CleanupDropGuard)> {
+        serve_with_delay(ip, Duration::ZERO).await
+    }
+
+    /// Sets up a simple STUN server which waits *delay* before each response.
+    ///
+    /// This mocks the latency of a remote STUN server.
+    pub(crate) async fn serve_with_delay(
+        ip: IpAddr,
+        delay: Duration,
+    ) -> Result<(SocketAddr, StunStats, CleanupDropGuard)> {
         let stats = StunStats::default();
 
         let pc = net::UdpSocket::bind((ip, 0)).await?;
@@ -237,13 +678,18 @@ pub mod test {
         let (s, r) = oneshot::channel();
         let stats_c = stats.clone();
         tokio::task::spawn(async move {
-            run_stun(pc, stats_c, r).await;
+            run_stun(Arc::new(pc), stats_c, delay, r).await;
         });
 
         Ok((addr, stats, CleanupDropGuard(s)))
     }
 
-    async fn run_stun(pc: net::UdpSocket, stats: StunStats, mut done: oneshot::Receiver<()>) {
+    async fn run_stun(
+        pc: Arc<net::UdpSocket>,
+        stats: StunStats,
+        delay: Duration,
+        mut done: oneshot::Receiver<()>,
+    ) {
         let mut buf = vec![0u8; 64 << 10];
         loop {
             trace!("read loop");
@@ -271,8 +717,18 @@ pub mod test {
                             drop(s);
 
                             let res = response(txid, addr);
-                            if let Err(err) = pc.send_to(&res, addr).await {
-                                eprintln!("STUN server write failed: {:?}", err);
+                            if delay.is_zero() {
+                                if let Err(err) = pc.send_to(&res, addr).await {
+                                    eprintln!("STUN server write failed: {:?}", err);
+                                }
+                            } else {
+                                let pc = pc.clone();
+                                tokio::task::spawn(async move {
+                                    tokio::time::sleep(delay).await;
+                                    if let Err(err) = pc.send_to(&res, addr).await {
+                                        eprintln!("STUN server write failed: {:?}", err);
+                                    }
+                                });
                             }
                         }
                     }
@@ -288,6 +744,9 @@ pub mod test {
 #[cfg(test)]
 mod tests {
     use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+
+    use stun_rs::attributes::stun::Fingerprint;
 
     use super::*;
 
@@ -556,4 +1015,227 @@ mod tests {
             assert_eq!(tt.port, addr2.port());
         }
     }
+
+    #[test]
+    fn test_request_fingerprint() {
+        let tx = TransactionId::default();
+        let req = request(tx);
+        assert_eq!(parse_binding_request(&req).unwrap(), tx);
+
+        let req = request_with_options(
+            tx,
+            RequestOptions {
+                fingerprint: false,
+                ..Default::default()
+            },
+        );
+        assert!(is(&req));
+        assert_eq!(req.len(), stun_rs::MESSAGE_HEADER_SIZE);
+        assert!(matches!(
+            parse_binding_request(&req),
+            Err(Error::NoFingerprint)
+        ));
+    }
+
+    #[test]
+    fn test_response_fingerprint() {
+        let tx = TransactionId::from([7; 12]);
+        let addr: SocketAddr = "1.2.3.4:1234".parse().unwrap();
+        let msg = StunMessageBuilder::new(methods::BINDING, MessageClass::SuccessResponse)
+            .with_transaction_id(tx)
+            .with_attribute(XorMappedAddress::from(addr))
+            .with_attribute(Fingerprint::default())
+            .build();
+        let encoder = MessageEncoderBuilder::default().build();
+        let mut res = vec![0u8; 150];
+        let size = encoder.encode(&mut res, &msg).unwrap();
+        res.truncate(size);
+
+        assert_eq!(parse_response(&res).unwrap(), (tx, addr));
+
+        // A corrupted fingerprint is rejected.
+        let last = res.len() - 1;
+        res[last] ^= 0xff;
+        assert!(matches!(parse_response(&res), Err(Error::InvalidMessage)));
+
+        // Responses without a fingerprint are still accepted.
+        let res = response(tx, addr);
+        assert_eq!(parse_response(&res).unwrap(), (tx, addr));
+    }
+
+    #[test]
+    fn test_response_with_options() {
+        let tx = TransactionId::from([9; 12]);
+        let addr: SocketAddr = "[2001:db8::1]:1234".parse().unwrap();
+        let options = ResponseOptions {
+            software: Some("iroh-derper".into()),
+            fingerprint: true,
+            ..Default::default()
+        };
+        let res = response_with_options(tx, addr, &options);
+        let msg = decode(&res).unwrap();
+        assert!(msg.attributes().last().unwrap().is_fingerprint());
+        assert_eq!(
+            parse_binding_response(&res).unwrap(),
+            BindingResponse::Success {
+                tx,
+                addr,
+                software: Some("iroh-derper".into()),
+                other_address: None,
+                response_origin: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_is_short_packet() {
+        assert!(!is(&[]));
+        assert!(!is(&[0x00, 0x01, 0x00, 0x00, 0x21, 0x12]));
+    }
+
+    #[tokio::test]
+    async fn test_serve() -> anyhow::Result<()> {
+        let server = UdpSocket::bind("127.0.0.1:0").await?;
+        let server_addr = server.local_addr()?;
+        let task = serve_with_options(
+            server,
+            ResponseOptions {
+                fingerprint: true,
+                ..Default::default()
+            },
+        );
+
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        // Garbage is ignored without taking down the server.
+        let tx = TransactionId::default();
+        let garbage: [&[u8]; 4] = [
+            &[],
+            &[0xff; 7],
+            &[0x00; 64],
+            &response(tx, client.local_addr()?),
+        ];
+        for pkt in garbage {
+            client.send_to(pkt, server_addr).await?;
+        }
+        let mut no_fingerprint = request_with_options(
+            tx,
+            RequestOptions {
+                fingerprint: false,
+                ..Default::default()
+            },
+        );
+        client.send_to(&no_fingerprint, server_addr).await?;
+        no_fingerprint.truncate(4);
+        client.send_to(&no_fingerprint, server_addr).await?;
+
+        let tx = TransactionId::default();
+        client.send_to(&request(tx), server_addr).await?;
+        let mut buf = vec![0u8; 1500];
+        let (n, src) =
+            tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf)).await??;
+        assert_eq!(src, server_addr);
+        assert_eq!(parse_response(&buf[..n])?, (tx, client.local_addr()?));
+
+        task.abort();
+        Ok(())
+    }
+
+    const FIXTURE_TID: [u8; 12] = [
+        0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c,
+    ];
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_parse_binding_response_try_alternate() {
+        let data = [
+            0x01, 0x11, 0x00, 0x2c, 0x21, 0x12, 0xa4, 0x42,
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+            0x09, 0x0a, 0x0b, 0x0c,
+            // ERROR-CODE 300 "Try Alternate"
+            0x00, 0x09, 0x00, 0x11, 0x00, 0x00, 0x03, 0x00,
+            0x54, 0x72, 0x79, 0x20, 0x41, 0x6c, 0x74, 0x65,
+            0x72, 0x6e, 0x61, 0x74, 0x65, 0x00, 0x00, 0x00,
+            // ALTERNATE-SERVER 192.0.2.1:3478
+            0x80, 0x23, 0x00, 0x08, 0x00, 0x01, 0x0d, 0x96,
+            0xc0, 0x00, 0x02, 0x01,
+            // SOFTWARE "iroh"
+            0x80, 0x22, 0x00, 0x04, 0x69, 0x72, 0x6f, 0x68,
+        ];
+        let res = parse_binding_response(&data).unwrap();
+        assert_eq!(res.tx(), TransactionId::from(FIXTURE_TID));
+        let BindingResponse::Error { error, .. } = res else {
+            panic!("expected an error response: {res:?}");
+        };
+        assert_eq!(
+            error,
+            ErrorResponse {
+                code: TRY_ALTERNATE,
+                reason: "Try Alternate".into(),
+                alternate_server: Some("192.0.2.1:3478".parse().unwrap()),
+                software: Some("iroh".into()),
+            }
+        );
+        assert!(error.is_try_alternate());
+
+        // The old parser only knows success responses.
+        assert!(matches!(parse_response(&data), Err(Error::NotSuccessResponse)));
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_parse_binding_response_error() {
+        let data = [
+            0x01, 0x11, 0x00, 0x14, 0x21, 0x12, 0xa4, 0x42,
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+            0x09, 0x0a, 0x0b, 0x0c,
+            // ERROR-CODE 401 "Unauthorized"
+            0x00, 0x09, 0x00, 0x10, 0x00, 0x00, 0x04, 0x01,
+            0x55, 0x6e, 0x61, 0x75, 0x74, 0x68, 0x6f, 0x72,
+            0x69, 0x7a, 0x65, 0x64,
+        ];
+        let res = parse_binding_response(&data).unwrap();
+        let BindingResponse::Error { error, .. } = res else {
+            panic!("expected an error response: {res:?}");
+        };
+        assert_eq!(error.code, 401);
+        assert_eq!(error.reason, "Unauthorized");
+        assert_eq!(error.alternate_server, None);
+        assert_eq!(error.software, None);
+        assert!(!error.is_try_alternate());
+        assert_eq!(error.to_string(), "STUN error 401 Unauthorized");
+
+        // An error response without ERROR-CODE is malformed.
+        let mut data = data[..20].to_vec();
+        data[3] = 0;
+        assert!(matches!(parse_binding_response(&data), Err(Error::MalformedAttrs)));
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_parse_binding_response_success() {
+        let data = [
+            0x01, 0x01, 0x00, 0x14, 0x21, 0x12, 0xa4, 0x42,
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+            0x09, 0x0a, 0x0b, 0x0c,
+            // XOR-MAPPED-ADDRESS 192.0.2.1:3478
+            0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0x2c, 0x84,
+            0xe1, 0x12, 0xa6, 0x43,
+            // SOFTWARE "iroh"
+            0x80, 0x22, 0x00, 0x04, 0x69, 0x72, 0x6f, 0x68,
+        ];
+        assert_eq!(
+            parse_binding_response(&data).unwrap(),
+            BindingResponse::Success {
+                tx: TransactionId::from(FIXTURE_TID),
+                addr: "192.0.2.1:3478".parse().unwrap(),
+                software: Some("iroh".into()),
+                other_address: None,
+                response_origin: None,
+            }
+        );
+
+        // Requests are not responses.
+        let req = request(TransactionId::default());
+        assert!(matches!(parse_binding_response(&req), Err(Error::NotSuccessResponse)));
+    }
 }
@@ -0,0 +1,242 @@
+//! STUN transactions over UDP with retransmissions.
+//!
+//! UDP is unreliable, so a STUN client retransmits its request until it gets a response,
+//! following the schedule of RFC 8489 §6.2.1.  Responses are not read from the socket by the
+//! [`Client`]: the socket is usually shared and its packets are routed by transaction ID
+//! elsewhere, the caller passes a future which resolves with the routed response.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time;
+use tracing::{debug, trace};
+
+/// The default initial retransmission timeout, from RFC 8489.
+const DEFAULT_RTO: Duration = Duration::from_millis(500);
+
+/// The default maximum number of requests sent, Rc in RFC 8489.
+const DEFAULT_MAX_REQUESTS: u32 = 7;
+
+/// The default wait after the last request in multiples of the RTO, Rm in RFC 8489.
+const DEFAULT_LAST_WAIT: u32 = 16;
+
+/// The retransmission schedule of a STUN transaction.
+///
+/// The first retransmission is sent after [`Retransmit::rto`], the interval is doubled
+/// after every retransmission.  After the last request the transaction waits for
+/// [`Retransmit::last_wait`] times the initial RTO before it fails.  The defaults are those
+/// recommended by RFC 8489: 7 requests sent at 0s, 0.5s, 1.5s, 3.5s, 7.5s, 15.5s and 31.5s
+/// and a failure after 39.5s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retransmit {
+    /// The initial retransmission timeout (RTO).
+    pub rto: Duration,
+    /// The maximum number of requests sent, including the first (Rc).
+    pub max_requests: u32,
+    /// How long to wait for a response after the last request, in multiples of the initial
+    /// RTO (Rm).
+    pub last_wait: u32,
+}
+
+impl Default for Retransmit {
+    fn default() -> Self {
+        Self {
+            rto: DEFAULT_RTO,
+            max_requests: DEFAULT_MAX_REQUESTS,
+            last_wait: DEFAULT_LAST_WAIT,
+        }
+    }
+}
+
+impl Retransmit {
+    /// How long a transaction without response takes to fail.
+    pub fn timeout(&self) -> Duration {
+        let retransmissions = self.max_requests.saturating_sub(1);
+        let last_request = self.rto * (2u32.saturating_pow(retransmissions) - 1);
+        last_request + self.rto * self.last_wait
+    }
+}
+
+/// Errors of a STUN transaction, see [`Client::transact`].
+#[derive(Debug, thiserror::Error)]
+pub enum TransactError {
+    /// The first request could not be sent.
+    #[error("sending STUN request failed: {0}")]
+    Send(#[source] io::Error),
+    /// No response arrived before the retransmission schedule ran out.
+    #[error("STUN transaction timed out")]
+    Timeout,
+}
+
+/// Sends STUN requests from a UDP socket, retransmitting them until answered.
+#[derive(Debug, Clone, Copy)]
+pub struct Client<'a> {
+    sock: &'a UdpSocket,
+    retransmit: Retransmit,
+    on_retransmit: Option<fn()>,
+}
+
+impl<'a> Client<'a> {
+    /// Creates a client sending from *sock* with the *retransmit* schedule.
+    pub fn new(sock: &'a UdpSocket, retransmit: Retransmit) -> Self {
+        Self {
+            sock,
+            retransmit,
+            on_retransmit: None,
+        }
+    }
+
+    /// Calls *f* whenever a request is retransmitted, e.g. to count the packets sent.
+    pub fn on_retransmit(mut self, f: fn()) -> Self {
+        self.on_retransmit = Some(f);
+        self
+    }
+
+    /// Performs a STUN transaction: sends *req* to *dst* until *response* resolves.
+    ///
+    /// The request is retransmitted unchanged, with the same transaction ID, so a response
+    /// to any of the requests resolves *response*.  Only a failure to send the first request
+    /// fails the transaction, failed retransmissions are only logged.
+    pub async fn transact<F: Future>(
+        &self,
+        dst: SocketAddr,
+        req: &[u8],
+        response: F,
+    ) -> Result<F::Output, TransactError> {
+        let n = self.send(dst, req).await.map_err(TransactError::Send)?;
+        if n != req.len() {
+            return Err(TransactError::Send(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!("sent {n} of {} bytes", req.len()),
+            )));
+        }
+        self.retransmit(dst, req, response).await
+    }
+
+    /// Sends a single request *req* to *dst*.
+    ///
+    /// Together with [`Client::retransmit`] this is [`Client::transact`], for callers which
+    /// handle errors sending the first request themselves.
+    pub async fn send(&self, dst: SocketAddr, req: &[u8]) -> io::Result<usize> {
+        self.sock.send_to(req, dst).await
+    }
+
+    /// Waits for *response* to the request *req* already sent to *dst*.
+    ///
+    /// The request is retransmitted following the schedule, until *response* resolves or
+    /// the schedule runs out.
+    pub async fn retransmit<F: Future>(
+        &self,
+        dst: SocketAddr,
+        req: &[u8],
+        response: F,
+    ) -> Result<F::Output, TransactError> {
+        tokio::pin!(response);
+
+        let mut rto = self.retransmit.rto;
+        for _ in 1..self.retransmit.max_requests {
+            tokio::select! {
+                biased;
+                res = &mut response => return Ok(res),
+                _ = time::sleep(rto) => (),
+            }
+            trace!(%dst, ?rto, "retransmitting STUN request");
+            if let Some(on_retransmit) = self.on_retransmit {
+                on_retransmit();
+            }
+            if let Err(err) = self.send(dst, req).await {
+                debug!(%dst, "retransmitting STUN request failed: {:?}", err);
+            }
+            rto *= 2;
+        }
+        time::timeout(self.retransmit.rto * self.retransmit.last_wait, response)
+            .await
+            .map_err(|_| TransactError::Timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::stun::{request, TransactionId};
+
+    /// Binds a server socket counting the requests it receives.
+    async fn counting_server() -> (SocketAddr, Arc<AtomicUsize>, oneshot::Receiver<()>) {
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = sock.local_addr().unwrap();
+        let count = Arc::new(AtomicUsize::new(0));
+        let (second_tx, second_rx) = oneshot::channel();
+        let mut second_tx = Some(second_tx);
+        let counter = count.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while sock.recv_from(&mut buf).await.is_ok() {
+                if counter.fetch_add(1, Ordering::SeqCst) == 1 {
+                    if let Some(tx) = second_tx.take() {
+                        tx.send(()).ok();
+                    }
+                }
+            }
+        });
+        (addr, count, second_rx)
+    }
+
+    #[test]
+    fn test_timeout() {
+        assert_eq!(
+            Retransmit::default().timeout(),
+            Duration::from_millis(39_500)
+        );
+        let single = Retransmit {
+            rto: Duration::from_millis(100),
+            max_requests: 1,
+            last_wait: 5,
+        };
+        assert_eq!(single.timeout(), Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_retransmit_until_timeout() {
+        let (server, count, _) = counting_server().await;
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let retransmit = Retransmit {
+            rto: Duration::from_millis(10),
+            max_requests: 3,
+            last_wait: 2,
+        };
+        let client = Client::new(&sock, retransmit);
+        let req = request(TransactionId::default());
+
+        let res = client
+            .transact(server, &req, std::future::pending::<()>())
+            .await;
+        assert!(matches!(res, Err(TransactError::Timeout)));
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_response_stops_retransmit() {
+        let (server, count, second) = counting_server().await;
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let retransmit = Retransmit {
+            rto: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let client = Client::new(&sock, retransmit);
+        let req = request(TransactionId::default());
+
+        // The response arrives after the first retransmission.
+        let res = client.transact(server, &req, second).await;
+        assert!(matches!(res, Ok(Ok(()))));
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+}
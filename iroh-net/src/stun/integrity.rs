@@ -0,0 +1,270 @@
+//! STUN message integrity using a shared secret.
+//!
+//! Binding requests and responses carry a MESSAGE-INTEGRITY attribute (HMAC-SHA1, RFC 5389
+//! §15.4) followed by a MESSAGE-INTEGRITY-SHA256 attribute (RFC 8489 §14.6), keyed with a
+//! secret shared between the clients and the STUN server.  Validation accepts either
+//! attribute, preferring MESSAGE-INTEGRITY-SHA256.
+
+use std::fmt;
+
+use ring::hmac;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use zeroize::Zeroize;
+
+use super::{append_attribute, raw_attributes, Error, RawAttribute};
+
+/// The STUN attribute type of MESSAGE-INTEGRITY.
+const MESSAGE_INTEGRITY_TYPE: u16 = 0x0008;
+
+/// The STUN attribute type of MESSAGE-INTEGRITY-SHA256.
+const MESSAGE_INTEGRITY_SHA256_TYPE: u16 = 0x001c;
+
+/// A shared secret to compute STUN message integrity.
+///
+/// The key is wiped from memory when dropped and never printed, its [`fmt::Debug`]
+/// output is redacted.  It is serialized as a hex string.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IntegrityKey(Vec<u8>);
+
+impl IntegrityKey {
+    /// Creates a key from the shared secret.
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self(secret.into())
+    }
+
+    fn hmac(&self, algorithm: hmac::Algorithm) -> hmac::Key {
+        hmac::Key::new(algorithm, &self.0)
+    }
+}
+
+impl Drop for IntegrityKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for IntegrityKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IntegrityKey(..)")
+    }
+}
+
+impl Serialize for IntegrityKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for IntegrityKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut encoded = String::deserialize(deserializer)?;
+        let res = hex::decode(&encoded).map(Self);
+        encoded.zeroize();
+        res.map_err(|_| serde::de::Error::custom("invalid hex encoded integrity key"))
+    }
+}
+
+/// The STUN integrity keys of a DERP region.
+///
+/// Messages are signed with the [`IntegrityKeys::current`] key.  To rotate keys, the old
+/// key is kept as [`IntegrityKeys::previous`] until all servers use the new key, during
+/// this window messages signed with either key are accepted.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct IntegrityKeys {
+    /// The key to sign messages with.
+    pub current: IntegrityKey,
+    /// A previous key, still accepted when validating messages.
+    #[serde(default)]
+    pub previous: Option<IntegrityKey>,
+}
+
+impl IntegrityKeys {
+    /// Creates the keys with only a single key.
+    pub fn new(current: IntegrityKey) -> Self {
+        Self {
+            current,
+            previous: None,
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &IntegrityKey> + '_ {
+        std::iter::once(&self.current).chain(self.previous.as_ref())
+    }
+}
+
+/// Appends the MESSAGE-INTEGRITY and MESSAGE-INTEGRITY-SHA256 attributes.
+///
+/// Must be called after all other attributes were added.
+pub(super) fn append_message_integrity(msg: &mut Vec<u8>, key: &IntegrityKey) {
+    for (attr_type, algorithm, len) in [
+        (
+            MESSAGE_INTEGRITY_TYPE,
+            hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            20,
+        ),
+        (MESSAGE_INTEGRITY_SHA256_TYPE, hmac::HMAC_SHA256, 32),
+    ] {
+        // The HMAC covers the header with a length which already includes this attribute.
+        let total = msg.len() + 4 + len;
+        set_length(msg, total);
+        let tag = hmac::sign(&key.hmac(algorithm), msg);
+        append_attribute(msg, attr_type, tag.as_ref());
+    }
+}
+
+/// Verifies the message integrity of a STUN message using any of the *keys*.
+///
+/// Uses MESSAGE-INTEGRITY-SHA256 if present, otherwise MESSAGE-INTEGRITY.  Messages
+/// without either attribute fail with [`Error::NoIntegrity`].
+pub fn check_integrity(b: &[u8], keys: &IntegrityKeys) -> Result<(), Error> {
+    let attrs = raw_attributes(b)?;
+    let find = |attr_type| attrs.iter().find(|attr| attr.attr_type == attr_type);
+    let (attr, algorithm) = match (
+        find(MESSAGE_INTEGRITY_SHA256_TYPE),
+        find(MESSAGE_INTEGRITY_TYPE),
+    ) {
+        (Some(attr), _) => (attr, hmac::HMAC_SHA256),
+        (None, Some(attr)) => (attr, hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY),
+        (None, None) => return Err(Error::NoIntegrity),
+    };
+    let RawAttribute { offset, value, .. } = *attr;
+
+    let mut signed = b[..offset].to_vec();
+    set_length(&mut signed, offset + 4 + value.len());
+    let valid = keys
+        .iter()
+        .any(|key| hmac::verify(&key.hmac(algorithm), &signed, value).is_ok());
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidIntegrity)
+    }
+}
+
+/// Sets the message length in the header of a STUN message which will be *total* bytes.
+fn set_length(msg: &mut [u8], total: usize) {
+    let len = (total - stun_rs::MESSAGE_HEADER_SIZE) as u16;
+    msg[2..4].copy_from_slice(&len.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+    use super::*;
+
+    fn keys(secret: &str) -> IntegrityKeys {
+        IntegrityKeys::new(IntegrityKey::new(secret))
+    }
+
+    #[test]
+    fn test_request_integrity() {
+        let tx = TransactionId::default();
+        let req = request_with_integrity(tx, RequestOptions::default(), &keys("s3cret").current);
+        assert!(check_integrity(&req, &keys("s3cret")).is_ok());
+        assert!(matches!(
+            check_integrity(&req, &keys("other")),
+            Err(Error::InvalidIntegrity)
+        ));
+        // Signed requests end in their MESSAGE-INTEGRITY instead of a FINGERPRINT.
+        assert_eq!(parse_binding_request(&req).unwrap(), tx);
+
+        assert!(matches!(
+            check_integrity(&request(tx), &keys("s3cret")),
+            Err(Error::NoIntegrity)
+        ));
+    }
+
+    #[test]
+    fn test_response_integrity() {
+        let tx = TransactionId::default();
+        let addr = "1.2.3.4:1234".parse().unwrap();
+        let options = ResponseOptions {
+            integrity: Some(IntegrityKey::new("s3cret")),
+            fingerprint: true,
+            ..Default::default()
+        };
+        let mut res = response_with_options(tx, addr, &options);
+        assert!(check_integrity(&res, &keys("s3cret")).is_ok());
+        assert_eq!(parse_response(&res).unwrap(), (tx, addr));
+
+        // Tampering with the mapped address breaks the integrity.
+        res[stun_rs::MESSAGE_HEADER_SIZE + 7] ^= 0x01;
+        assert!(matches!(
+            check_integrity(&res, &keys("s3cret")),
+            Err(Error::InvalidIntegrity)
+        ));
+    }
+
+    #[test]
+    fn test_sha1_only() {
+        // Older implementations only add MESSAGE-INTEGRITY.
+        let key = IntegrityKey::new("s3cret");
+        let mut msg = request_with_options(
+            TransactionId::default(),
+            RequestOptions {
+                fingerprint: false,
+                ..Default::default()
+            },
+        );
+        let total = msg.len() + 24;
+        set_length(&mut msg, total);
+        let tag = hmac::sign(&key.hmac(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY), &msg);
+        append_attribute(&mut msg, MESSAGE_INTEGRITY_TYPE, tag.as_ref());
+        assert!(check_integrity(&msg, &IntegrityKeys::new(key)).is_ok());
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let tx = TransactionId::default();
+        let old = IntegrityKey::new("old");
+        let new = IntegrityKey::new("new");
+        let req = request_with_integrity(tx, RequestOptions::default(), &old);
+
+        let rotating = IntegrityKeys {
+            current: new.clone(),
+            previous: Some(old),
+        };
+        assert!(check_integrity(&req, &rotating).is_ok());
+        assert!(matches!(
+            check_integrity(&req, &IntegrityKeys::new(new)),
+            Err(Error::InvalidIntegrity)
+        ));
+    }
+
+    #[test]
+    fn test_key_redacted() {
+        let keys = IntegrityKeys {
+            current: IntegrityKey::new("s3cret"),
+            previous: None,
+        };
+        let debug = format!("{keys:?}");
+        assert!(!debug.contains("s3cret"));
+        assert!(!debug.contains(&hex::encode("s3cret")));
+    }
+
+    #[test]
+    fn test_key_serde() {
+        let keys = IntegrityKeys {
+            current: IntegrityKey::new("s3cret"),
+            previous: Some(IntegrityKey::new("old")),
+        };
+        let data = postcard::to_stdvec(&keys).unwrap();
+        let decoded: IntegrityKeys = postcard::from_bytes(&data).unwrap();
+        assert_eq!(decoded, keys);
+
+        // postcard is not self-describing, a missing previous key is still encoded.
+        let keys = IntegrityKeys::new(IntegrityKey::new("s3cret"));
+        let data = postcard::to_stdvec(&keys).unwrap();
+        let decoded: IntegrityKeys = postcard::from_bytes(&data).unwrap();
+        assert_eq!(decoded, keys);
+
+        let region = crate::defaults::default_derp_map().regions[&1].clone();
+        assert!(region.stun_integrity.is_none());
+        let data = postcard::to_stdvec(&region).unwrap();
+        let decoded: crate::derp::DerpRegion = postcard::from_bytes(&data).unwrap();
+        assert_eq!(decoded, region);
+
+        let invalid = postcard::to_stdvec("not hex").unwrap();
+        assert!(postcard::from_bytes::<IntegrityKey>(&invalid).is_err());
+    }
+}
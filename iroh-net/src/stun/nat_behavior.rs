@@ -0,0 +1,486 @@
+//! NAT behavior discovery, RFC 5780.
+//!
+//! A STUN server supporting RFC 5780 has a second IP address and port, which it advertises
+//! in the OTHER-ADDRESS attribute of its binding responses.  Sending requests to the
+//! different addresses tells how the NAT maps our endpoint, asking the server to respond
+//! from the different addresses using CHANGE-REQUEST tells how the NAT filters incoming
+//! packets.
+//!
+//! The tests are driven by [`NatBehaviorTest`], which does not do any IO itself.  Use
+//! [`discover`] to run them from a [`UdpSocket`].
+
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::time::{self, Instant};
+use tracing::{debug, trace};
+
+use super::{parse_binding_response, request_with_options, BindingResponse, RequestOptions};
+
+/// How a NAT maps our endpoint to public endpoints, RFC 5780 §4.3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, derive_more::Display)]
+pub enum MappingBehavior {
+    /// The same public endpoint is used for all destinations, or there is no NAT at all.
+    #[display("endpoint independent")]
+    EndpointIndependent,
+    /// The public endpoint depends on the destination IP address.
+    #[display("address dependent")]
+    AddressDependent,
+    /// The public endpoint depends on the destination IP address and port.
+    #[display("address and port dependent")]
+    AddressAndPortDependent,
+}
+
+/// Which incoming packets a NAT lets through to our endpoint, RFC 5780 §4.4.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, derive_more::Display)]
+pub enum FilteringBehavior {
+    /// Packets from any address are let through.
+    #[display("endpoint independent")]
+    EndpointIndependent,
+    /// Only packets from IP addresses we sent to are let through.
+    #[display("address dependent")]
+    AddressDependent,
+    /// Only packets from IP addresses and ports we sent to are let through.
+    #[display("address and port dependent")]
+    AddressAndPortDependent,
+}
+
+/// The result of the RFC 5780 behavior tests.
+///
+/// Either behavior is `None` if its tests were inconclusive, e.g. because responses which
+/// should have arrived were lost.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NatBehavior {
+    /// The mapping behavior of the NAT.
+    pub mapping: Option<MappingBehavior>,
+    /// The filtering behavior of the NAT.
+    pub filtering: Option<FilteringBehavior>,
+}
+
+/// Errors which prevent running the behavior tests.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum NatBehaviorError {
+    /// The server did not answer the initial binding request.
+    #[error("no response from the STUN server")]
+    NoResponse,
+    /// The server does not support RFC 5780, its response had no OTHER-ADDRESS.
+    #[error("STUN server does not support NAT behavior discovery")]
+    Unsupported,
+}
+
+/// A binding request the behavior tests want to send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transaction {
+    /// Where to send the request.
+    pub dst: SocketAddr,
+    /// Ask the server to respond from its other IP address.
+    pub change_ip: bool,
+    /// Ask the server to respond from its other port.
+    pub change_port: bool,
+}
+
+impl Transaction {
+    fn to(dst: SocketAddr) -> Self {
+        Self {
+            dst,
+            change_ip: false,
+            change_port: false,
+        }
+    }
+}
+
+/// The successful response to a [`Transaction`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Binding {
+    /// Our public address as seen by the server.
+    pub mapped: SocketAddr,
+    /// The server's OTHER-ADDRESS, if it included one.
+    pub other_address: Option<SocketAddr>,
+}
+
+/// Drives the RFC 5780 mapping and filtering behavior tests against one server.
+///
+/// The caller performs each [`Transaction`] and reports the response, or `None` if no
+/// response arrived in time.  The mapping tests send to the server's alternate address,
+/// which opens the NAT's filter for it, so the filtering tests must be run from a
+/// different local endpoint.
+#[derive(Debug, Clone)]
+pub struct NatBehaviorTest {
+    server: SocketAddr,
+    local_addr: SocketAddr,
+}
+
+impl NatBehaviorTest {
+    /// Creates the tests against the STUN *server*, sending from *local_addr*.
+    ///
+    /// The *local_addr* is used to detect that there is no NAT at all, it must be the address
+    /// requests are sent from rather than an unspecified address.
+    pub fn new(server: SocketAddr, local_addr: SocketAddr) -> Self {
+        Self { server, local_addr }
+    }
+
+    /// Test I: a plain binding request to the server's primary address.
+    ///
+    /// Returns the response and the server's alternate address.
+    async fn test_one<F, Fut>(
+        &self,
+        transact: &mut F,
+    ) -> Result<(Binding, SocketAddr), NatBehaviorError>
+    where
+        F: FnMut(Transaction) -> Fut,
+        Fut: Future<Output = Option<Binding>>,
+    {
+        let first = transact(Transaction::to(self.server))
+            .await
+            .ok_or(NatBehaviorError::NoResponse)?;
+        let other = first.other_address.ok_or(NatBehaviorError::Unsupported)?;
+        if other.ip() == self.server.ip() || other.port() == self.server.port() {
+            debug!(%other, "OTHER-ADDRESS does not differ in both IP and port");
+            return Err(NatBehaviorError::Unsupported);
+        }
+        Ok((first, other))
+    }
+
+    /// Runs the mapping behavior tests, RFC 5780 §4.3.
+    ///
+    /// Returns `None` if the tests were inconclusive because a response was lost.
+    pub async fn mapping<F, Fut>(
+        &self,
+        mut transact: F,
+    ) -> Result<Option<MappingBehavior>, NatBehaviorError>
+    where
+        F: FnMut(Transaction) -> Fut,
+        Fut: Future<Output = Option<Binding>>,
+    {
+        let (first, other) = self.test_one(&mut transact).await?;
+        if first.mapped == self.local_addr {
+            trace!("no NAT");
+            return Ok(Some(MappingBehavior::EndpointIndependent));
+        }
+
+        // Test II: the alternate IP address with the primary port.
+        let dst = SocketAddr::new(other.ip(), self.server.port());
+        let Some(second) = transact(Transaction::to(dst)).await else {
+            return Ok(None);
+        };
+        if second.mapped == first.mapped {
+            return Ok(Some(MappingBehavior::EndpointIndependent));
+        }
+
+        // Test III: the alternate IP address and port.
+        let Some(third) = transact(Transaction::to(other)).await else {
+            return Ok(None);
+        };
+        if third.mapped == second.mapped {
+            Ok(Some(MappingBehavior::AddressDependent))
+        } else {
+            Ok(Some(MappingBehavior::AddressAndPortDependent))
+        }
+    }
+
+    /// Runs the filtering behavior tests, RFC 5780 §4.4.
+    ///
+    /// Must not use the local endpoint of the mapping tests, see [`NatBehaviorTest`].
+    pub async fn filtering<F, Fut>(
+        &self,
+        mut transact: F,
+    ) -> Result<FilteringBehavior, NatBehaviorError>
+    where
+        F: FnMut(Transaction) -> Fut,
+        Fut: Future<Output = Option<Binding>>,
+    {
+        self.test_one(&mut transact).await?;
+
+        // Test II: respond from the alternate IP address and port.
+        let change_both = Transaction {
+            change_ip: true,
+            change_port: true,
+            ..Transaction::to(self.server)
+        };
+        if transact(change_both).await.is_some() {
+            return Ok(FilteringBehavior::EndpointIndependent);
+        }
+
+        // Test III: respond from the alternate port only.
+        let change_port = Transaction {
+            change_port: true,
+            ..Transaction::to(self.server)
+        };
+        if transact(change_port).await.is_some() {
+            Ok(FilteringBehavior::AddressDependent)
+        } else {
+            Ok(FilteringBehavior::AddressAndPortDependent)
+        }
+    }
+}
+
+/// Runs the RFC 5780 behavior tests against *server* using *sock*.
+///
+/// The socket must not be read by anything else while the tests run, the filtering tests
+/// use another socket bound to the same IP address.  Each transaction waits at most
+/// *timeout* for the response, a lost response makes the filtering behavior look more
+/// restrictive than it is.
+pub async fn discover(
+    sock: &UdpSocket,
+    server: SocketAddr,
+    timeout: Duration,
+) -> Result<NatBehavior, NatBehaviorError> {
+    let local_addr = sock
+        .local_addr()
+        .unwrap_or_else(|_| (Ipv4Addr::UNSPECIFIED, 0).into());
+    let test = NatBehaviorTest::new(server, source_addr(local_addr, server));
+    let mapping = test
+        .mapping(|transaction| transact(sock, transaction, timeout))
+        .await?;
+    let filtering = match UdpSocket::bind(SocketAddr::new(local_addr.ip(), 0)).await {
+        Ok(sock) => test
+            .filtering(|transaction| transact(&sock, transaction, timeout))
+            .await
+            .ok(),
+        Err(err) => {
+            debug!("failed to bind socket for the filtering tests: {err:#}");
+            None
+        }
+    };
+    Ok(NatBehavior { mapping, filtering })
+}
+
+/// Returns the address packets from a socket bound to *local_addr* to *server* are sent
+/// from.
+///
+/// A socket bound to the unspecified address has no IP address of its own, the OS picks the
+/// address of the interface routing to *server*.  This is the local address of a socket
+/// connected to *server*.
+fn source_addr(local_addr: SocketAddr, server: SocketAddr) -> SocketAddr {
+    if !local_addr.ip().is_unspecified() {
+        return local_addr;
+    }
+    let ip = std::net::UdpSocket::bind(SocketAddr::new(local_addr.ip(), 0))
+        .and_then(|sock| {
+            sock.connect(server)?;
+            sock.local_addr()
+        })
+        .map(|addr| addr.ip());
+    match ip {
+        Ok(ip) => SocketAddr::new(ip, local_addr.port()),
+        Err(err) => {
+            debug!(%server, "failed to find the source address: {err:#}");
+            local_addr
+        }
+    }
+}
+
+/// Sends the binding request for *transaction* and waits for its response.
+async fn transact(
+    sock: &UdpSocket,
+    transaction: Transaction,
+    timeout: Duration,
+) -> Option<Binding> {
+    let txid = super::TransactionId::default();
+    let options = RequestOptions {
+        change_ip: transaction.change_ip,
+        change_port: transaction.change_port,
+        ..Default::default()
+    };
+    let req = request_with_options(txid, options);
+    if let Err(err) = sock.send_to(&req, transaction.dst).await {
+        debug!(dst = %transaction.dst, "failed to send binding request: {err:#}");
+        return None;
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0u8; 1500];
+    loop {
+        let (n, src) = time::timeout_at(deadline, sock.recv_from(&mut buf))
+            .await
+            .ok()?
+            .ok()?;
+        match parse_binding_response(&buf[..n]) {
+            Ok(BindingResponse::Success {
+                tx,
+                addr,
+                other_address,
+                ..
+            }) if tx == txid => {
+                trace!(%src, ?transaction, mapped = %addr, "binding response");
+                return Some(Binding {
+                    mapped: addr,
+                    other_address,
+                });
+            }
+            // Late responses of earlier transactions or other garbage.
+            _ => continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVER: &str = "1.0.0.1:3478";
+    const OTHER: &str = "1.0.0.2:3479";
+    const LOCAL: &str = "10.0.0.2:5000";
+
+    /// A simulated NAT in front of the client, with the server at [`SERVER`] and its
+    /// alternate address [`OTHER`].
+    struct SimulatedNat {
+        mapping: MappingBehavior,
+        filtering: FilteringBehavior,
+        other_address: Option<SocketAddr>,
+    }
+
+    impl SimulatedNat {
+        fn new(mapping: MappingBehavior, filtering: FilteringBehavior) -> Self {
+            Self {
+                mapping,
+                filtering,
+                other_address: Some(OTHER.parse().unwrap()),
+            }
+        }
+
+        /// Returns the response a client behind this NAT receives.
+        ///
+        /// *sent* are the destinations the local endpoint sent to so far.
+        fn transact(
+            &self,
+            transaction: Transaction,
+            sent: &mut Vec<SocketAddr>,
+        ) -> Option<Binding> {
+            let dst = transaction.dst;
+            sent.push(dst);
+            let octet = match dst.ip() {
+                std::net::IpAddr::V4(ip) => u16::from(ip.octets()[3]),
+                std::net::IpAddr::V6(_) => unreachable!(),
+            };
+            let mapped_port = match self.mapping {
+                MappingBehavior::EndpointIndependent => 40000,
+                MappingBehavior::AddressDependent => 40000 + octet * 10,
+                MappingBehavior::AddressAndPortDependent => 40000 + octet * 10 + dst.port() % 10,
+            };
+
+            // The address the response is sent from.
+            let other: SocketAddr = OTHER.parse().unwrap();
+            let mut src = dst;
+            if transaction.change_ip {
+                src.set_ip(other.ip());
+            }
+            if transaction.change_port {
+                src.set_port(other.port());
+            }
+            let allowed = match self.filtering {
+                FilteringBehavior::EndpointIndependent => true,
+                FilteringBehavior::AddressDependent => sent.iter().any(|a| a.ip() == src.ip()),
+                FilteringBehavior::AddressAndPortDependent => sent.contains(&src),
+            };
+            allowed.then_some(Binding {
+                mapped: SocketAddr::new("192.0.2.1".parse().unwrap(), mapped_port),
+                other_address: self.other_address,
+            })
+        }
+
+        /// Runs both tests, each from its own local endpoint.
+        async fn run(&self) -> Result<NatBehavior, NatBehaviorError> {
+            let test = NatBehaviorTest::new(SERVER.parse().unwrap(), LOCAL.parse().unwrap());
+            let mut sent = Vec::new();
+            let mapping = test
+                .mapping(|transaction| std::future::ready(self.transact(transaction, &mut sent)))
+                .await?;
+            let mut sent = Vec::new();
+            let filtering = test
+                .filtering(|transaction| std::future::ready(self.transact(transaction, &mut sent)))
+                .await?;
+            Ok(NatBehavior {
+                mapping,
+                filtering: Some(filtering),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_behaviors() {
+        use FilteringBehavior as F;
+        use MappingBehavior as M;
+
+        for mapping in [
+            M::EndpointIndependent,
+            M::AddressDependent,
+            M::AddressAndPortDependent,
+        ] {
+            for filtering in [
+                F::EndpointIndependent,
+                F::AddressDependent,
+                F::AddressAndPortDependent,
+            ] {
+                let behavior = SimulatedNat::new(mapping, filtering).run().await.unwrap();
+                assert_eq!(
+                    behavior,
+                    NatBehavior {
+                        mapping: Some(mapping),
+                        filtering: Some(filtering),
+                    },
+                    "mapping {mapping}, filtering {filtering}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_no_nat() {
+        let test = NatBehaviorTest::new(SERVER.parse().unwrap(), LOCAL.parse().unwrap());
+        let binding = Binding {
+            mapped: LOCAL.parse().unwrap(),
+            other_address: Some(OTHER.parse().unwrap()),
+        };
+        let mapping = test
+            .mapping(|_| std::future::ready(Some(binding)))
+            .await
+            .unwrap();
+        assert_eq!(mapping, Some(MappingBehavior::EndpointIndependent));
+    }
+
+    #[tokio::test]
+    async fn test_unsupported() {
+        let mut nat = SimulatedNat::new(
+            MappingBehavior::EndpointIndependent,
+            FilteringBehavior::EndpointIndependent,
+        );
+        nat.other_address = None;
+        assert_eq!(nat.run().await, Err(NatBehaviorError::Unsupported));
+    }
+
+    #[tokio::test]
+    async fn test_no_response() {
+        let test = NatBehaviorTest::new(SERVER.parse().unwrap(), LOCAL.parse().unwrap());
+        let res = test.mapping(|_| std::future::ready(None)).await;
+        assert_eq!(res, Err(NatBehaviorError::NoResponse));
+    }
+
+    #[test]
+    fn test_source_addr() {
+        let server: SocketAddr = "127.0.0.1:3478".parse().unwrap();
+        let bound: SocketAddr = "127.0.0.2:5000".parse().unwrap();
+        assert_eq!(source_addr(bound, server), bound);
+        let unspecified: SocketAddr = "0.0.0.0:5000".parse().unwrap();
+        assert_eq!(
+            source_addr(unspecified, server),
+            "127.0.0.1:5000".parse().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_discover_unsupported_server() {
+        // The embedded responder does not implement RFC 5780.
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let task = super::super::serve(server);
+
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let res = discover(&sock, server_addr, Duration::from_secs(5)).await;
+        assert_eq!(res, Err(NatBehaviorError::Unsupported));
+        task.abort();
+    }
+}
@@ -168,6 +168,7 @@ pub(crate) async fn run_derp_and_stun(
                     stun_test_ip: Some(stun_addr.ip()),
                 }],
                 avoid: false,
+                stun_integrity: None,
             },
         )]
         .into_iter()